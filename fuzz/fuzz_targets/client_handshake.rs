@@ -0,0 +1,37 @@
+#![no_main]
+//! ClientSession fed with hostile Welcome and Ready. Attacker owns server
+//! identity key, so fuzzer input lands inside the Welcome box.
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libwhisper;
+extern crate sodiumoxide;
+
+use libwhisper::crypto::KeyPair;
+use libwhisper::frame::{Frame, FrameKind};
+use libwhisper::session::ClientSession;
+use sodiumoxide::crypto::box_;
+
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+    // First byte decides where Welcome payload ends and Ready frame begins.
+    let split = (data[0] as usize).min(data.len() - 1) + 1;
+    let (welcome_payload, ready) = data[1..].split_at(split - 1);
+
+    let attacker = KeyPair::new().unwrap();
+    let mut client = ClientSession::new(KeyPair::new().unwrap(), attacker.public_key).unwrap();
+    let hello = client.make_hello().unwrap();
+
+    let nonce = box_::gen_nonce();
+    let welcome = Frame {
+        id: hello.id,
+        nonce: nonce,
+        kind: FrameKind::Welcome,
+        payload: box_::seal(welcome_payload, &nonce, &hello.id, &attacker.secret_key).into(),
+    };
+    let _ = client.make_initiate(&welcome);
+    if let Ok(ready) = Frame::from_slice(ready) {
+        let _ = client.read_ready(&ready);
+    }
+});
@@ -0,0 +1,20 @@
+#![no_main]
+//! Arbitrary bytes fed to every frame parser. Anything that parses must pack
+//! back to exactly the bytes it was parsed from.
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libwhisper;
+
+use libwhisper::frame::Frame;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(frame) = Frame::from_slice(data) {
+        assert_eq!(frame.pack().as_ref(), data);
+    }
+    if let Ok((version, frame)) = Frame::from_slice_any(data) {
+        assert_eq!(frame.pack_as(version).as_ref(), data);
+    }
+    if let Ok((frame, used)) = Frame::from_slice_with_length(data) {
+        assert_eq!(frame.pack_with_length().as_ref(), &data[..used]);
+    }
+});
@@ -0,0 +1,23 @@
+#![no_main]
+//! Arbitrary frames pushed through stream framing in chunks of arbitrary
+//! size. Whatever goes in must come out of `FrameDecoder` unchanged.
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libwhisper;
+
+use libwhisper::frame::{Frame, FrameDecoder};
+
+fuzz_target!(|input: (Vec<Frame>, u8)| {
+    let (frames, chunk_size) = input;
+    let mut stream = Vec::new();
+    for frame in &frames {
+        stream.extend_from_slice(&frame.pack_with_length());
+    }
+    let mut decoder = FrameDecoder::new();
+    let mut decoded = Vec::with_capacity(frames.len());
+    for chunk in stream.chunks(chunk_size as usize + 1) {
+        decoded.extend(decoder.feed(chunk).expect("Packed frames must decode"));
+    }
+    assert_eq!(decoded, frames);
+    assert_eq!(decoder.buffered(), 0);
+});
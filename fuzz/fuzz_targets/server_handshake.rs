@@ -0,0 +1,64 @@
+#![no_main]
+//! ServerSession fed with attacker controlled Hello and Initiate. Attacker
+//! owns client session key, so fuzzer input lands inside the boxes and
+//! reaches payload parsing instead of dying on decryption.
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libwhisper;
+extern crate sodiumoxide;
+
+use libwhisper::crypto::KeyPair;
+use libwhisper::frame::{Frame, FrameKind};
+use libwhisper::session::ServerSession;
+use sodiumoxide::crypto::box_;
+
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+    // First byte decides where Hello payload ends and Initiate payload begins.
+    let split = (data[0] as usize).min(data.len() - 1) + 1;
+    let (hello_payload, initiate_payload) = data[1..].split_at(split - 1);
+
+    let server_identity = KeyPair::new().unwrap();
+    let attacker = KeyPair::new().unwrap();
+    let mut server = ServerSession::new(server_identity.clone(), attacker.public_key).unwrap();
+
+    let nonce = box_::gen_nonce();
+    let hello = Frame {
+        id: attacker.public_key,
+        nonce: nonce,
+        kind: FrameKind::Hello,
+        payload: box_::seal(hello_payload,
+                            &nonce,
+                            &server_identity.public_key,
+                            &attacker.secret_key)
+                .into(),
+    };
+    let welcome = match server.make_welcome(&hello) {
+        Ok(welcome) => welcome,
+        Err(_) => return,
+    };
+    let server_session_key = box_::open(&welcome.payload,
+                                        &welcome.nonce,
+                                        &server_identity.public_key,
+                                        &attacker.secret_key)
+            .ok()
+            .and_then(|key| box_::PublicKey::from_slice(&key))
+            .expect("Server sent broken Welcome");
+
+    let nonce = box_::gen_nonce();
+    let initiate = Frame {
+        id: attacker.public_key,
+        nonce: nonce,
+        kind: FrameKind::Initiate,
+        payload: box_::seal(initiate_payload,
+                            &nonce,
+                            &server_session_key,
+                            &attacker.secret_key)
+                .into(),
+    };
+    if let Ok(client_identity) = server.validate_initiate(&initiate) {
+        let _ = server.make_ready(&initiate, &client_identity);
+    }
+});
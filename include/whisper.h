@@ -0,0 +1,115 @@
+/*
+ * C interface of libwhisper. Build with:
+ *
+ *     cargo rustc --release --features ffi --crate-type cdylib
+ *
+ * Functions return WHISPER_OK, a negative code below, or positive error
+ * code of WhisperError. Library panics come back as WHISPER_PANIC (or NULL
+ * from whisper_client_new). Keys are 32 bytes, nonces 24. Frames are
+ * packed without length prefix. Buffers returned by the library must be
+ * released with whisper_buffer_free, handles with their own *_free.
+ */
+
+#ifndef WHISPER_H
+#define WHISPER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define WHISPER_OK 0
+#define WHISPER_NULL_POINTER -1
+#define WHISPER_PANIC -2
+
+#define WHISPER_KIND_HELLO 1
+#define WHISPER_KIND_WELCOME 2
+#define WHISPER_KIND_INITIATE 3
+#define WHISPER_KIND_READY 4
+#define WHISPER_KIND_REQUEST 5
+#define WHISPER_KIND_RESPONSE 6
+#define WHISPER_KIND_NOTIFICATION 7
+#define WHISPER_KIND_CONTROL 8
+#define WHISPER_KIND_RESUME 9
+#define WHISPER_KIND_TERMINATION 255
+
+typedef struct WhisperClient WhisperClient;
+typedef struct WhisperServer WhisperServer;
+typedef struct WhisperSession WhisperSession;
+
+typedef struct {
+    uint8_t *data;
+    size_t len;
+} WhisperBuffer;
+
+void whisper_buffer_free(WhisperBuffer buffer);
+
+int whisper_init(void);
+int whisper_keypair_generate(uint8_t public_key[32], uint8_t secret_key[32]);
+
+/* Client side of the handshake. */
+WhisperClient *whisper_client_new(const uint8_t identity_public_key[32],
+                                  const uint8_t identity_secret_key[32],
+                                  const uint8_t server_public_key[32]);
+void whisper_client_free(WhisperClient *client);
+int whisper_client_hello(WhisperClient *client, WhisperBuffer *hello);
+int whisper_client_initiate(WhisperClient *client,
+                            const uint8_t *welcome, size_t welcome_len,
+                            WhisperBuffer *initiate);
+int whisper_client_ready(WhisperClient *client,
+                         const uint8_t *ready, size_t ready_len,
+                         WhisperSession **session);
+
+/* Server side of the handshake. */
+int whisper_server_new(const uint8_t identity_public_key[32],
+                       const uint8_t identity_secret_key[32],
+                       const uint8_t *hello, size_t hello_len,
+                       WhisperServer **server);
+void whisper_server_free(WhisperServer *server);
+int whisper_server_welcome(WhisperServer *server,
+                           const uint8_t *hello, size_t hello_len,
+                           WhisperBuffer *welcome);
+int whisper_server_validate_initiate(WhisperServer *server,
+                                     const uint8_t *initiate, size_t initiate_len,
+                                     uint8_t client_key[32]);
+int whisper_server_ready(WhisperServer *server,
+                         const uint8_t *initiate, size_t initiate_len,
+                         const uint8_t client_key[32],
+                         WhisperSession **session,
+                         WhisperBuffer *ready);
+
+/* Established session. */
+void whisper_session_free(WhisperSession *session);
+int whisper_session_request(const WhisperSession *session,
+                            const uint8_t *data, size_t len,
+                            WhisperBuffer *request);
+int whisper_session_response(const WhisperSession *session,
+                             const uint8_t *data, size_t len,
+                             WhisperBuffer *response);
+int whisper_session_notification(const WhisperSession *session,
+                                 const uint8_t *data, size_t len,
+                                 WhisperBuffer *notification);
+int whisper_session_read(const WhisperSession *session,
+                         const uint8_t *packed, size_t packed_len,
+                         uint8_t *kind,
+                         WhisperBuffer *payload);
+
+/* Frames. */
+int whisper_frame_pack(const uint8_t id[32],
+                       const uint8_t nonce[24],
+                       uint8_t kind,
+                       const uint8_t *payload, size_t payload_len,
+                       WhisperBuffer *packed);
+int whisper_frame_parse(const uint8_t *packed, size_t packed_len,
+                        uint8_t id[32],
+                        uint8_t nonce[24],
+                        uint8_t *kind,
+                        size_t *payload_offset);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WHISPER_H */
@@ -0,0 +1,66 @@
+//! Remote attestation hook. Client can carry a device attestation blob (TPM
+//! quote, secure-boot measurement, etc.) in Initiate metadata. Server consults
+//! `AttestationVerifier` before it sends Ready, so sessions can be gated on
+//! device health and not just on possession of a key.
+//!
+//! This library doesn't know anything about attestation formats — the blob
+//! is passed to the verifier as is.
+//!
+//! Blob has to be bound to the handshake, or one recorded quote would do
+//! for every session. Client's `Attester` gets a challenge: hash of both
+//! short term keys and both identity keys of this handshake. Short term keys
+//! are fresh for every handshake, so the challenge is too. Attester puts it
+//! into the quote (e.g. as TPM quote nonce), and verifier gets the same
+//! challenge to check the quote against.
+
+use sodiumoxide::crypto::box_::PublicKey;
+use sodiumoxide::crypto::hash::sha256;
+use vouch::VouchKeys;
+
+// So challenge can't be confused with hashes made for other purposes.
+const CONTEXT: &[u8] = b"whisper attestation";
+
+/// Client side source of attestation blobs.
+pub trait Attester: Send + Sync {
+    /// Attestation blob for the handshake `challenge` stands for.
+    fn attest(&self, challenge: &[u8]) -> Vec<u8>;
+}
+
+impl<F> Attester for F
+    where F: Fn(&[u8]) -> Vec<u8> + Send + Sync
+{
+    fn attest(&self, challenge: &[u8]) -> Vec<u8> { self(challenge) }
+}
+
+/// Server side check of device attestation.
+pub trait AttestationVerifier: Send + Sync {
+    /// Returns true if client is allowed to proceed. `attestation` is `None`
+    /// when client didn't send any. Attestation must cover `challenge`.
+    fn verify(&self,
+              client_identity_key: &PublicKey,
+              challenge: &[u8],
+              attestation: Option<&[u8]>)
+              -> bool;
+}
+
+/// Challenge attestation of this handshake must cover.
+pub fn challenge(keys: &VouchKeys) -> [u8; 32] {
+    let mut input = Vec::with_capacity(CONTEXT.len() + 128);
+    input.extend_from_slice(CONTEXT);
+    input.extend_from_slice(&keys.client_session_key.0);
+    input.extend_from_slice(&keys.server_session_key.0);
+    input.extend_from_slice(&keys.server_identity_key.0);
+    input.extend_from_slice(&keys.client_identity_key.0);
+    sha256::hash(&input).0
+}
+
+/// Verifier that only checks that attestation is present, and doesn't look
+/// at the challenge. Useful for tests and as a starting point.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequireAttestation;
+
+impl AttestationVerifier for RequireAttestation {
+    fn verify(&self, _: &PublicKey, _: &[u8], attestation: Option<&[u8]>) -> bool {
+        attestation.is_some_and(|blob| !blob.is_empty())
+    }
+}
@@ -0,0 +1,118 @@
+//! Client authorization hook. Handshake proves client owns its identity
+//! key, `Authenticator` decides whether that key may have a session.
+//! `ServerSession::make_ready` consults it (see
+//! `ServerSession::set_authenticator`); denied client gets no Ready and
+//! `make_ready` fails with `InvalidPublicKey`.
+
+use sodiumoxide::crypto::box_::PublicKey;
+use std::collections::HashSet;
+use std::iter::FromIterator;
+
+/// Verdict of `Authenticator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthDecision {
+    /// Client may proceed.
+    Allow,
+    /// Client is refused.
+    Deny,
+}
+
+/// Server side check of client identity key.
+pub trait Authenticator: Send + Sync {
+    /// Decide on client with this identity key.
+    fn authenticate(&self, client_identity_key: &PublicKey) -> AuthDecision;
+}
+
+impl<F: Fn(&PublicKey) -> AuthDecision + Send + Sync> Authenticator for F {
+    fn authenticate(&self, client_identity_key: &PublicKey) -> AuthDecision {
+        self(client_identity_key)
+    }
+}
+
+/// Lets everyone in. Same as having no authenticator.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, _: &PublicKey) -> AuthDecision { AuthDecision::Allow }
+}
+
+/// Lets in only keys it was given.
+#[derive(Debug, Clone, Default)]
+pub struct Whitelist {
+    keys: HashSet<PublicKey>,
+}
+
+impl Whitelist {
+    /// Empty whitelist. Denies everyone.
+    pub fn new() -> Whitelist { Whitelist::default() }
+
+    /// Allow client with this identity key.
+    pub fn allow(&mut self, client_identity_key: PublicKey) {
+        self.keys.insert(client_identity_key);
+    }
+
+    /// Stop allowing this key. Sessions that already exist aren't touched.
+    pub fn revoke(&mut self, client_identity_key: &PublicKey) {
+        self.keys.remove(client_identity_key);
+    }
+}
+
+impl FromIterator<PublicKey> for Whitelist {
+    fn from_iter<I: IntoIterator<Item = PublicKey>>(keys: I) -> Whitelist {
+        Whitelist { keys: keys.into_iter().collect() }
+    }
+}
+
+impl Authenticator for Whitelist {
+    fn authenticate(&self, client_identity_key: &PublicKey) -> AuthDecision {
+        if self.keys.contains(client_identity_key) {
+            AuthDecision::Allow
+        } else {
+            AuthDecision::Deny
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::KeyPair;
+    use errors::WhisperError;
+    use session::{ClientSession, ServerSession, SessionState};
+    use std::sync::Arc;
+
+    fn handshake_with(authenticator: Arc<dyn Authenticator>, client: &KeyPair) -> ServerSession {
+        let server = KeyPair::new().unwrap();
+        let mut client_session = ClientSession::new(client.clone(), server.public_key).unwrap();
+        let hello = client_session.make_hello().unwrap();
+        let mut server_session = ServerSession::from_hello(server, &hello).unwrap();
+        server_session.set_authenticator(authenticator);
+        let welcome = server_session.make_welcome(&hello).unwrap();
+        let initiate = client_session.make_initiate(&welcome).unwrap();
+        let key = server_session.validate_initiate(&initiate).unwrap();
+        match server_session.make_ready(&initiate, &key) {
+            Ok(_) => assert_eq!(server_session.info().state, SessionState::Ready),
+            Err(WhisperError::InvalidPublicKey) => {
+                assert_eq!(server_session.info().state, SessionState::Error)
+            }
+            Err(err) => panic!("Unexpected error {:?}", err),
+        }
+        server_session
+    }
+
+    #[test]
+    fn authenticator_gates_ready() {
+        let known = KeyPair::new().unwrap();
+        let stranger = KeyPair::new().unwrap();
+        let whitelist: Whitelist = vec![known.public_key].into_iter().collect();
+        let whitelist = Arc::new(whitelist);
+
+        let ready = |session: ServerSession| session.info().state == SessionState::Ready;
+        assert!(ready(handshake_with(whitelist.clone(), &known)));
+        assert!(!ready(handshake_with(whitelist, &stranger)));
+        assert!(ready(handshake_with(Arc::new(AllowAll), &stranger)));
+        let deny_all = |_: &PublicKey| AuthDecision::Deny;
+        assert!(!ready(handshake_with(Arc::new(deny_all), &known)));
+    }
+}
@@ -0,0 +1,53 @@
+//! Known answer vectors of Angel Whisper Wire Protocol. See `vectors`
+//! module.
+//!
+//! - `whisper-vectors` or `whisper-vectors json`: transcript as JSON.
+//! - `whisper-vectors hex`: packed frames as `name hex` lines.
+//! - `whisper-vectors verify FILE`: check `name hex` lines made by other
+//!   implementation. Exits with 1 on mismatch.
+//!
+//! Built with `null-cipher` feature it only says so and exits with 2.
+extern crate libwhisper;
+
+#[cfg(not(feature = "null-cipher"))]
+use libwhisper::vectors;
+#[cfg(not(feature = "null-cipher"))]
+use std::env;
+#[cfg(not(feature = "null-cipher"))]
+use std::fs;
+use std::process;
+
+#[cfg(feature = "null-cipher")]
+fn main() {
+    eprintln!("whisper-vectors is built with null-cipher feature, its frames aren't real");
+    process::exit(2);
+}
+
+#[cfg(not(feature = "null-cipher"))]
+fn main() {
+    let transcript = vectors::transcript();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None | Some("json") => print!("{}", transcript.to_json()),
+        Some("hex") => print!("{}", transcript.to_hex_lines()),
+        Some("verify") if args.len() == 2 => {
+            let text = fs::read_to_string(&args[1]).unwrap_or_else(|e| {
+                eprintln!("Can't read {}: {}", args[1], e);
+                process::exit(2)
+            });
+            let mismatches = transcript.verify_lines(&text);
+            if mismatches.is_empty() {
+                println!("All {} vectors match", transcript.vectors.len());
+                return;
+            }
+            for mismatch in &mismatches {
+                println!("{}", mismatch);
+            }
+            process::exit(1);
+        }
+        _ => {
+            eprintln!("Usage: whisper-vectors [json | hex | verify FILE]");
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Source of current time for session expiry and handshake deadlines.
+//! Sessions use `SystemClock` unless given another one with `set_clock`;
+//! `MockClock` only moves when told to, so expiry can be tested without
+//! sleeping, and devices without wall clock can plug in whatever they
+//! have.
+//!
+//! Established session gets clock of the handshake session that made it,
+//! shifted by server time offset if client adopted it.
+
+use chrono::{DateTime, Duration, Utc};
+use std::sync::{Arc, Mutex};
+
+/// Tells current time.
+pub trait Clock: Send + Sync {
+    /// Current time.
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub(crate) type SharedClock = Arc<dyn Clock>;
+
+pub(crate) fn system() -> SharedClock { Arc::new(SystemClock) }
+
+/// Clock that is `offset` ahead of `inner`. Client that adopted server
+/// time hands it to established session.
+pub(crate) fn offset(inner: SharedClock, offset: Duration) -> SharedClock {
+    if offset == Duration::zero() {
+        inner
+    } else {
+        Arc::new(OffsetClock { inner, offset })
+    }
+}
+
+struct OffsetClock {
+    inner: SharedClock,
+    offset: Duration,
+}
+
+impl Clock for OffsetClock {
+    fn now(&self) -> DateTime<Utc> { self.inner.now() + self.offset }
+}
+
+/// Wall clock of the machine.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> { Utc::now() }
+}
+
+/// Clock that stands still until moved. Cheap to clone, clones share time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl MockClock {
+    /// Clock stopped at this time.
+    pub fn new(now: DateTime<Utc>) -> MockClock { MockClock { now: Arc::new(Mutex::new(now)) } }
+
+    /// Move clock forward, or back with negative duration.
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().expect("Clock lock poisoned");
+        *now = *now + by;
+    }
+
+    /// Set clock to this time.
+    pub fn set(&self, now: DateTime<Utc>) { *self.now.lock().expect("Clock lock poisoned") = now; }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> { *self.now.lock().expect("Clock lock poisoned") }
+}
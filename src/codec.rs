@@ -0,0 +1,170 @@
+//! Structured values on top of byte payloads. Wire stays format agnostic:
+//! `PayloadCodec` turns values into bytes and back, and `CodecRegistry`
+//! picks codec by content type hint (see `content` module), so peers can
+//! use different formats for the same value type.
+//!
+//! JSON and CBOR codecs for serde types are behind `json` and `cbor`
+//! features.
+
+use bytes::Bytes;
+use content::{self, ContentType};
+use errors::{WhisperError, WhisperResult};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Turns values of `T` into payload bytes and back.
+pub trait PayloadCodec<T>: Send + Sync {
+    /// Content type this codec produces.
+    fn content_type(&self) -> ContentType;
+    /// Value to bytes.
+    fn encode(&self, value: &T) -> WhisperResult<Bytes>;
+    /// Bytes to value.
+    fn decode(&self, data: &[u8]) -> WhisperResult<T>;
+}
+
+/// Codecs for one value type by content type. Outgoing values use the
+/// preferred codec, incoming ones whatever their hint says.
+pub struct CodecRegistry<T> {
+    codecs: HashMap<ContentType, Box<dyn PayloadCodec<T>>>,
+    preferred: ContentType,
+}
+
+impl<T> CodecRegistry<T> {
+    /// Registry with one codec that is also preferred.
+    pub fn new<C: PayloadCodec<T> + 'static>(codec: C) -> CodecRegistry<T> {
+        let preferred = codec.content_type();
+        let mut codecs: HashMap<ContentType, Box<dyn PayloadCodec<T>>> = HashMap::new();
+        codecs.insert(preferred, Box::new(codec));
+        CodecRegistry { codecs, preferred }
+    }
+
+    /// Add codec for incoming payloads. Replaces codec for the same content
+    /// type.
+    pub fn register<C: PayloadCodec<T> + 'static>(&mut self, codec: C) {
+        self.codecs.insert(codec.content_type(), Box::new(codec));
+    }
+
+    /// Use codec for this content type for outgoing values. Returns false
+    /// if there is no such codec.
+    pub fn prefer(&mut self, content_type: ContentType) -> bool {
+        if self.codecs.contains_key(&content_type) {
+            self.preferred = content_type;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Content type of outgoing values.
+    pub fn preferred(&self) -> ContentType { self.preferred }
+
+    /// Encode value with preferred codec and prefix it with content type.
+    /// Pass result to `make_request` and friends.
+    pub fn encode(&self, value: &T) -> WhisperResult<Bytes> {
+        let data = self.codecs[&self.preferred].encode(value)?;
+        Ok(content::wrap(self.preferred, &data).freeze())
+    }
+
+    /// Decode opened payload with codec its content type asks for.
+    pub fn decode(&self, payload: &Bytes) -> WhisperResult<T> {
+        let (content_type, data) = content::unwrap(payload)?;
+        match self.codecs.get(&content_type) {
+            Some(codec) => codec.decode(&data),
+            None => Err(WhisperError::CodecFailed(format!("No codec for {}", content_type.mime()))),
+        }
+    }
+}
+
+impl<T> fmt::Debug for CodecRegistry<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut content_types: Vec<_> = self.codecs.keys().map(|ct| ct.mime()).collect();
+        content_types.sort_unstable();
+        f.debug_struct("CodecRegistry")
+         .field("codecs", &content_types)
+         .field("preferred", &self.preferred)
+         .finish()
+    }
+}
+
+/// JSON for any serde type.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl<T: ::serde::Serialize + ::serde::de::DeserializeOwned> PayloadCodec<T> for JsonCodec {
+    fn content_type(&self) -> ContentType { ContentType::Json }
+
+    fn encode(&self, value: &T) -> WhisperResult<Bytes> {
+        ::serde_json::to_vec(value).map(Bytes::from)
+                                   .map_err(|e| WhisperError::CodecFailed(e.to_string()))
+    }
+
+    fn decode(&self, data: &[u8]) -> WhisperResult<T> {
+        ::serde_json::from_slice(data).map_err(|e| WhisperError::CodecFailed(e.to_string()))
+    }
+}
+
+/// CBOR for any serde type.
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl<T: ::serde::Serialize + ::serde::de::DeserializeOwned> PayloadCodec<T> for CborCodec {
+    fn content_type(&self) -> ContentType { ContentType::Cbor }
+
+    fn encode(&self, value: &T) -> WhisperResult<Bytes> {
+        let mut buf = Vec::new();
+        ::ciborium::into_writer(value, &mut buf)
+            .map_err(|e| WhisperError::CodecFailed(e.to_string()))?;
+        Ok(buf.into())
+    }
+
+    fn decode(&self, data: &[u8]) -> WhisperResult<T> {
+        ::ciborium::from_reader(data).map_err(|e| WhisperError::CodecFailed(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Utf8;
+
+    impl PayloadCodec<String> for Utf8 {
+        fn content_type(&self) -> ContentType { ContentType::Raw }
+        fn encode(&self, value: &String) -> WhisperResult<Bytes> { Ok(value.clone().into()) }
+        fn decode(&self, data: &[u8]) -> WhisperResult<String> {
+            String::from_utf8(data.to_vec()).map_err(|e| WhisperError::CodecFailed(e.to_string()))
+        }
+    }
+
+    #[test]
+    fn registry_picks_codec_by_hint() {
+        let registry = CodecRegistry::new(Utf8);
+        let payload = registry.encode(&"hi".to_owned()).unwrap();
+        assert_eq!(payload.as_ref(), b"\x00hi");
+        assert_eq!(registry.decode(&payload).unwrap(), "hi");
+        let json = content::wrap(ContentType::Json, b"\"hi\"").freeze();
+        match registry.decode(&json) {
+            Err(WhisperError::CodecFailed(_)) => {}
+            other => panic!("Expected CodecFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "json", feature = "cbor"))]
+    fn json_and_cbor() {
+        let mut registry: CodecRegistry<Vec<u32>> = CodecRegistry::new(JsonCodec);
+        registry.register(CborCodec);
+        let value = vec![1, 2, 3];
+        let json = registry.encode(&value).unwrap();
+        assert_eq!(&json[1..], b"[1,2,3]");
+        assert!(registry.prefer(ContentType::Cbor));
+        assert!(!registry.prefer(ContentType::Protobuf));
+        let cbor = registry.encode(&value).unwrap();
+        assert_eq!(registry.decode(&json).unwrap(), value);
+        assert_eq!(registry.decode(&cbor).unwrap(), value);
+    }
+}
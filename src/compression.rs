@@ -0,0 +1,227 @@
+//! Streaming compression of payloads. Unlike compressing every payload on
+//! its own, one zstd stream spans the whole session, so repetitive payloads
+//! (telemetry!) after the first few shrink to a handful of bytes. Optional
+//! shared dictionary helps the first few too.
+//!
+//! ### Negotiation
+//! Client offers dictionary id with `ClientSession::offer_compression`,
+//! server lists ids it has with `ServerSession::accept_compression`. If
+//! they match, `EstablishedSession::compression` returns the id on both
+//! sides, and each side makes `Compressor` for its writes and
+//! `Decompressor` for its reads with dictionary of that id. Id zero means
+//! no dictionary. What id means which dictionary is up to the application.
+//!
+//! Stream only works if every compressed payload is decompressed exactly
+//! once and in order, so it's never agreed on in datagram mode.
+//! Compression is applied to payload before sealing it, and can leak
+//! plaintext through sizes when attacker controls part of it.
+//!
+//! ### Per payload compression
+//! Simpler alternative that works in both transport modes and needs
+//! nothing from the application. Client offers it with
+//! `ClientSession::offer_payload_compression`, server takes it with
+//! `ServerSession::accept_payload_compression`. It's offered in the same
+//! metadata entry as streaming compression, so client offers one or the
+//! other and the last offer wins. Once agreed, established
+//! session compresses every payload on its own in `make_*` and
+//! decompresses it in `read_msg`. Sealed payload starts with flag byte:
+//! `RAW` if payload was left as is (short or incompressible ones), `ZSTD`
+//! if it was compressed. Peers that didn't agree never see the flag.
+
+use bytes::Bytes;
+use errors::{WhisperError, WhisperResult};
+use std::fmt;
+use std::io::{self, Read};
+use transport::MAX_FRAME_SIZE;
+use zstd;
+use zstd::stream::raw::{Decoder, Encoder, InBuffer, Operation, OutBuffer};
+
+/// zstd level used by `Compressor`.
+pub const LEVEL: i32 = 3;
+/// Flag of payload sent as is.
+pub const RAW: u8 = 0;
+/// Flag of zstd compressed payload.
+pub const ZSTD: u8 = 1;
+/// Payloads shorter than this aren't worth compressing.
+pub const MIN_COMPRESSED_SIZE: usize = 64;
+
+// How much output buffer grows at a time.
+const CHUNK: usize = 4096;
+
+/// Compresses outgoing payloads of one session.
+pub struct Compressor {
+    encoder: Encoder<'static>,
+}
+
+impl Compressor {
+    /// Start stream. Pass empty dictionary for none.
+    pub fn new(dictionary: &[u8]) -> WhisperResult<Compressor> {
+        let encoder = if dictionary.is_empty() {
+            Encoder::new(LEVEL)
+        } else {
+            Encoder::with_dictionary(LEVEL, dictionary)
+        };
+        Ok(Compressor { encoder: encoder.map_err(failed)? })
+    }
+
+    /// Compress next payload. Result can only be decompressed after every
+    /// payload compressed before it.
+    pub fn compress(&mut self, data: &[u8]) -> WhisperResult<Bytes> {
+        let mut out = Vec::with_capacity(CHUNK);
+        let mut input = InBuffer::around(data);
+        while input.pos() < data.len() {
+            out.reserve(CHUNK);
+            let pos = out.len();
+            self.encoder.run(&mut input, &mut OutBuffer::around_pos(&mut out, pos))
+                .map_err(failed)?;
+        }
+        // Flush, so peer can decompress it right away.
+        loop {
+            out.reserve(CHUNK);
+            let pos = out.len();
+            let remaining = self.encoder.flush(&mut OutBuffer::around_pos(&mut out, pos))
+                .map_err(failed)?;
+            if remaining == 0 {
+                return Ok(out.into());
+            }
+        }
+    }
+}
+
+/// Decompresses incoming payloads of one session.
+pub struct Decompressor {
+    decoder: Decoder<'static>,
+}
+
+impl Decompressor {
+    /// Start stream. Dictionary must be the same as peer's one.
+    pub fn new(dictionary: &[u8]) -> WhisperResult<Decompressor> {
+        let decoder = if dictionary.is_empty() {
+            Decoder::new()
+        } else {
+            Decoder::with_dictionary(dictionary)
+        };
+        Ok(Decompressor { decoder: decoder.map_err(failed)? })
+    }
+
+    /// Decompress next payload. Refuses to inflate anything beyond
+    /// `MAX_FRAME_SIZE`. Stream is broken after error.
+    pub fn decompress(&mut self, data: &[u8]) -> WhisperResult<Bytes> {
+        let mut out = Vec::with_capacity(CHUNK);
+        let mut input = InBuffer::around(data);
+        loop {
+            if out.len() > MAX_FRAME_SIZE {
+                return Err(WhisperError::CompressionFailed("Payload is too large".to_owned()));
+            }
+            out.reserve(CHUNK);
+            let pos = out.len();
+            let mut output = OutBuffer::around_pos(&mut out, pos);
+            self.decoder.run(&mut input, &mut output).map_err(failed)?;
+            // Full output buffer means decoder might have more for us.
+            if input.pos() == data.len() && output.pos() < output.capacity() {
+                break;
+            }
+        }
+        Ok(out.into())
+    }
+}
+
+impl fmt::Debug for Compressor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Compressor") }
+}
+
+impl fmt::Debug for Decompressor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Decompressor") }
+}
+
+// Flag and payload, compressed if that makes it shorter.
+pub(crate) fn pack(data: &[u8]) -> Bytes {
+    if data.len() >= MIN_COMPRESSED_SIZE {
+        if let Ok(compressed) = zstd::bulk::compress(data, LEVEL) {
+            if compressed.len() < data.len() {
+                return flagged(ZSTD, &compressed);
+            }
+        }
+    }
+    flagged(RAW, data)
+}
+
+// Inverse of `pack`. Refuses to inflate anything beyond `MAX_FRAME_SIZE`.
+// Output grows as data is decoded, nothing is allocated up front.
+pub(crate) fn unpack(payload: &[u8]) -> WhisperResult<Vec<u8>> {
+    match payload.split_first() {
+        Some((&RAW, data)) => Ok(data.to_vec()),
+        Some((&ZSTD, data)) => {
+            let decoder = zstd::stream::read::Decoder::new(data).map_err(failed)?;
+            let mut out = Vec::new();
+            decoder.take(MAX_FRAME_SIZE as u64 + 1).read_to_end(&mut out).map_err(failed)?;
+            if out.len() > MAX_FRAME_SIZE {
+                return Err(WhisperError::CompressionFailed("Payload is too large".to_owned()));
+            }
+            Ok(out)
+        }
+        _ => Err(WhisperError::CompressionFailed("Unknown payload flag".to_owned())),
+    }
+}
+
+fn flagged(flag: u8, data: &[u8]) -> Bytes {
+    let mut payload = Vec::with_capacity(1 + data.len());
+    payload.push(flag);
+    payload.extend_from_slice(data);
+    payload.into()
+}
+
+fn failed(err: io::Error) -> WhisperError { WhisperError::CompressionFailed(err.to_string()) }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stream_gets_better_with_time() {
+        let mut compressor = Compressor::new(&[]).unwrap();
+        let mut decompressor = Decompressor::new(&[]).unwrap();
+        let reading = b"{\"sensor\":\"kitchen\",\"temperature\":21.5,\"humidity\":40}";
+        let first = compressor.compress(reading).unwrap();
+        let second = compressor.compress(reading).unwrap();
+        assert!(second.len() < first.len() / 2);
+        assert_eq!(decompressor.decompress(&first).unwrap().as_ref(), &reading[..]);
+        assert_eq!(decompressor.decompress(&second).unwrap().as_ref(), &reading[..]);
+
+        // Large payload that takes several rounds of output.
+        let big = vec![7; CHUNK * 10];
+        let compressed = compressor.compress(&big).unwrap();
+        assert_eq!(decompressor.decompress(&compressed).unwrap().as_ref(), &big[..]);
+
+        // Out of order payload doesn't make sense without the ones before.
+        let mut late = Decompressor::new(&[]).unwrap();
+        assert!(late.decompress(&second).is_err());
+    }
+
+    #[test]
+    fn payload_flags() {
+        let reading = b"{\"sensor\":\"kitchen\",\"temperature\":21.5,\"humidity\":40}".repeat(4);
+        let packed = pack(&reading);
+        assert_eq!(packed[0], ZSTD);
+        assert!(packed.len() < reading.len());
+        assert_eq!(&unpack(&packed).unwrap()[..], &reading[..]);
+        assert_eq!(pack(b"short").as_ref(), b"\x00short");
+        assert_eq!(&unpack(b"\x00short").unwrap()[..], b"short");
+        assert!(unpack(b"\x07short").is_err());
+        assert!(unpack(b"").is_err());
+        let bomb = vec![0; MAX_FRAME_SIZE + 1];
+        assert!(unpack(&pack(&bomb)).is_err());
+        assert_eq!(unpack(&pack(&bomb[1..])).unwrap().len(), MAX_FRAME_SIZE);
+    }
+
+    #[test]
+    fn shared_dictionary() {
+        let dictionary = b"\"sensor\":\"kitchen\",\"temperature\":\"humidity\":";
+        let reading = b"{\"sensor\":\"kitchen\",\"temperature\":21.5,\"humidity\":40}";
+        let plain = Compressor::new(&[]).unwrap().compress(reading).unwrap();
+        let with_dictionary = Compressor::new(dictionary).unwrap().compress(reading).unwrap();
+        assert!(with_dictionary.len() < plain.len());
+        let mut decompressor = Decompressor::new(dictionary).unwrap();
+        assert_eq!(decompressor.decompress(&with_dictionary).unwrap().as_ref(), &reading[..]);
+    }
+}
@@ -0,0 +1,83 @@
+//! Content type hint for payloads. Hinted payload is prefixed with one
+//! content type byte inside the encrypted payload, so frame header stays the
+//! same and the hint is authenticated. Peers have to agree to use hints on
+//! a session (or on a stream): there is no way to tell hinted payload from
+//! plain one.
+
+use bytes::{Bytes, BytesMut};
+use errors::{WhisperError, WhisperResult};
+
+/// Registered content types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentType {
+    /// Opaque bytes.
+    Raw = 0,
+    /// JSON text.
+    Json = 1,
+    /// CBOR.
+    Cbor = 2,
+    /// Protocol Buffers.
+    Protobuf = 3,
+    /// MessagePack.
+    MsgPack = 4,
+}
+
+impl ContentType {
+    /// Decode content type byte.
+    pub fn from(code: u8) -> Option<ContentType> {
+        match code {
+            0 => Some(ContentType::Raw),
+            1 => Some(ContentType::Json),
+            2 => Some(ContentType::Cbor),
+            3 => Some(ContentType::Protobuf),
+            4 => Some(ContentType::MsgPack),
+            _ => None,
+        }
+    }
+
+    /// MIME type. Handy for logs and bridges to HTTP.
+    pub fn mime(&self) -> &'static str {
+        match *self {
+            ContentType::Raw => "application/octet-stream",
+            ContentType::Json => "application/json",
+            ContentType::Cbor => "application/cbor",
+            ContentType::Protobuf => "application/x-protobuf",
+            ContentType::MsgPack => "application/msgpack",
+        }
+    }
+}
+
+/// Prefix data with content type. Pass result to `make_request` and friends.
+pub fn wrap(content_type: ContentType, data: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(1 + data.len());
+    buf.extend_from_slice(&[content_type as u8]);
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// Split opened payload into content type and data.
+pub fn unwrap(payload: &Bytes) -> WhisperResult<(ContentType, Bytes)> {
+    let content_type = payload.first()
+                              .and_then(|&code| ContentType::from(code))
+                              .ok_or(WhisperError::BadFrame)?;
+    Ok((content_type, payload.slice_from(1)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::test::handshake;
+
+    #[test]
+    fn hint_survives_session() {
+        let (client, server) = handshake();
+        let frame = client.make_request(&wrap(ContentType::Json, b"{}")).unwrap();
+        let (content_type, data) = unwrap(&server.read_msg(&frame).unwrap()).unwrap();
+        assert_eq!(content_type, ContentType::Json);
+        assert_eq!(content_type.mime(), "application/json");
+        assert_eq!(data.as_ref(), b"{}");
+
+        assert!(unwrap(&Bytes::new()).is_err());
+        assert!(unwrap(&Bytes::from(&[42u8][..])).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! Op bytes of Control frames. Payload of every Control frame starts with
+//! one of these, and each module reads only its own ops and leaves the rest
+//! for the next one, so every Control frame can be passed through all of
+//! them. Ops live here, in one list, so two modules never take the same
+//! byte.
+
+/// `renegotiation`: proposal of new parameters.
+pub const PROPOSE: u8 = 1;
+/// `renegotiation`: proposal accepted.
+pub const ACCEPT: u8 = 2;
+/// `renegotiation`: proposal rejected.
+pub const REJECT: u8 = 3;
+/// `renegotiation`: proposer switched to accepted parameters.
+pub const CONFIRM: u8 = 4;
+/// `pubsub`: subscribe to a topic.
+pub const SUBSCRIBE: u8 = 6;
+/// `pubsub`: unsubscribe from a topic.
+pub const UNSUBSCRIBE: u8 = 7;
+/// `tracker`: peer doesn't need response anymore.
+pub const CANCEL: u8 = 8;
+/// `redirect`: session moves to another server.
+pub const REDIRECT: u8 = 9;
+/// `resumption`: ticket for abbreviated reconnect.
+pub const RESUMPTION_TICKET: u8 = 10;
+/// `liveness`: heartbeat.
+pub const HEARTBEAT: u8 = 11;
+/// `liveness`: heartbeat acknowledgement.
+pub const HEARTBEAT_ACK: u8 = 12;
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use errors::WhisperResult;
+    use frame::Frame;
+    use liveness;
+    use pubsub::{self, SubscriptionRegistry};
+    use redirect::Redirect;
+    use renegotiation::{Renegotiation, SessionParams};
+    use resumption::{self, ResumptionTicket};
+    use session::test::handshake;
+    use tickets::TicketKeys;
+    use tracker::RequestTracker;
+
+    #[test]
+    fn modules_leave_each_others_frames() {
+        let (client, server) = handshake();
+        let keys = TicketKeys::new(Duration::hours(1), Duration::hours(1)).unwrap();
+        let mut server_tracker = RequestTracker::new();
+        let mut tracker = RequestTracker::new();
+        let (id, request) = server_tracker.make_request(&server, b"slow").unwrap();
+        tracker.read(&client, &request).unwrap();
+        let params = SessionParams {
+            compression: true,
+            ..SessionParams::default()
+        };
+        let propose = Renegotiation::new(&server, SessionParams::default()).propose(&server, params)
+                                                                           .unwrap();
+        let frames = vec![propose,
+                          liveness::ping(&server).unwrap(),
+                          pubsub::subscribe(&server, "news").unwrap(),
+                          server_tracker.cancel(&server, id).unwrap(),
+                          Redirect::new("example.com:443").to_frame(&server).unwrap(),
+                          resumption::issue(&keys, &server, Duration::hours(1)).unwrap()];
+
+        let mut renegotiation = Renegotiation::new(&client, SessionParams::default());
+        let mut registry = SubscriptionRegistry::new();
+        let mut read = |module: usize, frame: &Frame| -> WhisperResult<bool> {
+            Ok(match module {
+                   0 => renegotiation.read(&client, frame)?.is_some(),
+                   1 => liveness::answer(&client, frame)?.is_some(),
+                   2 => registry.read(&client, frame)?.is_some(),
+                   3 => tracker.read(&client, frame)?.is_some(),
+                   4 => Redirect::from_frame(&client, frame)?.is_some(),
+                   _ => ResumptionTicket::from_frame(&client, frame)?.is_some(),
+               })
+        };
+        for (owner, frame) in frames.iter().enumerate() {
+            for module in (0..frames.len()).filter(|&module| module != owner) {
+                match read(module, frame) {
+                    Ok(false) => {}
+                    other => panic!("Module {} read frame of {}: {:?}", module, owner, other),
+                }
+            }
+            assert!(read(owner, frame).unwrap(), "Module {} lost its own frame", owner);
+        }
+    }
+}
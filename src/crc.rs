@@ -0,0 +1,86 @@
+//! CRC32 trailer for noisy links (serial lines, radios) without checksums
+//! of their own. Flipped bit in a frame otherwise shows up as
+//! `DecryptionFailed`, which looks like an attack or a key mix up. With the
+//! trailer it's `CorruptedFrame` and checked before parsing.
+//!
+//! Trailer is CRC-32 (IEEE, same as Ethernet and zlib) of the packed frame
+//! as u32 BigEndian, appended after it. It catches noise, not attackers —
+//! payload MAC is still what keeps frames authentic. Both sides must agree
+//! on using it.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use errors::{WhisperError, WhisperResult};
+use frame::Frame;
+
+/// Size of the trailer.
+pub const TRAILER_SIZE: usize = 4;
+
+/// CRC-32 of data.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            // Reflected polynomial 0x04C11DB7.
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Append trailer to packed frame (or whatever else goes on the wire).
+pub fn append(packet: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(packet.len() + TRAILER_SIZE);
+    buf.extend_from_slice(packet);
+    buf.put_u32_be(crc32(packet));
+    buf.freeze()
+}
+
+/// Check trailer and return what's in front of it.
+pub fn strip(packet: &[u8]) -> WhisperResult<&[u8]> {
+    if packet.len() < TRAILER_SIZE {
+        return Err(WhisperError::IncompleteFrame);
+    }
+    let (body, trailer) = packet.split_at(packet.len() - TRAILER_SIZE);
+    if crc32(body) != BigEndian::read_u32(trailer) {
+        return Err(WhisperError::CorruptedFrame);
+    }
+    Ok(body)
+}
+
+/// Pack frame with trailer.
+pub fn pack(frame: &Frame) -> Bytes { append(&frame.pack()) }
+
+/// Check trailer, then parse frame.
+pub fn unpack(packet: &[u8]) -> WhisperResult<Frame> { Frame::from_slice(strip(packet)?) }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::test::handshake;
+
+    #[test]
+    fn known_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn corruption_is_not_decryption_failure() {
+        let (client, server) = handshake();
+        let frame = client.make_notification(b"over the air").unwrap();
+        let packet = pack(&frame);
+        assert_eq!(packet.len(), frame.length() + TRAILER_SIZE);
+        let unpacked = unpack(&packet).unwrap();
+        assert_eq!(server.read_msg(&unpacked).unwrap().as_ref(), b"over the air");
+
+        let mut noisy = packet.to_vec();
+        noisy[70] ^= 0x10;
+        match unpack(&noisy) {
+            Err(WhisperError::CorruptedFrame) => {}
+            other => panic!("Expected CorruptedFrame, got {:?}", other),
+        }
+        assert!(unpack(&packet[..2]).is_err());
+    }
+}
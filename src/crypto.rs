@@ -1,12 +1,31 @@
 //! This module is mostly reexports of sodiumoxide.
 
+use byteorder::{BigEndian, ByteOrder};
+use chrono::{DateTime, TimeZone};
+use chrono::offset::Utc;
+use encoding;
 use errors::{WhisperResult, WhisperError};
+use import;
 use sodiumoxide;
-use sodiumoxide::crypto::box_::gen_keypair;
+use sodiumoxide::crypto::box_::{SECRETKEYBYTES, gen_keypair};
+use sodiumoxide::crypto::pwhash;
+use sodiumoxide::utils::{memcmp, memzero};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::Once;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INIT: Once = Once::new();
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
 pub use sodiumoxide::crypto::box_::{PublicKey, SecretKey};
-/// A keypair. This is just a helper type.
-#[derive(Debug, Clone)]
+pub use sodiumoxide::crypto::pwhash::{MEMLIMIT_INTERACTIVE, MEMLIMIT_SENSITIVE, MemLimit,
+                                      OPSLIMIT_INTERACTIVE, OPSLIMIT_SENSITIVE, OpsLimit, Salt,
+                                      gen_salt};
+/// A keypair. This is just a helper type. Secret key of every copy is
+/// wiped with `sodium_memzero` when that copy is dropped, and `Debug`
+/// doesn't show it: use `reveal_secret` when you really need to see it.
+#[derive(Clone)]
 pub struct KeyPair {
     /// Public key.
     pub public_key: PublicKey,
@@ -14,23 +33,205 @@ pub struct KeyPair {
     pub secret_key: SecretKey,
 }
 impl KeyPair {
-    /// Generate new keypair using libsodium.
-    #[inline]
-    pub fn new() -> KeyPair {
+    /// Generate new keypair using libsodium. Fails with
+    /// `InitializationFailed` if libsodium can't be initialized.
+    pub fn new() -> WhisperResult<KeyPair> {
+        init()?;
         let (public_key, secret_key) = gen_keypair();
-        KeyPair {
-            secret_key: secret_key,
-            public_key: public_key,
+        Ok(KeyPair {
+               secret_key: secret_key,
+               public_key: public_key,
+           })
+    }
+
+    /// Derive keypair from password with libsodium's pwhash, so it doesn't
+    /// have to be stored anywhere. Hash is the seed of
+    /// `crypto_box_seed_keypair`, so other libsodium bindings derive the
+    /// same keypair. Same password, salt and limits always
+    /// give the same keypair. Salt isn't secret, but should be unique per
+    /// identity: keep it next to client config or make one from user name.
+    /// Use `*_INTERACTIVE` limits for CLI logins, `*_SENSITIVE` for keys
+    /// that guard a lot. Fails with `ResourceExhausted` if memory limit
+    /// can't be met.
+    pub fn from_password(password: &[u8],
+                         salt: &Salt,
+                         ops_limit: OpsLimit,
+                         mem_limit: MemLimit)
+                         -> WhisperResult<KeyPair> {
+        init()?;
+        let mut seed = [0; SECRETKEYBYTES];
+        let derived = pwhash::derive_key(&mut seed, password, salt, ops_limit, mem_limit).is_ok();
+        if !derived {
+            return Err(WhisperError::ResourceExhausted);
         }
+        let keypair = import::x25519_seed_keypair(&seed);
+        memzero(&mut seed);
+        Ok(keypair)
+    }
+
+    /// Lowercase hex of secret key. Escape hatch for tests and debugging,
+    /// never log it.
+    pub fn reveal_secret(&self) -> String { encoding::hex(&self.secret_key.0) }
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyPair")
+         .field("public_key", &self.public_key)
+         .field("secret_key", &format_args!("<redacted>"))
+         .finish()
     }
 }
 
-/// In order to make libsodium threadsafe you must call this function before using any of it's andom number generation functions.
+/// Period of time identity key is allowed to be used. Used to enforce key
+/// rollover: handshake with identity outside of this period fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyValidity {
+    /// Key can't be used before this moment.
+    pub not_before: DateTime<Utc>,
+    /// Key can't be used after this moment.
+    pub not_after: DateTime<Utc>,
+}
+
+impl KeyValidity {
+    /// Size of encoded validity: two i64 BigEndian unix timestamps.
+    pub const SIZE: usize = 16;
+
+    /// Returns true if key can be used at given moment.
+    pub fn is_valid_at(&self, now: DateTime<Utc>) -> bool {
+        self.not_before <= now && now <= self.not_after
+    }
+
+    /// Encode as two unix timestamps.
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut buf = [0; 16];
+        BigEndian::write_i64(&mut buf[0..8], self.not_before.timestamp());
+        BigEndian::write_i64(&mut buf[8..16], self.not_after.timestamp());
+        buf
+    }
+
+    /// Decode validity. Returns None if slice has wrong size.
+    pub fn from_slice(bytes: &[u8]) -> Option<KeyValidity> {
+        if bytes.len() != KeyValidity::SIZE {
+            return None;
+        }
+        let not_before = Utc.timestamp_opt(BigEndian::read_i64(&bytes[0..8]), 0).single()?;
+        let not_after = Utc.timestamp_opt(BigEndian::read_i64(&bytes[8..16]), 0).single()?;
+        Some(KeyValidity {
+                 not_before,
+                 not_after,
+             })
+    }
+}
+
+/// Initialize libsodium. Every entry point of this crate that needs random
+/// numbers does it lazily, exactly once, so calling this is optional: do it
+/// at startup to find out early if initialization fails.
 /// It's safe to call this method more than once and from more than one thread.
 pub fn init() -> WhisperResult<()> {
-  if sodiumoxide::init() {
-    Ok(())
-  } else {
-    Err(WhisperError::InitializationFailed)
-  }
-}
\ No newline at end of file
+    INIT.call_once(|| {
+        INITIALIZED.store(sodiumoxide::init(), Ordering::SeqCst);
+    });
+    if INITIALIZED.load(Ordering::SeqCst) {
+        Ok(())
+    } else {
+        Err(WhisperError::InitializationFailed)
+    }
+}
+
+/// Compare secrets and anything derived from them with libsodium's
+/// `sodium_memcmp`: time it takes doesn't depend on where they differ.
+/// Length isn't treated as secret, different lengths are unequal.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool { memcmp(a, b) }
+
+/// Decrypted payload that is wiped with `sodium_memzero` when dropped.
+/// Returned by `EstablishedSession::read_msg_secure`. Can't be cloned, and
+/// `Debug` shows only its length.
+pub struct SecureBytes(Vec<u8>);
+
+impl SecureBytes {
+    /// Length in bytes.
+    pub fn len(&self) -> usize { self.0.len() }
+
+    /// Returns true if there are no bytes.
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+}
+
+impl From<Vec<u8>> for SecureBytes {
+    fn from(bytes: Vec<u8>) -> SecureBytes { SecureBytes(bytes) }
+}
+
+impl Deref for SecureBytes {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] { &self.0 }
+}
+
+impl DerefMut for SecureBytes {
+    fn deref_mut(&mut self) -> &mut [u8] { &mut self.0 }
+}
+
+impl AsRef<[u8]> for SecureBytes {
+    fn as_ref(&self) -> &[u8] { &self.0 }
+}
+
+impl fmt::Debug for SecureBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecureBytes({} bytes)", self.0.len())
+    }
+}
+
+impl Drop for SecureBytes {
+    fn drop(&mut self) { memzero(&mut self.0); }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::ClientSession;
+    use std::thread;
+
+    #[test]
+    fn password_keypair_is_deterministic() {
+        let salt = gen_salt();
+        let derive = |password: &[u8], salt: &Salt| {
+            KeyPair::from_password(password, salt, OPSLIMIT_INTERACTIVE, MEMLIMIT_INTERACTIVE)
+                .unwrap()
+                .public_key
+        };
+        let key = derive(b"correct horse", &salt);
+        assert_eq!(derive(b"correct horse", &salt), key);
+        assert!(derive(b"battery staple", &salt) != key);
+        assert!(derive(b"correct horse", &gen_salt()) != key);
+    }
+
+    #[test]
+    fn debug_hides_secret() {
+        let keypair = KeyPair::new().unwrap();
+        let debug = format!("{:?}", keypair);
+        assert!(debug.contains("secret_key: <redacted>"));
+        assert!(!debug.contains("SecretKey"));
+        assert_eq!(keypair.reveal_secret().len(), 64);
+        let session = ClientSession::new(keypair, KeyPair::new().unwrap().public_key).unwrap();
+        assert!(!format!("{:?}", session).contains("SecretKey"));
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        let keypair = KeyPair::new().unwrap();
+        let copy = keypair.public_key.0;
+        assert!(constant_time_eq(&keypair.public_key.0, &copy));
+        assert!(!constant_time_eq(&keypair.public_key.0, &KeyPair::new().unwrap().public_key.0));
+        assert!(!constant_time_eq(b"My body is ready", b"My body is"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn init_from_many_threads() {
+        let threads: Vec<_> = (0..8).map(|_| thread::spawn(|| init().and(KeyPair::new())))
+                                    .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap().is_ok());
+        }
+        assert!(init().is_ok());
+    }
+}
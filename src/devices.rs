@@ -0,0 +1,156 @@
+//! Many devices, one identity. User keeps an Ed25519 master key (offline,
+//! ideally) and signs the identity key of each device with it. Device
+//! presents certificate in Initiate metadata (tag `metadata::DEVICE`), and
+//! server checks that it's signed by the master and names the very key
+//! device authenticated with. Devices never share a secret, and losing one
+//! means forgetting its certificate, not rotating every device.
+//!
+//! Certificate is master public key (32 bytes), device identity key (32
+//! bytes) and signature of the two keys (64 bytes). Master key is what
+//! application should authorize (see `facade::Server::allow_master`).
+
+use crypto::{self, KeyPair};
+use errors::{WhisperError, WhisperResult};
+use sodiumoxide::crypto::box_::PublicKey;
+use sodiumoxide::crypto::sign;
+
+/// Size of encoded certificate.
+pub const CERTIFICATE_SIZE: usize = 32 + 32 + sign::SIGNATUREBYTES;
+
+// So signature can't be confused with signatures made for other purposes.
+const CONTEXT: &[u8] = b"whisper device key";
+
+/// Ed25519 key that certifies device identities.
+#[derive(Debug, Clone)]
+pub struct MasterIdentity {
+    /// Public key. Given to servers as logical identity.
+    pub public_key: sign::PublicKey,
+    /// Secret key. Only needed to certify new devices.
+    pub secret_key: sign::SecretKey,
+}
+
+impl MasterIdentity {
+    /// Generate new master key. Fails with `InitializationFailed` if
+    /// libsodium can't be initialized.
+    pub fn new() -> WhisperResult<MasterIdentity> {
+        crypto::init()?;
+        let (public_key, secret_key) = sign::gen_keypair();
+        Ok(MasterIdentity {
+               public_key,
+               secret_key,
+           })
+    }
+
+    /// Certify device identity key.
+    pub fn certify(&self, device: &PublicKey) -> DeviceCertificate {
+        let signature = sign::sign_detached(&signed_message(&self.public_key, device),
+                                            &self.secret_key);
+        DeviceCertificate {
+            master: self.public_key,
+            device: *device,
+            signature,
+        }
+    }
+
+    /// Make new device identity and certify it.
+    pub fn enroll_device(&self) -> WhisperResult<(KeyPair, DeviceCertificate)> {
+        let device = KeyPair::new()?;
+        let certificate = self.certify(&device.public_key);
+        Ok((device, certificate))
+    }
+}
+
+/// Master's signature of device identity key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceCertificate {
+    /// Key that signed it.
+    pub master: sign::PublicKey,
+    /// Device identity key.
+    pub device: PublicKey,
+    signature: sign::Signature,
+}
+
+impl DeviceCertificate {
+    /// Encode certificate.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(CERTIFICATE_SIZE);
+        buf.extend_from_slice(&self.master.0);
+        buf.extend_from_slice(&self.device.0);
+        buf.extend_from_slice(&self.signature.0);
+        buf
+    }
+
+    /// Decode certificate. Signature isn't checked here, see `verify`.
+    pub fn from_bytes(bytes: &[u8]) -> WhisperResult<DeviceCertificate> {
+        if bytes.len() != CERTIFICATE_SIZE {
+            return Err(WhisperError::BadFrame);
+        }
+        Ok(DeviceCertificate {
+               master: sign::PublicKey::from_slice(&bytes[..32]).ok_or(WhisperError::BadFrame)?,
+               device: PublicKey::from_slice(&bytes[32..64]).ok_or(WhisperError::BadFrame)?,
+               signature: sign::Signature::from_slice(&bytes[64..])
+                   .ok_or(WhisperError::BadFrame)?,
+           })
+    }
+
+    /// Returns true if master really signed this device key.
+    pub fn verify(&self) -> bool {
+        sign::verify_detached(&self.signature,
+                              &signed_message(&self.master, &self.device),
+                              &self.master)
+    }
+
+    /// Check certificate presented by client that authenticated with
+    /// `identity_key`. Returns master key.
+    pub fn verify_for(&self, identity_key: &PublicKey) -> WhisperResult<sign::PublicKey> {
+        if self.device != *identity_key || !self.verify() {
+            return Err(WhisperError::InvalidInitiateFrame);
+        }
+        Ok(self.master)
+    }
+}
+
+fn signed_message(master: &sign::PublicKey, device: &PublicKey) -> Vec<u8> {
+    let mut message = Vec::with_capacity(CONTEXT.len() + 64);
+    message.extend_from_slice(CONTEXT);
+    message.extend_from_slice(&master.0);
+    message.extend_from_slice(&device.0);
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::{ClientSession, ServerSession, Session};
+
+    #[test]
+    fn devices_share_master() {
+        let master = MasterIdentity::new().unwrap();
+        let (phone, certificate) = master.enroll_device().unwrap();
+        let (laptop, _) = master.enroll_device().unwrap();
+        assert!(certificate.verify());
+        let decoded = DeviceCertificate::from_bytes(&certificate.to_bytes()).unwrap();
+        assert_eq!(decoded, certificate);
+        assert_eq!(decoded.verify_for(&phone.public_key).unwrap(), master.public_key);
+        assert!(decoded.verify_for(&laptop.public_key).is_err());
+        let stranger = MasterIdentity::new().unwrap();
+        let mut forged = stranger.certify(&phone.public_key);
+        forged.master = master.public_key;
+        assert!(!forged.verify());
+
+        let server_identity = KeyPair::new().unwrap();
+        let handshake = |device: KeyPair, certificate: &DeviceCertificate| {
+            let mut client = ClientSession::new(device, server_identity.public_key).unwrap();
+            client.set_device_certificate(certificate);
+            let mut server = ServerSession::new(server_identity.clone(), client.id()).unwrap();
+            let welcome = server.make_welcome(&client.make_hello().unwrap()).unwrap();
+            let initiate = client.make_initiate(&welcome).unwrap();
+            server.validate_initiate(&initiate)
+                  .and_then(|_| server.master_identity(&initiate))
+        };
+        assert_eq!(handshake(phone.clone(), &certificate).unwrap(), Some(master.public_key));
+        // Laptop presenting phone's certificate.
+        assert!(handshake(laptop, &certificate).is_err());
+        assert!(handshake(phone, &forged).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! BLAKE2b digests of plaintext payloads. Meant for dedup, integrity at
+//! rest and content addressing in upper layers. It's libsodium's
+//! `crypto_generichash`, so nothing else has to be linked for it.
+//!
+//! `digest` hashes one buffer, `Hasher` does the same incrementally — e.g.
+//! for message that arrives in fragments. Both give the same result for the
+//! same bytes. `fingerprint` is the first `FINGERPRINT_SIZE` bytes of
+//! public key's digest, shown as colon separated groups of four hex digits
+//! for people to compare.
+
+use libsodium_sys as ffi;
+use sodiumoxide::crypto::box_::PublicKey;
+use std::fmt;
+
+/// Size of digest in bytes.
+pub const DIGEST_SIZE: usize = ffi::crypto_generichash_BYTES;
+/// Size of fingerprint in bytes.
+pub const FINGERPRINT_SIZE: usize = 16;
+
+// libsodium wants state aligned to 64 bytes. Size is checked at runtime.
+const STATE_SIZE: usize = 512;
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+struct State([u8; STATE_SIZE]);
+
+/// BLAKE2b digest.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Digest(pub [u8; DIGEST_SIZE]);
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Digest(")?;
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl AsRef<[u8]> for Digest {
+    fn as_ref(&self) -> &[u8] { &self.0 }
+}
+
+/// Digest of the whole buffer.
+pub fn digest(data: &[u8]) -> Digest {
+    let mut out = [0; DIGEST_SIZE];
+    let ret = unsafe {
+        ffi::crypto_generichash(out.as_mut_ptr(),
+                                out.len(),
+                                data.as_ptr(),
+                                data.len() as u64,
+                                ::std::ptr::null(),
+                                0)
+    };
+    assert_eq!(ret, 0, "crypto_generichash failed");
+    Digest(out)
+}
+
+/// Short stable name of a public key, e.g. for logs and dashboards.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Fingerprint(pub [u8; FINGERPRINT_SIZE]);
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, pair) in self.0.chunks(2).enumerate() {
+            if i > 0 {
+                write!(f, ":")?;
+            }
+            write!(f, "{:02x}{:02x}", pair[0], pair[1])?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Fingerprint({})", self) }
+}
+
+/// Fingerprint of a public key.
+pub fn fingerprint(key: &PublicKey) -> Fingerprint {
+    let mut out = [0; FINGERPRINT_SIZE];
+    out.copy_from_slice(&digest(&key.0).0[..FINGERPRINT_SIZE]);
+    Fingerprint(out)
+}
+
+/// Incremental digest.
+#[derive(Clone)]
+pub struct Hasher {
+    state: Box<State>,
+}
+
+impl Hasher {
+    /// Start new digest.
+    pub fn new() -> Hasher {
+        assert!(unsafe { ffi::crypto_generichash_statebytes() } <= STATE_SIZE,
+                "libsodium hash state doesn't fit");
+        let mut state = Box::new(State([0; STATE_SIZE]));
+        let ret = unsafe {
+            ffi::crypto_generichash_init(state_ptr(&mut state), ::std::ptr::null(), 0, DIGEST_SIZE)
+        };
+        assert_eq!(ret, 0, "crypto_generichash_init failed");
+        Hasher { state }
+    }
+
+    /// Feed more bytes.
+    pub fn update(&mut self, data: &[u8]) {
+        let ret = unsafe {
+            ffi::crypto_generichash_update(state_ptr(&mut self.state),
+                                           data.as_ptr(),
+                                           data.len() as u64)
+        };
+        assert_eq!(ret, 0, "crypto_generichash_update failed");
+    }
+
+    /// Digest of everything fed so far.
+    pub fn finish(mut self) -> Digest {
+        let mut out = [0; DIGEST_SIZE];
+        let ret = unsafe {
+            ffi::crypto_generichash_final(state_ptr(&mut self.state), out.as_mut_ptr(), out.len())
+        };
+        assert_eq!(ret, 0, "crypto_generichash_final failed");
+        Digest(out)
+    }
+}
+
+impl Default for Hasher {
+    fn default() -> Hasher { Hasher::new() }
+}
+
+impl fmt::Debug for Hasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Hasher") }
+}
+
+fn state_ptr(state: &mut State) -> *mut ffi::crypto_generichash_state {
+    state as *mut State as *mut ffi::crypto_generichash_state
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn incremental_matches_oneshot() {
+        let data = b"Chatty sensor reporting for duty";
+        let mut hasher = Hasher::new();
+        for chunk in data.chunks(5) {
+            hasher.update(chunk);
+        }
+        let forked = hasher.clone();
+        assert_eq!(hasher.finish(), digest(data));
+        assert_eq!(forked.finish(), digest(data));
+        assert!(digest(b"a") != digest(b"b"));
+    }
+
+    #[test]
+    fn known_answer() {
+        // BLAKE2b-256 of empty input.
+        let expected = [0x0e, 0x57, 0x51, 0xc0, 0x26, 0xe5, 0x43, 0xb2, 0xe8, 0xab, 0x2e, 0xb0,
+                        0x60, 0x99, 0xda, 0xa1, 0xd1, 0xe5, 0xdf, 0x47, 0x77, 0x8f, 0x77, 0x87,
+                        0xfa, 0xab, 0x45, 0xcd, 0xf1, 0x2f, 0xe3, 0xa8];
+        assert_eq!(digest(b"").0, expected);
+    }
+
+    #[test]
+    fn fingerprint_is_readable() {
+        let key = PublicKey([0; 32]);
+        let text = fingerprint(&key).to_string();
+        assert_eq!(text.len(), FINGERPRINT_SIZE * 2 + FINGERPRINT_SIZE / 2 - 1);
+        assert_eq!(text.split(':').count(), FINGERPRINT_SIZE / 2);
+        assert!(text.split(':').all(|group| group.len() == 4));
+        assert_eq!(&fingerprint(&key).0[..], &digest(&key.0).0[..FINGERPRINT_SIZE]);
+    }
+}
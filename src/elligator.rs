@@ -0,0 +1,333 @@
+//! Elligator2 encoding of session keys, for networks that fingerprint
+//! handshakes. Every frame starts with client's short term public key and
+//! Curve25519 points are easy to tell from random bytes. With this mode
+//! session id on the wire is the key's Elligator2 representative instead,
+//! which looks like 32 uniformly random bytes. Server frames after Welcome
+//! carry server's short term key as id instead, so it needs the encoding
+//! too.
+//!
+//! Only about half of keys have a representative: make client session with
+//! `ClientSession::new_hidden` and server session with
+//! `ServerSession::new_hidden`, they pick ones that do. Both sides pass
+//! every frame they send through `hide` and every frame they receive
+//! through `reveal`, before anything else touches it. Representative is a
+//! function of the key, so server needs no state for this.
+//!
+//! What this doesn't hide:
+//! - Frame kind byte and frame length are still in the clear. Wrap the
+//!   connection in padding transport if they matter.
+//! - Keys made by X25519 are always in prime order subgroup. Adversary that
+//!   maps representatives back to points and checks subgroup can still tell
+//!   them apart from random, cheap DPI can't.
+//! - Top bits of representative are random, so frames can't be packed as
+//!   `WireVersion::V2`. Use `Frame::pack` and `Frame::from_slice`.
+
+use crypto::KeyPair;
+use errors::{WhisperError, WhisperResult};
+use frame::Frame;
+use sodiumoxide::crypto::box_::PublicKey;
+use sodiumoxide::randombytes::randombytes_into;
+use std::ops::{Add, Mul, Neg, Sub};
+
+/// Montgomery curve coefficient A of Curve25519.
+const A: u64 = 486_662;
+const MASK: u64 = (1 << 51) - 1;
+// Top two bits of representative carry no information.
+const RANDOM_BITS: u8 = 0xc0;
+
+/// Keypair whose public key has representative, and the representative.
+/// Fails with `InitializationFailed` if libsodium can't be initialized.
+pub fn keypair() -> WhisperResult<(KeyPair, [u8; 32])> {
+    loop {
+        let keypair = KeyPair::new()?;
+        if let Some(representative) = representative(&keypair.public_key) {
+            return Ok((keypair, representative));
+        }
+    }
+}
+
+/// Representative of the key with random top bits. None if key has no
+/// representative.
+pub fn representative(key: &PublicKey) -> Option<[u8; 32]> {
+    let u = Fe::from_bytes(&key.0);
+    let a = Fe::small(A);
+    if u.is_zero() || (u + a).is_zero() {
+        return None;
+    }
+    let mut random = [0; 1];
+    randombytes_into(&mut random);
+    // u = -A / (1 + 2r²) or u = -A - that. Both solutions decode to u; pick
+    // one at random, otherwise representatives would only cover half of
+    // the field.
+    let r = if random[0] & 1 == 0 {
+        (-(u + a) * (Fe::small(2) * u).invert()).sqrt()?
+    } else {
+        (-u * (Fe::small(2) * (u + a)).invert()).sqrt()?
+    };
+    let r = if r.is_negative() { -r } else { r };
+    let mut bytes = r.to_bytes();
+    // Keys on the twist solve the equations too, but map back to other key.
+    if public_key(&bytes) != *key {
+        return None;
+    }
+    bytes[31] |= random[0] & RANDOM_BITS;
+    Some(bytes)
+}
+
+/// Key encoded by the representative. Every 32 bytes decode to some key.
+pub fn public_key(representative: &[u8; 32]) -> PublicKey {
+    let mut bytes = *representative;
+    bytes[31] &= !RANDOM_BITS;
+    let r = Fe::from_bytes(&bytes);
+    let w = -Fe::small(A) * (Fe::one() + Fe::small(2) * r.square()).invert();
+    let curve = w * w.square() + Fe::small(A) * w.square() + w;
+    let u = if curve.sqrt().is_some() { w } else { -w - Fe::small(A) };
+    PublicKey(u.to_bytes())
+}
+
+/// Copy of frame to send, with session id replaced by its representative.
+/// Fails with `BadFrame` if session id has no representative, i.e. session
+/// wasn't made with `ClientSession::new_hidden` or
+/// `ServerSession::new_hidden`.
+pub fn hide(frame: &Frame) -> WhisperResult<Frame> {
+    let representative = representative(&frame.id).ok_or(WhisperError::BadFrame)?;
+    Ok(Frame {
+           id: PublicKey(representative),
+           ..frame.clone()
+       })
+}
+
+/// Copy of received frame with session id decoded back to public key.
+pub fn reveal(frame: &Frame) -> Frame {
+    Frame {
+        id: public_key(&frame.id.0),
+        ..frame.clone()
+    }
+}
+
+// Element of GF(2^255 - 19), five 51 bit limbs.
+#[derive(Clone, Copy)]
+struct Fe([u64; 5]);
+
+impl Fe {
+    fn small(small: u64) -> Fe { Fe([small, 0, 0, 0, 0]) }
+
+    fn one() -> Fe { Fe::small(1) }
+
+    // Top bit is ignored.
+    fn from_bytes(bytes: &[u8; 32]) -> Fe {
+        let mut limbs = [0; 5];
+        for i in 0..255 {
+            let bit = u64::from(bytes[i / 8] >> (i % 8) & 1);
+            limbs[i / 51] |= bit << (i % 51);
+        }
+        Fe(limbs)
+    }
+
+    // Canonical encoding, less than p.
+    fn to_bytes(&self) -> [u8; 32] {
+        let mut limbs = self.reduce().0;
+        // Subtract p if value is p or more: that's when adding 19 carries out.
+        let mut q = (limbs[0] + 19) >> 51;
+        for limb in &limbs[1..] {
+            q = (limb + q) >> 51;
+        }
+        limbs[0] += 19 * q;
+        for i in 0..4 {
+            limbs[i + 1] += limbs[i] >> 51;
+            limbs[i] &= MASK;
+        }
+        limbs[4] &= MASK;
+        let mut bytes = [0; 32];
+        for i in 0..255 {
+            let bit = (limbs[i / 51] >> (i % 51) & 1) as u8;
+            bytes[i / 8] |= bit << (i % 8);
+        }
+        bytes
+    }
+
+    fn reduce(&self) -> Fe {
+        let mut limbs = self.0;
+        let carry = limbs[4] >> 51;
+        limbs[4] &= MASK;
+        limbs[0] += carry * 19;
+        for i in 0..4 {
+            limbs[i + 1] += limbs[i] >> 51;
+            limbs[i] &= MASK;
+        }
+        Fe(limbs)
+    }
+
+    fn square(&self) -> Fe { *self * *self }
+
+    // Exponent is little endian.
+    fn pow(&self, exponent: &[u8; 32]) -> Fe {
+        let mut result = Fe::one();
+        for i in (0..256).rev() {
+            result = result.square();
+            if exponent[i / 8] >> (i % 8) & 1 == 1 {
+                result = result * *self;
+            }
+        }
+        result
+    }
+
+    // x^(p-2). Zero for zero.
+    fn invert(&self) -> Fe { self.pow(&exponent(0xeb, 0x7f)) }
+
+    // Square root, if there is one. p = 5 (mod 8), so candidate is
+    // x^((p+3)/8), possibly times sqrt(-1).
+    fn sqrt(&self) -> Option<Fe> {
+        let candidate = self.pow(&exponent(0xfe, 0x0f));
+        if candidate.square() == *self {
+            return Some(candidate);
+        }
+        if candidate.square() == -*self {
+            // sqrt(-1) = 2^((p-1)/4)
+            return Some(candidate * Fe::small(2).pow(&exponent(0xfb, 0x1f)));
+        }
+        None
+    }
+
+    fn is_zero(&self) -> bool { self.to_bytes() == [0; 32] }
+
+    // Greater than (p-1)/2.
+    fn is_negative(&self) -> bool {
+        let half = exponent(0xf6, 0x3f);
+        let bytes = self.to_bytes();
+        for i in (0..32).rev() {
+            if bytes[i] != half[i] {
+                return bytes[i] > half[i];
+            }
+        }
+        false
+    }
+}
+
+// 2^k - c style exponent: lowest byte, 0xff in between, highest byte.
+fn exponent(low: u8, high: u8) -> [u8; 32] {
+    let mut bytes = [0xff; 32];
+    bytes[0] = low;
+    bytes[31] = high;
+    bytes
+}
+
+impl PartialEq for Fe {
+    fn eq(&self, other: &Fe) -> bool { self.to_bytes() == other.to_bytes() }
+}
+
+impl Add for Fe {
+    type Output = Fe;
+    fn add(self, other: Fe) -> Fe {
+        let mut limbs = self.0;
+        for (limb, other) in limbs.iter_mut().zip(&other.0) {
+            *limb += other;
+        }
+        Fe(limbs).reduce()
+    }
+}
+
+impl Sub for Fe {
+    type Output = Fe;
+    // Adds 16p first, so limbs don't underflow.
+    fn sub(self, other: Fe) -> Fe {
+        let mut limbs = self.reduce().0;
+        let other = other.reduce().0;
+        limbs[0] = limbs[0] + 36_028_797_018_963_664 - other[0];
+        for i in 1..5 {
+            limbs[i] = limbs[i] + 36_028_797_018_963_952 - other[i];
+        }
+        Fe(limbs).reduce()
+    }
+}
+
+impl Neg for Fe {
+    type Output = Fe;
+    fn neg(self) -> Fe { Fe::small(0) - self }
+}
+
+impl Mul for Fe {
+    type Output = Fe;
+    fn mul(self, other: Fe) -> Fe {
+        let m = |a: u64, b: u64| u128::from(a) * u128::from(b);
+        let a = self.0;
+        let b = other.0;
+        let b19 = [b[0], b[1] * 19, b[2] * 19, b[3] * 19, b[4] * 19];
+        let mut c = [m(a[0], b[0]) + m(a[4], b19[1]) + m(a[3], b19[2]) + m(a[2], b19[3]) +
+                     m(a[1], b19[4]),
+                     m(a[1], b[0]) + m(a[0], b[1]) + m(a[4], b19[2]) + m(a[3], b19[3]) +
+                     m(a[2], b19[4]),
+                     m(a[2], b[0]) + m(a[1], b[1]) + m(a[0], b[2]) + m(a[4], b19[3]) +
+                     m(a[3], b19[4]),
+                     m(a[3], b[0]) + m(a[2], b[1]) + m(a[1], b[2]) + m(a[0], b[3]) +
+                     m(a[4], b19[4]),
+                     m(a[4], b[0]) + m(a[3], b[1]) + m(a[2], b[2]) + m(a[1], b[3]) +
+                     m(a[0], b[4])];
+        for i in 0..4 {
+            c[i + 1] += c[i] >> 51;
+        }
+        let mut limbs = [0; 5];
+        for i in 0..5 {
+            limbs[i] = c[i] as u64 & MASK;
+        }
+        limbs[0] += (c[4] >> 51) as u64 * 19;
+        Fe(limbs).reduce()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::{ClientSession, ServerSession, Session};
+
+    fn key(hex: &str) -> PublicKey {
+        let mut bytes = [0; 32];
+        for (byte, i) in bytes.iter_mut().zip((0..64).step_by(2)) {
+            *byte = u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+        }
+        PublicKey(bytes)
+    }
+
+    #[test]
+    fn hidden_handshake() {
+        // RFC 7748 keys. Bob's has two representatives, Alice's has none.
+        let bob = key("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+        let first = key("8841e5d343b9f0607f5fdc11b277e0acce0119a22e4c33950ba4af7aa498121e").0;
+        let second = key("ff0e34241eb8da8edc5f95248d7a468b48ad5bb104eb1689820b50fde32d961b").0;
+        assert_eq!(public_key(&first), bob);
+        assert_eq!(public_key(&second), bob);
+        for _ in 0..8 {
+            let mut representative = representative(&bob).unwrap();
+            assert_eq!(public_key(&representative), bob);
+            representative[31] &= !RANDOM_BITS;
+            assert!(representative == first || representative == second);
+        }
+        let alice = key("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        assert!(representative(&alice).is_none());
+
+        // Server frames carry server's short term key as id, so each run
+        // would fail about half the time if server key weren't encodable.
+        for _ in 0..8 {
+            let server_identity = KeyPair::new().unwrap();
+            let mut client = ClientSession::new_hidden(KeyPair::new().unwrap(),
+                                                       server_identity.public_key)
+                .unwrap();
+            let hello = hide(&client.make_hello().unwrap()).unwrap();
+            assert!(hello.id != client.id());
+            let hello = reveal(&hello);
+            let mut server = ServerSession::new_hidden(server_identity, hello.id).unwrap();
+            let welcome = hide(&server.make_welcome(&hello).unwrap()).unwrap();
+            let initiate = hide(&client.make_initiate(&reveal(&welcome)).unwrap()).unwrap();
+            let initiate = reveal(&initiate);
+            let client_identity = server.validate_initiate(&initiate).unwrap();
+            let (server, ready) = server.make_ready(&initiate, &client_identity).unwrap();
+            let client = client.read_ready(&reveal(&hide(&ready).unwrap())).unwrap();
+
+            let request = hide(&client.make_request(b"hidden").unwrap()).unwrap();
+            assert_eq!(server.read_msg(&reveal(&request)).unwrap().as_ref(), b"hidden");
+            let response = hide(&server.make_response(b"hidden too").unwrap()).unwrap();
+            assert!(response.id != server.id());
+            assert_eq!(client.read_msg(&reveal(&response)).unwrap().as_ref(), b"hidden too");
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Hex and base64 shared by key files, key import, traces and test
+//! vectors. Output buffers are allocated at full size up front, so secrets
+//! aren't left behind in buffers outgrown on the way. Wiping the result is
+//! up to the caller.
+
+use errors::{WhisperError, WhisperResult};
+
+const HEX_ALPHABET: &[u8] = b"0123456789abcdef";
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Lowercase hex.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        text.push(HEX_ALPHABET[(byte >> 4) as usize] as char);
+        text.push(HEX_ALPHABET[(byte & 0xf) as usize] as char);
+    }
+    text
+}
+
+/// Inverse of `hex`, either case. Odd length or bad digit is `MalformedKey`.
+pub(crate) fn from_hex(text: &str) -> WhisperResult<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return Err(malformed("expected even number of hex digits"));
+    }
+    let mut out = Vec::with_capacity(text.len() / 2);
+    for pair in text.as_bytes().chunks(2) {
+        out.push(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?);
+    }
+    Ok(out)
+}
+
+fn hex_digit(c: u8) -> WhisperResult<u8> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err(malformed("bad hex digit")),
+    }
+}
+
+/// Base64, standard alphabet with padding.
+pub(crate) fn base64(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let mut block = [0; 3];
+        block[..chunk.len()].copy_from_slice(chunk);
+        let value = u32::from(block[0]) << 16 | u32::from(block[1]) << 8 | u32::from(block[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64_ALPHABET[(value >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+/// Inverse of `base64`. Padding is optional, whitespace ignored. Bad
+/// character is `MalformedKey`.
+pub(crate) fn from_base64(text: &str) -> WhisperResult<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return Err(malformed("bad base64")),
+        };
+        acc = acc << 6 | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+fn malformed(reason: &str) -> WhisperError { WhisperError::MalformedKey(reason.to_string()) }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(hex(&[0x00, 0xab, 0x7f]), "00ab7f");
+        assert_eq!(from_hex("00AB7f").unwrap(), vec![0x00, 0xab, 0x7f]);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+        assert!(from_hex("+f").is_err());
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        assert_eq!(base64(b"whisper"), "d2hpc3Blcg==");
+        assert_eq!(from_base64("d2hpc3Blcg==").unwrap(), b"whisper");
+        assert_eq!(from_base64("d2hp\nc3Blcg").unwrap(), b"whisper");
+        assert!(from_base64("d2h*").is_err());
+    }
+}
@@ -0,0 +1,271 @@
+//! Device enrollment. Devices leave the factory with a bootstrap keypair. A
+//! device uses it to open a restricted session whose only allowed operation
+//! is submitting its new identity public key for registration. Once
+//! registered, the device uses the new identity for normal sessions and the
+//! bootstrap key is burned.
+//!
+//! ### Flow
+//! 1. Device does regular handshake using bootstrap keypair as identity.
+//! 2. Server checks `EnrollmentRegistry::access` and wraps session into
+//!    `RestrictedSession` for bootstrap keys.
+//! 3. Device sends `make_enrollment_request` with its new identity key.
+//! 4. Server replies with `EnrollmentRegistry::enroll`.
+//!
+//! Request proves the device owns the new identity: it carries a box from
+//! the new identity key to server's identity key over the session id, so
+//! device can't register a key someone else holds, and the proof can't be
+//! reused in another session.
+
+use bytes::Bytes;
+use crypto::KeyPair;
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use session::{EstablishedSession, Session};
+use sodiumoxide::crypto::box_::{self, Nonce, PublicKey};
+use std::collections::HashSet;
+
+/// First byte of enrollment request payload.
+pub const ENROLLMENT_REQUEST: u8 = 1;
+/// Response payload when new identity was registered.
+pub const ENROLLMENT_ACCEPTED: u8 = 0;
+/// Response payload when enrollment was refused.
+pub const ENROLLMENT_REJECTED: u8 = 1;
+
+// So proof can't be confused with boxes made for other purposes.
+const CONTEXT: &[u8] = b"whisper enrollment";
+// Request byte, new identity key and nonce of the proof.
+const PROOF_AT: usize = 1 + box_::PUBLICKEYBYTES + box_::NONCEBYTES;
+
+/// What client with given identity key is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Enrolled identity. Regular session.
+    Full,
+    /// Bootstrap key. Session can only be used to enroll.
+    EnrollmentOnly,
+    /// Unknown or burned key.
+    Denied,
+}
+
+/// Server side registry of bootstrap keys and enrolled identities.
+#[derive(Debug, Clone)]
+pub struct EnrollmentRegistry {
+    server_identity: KeyPair,
+    bootstrap_keys: HashSet<PublicKey>,
+    identities: HashSet<PublicKey>,
+}
+
+impl EnrollmentRegistry {
+    /// Create empty registry. Server identity is the one devices do
+    /// handshake with, it opens their proofs of new identity.
+    pub fn new(server_identity: KeyPair) -> EnrollmentRegistry {
+        EnrollmentRegistry {
+            server_identity,
+            bootstrap_keys: HashSet::new(),
+            identities: HashSet::new(),
+        }
+    }
+
+    /// Register factory provisioned bootstrap key. Each key can enroll once.
+    pub fn add_bootstrap_key(&mut self, key: PublicKey) { self.bootstrap_keys.insert(key); }
+
+    /// Register identity that doesn't need to go through enrollment.
+    pub fn add_identity(&mut self, key: PublicKey) { self.identities.insert(key); }
+
+    /// Decide what client is allowed to do. Call it with key returned by
+    /// `ServerSession::validate_initiate`.
+    pub fn access(&self, client_identity_key: &PublicKey) -> Access {
+        if self.identities.contains(client_identity_key) {
+            Access::Full
+        } else if self.bootstrap_keys.contains(client_identity_key) {
+            Access::EnrollmentOnly
+        } else {
+            Access::Denied
+        }
+    }
+
+    /// Handle enrollment request. Registers new identity, burns bootstrap key
+    /// and returns Response frame to send back. Rejected request leaves
+    /// bootstrap key as it was. On error reply with
+    /// `make_enrollment_rejection` or just drop the session.
+    pub fn enroll(&mut self, session: &RestrictedSession, request: &Frame) -> WhisperResult<Frame> {
+        let new_identity_key = session.read_enrollment_request(request, &self.server_identity)?;
+        if !self.bootstrap_keys.contains(&session.bootstrap_key) ||
+           self.identities.contains(&new_identity_key)
+        {
+            return Err(WhisperError::EnrollmentRejected);
+        }
+        self.bootstrap_keys.remove(&session.bootstrap_key);
+        self.identities.insert(new_identity_key);
+        session.session.make_response(&[ENROLLMENT_ACCEPTED])
+    }
+}
+
+/// Server side session opened with bootstrap key. It can't be used for
+/// anything but enrollment.
+pub struct RestrictedSession {
+    session: EstablishedSession,
+    bootstrap_key: PublicKey,
+}
+
+impl RestrictedSession {
+    /// Wrap session established with bootstrap key.
+    pub fn new(session: EstablishedSession, bootstrap_key: PublicKey) -> RestrictedSession {
+        RestrictedSession {
+            session,
+            bootstrap_key,
+        }
+    }
+
+    /// Response that tells device its enrollment was refused.
+    pub fn make_enrollment_rejection(&self) -> WhisperResult<Frame> {
+        self.session.make_response(&[ENROLLMENT_REJECTED])
+    }
+
+    /// Bootstrap key this session was opened with.
+    pub fn bootstrap_key(&self) -> &PublicKey { &self.bootstrap_key }
+
+    // The only kind of message restricted session accepts.
+    fn read_enrollment_request(&self,
+                               request: &Frame,
+                               server_identity: &KeyPair)
+                               -> WhisperResult<PublicKey> {
+        if request.kind != FrameKind::Request {
+            return Err(WhisperError::EnrollmentRejected);
+        }
+        let payload = self.session.read_msg(request)?;
+        if payload.len() != PROOF_AT + CONTEXT.len() + 32 + box_::MACBYTES ||
+           payload[0] != ENROLLMENT_REQUEST
+        {
+            return Err(WhisperError::EnrollmentRejected);
+        }
+        let new_identity_key = PublicKey::from_slice(&payload[1..1 + box_::PUBLICKEYBYTES])
+            .ok_or(WhisperError::EnrollmentRejected)?;
+        let nonce = Nonce::from_slice(&payload[1 + box_::PUBLICKEYBYTES..PROOF_AT])
+            .ok_or(WhisperError::EnrollmentRejected)?;
+        let proof = box_::open(&payload[PROOF_AT..],
+                               &nonce,
+                               &new_identity_key,
+                               &server_identity.secret_key)
+            .map_err(|_| WhisperError::EnrollmentRejected)?;
+        if proof != proof_message(&self.session) {
+            return Err(WhisperError::EnrollmentRejected);
+        }
+        Ok(new_identity_key)
+    }
+}
+
+/// Device side. Make request that submits new identity key over session
+/// opened with bootstrap key, with proof that device holds its secret key.
+pub fn make_enrollment_request(session: &EstablishedSession,
+                               new_identity: &KeyPair)
+                               -> WhisperResult<Frame> {
+    let server_identity_key = session.remote_identity().ok_or(WhisperError::InvalidSessionState)?;
+    let nonce = box_::gen_nonce();
+    let proof = box_::seal(&proof_message(session),
+                           &nonce,
+                           &server_identity_key,
+                           &new_identity.secret_key);
+    let mut payload = Vec::with_capacity(PROOF_AT + proof.len());
+    payload.push(ENROLLMENT_REQUEST);
+    payload.extend_from_slice(&new_identity.public_key.0);
+    payload.extend_from_slice(&nonce.0);
+    payload.extend_from_slice(&proof);
+    session.make_request(&payload)
+}
+
+// What new identity key boxes to server's identity key.
+fn proof_message(session: &EstablishedSession) -> Vec<u8> {
+    let mut message = Vec::with_capacity(CONTEXT.len() + 32);
+    message.extend_from_slice(CONTEXT);
+    message.extend_from_slice(&session.id().0);
+    message
+}
+
+/// Device side. Check server's reply to enrollment request.
+pub fn read_enrollment_response(session: &EstablishedSession, response: &Frame) -> WhisperResult<()> {
+    let payload: Bytes = session.read_msg(response)?;
+    if response.kind == FrameKind::Response && payload.as_ref() == [ENROLLMENT_ACCEPTED] {
+        Ok(())
+    } else {
+        Err(WhisperError::EnrollmentRejected)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::test::handshake_with;
+
+    #[test]
+    fn enroll_device() {
+        let bootstrap = KeyPair::new().unwrap();
+        let new_identity = KeyPair::new().unwrap();
+        let server_identity = KeyPair::new().unwrap();
+        let mut registry = EnrollmentRegistry::new(server_identity.clone());
+        registry.add_bootstrap_key(bootstrap.public_key);
+        assert_eq!(registry.access(&bootstrap.public_key), Access::EnrollmentOnly);
+        assert_eq!(registry.access(&new_identity.public_key), Access::Denied);
+
+        let (device, server) = handshake_with(bootstrap.clone(), server_identity);
+        let server = RestrictedSession::new(server, bootstrap.public_key);
+        let request = make_enrollment_request(&device, &new_identity).unwrap();
+        let response = registry.enroll(&server, &request).unwrap();
+        assert!(read_enrollment_response(&device, &response).is_ok());
+
+        assert_eq!(registry.access(&new_identity.public_key), Access::Full);
+        assert_eq!(registry.access(&bootstrap.public_key), Access::Denied);
+        // Bootstrap key is burned.
+        let request = make_enrollment_request(&device, &KeyPair::new().unwrap()).unwrap();
+        assert!(registry.enroll(&server, &request).is_err());
+        let response = server.make_enrollment_rejection().unwrap();
+        assert!(read_enrollment_response(&device, &response).is_err());
+    }
+
+    #[test]
+    fn rejected_enrollment_keeps_bootstrap_key() {
+        let bootstrap = KeyPair::new().unwrap();
+        let enrolled = KeyPair::new().unwrap();
+        let server_identity = KeyPair::new().unwrap();
+        let mut registry = EnrollmentRegistry::new(server_identity.clone());
+        registry.add_bootstrap_key(bootstrap.public_key);
+        registry.add_identity(enrolled.public_key);
+
+        let (device, server) = handshake_with(bootstrap.clone(), server_identity.clone());
+        let server = RestrictedSession::new(server, bootstrap.public_key);
+        let request = make_enrollment_request(&device, &enrolled).unwrap();
+        assert!(registry.enroll(&server, &request).is_err());
+        assert_eq!(registry.access(&bootstrap.public_key), Access::EnrollmentOnly);
+
+        // Proof made for another session doesn't count.
+        let new_identity = KeyPair::new().unwrap();
+        let (other_device, other_server) = handshake_with(bootstrap.clone(), server_identity);
+        let other = make_enrollment_request(&other_device, &new_identity).unwrap();
+        let payload = other_server.read_msg(&other).unwrap();
+        let replayed = device.make_request(&payload).unwrap();
+        assert!(registry.enroll(&server, &replayed).is_err());
+
+        // Neither does a key device can't box with.
+        let mut payload = payload.to_vec();
+        payload[1..33].copy_from_slice(&KeyPair::new().unwrap().public_key.0);
+        let stolen = device.make_request(&payload).unwrap();
+        assert!(registry.enroll(&server, &stolen).is_err());
+        assert_eq!(registry.access(&bootstrap.public_key), Access::EnrollmentOnly);
+    }
+
+    #[test]
+    fn only_enrollment_allowed() {
+        let bootstrap = KeyPair::new().unwrap();
+        let server_identity = KeyPair::new().unwrap();
+        let mut registry = EnrollmentRegistry::new(server_identity.clone());
+        registry.add_bootstrap_key(bootstrap.public_key);
+
+        let (device, server) = handshake_with(bootstrap.clone(), server_identity);
+        let server = RestrictedSession::new(server, bootstrap.public_key);
+        let request = device.make_request(b"reboot").unwrap();
+        assert!(registry.enroll(&server, &request).is_err());
+        let notification = device.make_notification(&[0; 33]).unwrap();
+        assert!(registry.enroll(&server, &notification).is_err());
+        assert_eq!(registry.access(&bootstrap.public_key), Access::EnrollmentOnly);
+    }
+}
@@ -1,6 +1,15 @@
 //! This module contain error type returned by this library.
+//!
+//! Every variant has a stable numeric code (see `WhisperError::code`) for
+//! callers that can't match on Rust enums, e.g. FFI. Codes are never reused
+//! or renumbered; new variants get new codes.
 
+use frame::FrameKind;
+use session::HandshakePhase;
+use std::io;
+use termination::TerminationReason;
 use std::result::Result;
+use std::time::Duration;
 
 quick_error! {
     #[derive(Debug)]
@@ -32,6 +41,100 @@ quick_error! {
         BadFrame {}
         /// Trying to use expired session.
         ExpiredSession {}
+        /// Remote identity key is outside of its validity period.
+        ExpiredIdentity {}
+        /// Attestation verifier refused client device.
+        AttestationFailed {}
+        /// Enrollment request was malformed or refused.
+        EnrollmentRejected {}
+        /// PIN pairing failed. Most likely PINs didn't match.
+        PairingFailed {}
+        /// Password authentication failed. Either password is wrong or peer
+        /// sent garbage.
+        PasswordAuthFailed {}
+        /// Message for a stream that isn't open.
+        UnknownStream {}
+        /// Stream window exceeded. Either peer sent more than it was allowed
+        /// or message doesn't fit into window at all.
+        FlowControl {}
+        /// Frame was already received. Someone is replaying captured packets.
+        ReplayedFrame {}
+        /// Frame nonce says it was sent by our side. Either it was reflected
+        /// back at us or session objects got mixed up.
+        WrongDirection {}
+        /// Clocks of client and server are further apart than configured
+        /// skew tolerance.
+        ClockSkew {}
+        /// Other side didn't answer within deadline of this handshake phase.
+        HandshakeTimeout(phase: HandshakePhase) {
+            display("Handshake timed out while awaiting {:?}", phase)
+        }
+        /// Payload codec couldn't encode or decode value.
+        CodecFailed(reason: String) {
+            display("Payload codec failed: {}", reason)
+        }
+        /// Compressed payload is broken or doesn't belong to this stream.
+        CompressionFailed(reason: String) {
+            display("Compression failed: {}", reason)
+        }
+        /// Frame doesn't fit into pacing limits. Try again after this long.
+        RateLimited(retry_in: Duration) {
+            display("Rate limited, retry in {:?}", retry_in)
+        }
+        /// Timestamped payload is older than allowed or from the future.
+        StaleFrame {}
+        /// Ticket is malformed, was sealed with a key that's no longer
+        /// accepted, or wasn't sealed by us at all.
+        InvalidTicket {}
+        /// Integrity trailer doesn't match. Frame was damaged in transit.
+        CorruptedFrame {}
+        /// Frame belongs to another session: its id isn't our peer's session
+        /// key.
+        WrongPeer {}
+        /// Typed reader got frame of another kind: expected, actual.
+        WrongKind(expected: FrameKind, actual: FrameKind) {
+            display("Expected {:?} frame, got {:?}", expected, actual)
+        }
+        /// Response belongs to another request: expected id, actual id.
+        WrongRequestId(expected: u32, actual: u32) {
+            display("Expected response to request {}, got response to {}", expected, actual)
+        }
+        /// Session was closed, nothing can be sent anymore.
+        SessionClosed {}
+        /// Identity is temporarily banned. Ban is lifted after this long.
+        Banned(retry_in: Duration) {
+            display("Banned, retry in {:?}", retry_in)
+        }
+        /// Memory quota of session or the global one is used up.
+        ResourceExhausted {}
+        /// Limit that can't work, e.g. token bucket with zero rate.
+        InvalidLimit {}
+        /// Key type or key file feature isn't supported for import.
+        UnsupportedKey(kind: String) {
+            display("Unsupported key: {}", kind)
+        }
+        /// Key to import doesn't parse.
+        MalformedKey(reason: String) {
+            display("Malformed key: {}", reason)
+        }
+        /// Peer ended handshake with Termination frame for this reason.
+        Terminated(reason: TerminationReason) {
+            display("Peer terminated handshake: {:?}", reason)
+        }
+        /// Message can't be split into fragments, or fragments don't add up.
+        Fragmentation(reason: String) {
+            display("Fragmentation failed: {}", reason)
+        }
+        /// Identity key of this host differs from the one pinned on first
+        /// use. See `known_hosts` module.
+        KeyMismatch(host: String) {
+            display("Identity key of {} doesn't match pinned one", host)
+        }
+        /// IO error of underlying transport.
+        Io(err: io::Error) {
+            from()
+            cause(err)
+        }
         /// Initialization of libsodium failed.
         /// This might happen when machine just booted and doesn't have enough entropy.
         InitializationFailed {}
@@ -40,3 +143,110 @@ quick_error! {
 
 /// Result type used by this library.
 pub type WhisperResult<T> = Result<T, WhisperError>;
+
+impl WhisperError {
+    /// Stable numeric code of the error. Zero is never used.
+    pub fn code(&self) -> u16 {
+        match *self {
+            WhisperError::InvalidReadyFrame => 1,
+            WhisperError::InvalidHelloFrame => 2,
+            WhisperError::InvalidPublicKey => 3,
+            WhisperError::DecryptionFailed => 4,
+            WhisperError::InvalidWelcomeFrame => 5,
+            WhisperError::InvalidInitiateFrame => 6,
+            WhisperError::IncompleteFrame => 7,
+            WhisperError::InvalidSessionState => 8,
+            WhisperError::BadFrame => 9,
+            WhisperError::ExpiredSession => 10,
+            WhisperError::InitializationFailed => 11,
+            WhisperError::ExpiredIdentity => 12,
+            WhisperError::AttestationFailed => 13,
+            WhisperError::EnrollmentRejected => 14,
+            WhisperError::PairingFailed => 15,
+            WhisperError::PasswordAuthFailed => 16,
+            WhisperError::UnknownStream => 17,
+            WhisperError::FlowControl => 18,
+            WhisperError::ReplayedFrame => 19,
+            WhisperError::Io(_) => 20,
+            WhisperError::WrongDirection => 21,
+            WhisperError::ClockSkew => 22,
+            WhisperError::HandshakeTimeout(_) => 23,
+            WhisperError::CodecFailed(_) => 24,
+            WhisperError::CompressionFailed(_) => 25,
+            WhisperError::RateLimited(_) => 26,
+            WhisperError::StaleFrame => 27,
+            WhisperError::InvalidTicket => 28,
+            WhisperError::CorruptedFrame => 29,
+            WhisperError::WrongPeer => 30,
+            WhisperError::WrongKind(..) => 31,
+            WhisperError::WrongRequestId(..) => 32,
+            WhisperError::SessionClosed => 33,
+            WhisperError::Banned(_) => 34,
+            WhisperError::ResourceExhausted => 35,
+            WhisperError::UnsupportedKey(_) => 36,
+            WhisperError::MalformedKey(_) => 37,
+            WhisperError::Terminated(_) => 38,
+            WhisperError::Fragmentation(_) => 39,
+            WhisperError::KeyMismatch(_) => 40,
+            WhisperError::InvalidLimit => 41,
+        }
+    }
+
+    /// Closest `io::ErrorKind`.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match *self {
+            WhisperError::Io(ref err) => err.kind(),
+            WhisperError::IncompleteFrame => io::ErrorKind::UnexpectedEof,
+            WhisperError::ExpiredSession |
+            WhisperError::HandshakeTimeout(_) => io::ErrorKind::TimedOut,
+            WhisperError::InvalidPublicKey |
+            WhisperError::InvalidSessionState |
+            WhisperError::UnsupportedKey(_) |
+            WhisperError::MalformedKey(_) |
+            WhisperError::InvalidLimit => io::ErrorKind::InvalidInput,
+            WhisperError::SessionClosed => io::ErrorKind::NotConnected,
+            WhisperError::Terminated(_) => io::ErrorKind::ConnectionRefused,
+            WhisperError::ExpiredIdentity |
+            WhisperError::AttestationFailed |
+            WhisperError::EnrollmentRejected |
+            WhisperError::PairingFailed |
+            WhisperError::PasswordAuthFailed |
+            WhisperError::InvalidTicket |
+            WhisperError::KeyMismatch(_) |
+            WhisperError::Banned(_) => io::ErrorKind::PermissionDenied,
+            WhisperError::InitializationFailed => io::ErrorKind::Other,
+            WhisperError::ResourceExhausted => io::ErrorKind::OutOfMemory,
+            WhisperError::RateLimited(_) => io::ErrorKind::WouldBlock,
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl From<WhisperError> for io::Error {
+    fn from(err: WhisperError) -> io::Error {
+        match err {
+            // Don't wrap twice.
+            WhisperError::Io(err) => err,
+            err => io::Error::new(err.io_kind(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn io_error_conversion() {
+        let err: io::Error = WhisperError::IncompleteFrame.into();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let inner = err.get_ref().unwrap().downcast_ref::<WhisperError>().unwrap();
+        assert_eq!(inner.code(), 7);
+
+        let err: io::Error = WhisperError::Io(io::ErrorKind::BrokenPipe.into()).into();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(err.get_ref().is_none());
+        assert_eq!(io::Error::from(WhisperError::DecryptionFailed).kind(),
+                   io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,840 @@
+//! High level `Client` and `Server` for the common case: blocking byte
+//! stream (e.g. `TcpStream`), one session per stream, plain byte payloads.
+//! Handshake and framing are done for you. Low level modules are still
+//! there for everything else.
+//!
+//! Heartbeats (see `liveness` module) are answered while reading, so peer
+//! that pings with `Connection::ping` hears back as long as this side keeps
+//! calling `recv` or `request`.
+//!
+//! Server can hand client over to another server of the fleet (same
+//! identity key) with `Connection::redirect_to`. Client's `recv` then ends
+//! with `Connection::redirect` set, and `Client::reconnect` takes it from
+//! there.
+//!
+//! Give server an `IdentityLimiter` (`Server::set_limiter`) to rate limit
+//! clients by identity key and temporarily ban abusers. Banned client's
+//! handshake ends with `Banned` error telling when to retry.
+//!
+//! With `Server::set_memory_quota` every connection gets its share of
+//! global memory budget. Messages kept for `recv` are charged to it, and
+//! clients that don't fit are refused with `RetryLater`.
+//!
+//! With `Server::set_ticket_keys` server hands every client a resumption
+//! ticket after handshake (see `resumption` module). Client finds it in
+//! `Connection::resumption_ticket` and reconnects in one round trip with
+//! `Client::resume`. Clients allowed only through `allow_master` can't
+//! resume: ticket doesn't carry device certificate.
+//!
+//! ```no_run
+//! use libwhisper::{Client, Server};
+//! use std::net::{TcpListener, TcpStream};
+//!
+//! let server = Server::generate().unwrap();
+//! let client = Client::generate(server.public_key()).unwrap();
+//! # let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+//! # let addr = listener.local_addr().unwrap();
+//! let mut connection = client.connect(TcpStream::connect(addr).unwrap()).unwrap();
+//! let reply = connection.request(b"ping").unwrap();
+//! ```
+
+use bytes::Bytes;
+use chrono::Duration;
+use codec::CodecRegistry;
+use crypto::KeyPair;
+use devices::DeviceCertificate;
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use limits::{IdentityLimiter, ban_termination};
+use liveness;
+use metadata;
+use quota::{MemoryQuota, Reservation, SessionQuota};
+use redirect::Redirect;
+use resumption::{self, ResumeRequest, ResumptionTicket};
+use server::ServerIdentity;
+use session::{ClientSession, EstablishedSession, SessionConfig};
+use sodiumoxide::crypto::box_::PublicKey;
+use sodiumoxide::crypto::sign;
+use std::collections::{HashSet, VecDeque};
+use std::io::{Read, Write};
+use termination::TerminationReason;
+use tickets::TicketKeys;
+use transport::{pack_prefixed, read_body, read_length, read_prefixed, unpack_prefixed};
+
+/// Client side. Knows its own identity and the server's public key.
+#[derive(Debug, Clone)]
+pub struct Client {
+    identity: KeyPair,
+    server_key: PublicKey,
+    certificate: Option<DeviceCertificate>,
+    config: SessionConfig,
+}
+
+impl Client {
+    /// Client with existing identity.
+    pub fn new(identity: KeyPair, server_key: PublicKey) -> Client {
+        Client {
+            identity,
+            server_key,
+            certificate: None,
+            config: SessionConfig::default(),
+        }
+    }
+
+    /// Client with freshly generated identity.
+    pub fn generate(server_key: PublicKey) -> WhisperResult<Client> {
+        Ok(Client::new(KeyPair::new()?, server_key))
+    }
+
+    /// Our identity public key. Server needs it to `allow` us.
+    pub fn public_key(&self) -> PublicKey { self.identity.public_key }
+
+    /// Present certificate of our identity key in every handshake, so
+    /// servers can `allow_master` instead of each device.
+    pub fn set_device_certificate(&mut self, certificate: DeviceCertificate) {
+        self.certificate = Some(certificate);
+    }
+
+    /// Do handshakes with these protocol parameters.
+    pub fn set_session_config(&mut self, config: SessionConfig) { self.config = config; }
+
+    /// Do handshake over the stream.
+    pub fn connect<S: Read + Write>(&self, stream: S) -> WhisperResult<Connection<S>> {
+        self.handshake(stream, None)
+    }
+
+    /// Do handshake over the stream to the endpoint server redirected us
+    /// to, presenting its ticket.
+    pub fn reconnect<S: Read + Write>(&self,
+                                      redirect: &Redirect,
+                                      stream: S)
+                                      -> WhisperResult<Connection<S>> {
+        self.handshake(stream, redirect.ticket.clone())
+    }
+
+    /// Resume session in one round trip with ticket server gave us.
+    /// Expired ticket is `InvalidTicket`; refused one is `Terminated`.
+    /// `connect` instead in both cases.
+    pub fn resume<S: Read + Write>(&self,
+                                   ticket: &ResumptionTicket,
+                                   mut stream: S)
+                                   -> WhisperResult<Connection<S>> {
+        let (resuming, resume) = ticket.make_resume()?;
+        write_frame(&mut stream, &resume)?;
+        let ready = read_handshake_frame(&mut stream)?;
+        let session = resuming.read_ready(&ready)?;
+        Ok(Connection::new(session, stream, ticket.server_identity_key()))
+    }
+
+    fn handshake<S: Read + Write>(&self,
+                                  mut stream: S,
+                                  ticket: Option<Bytes>)
+                                  -> WhisperResult<Connection<S>> {
+        let mut session =
+            ClientSession::with_config(self.identity.clone(), self.server_key, self.config)?;
+        if let Some(ticket) = ticket {
+            session.set_initiate_metadata(metadata::TICKET, ticket);
+        }
+        if let Some(ref certificate) = self.certificate {
+            session.set_device_certificate(certificate);
+        }
+        write_frame(&mut stream, &session.make_hello()?)?;
+        let welcome = read_handshake_frame(&mut stream)?;
+        write_frame(&mut stream, &session.make_initiate(&welcome)?)?;
+        let ready = read_handshake_frame(&mut stream)?;
+        let session = session.read_ready(&ready)?;
+        Ok(Connection::new(session, stream, self.server_key))
+    }
+}
+
+/// Server side. Accepts clients on streams handed to it.
+#[derive(Debug, Clone)]
+pub struct Server {
+    identity: ServerIdentity,
+    allowed: Option<HashSet<PublicKey>>,
+    allowed_masters: Option<HashSet<sign::PublicKey>>,
+    limiter: Option<IdentityLimiter>,
+    // Global budget and limit of each session.
+    quota: Option<(MemoryQuota, usize)>,
+    config: SessionConfig,
+    // Keys and lifetime of resumption tickets.
+    tickets: Option<(TicketKeys, Duration)>,
+}
+
+impl Server {
+    /// Server with existing identity. Accepts any client until `allow` is
+    /// called.
+    pub fn new(identity: KeyPair) -> Server {
+        Server {
+            identity: ServerIdentity::new(identity),
+            allowed: None,
+            allowed_masters: None,
+            limiter: None,
+            quota: None,
+            config: SessionConfig::default(),
+            tickets: None,
+        }
+    }
+
+    /// Server with freshly generated identity.
+    pub fn generate() -> WhisperResult<Server> { Ok(Server::new(KeyPair::new()?)) }
+
+    /// Our identity public key. Clients need it to connect.
+    pub fn public_key(&self) -> PublicKey { self.identity.public_key() }
+
+    /// Identity handle. Use it to rotate keys.
+    pub fn identity(&self) -> &ServerIdentity { &self.identity }
+
+    /// Only accept clients that were allowed.
+    pub fn allow(&mut self, client_key: PublicKey) {
+        self.allowed.get_or_insert_with(HashSet::new).insert(client_key);
+    }
+
+    /// Accept every device certified by this master key. See `devices`
+    /// module.
+    pub fn allow_master(&mut self, master: sign::PublicKey) {
+        self.allowed_masters.get_or_insert_with(HashSet::new).insert(master);
+    }
+
+    /// Rate limit clients and ban abusers. Every frame client sends counts
+    /// as a message and is charged before it's read: `recv` over the rate
+    /// fails with `RateLimited` and leaves the frame in the stream for the
+    /// next call. Refused strangers count as failures of their identity.
+    pub fn set_limiter(&mut self, limiter: IdentityLimiter) { self.limiter = Some(limiter); }
+
+    /// Charge connections to global memory budget, each up to
+    /// `per_session` bytes.
+    pub fn set_memory_quota(&mut self, quota: MemoryQuota, per_session: usize) {
+        self.quota = Some((quota, per_session));
+    }
+
+    /// Do handshakes with these protocol parameters.
+    pub fn set_session_config(&mut self, config: SessionConfig) { self.config = config; }
+
+    /// Give every client resumption ticket sealed by these keys, good for
+    /// `lifetime`, and accept Resume with them.
+    pub fn set_ticket_keys(&mut self, keys: TicketKeys, lifetime: Duration) {
+        self.tickets = Some((keys, lifetime));
+    }
+
+    fn is_allowed(&self, client_key: &PublicKey, master: Option<sign::PublicKey>) -> bool {
+        if self.allowed.is_none() && self.allowed_masters.is_none() {
+            return true;
+        }
+        self.allowed.as_ref().is_some_and(|allowed| allowed.contains(client_key)) ||
+        master.is_some_and(|master| {
+                               self.allowed_masters
+                                   .as_ref()
+                                   .is_some_and(|masters| masters.contains(&master))
+                           })
+    }
+
+    /// Do handshake over the stream, or resume session if client starts
+    /// with Resume. Clients that aren't allowed get Termination and
+    /// `InvalidPublicKey` error is returned. Banned clients get `Banned`
+    /// Termination and error. Clients that don't fit into memory quota get
+    /// `RetryLater` and `ResourceExhausted`. Resume we can't open gets
+    /// Termination and `InvalidTicket`.
+    pub fn accept<S: Read + Write>(&self, mut stream: S) -> WhisperResult<Connection<S>> {
+        let hello = read_handshake_frame(&mut stream)?;
+        let quota = match self.quota {
+            Some((ref quota, per_session)) => {
+                match quota.session(per_session) {
+                    Ok(quota) => Some(quota),
+                    Err(err) => {
+                        let termination = TerminationReason::RetryLater.to_frame(hello.id)?;
+                        write_frame(&mut stream, &termination)?;
+                        return Err(err);
+                    }
+                }
+            }
+            None => None,
+        };
+        if hello.kind == FrameKind::Resume {
+            return self.resume(&hello, stream, quota);
+        }
+        let mut session = self.identity.session_for_hello_with_config(&hello, self.config)?;
+        write_frame(&mut stream, &session.make_welcome(&hello)?)?;
+        let initiate = read_handshake_frame(&mut stream)?;
+        let opened = session.open_initiate(&initiate)?;
+        let client_key = opened.client_identity_key;
+        self.admit(&mut stream, initiate.id, &client_key, opened.master_identity)?;
+        let ticket = opened.metadata.get(metadata::TICKET).cloned();
+        let (session, ready) = session.make_ready(&initiate, &client_key)?;
+        write_frame(&mut stream, &ready)?;
+        let mut connection = self.established(session, stream, client_key, quota)?;
+        connection.ticket = ticket;
+        Ok(connection)
+    }
+
+    fn resume<S: Read + Write>(&self,
+                               resume: &Frame,
+                               mut stream: S,
+                               quota: Option<SessionQuota>)
+                               -> WhisperResult<Connection<S>> {
+        let request = match self.tickets {
+            Some((ref keys, _)) => ResumeRequest::open(keys, resume),
+            None => Err(WhisperError::InvalidTicket),
+        };
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                // Client does full handshake instead.
+                write_frame(&mut stream, &TerminationReason::Unspecified.to_frame(resume.id)?)?;
+                return Err(err);
+            }
+        };
+        let client_key = request.client_identity_key();
+        self.admit(&mut stream, resume.id, &client_key, None)?;
+        let (session, ready) = request.make_ready()?;
+        write_frame(&mut stream, &ready)?;
+        self.established(session, stream, client_key, quota)
+    }
+
+    // Refuse banned clients and clients that aren't allowed.
+    fn admit<S: Write>(&self,
+                       stream: &mut S,
+                       id: PublicKey,
+                       client_key: &PublicKey,
+                       master: Option<sign::PublicKey>)
+                       -> WhisperResult<()> {
+        if let Some(ref limiter) = self.limiter {
+            if let Err(WhisperError::Banned(retry_in)) = limiter.check(client_key) {
+                write_frame(stream, &ban_termination(id, retry_in)?)?;
+                return Err(WhisperError::Banned(retry_in));
+            }
+        }
+        if !self.is_allowed(client_key, master) {
+            if let Some(ref limiter) = self.limiter {
+                let _ = limiter.record_failure(client_key);
+            }
+            write_frame(stream, &TerminationReason::Unspecified.to_frame(id)?)?;
+            return Err(WhisperError::InvalidPublicKey);
+        }
+        Ok(())
+    }
+
+    fn established<S: Read + Write>(&self,
+                                    session: EstablishedSession,
+                                    stream: S,
+                                    client_key: PublicKey,
+                                    quota: Option<SessionQuota>)
+                                    -> WhisperResult<Connection<S>> {
+        let mut connection = Connection::new(session, stream, client_key);
+        connection.limiter = self.limiter.clone();
+        connection.quota = quota;
+        if let Some((ref keys, lifetime)) = self.tickets {
+            let frame = resumption::issue(keys, &connection.session, lifetime)?;
+            connection.write(&frame)?;
+        }
+        Ok(connection)
+    }
+}
+
+// Message with memory it's charged for.
+type Message = (FrameKind, Bytes, Option<Reservation>);
+
+/// Established encrypted connection. Same on both sides.
+pub struct Connection<S> {
+    session: EstablishedSession,
+    stream: S,
+    remote_identity_key: PublicKey,
+    // Messages that arrived while waiting for a response.
+    inbox: VecDeque<Message>,
+    redirect: Option<Redirect>,
+    ticket: Option<Bytes>,
+    resumption: Option<ResumptionTicket>,
+    limiter: Option<IdentityLimiter>,
+    quota: Option<SessionQuota>,
+    // Length of frame whose prefix is read, but that didn't fit into quota.
+    pending: Option<usize>,
+}
+
+impl<S: Read + Write> Connection<S> {
+    fn new(session: EstablishedSession, stream: S, remote_identity_key: PublicKey) -> Connection<S> {
+        Connection {
+            session,
+            stream,
+            remote_identity_key,
+            inbox: VecDeque::new(),
+            redirect: None,
+            ticket: None,
+            resumption: None,
+            limiter: None,
+            quota: None,
+            pending: None,
+        }
+    }
+
+    /// Identity key of the other side.
+    pub fn remote_identity_key(&self) -> PublicKey { self.remote_identity_key }
+
+    /// Send request and wait for response. Anything else that arrives in the
+    /// meantime is kept for `recv`. Fails with `RateLimited` if session's
+    /// pacer doesn't let request out yet; nothing is sent then. Message
+    /// that doesn't fit into memory quota fails it with
+    /// `ResourceExhausted` and stays in the stream for `recv`.
+    pub fn request(&mut self, data: &[u8]) -> WhisperResult<Bytes> {
+        let frame = self.session.make_request(data)?;
+        self.write(&frame)?;
+        loop {
+            match self.read(true)? {
+                Some((FrameKind::Response, data, _)) => return Ok(data),
+                Some(message) => self.inbox.push_back(message),
+                None => return Err(WhisperError::InvalidSessionState),
+            }
+        }
+    }
+
+    /// Reply to a request received with `recv`. Can fail with
+    /// `RateLimited` same as `request`.
+    pub fn respond(&mut self, data: &[u8]) -> WhisperResult<()> {
+        let frame = self.session.make_response(data)?;
+        self.write(&frame)
+    }
+
+    /// Send message that doesn't need a reply. Can fail with `RateLimited`
+    /// same as `request`.
+    pub fn send(&mut self, data: &[u8]) -> WhisperResult<()> {
+        let frame = self.session.make_notification(data)?;
+        self.write(&frame)
+    }
+
+    /// Ask peer to answer with heartbeat ack. Ack bumps
+    /// `EstablishedSession::last_seen` when it's read and isn't returned.
+    pub fn ping(&mut self) -> WhisperResult<()> {
+        let frame = self.session.make_ping()?;
+        self.write(&frame)
+    }
+
+    /// Next incoming message and its kind. None once other side is gone.
+    pub fn recv(&mut self) -> WhisperResult<Option<(FrameKind, Bytes)>> {
+        if let Some((kind, data, _)) = self.inbox.pop_front() {
+            return Ok(Some((kind, data)));
+        }
+        Ok(self.read(false)?.map(|(kind, data, _)| (kind, data)))
+    }
+
+    /// Same as `request`, but for structured values. Request and response
+    /// carry content type hint.
+    pub fn request_value<T>(&mut self, codecs: &CodecRegistry<T>, value: &T) -> WhisperResult<T> {
+        let response = self.request(&codecs.encode(value)?)?;
+        codecs.decode(&response)
+    }
+
+    /// Same as `respond`, but for structured values.
+    pub fn respond_value<T>(&mut self, codecs: &CodecRegistry<T>, value: &T) -> WhisperResult<()> {
+        self.respond(&codecs.encode(value)?)
+    }
+
+    /// Same as `send`, but for structured values.
+    pub fn send_value<T>(&mut self, codecs: &CodecRegistry<T>, value: &T) -> WhisperResult<()> {
+        self.send(&codecs.encode(value)?)
+    }
+
+    /// Same as `recv`, but for structured values.
+    pub fn recv_value<T>(&mut self, codecs: &CodecRegistry<T>) -> WhisperResult<Option<(FrameKind, T)>> {
+        match self.recv()? {
+            Some((kind, payload)) => Ok(Some((kind, codecs.decode(&payload)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Tell client to reconnect elsewhere. Session is over after this: it's
+    /// closed, and client isn't sent Termination on drop.
+    pub fn redirect_to(&mut self, redirect: &Redirect) -> WhisperResult<()> {
+        let frame = redirect.to_frame(&self.session)?;
+        // Redirect stands in for Termination, so the one close makes is dropped.
+        self.session.close(TerminationReason::Unspecified)?;
+        self.write(&frame)
+    }
+
+    /// Where server told us to reconnect. Set once `recv` (or `request`)
+    /// ended because of it.
+    pub fn redirect(&self) -> Option<&Redirect> { self.redirect.as_ref() }
+
+    /// Memory quota of this connection, if server has one.
+    pub fn quota(&self) -> Option<&SessionQuota> { self.quota.as_ref() }
+
+    /// Ticket client presented in handshake, if it came from redirect.
+    pub fn ticket(&self) -> Option<&Bytes> { self.ticket.as_ref() }
+
+    /// Latest resumption ticket server gave us, for `Client::resume`. Comes
+    /// along with other messages, so it's here after the first `recv` or
+    /// `request`.
+    pub fn resumption_ticket(&self) -> Option<&ResumptionTicket> { self.resumption.as_ref() }
+
+    /// Underlying session for everything facade doesn't cover.
+    pub fn session(&mut self) -> &mut EstablishedSession { &mut self.session }
+
+    /// Give back the stream.
+    pub fn into_inner(self) -> S { self.stream }
+
+    fn write(&mut self, frame: &Frame) -> WhisperResult<()> {
+        self.stream.write_all(&self.session.pack(frame))?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    // Messages to keep are charged to quota before they're read.
+    fn read(&mut self, keep: bool) -> WhisperResult<Option<Message>> {
+        loop {
+            // Frame that didn't fit was charged to limiter already.
+            let len = match self.pending.take() {
+                Some(len) => len,
+                None => {
+                    self.limit()?;
+                    match read_length(&mut self.stream)? {
+                        Some(len) => len,
+                        None => return Ok(None),
+                    }
+                }
+            };
+            let reservation = match self.quota {
+                Some(ref quota) if keep => {
+                    match quota.reserve(len) {
+                        Ok(reservation) => Some(reservation),
+                        Err(err) => {
+                            self.pending = Some(len);
+                            return Err(err);
+                        }
+                    }
+                }
+                _ => None,
+            };
+            let packet = read_body(&mut self.stream, len)?;
+            let (frame, data) = self.session.read_packet(&packet)?;
+            if frame.kind == FrameKind::Termination {
+                return Ok(None);
+            }
+            if frame.kind == FrameKind::Control {
+                if let Some(redirect) = Redirect::decode(&data)? {
+                    self.redirect = Some(redirect);
+                    return Ok(None);
+                }
+                if let Some(ticket) = ResumptionTicket::decode(&self.session, &data)? {
+                    self.resumption = Some(ticket);
+                    continue;
+                }
+                if let Some(ack) = liveness::answer_payload(&self.session, &data)? {
+                    if let Some(ack) = ack {
+                        self.write(&ack)?;
+                    }
+                    continue;
+                }
+            }
+            return Ok(Some((frame.kind, data, reservation)));
+        }
+    }
+
+    // Charged before reading, so nothing read is thrown away. Client that
+    // got banned is hung up on.
+    fn limit(&mut self) -> WhisperResult<()> {
+        let result = match self.limiter {
+            Some(ref limiter) => limiter.message(&self.remote_identity_key),
+            None => return Ok(()),
+        };
+        if let Err(WhisperError::Banned(_)) = result {
+            let frame = self.session.close(TerminationReason::Banned)?;
+            self.write(&frame)?;
+        }
+        result
+    }
+}
+
+fn write_frame<W: Write>(writer: &mut W, frame: &Frame) -> WhisperResult<()> {
+    writer.write_all(&pack_prefixed(frame))?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_handshake_frame<R: Read>(reader: &mut R) -> WhisperResult<Frame> {
+    let packet = read_prefixed(reader)?.ok_or(WhisperError::IncompleteFrame)?;
+    let frame = unpack_prefixed(&packet)?;
+    if let Some(reason) = TerminationReason::from_frame(&frame) {
+        if reason == TerminationReason::Banned {
+            let retry_in = TerminationReason::retry_after(&frame).unwrap_or_default();
+            return Err(WhisperError::Banned(retry_in));
+        }
+        return Err(WhisperError::Terminated(reason));
+    }
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pacing::Pacer;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn ping_over_tcp() {
+        let mut server = Server::generate().unwrap();
+        let client = Client::generate(server.public_key()).unwrap();
+        server.allow(client.public_key());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client_key = client.public_key();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = server.accept(stream).unwrap();
+            assert_eq!(connection.remote_identity_key(), client_key);
+            let (kind, data) = connection.recv().unwrap().unwrap();
+            assert_eq!(kind, FrameKind::Notification);
+            assert_eq!(data.as_ref(), b"hi");
+            let (kind, data) = connection.recv().unwrap().unwrap();
+            assert_eq!(kind, FrameKind::Request);
+            assert_eq!(data.as_ref(), b"ping");
+            connection.respond(b"pong").unwrap();
+            assert!(connection.recv().unwrap().is_none());
+        });
+
+        let mut connection = client.connect(TcpStream::connect(addr).unwrap()).unwrap();
+        connection.send(b"hi").unwrap();
+        assert_eq!(connection.request(b"ping").unwrap().as_ref(), b"pong");
+        drop(connection);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn heartbeats_are_answered() {
+        let mut server = Server::generate().unwrap();
+        let client = Client::generate(server.public_key()).unwrap();
+        server.allow(client.public_key());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = server.accept(stream).unwrap();
+            let (kind, data) = connection.recv().unwrap().unwrap();
+            assert_eq!(kind, FrameKind::Request);
+            assert_eq!(data.as_ref(), b"after ping");
+            connection.respond(b"ok").unwrap();
+            assert!(connection.recv().unwrap().is_none());
+        });
+
+        let mut connection = client.connect(TcpStream::connect(addr).unwrap()).unwrap();
+        connection.ping().unwrap();
+        // Ack comes before response and is taken on the way.
+        assert_eq!(connection.request(b"after ping").unwrap().as_ref(), b"ok");
+        assert!(connection.inbox.is_empty());
+        drop(connection);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn pacing_is_left_to_caller() {
+        let mut server = Server::generate().unwrap();
+        let client = Client::generate(server.public_key()).unwrap();
+        server.allow(client.public_key());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = server.accept(stream).unwrap();
+            assert_eq!(connection.recv().unwrap().unwrap().1.as_ref(), b"one");
+            assert!(connection.recv().unwrap().is_none());
+        });
+
+        let mut connection = client.connect(TcpStream::connect(addr).unwrap()).unwrap();
+        let mut pacer = Pacer::new();
+        pacer.limit_frames(1, 1).unwrap();
+        connection.session().set_pacer(pacer);
+        connection.send(b"one").unwrap();
+        match connection.send(b"two") {
+            Err(WhisperError::RateLimited(_)) => {}
+            other => panic!("Expected RateLimited, got {:?}", other),
+        }
+        drop(connection);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn redirect_to_other_server() {
+        let identity = KeyPair::new().unwrap();
+        let old = Server::new(identity.clone());
+        let new = Server::new(identity);
+        let client = Client::generate(old.public_key()).unwrap();
+        let old_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let old_addr = old_listener.local_addr().unwrap();
+        let new_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let new_addr = new_listener.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = old_listener.accept().unwrap();
+            let mut connection = old.accept(stream).unwrap();
+            assert!(connection.ticket().is_none());
+            let redirect = Redirect::with_ticket(&new_addr.to_string(), &b"ticket"[..]);
+            connection.redirect_to(&redirect).unwrap();
+            assert!(connection.session().is_closed());
+            assert!(connection.send(b"still here").is_err());
+
+            let (stream, _) = new_listener.accept().unwrap();
+            let mut connection = new.accept(stream).unwrap();
+            assert_eq!(connection.ticket().unwrap().as_ref(), b"ticket");
+            connection.respond(b"welcome back").unwrap();
+        });
+
+        let mut connection = client.connect(TcpStream::connect(old_addr).unwrap()).unwrap();
+        assert!(connection.recv().unwrap().is_none());
+        let redirect = connection.redirect().unwrap().clone();
+        let stream = TcpStream::connect(&redirect.endpoint[..]).unwrap();
+        let mut connection = client.reconnect(&redirect, stream).unwrap();
+        assert_eq!(connection.recv().unwrap().unwrap().1.as_ref(), b"welcome back");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn resume_with_ticket() {
+        let mut server = Server::generate().unwrap();
+        let keys = TicketKeys::new(Duration::hours(1), Duration::hours(1)).unwrap();
+        server.set_ticket_keys(keys.clone(), Duration::hours(1));
+        let client = Client::generate(server.public_key()).unwrap();
+        server.allow(client.public_key());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client_key = client.public_key();
+        let handle = thread::spawn(move || {
+            for reply in &[&b"full"[..], &b"resumed"[..]] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut connection = server.accept(stream).unwrap();
+                assert_eq!(connection.remote_identity_key(), client_key);
+                connection.recv().unwrap().unwrap();
+                connection.respond(reply).unwrap();
+            }
+            let (stream, _) = listener.accept().unwrap();
+            server.accept(stream).map(|_| ())
+        });
+
+        let mut connection = client.connect(TcpStream::connect(addr).unwrap()).unwrap();
+        assert_eq!(connection.request(b"hi").unwrap().as_ref(), b"full");
+        let ticket = connection.resumption_ticket().unwrap().clone();
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut connection = client.resume(&ticket, stream).unwrap();
+        assert_eq!(connection.request(b"hi").unwrap().as_ref(), b"resumed");
+        assert!(connection.resumption_ticket().is_some());
+
+        // Tickets die with their keys.
+        keys.retire_all();
+        let stream = TcpStream::connect(addr).unwrap();
+        match client.resume(&ticket, stream) {
+            Err(WhisperError::Terminated(_)) => {}
+            other => panic!("Expected Terminated, got {:?}", other.map(|_| ())),
+        }
+        match handle.join().unwrap() {
+            Err(WhisperError::InvalidTicket) => {}
+            other => panic!("Expected InvalidTicket, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stranger_is_refused() {
+        let mut server = Server::generate().unwrap();
+        server.allow(KeyPair::new().unwrap().public_key);
+        let client = Client::generate(server.public_key()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            server.accept(stream).map(|_| ())
+        });
+        assert!(client.connect(TcpStream::connect(addr).unwrap()).is_err());
+        match handle.join().unwrap() {
+            Err(WhisperError::InvalidPublicKey) => {}
+            other => panic!("Expected InvalidPublicKey, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn message_over_quota_is_kept() {
+        use quota::SESSION_COST;
+
+        let mut server = Server::generate().unwrap();
+        server.set_memory_quota(MemoryQuota::new(SESSION_COST + 1000), 100);
+        let client = Client::generate(server.public_key()).unwrap();
+        server.allow(client.public_key());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = server.accept(stream).unwrap();
+            match connection.request(b"ping") {
+                Err(WhisperError::ResourceExhausted) => {}
+                other => panic!("Expected ResourceExhausted, got {:?}", other),
+            }
+            let (kind, data) = connection.recv().unwrap().unwrap();
+            assert_eq!(kind, FrameKind::Notification);
+            assert_eq!(data.as_ref(), &[7; 300][..]);
+            let (kind, data) = connection.recv().unwrap().unwrap();
+            assert_eq!(kind, FrameKind::Response);
+            assert_eq!(data.as_ref(), b"pong");
+            assert_eq!(connection.quota().unwrap().used(), 0);
+        });
+
+        let mut connection = client.connect(TcpStream::connect(addr).unwrap()).unwrap();
+        connection.send(&[7; 300]).unwrap();
+        assert_eq!(connection.recv().unwrap().unwrap().1.as_ref(), b"ping");
+        connection.respond(b"pong").unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn limited_message_is_kept() {
+        use limits::IdentityLimits;
+
+        let mut server = Server::generate().unwrap();
+        let limiter = IdentityLimiter::new(IdentityLimits {
+                                               messages_per_sec: 1,
+                                               burst: 1,
+                                               ..Default::default()
+                                           })
+            .unwrap();
+        server.set_limiter(limiter);
+        let client = Client::generate(server.public_key()).unwrap();
+        server.allow(client.public_key());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = server.accept(stream).unwrap();
+            assert_eq!(connection.recv().unwrap().unwrap().1.as_ref(), b"one");
+            let wait = match connection.recv() {
+                Err(WhisperError::RateLimited(wait)) => wait,
+                other => panic!("Expected RateLimited, got {:?}", other),
+            };
+            thread::sleep(wait);
+            assert_eq!(connection.recv().unwrap().unwrap().1.as_ref(), b"two");
+        });
+
+        let mut connection = client.connect(TcpStream::connect(addr).unwrap()).unwrap();
+        connection.send(b"one").unwrap();
+        connection.send(b"two").unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn banned_client_is_terminated() {
+        let mut server = Server::generate().unwrap();
+        let limiter = IdentityLimiter::new(Default::default()).unwrap();
+        server.set_limiter(limiter.clone());
+        let client = Client::generate(server.public_key()).unwrap();
+        limiter.ban(&client.public_key(), ::std::time::Duration::from_secs(90));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            server.accept(stream).map(|_| ())
+        });
+        match client.connect(TcpStream::connect(addr).unwrap()) {
+            Err(WhisperError::Banned(retry_in)) => assert_eq!(retry_in.as_secs(), 90),
+            other => panic!("Expected Banned, got {:?}", other.map(|_| ())),
+        }
+        match handle.join().unwrap() {
+            Err(WhisperError::Banned(_)) => {}
+            other => panic!("Expected Banned, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Fault injection for resilience testing, behind `faults` feature.
+//! Frameworks built on top of this crate can make sessions fail on demand
+//! and check their error handling deterministically, instead of hoping a
+//! real network misbehaves the right way.
+//!
+//! Give `Faults` to a session with `EstablishedSession::set_faults`, then
+//! arm faults from the test:
+//! - `fail_next_decryption`: next frame read fails with `DecryptionFailed`.
+//! - `corrupt_next_nonce`: next frame made gets a flipped nonce bit, so
+//!   peer fails to decrypt it.
+//! - `force_expiry`: session behaves as expired until `restore_expiry`.
+//!
+//! Established sessions are never rekeyed by the protocol, so there's no
+//! rekey to delay; renegotiation doesn't change keys. Don't enable this
+//! feature in production builds.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[derive(Debug, Default)]
+struct Flags {
+    decryption_failures: AtomicUsize,
+    nonce_corruptions: AtomicUsize,
+    expired: AtomicBool,
+}
+
+/// Faults armed for a session. Clones share them, so keep one to arm
+/// faults of session (and all its clones and halves) it was given to.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    flags: Arc<Flags>,
+}
+
+impl Faults {
+    /// Nothing armed.
+    pub fn new() -> Faults { Faults::default() }
+
+    /// Make next read of an authentic frame fail with `DecryptionFailed`.
+    /// Calls add up.
+    pub fn fail_next_decryption(&self) {
+        self.flags.decryption_failures.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Flip a bit in the nonce of next frame made. Direction bit is left
+    /// alone, so peer gets `DecryptionFailed`. With `null-cipher` nothing
+    /// checks the nonce and the frame goes through. Calls add up.
+    pub fn corrupt_next_nonce(&self) { self.flags.nonce_corruptions.fetch_add(1, Ordering::SeqCst); }
+
+    /// Session is expired from now on: making frames fails with
+    /// `ExpiredSession`.
+    pub fn force_expiry(&self) { self.flags.expired.store(true, Ordering::SeqCst); }
+
+    /// Undo `force_expiry`.
+    pub fn restore_expiry(&self) { self.flags.expired.store(false, Ordering::SeqCst); }
+
+    /// Disarm everything.
+    pub fn clear(&self) {
+        self.flags.decryption_failures.store(0, Ordering::SeqCst);
+        self.flags.nonce_corruptions.store(0, Ordering::SeqCst);
+        self.restore_expiry();
+    }
+
+    pub(crate) fn take_decryption_failure(&self) -> bool { take(&self.flags.decryption_failures) }
+
+    pub(crate) fn take_nonce_corruption(&self) -> bool { take(&self.flags.nonce_corruptions) }
+
+    pub(crate) fn is_expired(&self) -> bool { self.flags.expired.load(Ordering::SeqCst) }
+}
+
+fn take(counter: &AtomicUsize) -> bool {
+    counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1))
+           .is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use errors::WhisperError;
+    use session::test::handshake;
+
+    #[test]
+    fn armed_faults_fire_once() {
+        let (mut client, mut server) = handshake();
+        let client_faults = Faults::new();
+        let server_faults = Faults::new();
+        client.set_faults(client_faults.clone());
+        server.set_faults(server_faults.clone());
+
+        server_faults.fail_next_decryption();
+        let request = client.make_request(b"one").unwrap();
+        match server.read_msg(&request) {
+            Err(WhisperError::DecryptionFailed) => {}
+            other => panic!("Expected DecryptionFailed, got {:?}", other),
+        }
+        assert_eq!(server.read_msg(&request).unwrap().as_ref(), b"one");
+
+        client_faults.corrupt_next_nonce();
+        let corrupted = client.make_request(b"two").unwrap();
+        assert!(server.read_msg(&corrupted).is_err());
+        let fine = client.make_request(b"two").unwrap();
+        assert_eq!(server.read_msg(&fine).unwrap().as_ref(), b"two");
+
+        client_faults.force_expiry();
+        match client.make_notification(b"three") {
+            Err(WhisperError::ExpiredSession) => {}
+            other => panic!("Expected ExpiredSession, got {:?}", other),
+        }
+        client_faults.restore_expiry();
+        assert!(client.make_notification(b"three").is_ok());
+    }
+}
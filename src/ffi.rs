@@ -0,0 +1,569 @@
+//! C interface, behind `ffi` feature, so C, Python and firmware can embed
+//! this crate instead of reimplementing it. Declarations are in
+//! `include/whisper.h`. Build shared library with
+//! `cargo rustc --release --features ffi --crate-type cdylib`.
+//!
+//! Sessions are opaque handles, each freed with its own `*_free`. Frames
+//! cross the boundary packed (`Frame::pack`), without length prefix. Bytes
+//! returned to the caller are in `WhisperBuffer` allocated by the library;
+//! free it with `whisper_buffer_free`. Functions return `WHISPER_OK`, one of
+//! negative codes below, or `WhisperError::code` of what went wrong. Keys
+//! are 32 bytes, nonces 24.
+//!
+//! Panics never unwind into C: function that panicked returns
+//! `WHISPER_PANIC` (or null). Handle it was called with may be in the
+//! middle of something, free it.
+//!
+//! Every pointer must be valid for the size the header says, and handles
+//! must not be used from two threads at once.
+
+#![allow(clippy::missing_safety_doc)]
+
+use crypto::{self, KeyPair};
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use session::{ClientSession, EstablishedSession, ServerSession};
+use sodiumoxide::crypto::box_::{Nonce, PublicKey, SecretKey};
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+/// Success.
+pub const WHISPER_OK: c_int = 0;
+/// Required pointer was null.
+pub const WHISPER_NULL_POINTER: c_int = -1;
+/// Library panicked.
+pub const WHISPER_PANIC: c_int = -2;
+
+/// Bytes owned by the library.
+#[repr(C)]
+pub struct WhisperBuffer {
+    /// Start of the bytes.
+    pub data: *mut u8,
+    /// Number of bytes.
+    pub len: usize,
+}
+
+// Unwinding into C is undefined behavior, so every extern fn runs its body
+// through this.
+fn guard<T, F: FnOnce() -> T>(on_panic: T, body: F) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or(on_panic)
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        return &[];
+    }
+    slice::from_raw_parts(data, len)
+}
+
+unsafe fn key(data: *const u8) -> [u8; 32] {
+    let mut key = [0; 32];
+    key.copy_from_slice(bytes(data, 32));
+    key
+}
+
+unsafe fn keypair(public_key: *const u8, secret_key: *const u8) -> KeyPair {
+    KeyPair {
+        public_key: PublicKey(key(public_key)),
+        secret_key: SecretKey(key(secret_key)),
+    }
+}
+
+unsafe fn frame(data: *const u8, len: usize) -> WhisperResult<Frame> {
+    Frame::from_slice(bytes(data, len))
+}
+
+fn code<T>(result: &WhisperResult<T>) -> c_int {
+    match *result {
+        Ok(_) => WHISPER_OK,
+        Err(ref err) => c_int::from(err.code()),
+    }
+}
+
+// Hand packed frame to the caller.
+unsafe fn give_frame(result: WhisperResult<Frame>, out: *mut WhisperBuffer) -> c_int {
+    give(result.map(|frame| frame.pack().to_vec()), out)
+}
+
+unsafe fn give(result: WhisperResult<Vec<u8>>, out: *mut WhisperBuffer) -> c_int {
+    let code = code(&result);
+    if let Ok(data) = result {
+        let data = Box::into_raw(data.into_boxed_slice());
+        *out = WhisperBuffer {
+            data: data as *mut u8,
+            len: (*data).len(),
+        };
+    }
+    code
+}
+
+unsafe fn give_session(result: WhisperResult<EstablishedSession>,
+                       session: *mut *mut EstablishedSession)
+                       -> c_int {
+    let code = code(&result);
+    if let Ok(established) = result {
+        *session = Box::into_raw(Box::new(established));
+    }
+    code
+}
+
+/// Free bytes returned by the library.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_buffer_free(buffer: WhisperBuffer) {
+    guard((), || {
+        if !buffer.data.is_null() {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buffer.data, buffer.len)));
+        }
+    })
+}
+
+/// Initialize libsodium. Optional, see `crypto::init`.
+#[no_mangle]
+pub extern "C" fn whisper_init() -> c_int { guard(WHISPER_PANIC, || code(&crypto::init())) }
+
+/// Generate identity keypair.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_keypair_generate(public_key: *mut u8,
+                                                  secret_key: *mut u8)
+                                                  -> c_int {
+    if public_key.is_null() || secret_key.is_null() {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || {
+        let result = KeyPair::new();
+        if let Ok(ref keypair) = result {
+            ptr::copy_nonoverlapping(keypair.public_key.0.as_ptr(), public_key, 32);
+            ptr::copy_nonoverlapping(keypair.secret_key.0.as_ptr(), secret_key, 32);
+        }
+        code(&result)
+    })
+}
+
+/// New client session. Null if any key is null or libsodium can't be
+/// initialized.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_client_new(identity_public_key: *const u8,
+                                            identity_secret_key: *const u8,
+                                            server_public_key: *const u8)
+                                            -> *mut ClientSession {
+    if identity_public_key.is_null() || identity_secret_key.is_null() ||
+       server_public_key.is_null()
+    {
+        return ptr::null_mut();
+    }
+    guard(ptr::null_mut(), || {
+        let identity = keypair(identity_public_key, identity_secret_key);
+        match ClientSession::new(identity, PublicKey(key(server_public_key))) {
+            Ok(session) => Box::into_raw(Box::new(session)),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+/// Free client session.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_client_free(client: *mut ClientSession) {
+    guard((), || {
+        if !client.is_null() {
+            drop(Box::from_raw(client));
+        }
+    })
+}
+
+/// Hello frame.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_client_hello(client: *mut ClientSession,
+                                              hello: *mut WhisperBuffer)
+                                              -> c_int {
+    if client.is_null() || hello.is_null() {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || give_frame((*client).make_hello(), hello))
+}
+
+/// Initiate frame in reply to Welcome.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_client_initiate(client: *mut ClientSession,
+                                                 welcome: *const u8,
+                                                 welcome_len: usize,
+                                                 initiate: *mut WhisperBuffer)
+                                                 -> c_int {
+    if client.is_null() || welcome.is_null() || initiate.is_null() {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || {
+        let result = frame(welcome, welcome_len).and_then(|welcome| {
+                                                               (*client).make_initiate(&welcome)
+                                                           });
+        give_frame(result, initiate)
+    })
+}
+
+/// Read Ready and get established session. Client session is done after
+/// that, but still has to be freed.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_client_ready(client: *mut ClientSession,
+                                              ready: *const u8,
+                                              ready_len: usize,
+                                              session: *mut *mut EstablishedSession)
+                                              -> c_int {
+    if client.is_null() || ready.is_null() || session.is_null() {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || {
+        give_session(frame(ready, ready_len).and_then(|ready| (*client).read_ready(&ready)),
+                     session)
+    })
+}
+
+/// New server session for the Hello.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_server_new(identity_public_key: *const u8,
+                                            identity_secret_key: *const u8,
+                                            hello: *const u8,
+                                            hello_len: usize,
+                                            server: *mut *mut ServerSession)
+                                            -> c_int {
+    if identity_public_key.is_null() || identity_secret_key.is_null() || hello.is_null() ||
+       server.is_null()
+    {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || {
+        let identity = keypair(identity_public_key, identity_secret_key);
+        let hello = frame(hello, hello_len);
+        let result = hello.and_then(|hello| ServerSession::from_hello(identity, &hello));
+        let code = code(&result);
+        if let Ok(session) = result {
+            *server = Box::into_raw(Box::new(session));
+        }
+        code
+    })
+}
+
+/// Free server session.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_server_free(server: *mut ServerSession) {
+    guard((), || {
+        if !server.is_null() {
+            drop(Box::from_raw(server));
+        }
+    })
+}
+
+/// Welcome frame in reply to Hello.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_server_welcome(server: *mut ServerSession,
+                                                hello: *const u8,
+                                                hello_len: usize,
+                                                welcome: *mut WhisperBuffer)
+                                                -> c_int {
+    if server.is_null() || hello.is_null() || welcome.is_null() {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || {
+        give_frame(frame(hello, hello_len).and_then(|hello| (*server).make_welcome(&hello)),
+                   welcome)
+    })
+}
+
+/// Check Initiate and write client's identity key. Decide whether to let
+/// the client in before `whisper_server_ready`.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_server_validate_initiate(server: *mut ServerSession,
+                                                          initiate: *const u8,
+                                                          initiate_len: usize,
+                                                          client_key: *mut u8)
+                                                          -> c_int {
+    if server.is_null() || initiate.is_null() || client_key.is_null() {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || {
+        let initiate = frame(initiate, initiate_len);
+        let result = initiate.and_then(|initiate| (*server).validate_initiate(&initiate));
+        if let Ok(ref key) = result {
+            ptr::copy_nonoverlapping(key.0.as_ptr(), client_key, 32);
+        }
+        code(&result)
+    })
+}
+
+/// Ready frame and established session.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_server_ready(server: *mut ServerSession,
+                                              initiate: *const u8,
+                                              initiate_len: usize,
+                                              client_key: *const u8,
+                                              session: *mut *mut EstablishedSession,
+                                              ready: *mut WhisperBuffer)
+                                              -> c_int {
+    if server.is_null() || initiate.is_null() || client_key.is_null() || session.is_null() ||
+       ready.is_null()
+    {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || {
+        let client_key = PublicKey(key(client_key));
+        let result = frame(initiate, initiate_len).and_then(|initiate| {
+                                                                 (*server).make_ready(&initiate,
+                                                                                      &client_key)
+                                                             });
+        let code = code(&result);
+        if let Ok((established, frame)) = result {
+            *session = Box::into_raw(Box::new(established));
+            give_frame(Ok(frame), ready);
+        }
+        code
+    })
+}
+
+/// Free established session.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_session_free(session: *mut EstablishedSession) {
+    guard((), || {
+        if !session.is_null() {
+            drop(Box::from_raw(session));
+        }
+    })
+}
+
+/// Request frame.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_session_request(session: *const EstablishedSession,
+                                                 data: *const u8,
+                                                 len: usize,
+                                                 request: *mut WhisperBuffer)
+                                                 -> c_int {
+    if session.is_null() || (data.is_null() && len > 0) || request.is_null() {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || give_frame((*session).make_request(bytes(data, len)), request))
+}
+
+/// Response frame.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_session_response(session: *const EstablishedSession,
+                                                  data: *const u8,
+                                                  len: usize,
+                                                  response: *mut WhisperBuffer)
+                                                  -> c_int {
+    if session.is_null() || (data.is_null() && len > 0) || response.is_null() {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || give_frame((*session).make_response(bytes(data, len)), response))
+}
+
+/// Notification frame.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_session_notification(session: *const EstablishedSession,
+                                                      data: *const u8,
+                                                      len: usize,
+                                                      notification: *mut WhisperBuffer)
+                                                      -> c_int {
+    if session.is_null() || (data.is_null() && len > 0) || notification.is_null() {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || {
+        give_frame((*session).make_notification(bytes(data, len)), notification)
+    })
+}
+
+/// Open frame from the peer. Writes its kind and payload.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_session_read(session: *const EstablishedSession,
+                                              packed: *const u8,
+                                              packed_len: usize,
+                                              kind: *mut u8,
+                                              payload: *mut WhisperBuffer)
+                                              -> c_int {
+    if session.is_null() || packed.is_null() || kind.is_null() || payload.is_null() {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || {
+        let result = frame(packed, packed_len).and_then(|frame| {
+                                                            *kind = frame.kind as u8;
+                                                            (*session).read_msg(&frame)
+                                                        });
+        give(result.map(|data| data.to_vec()), payload)
+    })
+}
+
+/// Pack frame from its parts. Unknown kind is `BadFrame`.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_frame_pack(id: *const u8,
+                                            nonce: *const u8,
+                                            kind: u8,
+                                            payload: *const u8,
+                                            payload_len: usize,
+                                            packed: *mut WhisperBuffer)
+                                            -> c_int {
+    if id.is_null() || nonce.is_null() || (payload.is_null() && payload_len > 0) ||
+       packed.is_null()
+    {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || {
+        let kind = FrameKind::from(kind).ok_or(WhisperError::BadFrame);
+        let result = kind.and_then(|kind| {
+            let nonce = Nonce::from_slice(bytes(nonce, 24)).ok_or(WhisperError::BadFrame)?;
+            Ok(Frame {
+                   id: PublicKey(key(id)),
+                   nonce,
+                   kind,
+                   payload: bytes(payload, payload_len).to_vec().into(),
+               })
+        });
+        give_frame(result, packed)
+    })
+}
+
+/// Parse packed frame. Writes its header; payload is the rest of `packed`
+/// starting at `payload_offset`.
+#[no_mangle]
+pub unsafe extern "C" fn whisper_frame_parse(packed: *const u8,
+                                             packed_len: usize,
+                                             id: *mut u8,
+                                             nonce: *mut u8,
+                                             kind: *mut u8,
+                                             payload_offset: *mut usize)
+                                             -> c_int {
+    if packed.is_null() || id.is_null() || nonce.is_null() || kind.is_null() ||
+       payload_offset.is_null()
+    {
+        return WHISPER_NULL_POINTER;
+    }
+    guard(WHISPER_PANIC, || {
+        let result = frame(packed, packed_len);
+        if let Ok(ref frame) = result {
+            ptr::copy_nonoverlapping(frame.id.0.as_ptr(), id, 32);
+            ptr::copy_nonoverlapping(frame.nonce.0.as_ptr(), nonce, 24);
+            *kind = frame.kind as u8;
+            *payload_offset = packed_len - frame.payload.len();
+        }
+        code(&result)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn take(buffer: WhisperBuffer) -> Vec<u8> {
+        let data = unsafe { bytes(buffer.data, buffer.len).to_vec() };
+        unsafe { whisper_buffer_free(buffer) };
+        data
+    }
+
+    fn empty() -> WhisperBuffer {
+        WhisperBuffer {
+            data: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    #[test]
+    fn panics_stay_inside() {
+        assert_eq!(guard(WHISPER_PANIC, || -> c_int { panic!("boom") }), WHISPER_PANIC);
+        assert!(guard(ptr::null_mut(), || -> *mut ClientSession { panic!("boom") }).is_null());
+    }
+
+    #[test]
+    fn handshake_through_c_interface() {
+        unsafe {
+            assert_eq!(whisper_init(), WHISPER_OK);
+            let (mut client_public, mut client_secret) = ([0; 32], [0; 32]);
+            let (mut server_public, mut server_secret) = ([0; 32], [0; 32]);
+            whisper_keypair_generate(client_public.as_mut_ptr(), client_secret.as_mut_ptr());
+            whisper_keypair_generate(server_public.as_mut_ptr(), server_secret.as_mut_ptr());
+
+            let client = whisper_client_new(client_public.as_ptr(),
+                                            client_secret.as_ptr(),
+                                            server_public.as_ptr());
+            let mut buffer = empty();
+            assert_eq!(whisper_client_hello(client, &mut buffer), WHISPER_OK);
+            let hello = take(buffer);
+
+            let mut server = ptr::null_mut();
+            assert_eq!(whisper_server_new(server_public.as_ptr(),
+                                          server_secret.as_ptr(),
+                                          hello.as_ptr(),
+                                          hello.len(),
+                                          &mut server),
+                       WHISPER_OK);
+            let mut buffer = empty();
+            whisper_server_welcome(server, hello.as_ptr(), hello.len(), &mut buffer);
+            let welcome = take(buffer);
+            let mut buffer = empty();
+            whisper_client_initiate(client, welcome.as_ptr(), welcome.len(), &mut buffer);
+            let initiate = take(buffer);
+
+            let mut client_key = [0; 32];
+            assert_eq!(whisper_server_validate_initiate(server,
+                                                        initiate.as_ptr(),
+                                                        initiate.len(),
+                                                        client_key.as_mut_ptr()),
+                       WHISPER_OK);
+            assert_eq!(client_key, client_public);
+            let (mut server_session, mut buffer) = (ptr::null_mut(), empty());
+            assert_eq!(whisper_server_ready(server,
+                                            initiate.as_ptr(),
+                                            initiate.len(),
+                                            client_key.as_ptr(),
+                                            &mut server_session,
+                                            &mut buffer),
+                       WHISPER_OK);
+            let ready = take(buffer);
+            let mut client_session = ptr::null_mut();
+            assert_eq!(whisper_client_ready(client,
+                                            ready.as_ptr(),
+                                            ready.len(),
+                                            &mut client_session),
+                       WHISPER_OK);
+
+            let mut buffer = empty();
+            whisper_session_request(client_session, b"ping".as_ptr(), 4, &mut buffer);
+            let request = take(buffer);
+            let (mut kind, mut id, mut nonce, mut offset) = (0, [0; 32], [0; 24], 0);
+            assert_eq!(whisper_frame_parse(request.as_ptr(),
+                                           request.len(),
+                                           id.as_mut_ptr(),
+                                           nonce.as_mut_ptr(),
+                                           &mut kind,
+                                           &mut offset),
+                       WHISPER_OK);
+            assert_eq!(kind, FrameKind::Request as u8);
+            let mut buffer = empty();
+            whisper_frame_pack(id.as_ptr(),
+                               nonce.as_ptr(),
+                               kind,
+                               request[offset..].as_ptr(),
+                               request.len() - offset,
+                               &mut buffer);
+            assert_eq!(take(buffer), request);
+
+            let mut buffer = empty();
+            assert_eq!(whisper_session_read(server_session,
+                                            request.as_ptr(),
+                                            request.len(),
+                                            &mut kind,
+                                            &mut buffer),
+                       WHISPER_OK);
+            assert_eq!(take(buffer), b"ping");
+            let mut buffer = empty();
+            assert_eq!(whisper_session_read(server_session,
+                                            request.as_ptr(),
+                                            10,
+                                            &mut kind,
+                                            &mut buffer),
+                       c_int::from(WhisperError::IncompleteFrame.code()));
+            assert_eq!(whisper_client_hello(ptr::null_mut(), &mut buffer), WHISPER_NULL_POINTER);
+
+            whisper_session_free(client_session);
+            whisper_session_free(server_session);
+            whisper_client_free(client);
+            whisper_server_free(server);
+        }
+    }
+}
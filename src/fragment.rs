@@ -0,0 +1,314 @@
+//! Fragmentation for transports with small MTU (UDP, LoRa, BLE).
+//! `EstablishedSession::make_request_fragmented` splits message into
+//! several frames, each sealed on its own, and `Reassembler` on the other
+//! end glues opened payloads back together.
+//!
+//! Every fragment starts with 12 byte header in front of the data, inside
+//! the sealed payload: message id (u32), fragment index (u16), fragment
+//! count (u16) and message length (u32), all BigEndian. Message id is
+//! random. Every fragment but the last carries the same amount of data.
+//! Peers have to agree to use fragmentation: payload of plain frame doesn't
+//! have the header.
+//!
+//! Fragments may arrive in any order and more than once. Message whose
+//! fragments don't all arrive within timeout is dropped by `expire`.
+//! Fragments that don't agree on count, length or size of the data are
+//! refused, so two messages that got the same id can't be glued together.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use errors::{WhisperError, WhisperResult};
+use sodiumoxide::crypto::box_::PublicKey;
+use sodiumoxide::randombytes::randombytes_into;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// Size of fragment header.
+pub const FRAGMENT_HEADER_SIZE: usize = 12;
+/// Default limit of reassembled message.
+pub const DEFAULT_MAX_MESSAGE: usize = 1024 * 1024;
+/// Default limit of messages waiting for more fragments.
+pub const DEFAULT_MAX_PENDING: usize = 64;
+
+// Put header in front of every piece of data, at most `room` bytes each.
+pub(crate) fn split(data: &[u8], room: usize) -> WhisperResult<Vec<Bytes>> {
+    if room == 0 {
+        return Err(failed("fragment has no room for data"));
+    }
+    let count = ::std::cmp::max(1, data.len().div_ceil(room));
+    if count > u16::MAX as usize || data.len() > u32::MAX as usize {
+        return Err(failed("too many fragments"));
+    }
+    let mut id = [0; 4];
+    randombytes_into(&mut id);
+    let pieces: Vec<&[u8]> = if data.is_empty() { vec![data] } else { data.chunks(room).collect() };
+    Ok(pieces.into_iter()
+             .enumerate()
+             .map(|(index, piece)| {
+                      let mut buf = BytesMut::with_capacity(FRAGMENT_HEADER_SIZE + piece.len());
+                      buf.extend_from_slice(&id);
+                      buf.put_u16_be(index as u16);
+                      buf.put_u16_be(count as u16);
+                      buf.put_u32_be(data.len() as u32);
+                      buf.extend_from_slice(piece);
+                      buf.freeze()
+                  })
+             .collect())
+}
+
+#[derive(Debug)]
+struct Partial {
+    started: Instant,
+    count: usize,
+    length: usize,
+    // Data in every fragment but the last, once one of them is in.
+    room: Option<usize>,
+    // Only fragments that arrived, so lying count costs nothing.
+    fragments: BTreeMap<usize, Bytes>,
+    size: usize,
+}
+
+impl Partial {
+    // Whether fragment agrees with header of the first one and with size of
+    // the fragments already in.
+    fn fits(&self, index: usize, count: usize, length: usize, data: &[u8]) -> bool {
+        if count != self.count || length != self.length {
+            return false;
+        }
+        let last = count - 1;
+        if count == 1 {
+            return data.len() == length;
+        }
+        if index < last {
+            // Full fragment: the last one has the rest, at least a byte.
+            let room = data.len();
+            return room > 0 && length.div_ceil(room) == count &&
+                   self.room.map_or(true, |known| known == room) &&
+                   self.fragments
+                       .get(&last)
+                       .map_or(true, |rest| rest.len() == length - last * room);
+        }
+        match self.room {
+            Some(room) => data.len() == length - last * room,
+            None => !data.is_empty() && data.len() + last <= length,
+        }
+    }
+}
+
+/// Collects fragments until message is complete. Messages are told apart
+/// by peer and message id.
+#[derive(Debug)]
+pub struct Reassembler {
+    timeout: Duration,
+    max_message: usize,
+    max_pending: usize,
+    partial: HashMap<(PublicKey, u32), Partial>,
+}
+
+impl Reassembler {
+    /// Reassembler that waits `timeout` for the rest of a message after
+    /// its first fragment, refuses messages over `DEFAULT_MAX_MESSAGE` and
+    /// keeps at most `DEFAULT_MAX_PENDING` incomplete ones.
+    pub fn new(timeout: Duration) -> Reassembler {
+        Reassembler {
+            timeout,
+            max_message: DEFAULT_MAX_MESSAGE,
+            max_pending: DEFAULT_MAX_PENDING,
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Refuse messages longer than this.
+    pub fn set_max_message(&mut self, max_message: usize) { self.max_message = max_message; }
+
+    /// Refuse first fragment of a new message while this many messages are
+    /// waiting for more fragments and none of them has timed out.
+    pub fn set_max_pending(&mut self, max_pending: usize) { self.max_pending = max_pending; }
+
+    /// Messages waiting for more fragments.
+    pub fn pending(&self) -> usize { self.partial.len() }
+
+    /// Take opened payload of a fragment from `peer`, the id of the frame
+    /// it came in. Returns whole message once its last fragment is in.
+    /// Fails with `Fragmentation` on bad header, message over the limit or
+    /// fragment that doesn't match the rest, and forgets that message.
+    /// Fails the same way, keeping the rest, when there are too many
+    /// incomplete messages.
+    pub fn push(&mut self, peer: &PublicKey, payload: &[u8]) -> WhisperResult<Option<Bytes>> {
+        self.push_at(peer, payload, Instant::now())
+    }
+
+    /// Drop messages that are still missing fragments after timeout.
+    /// Returns how many were dropped. Call it from time to time.
+    pub fn expire(&mut self) -> usize { self.expire_at(Instant::now()) }
+
+    fn push_at(&mut self,
+               peer: &PublicKey,
+               payload: &[u8],
+               now: Instant)
+               -> WhisperResult<Option<Bytes>> {
+        if payload.len() < FRAGMENT_HEADER_SIZE {
+            return Err(failed("fragment header is missing"));
+        }
+        let key = (*peer, BigEndian::read_u32(&payload[..4]));
+        let index = BigEndian::read_u16(&payload[4..6]) as usize;
+        let count = BigEndian::read_u16(&payload[6..8]) as usize;
+        let length = BigEndian::read_u32(&payload[8..12]) as usize;
+        let data = &payload[FRAGMENT_HEADER_SIZE..];
+        if index >= count {
+            return Err(failed("fragment index out of range"));
+        }
+        if length > self.max_message {
+            self.partial.remove(&key);
+            return Err(failed("message is too long"));
+        }
+        if !self.partial.contains_key(&key) && self.partial.len() >= self.max_pending &&
+           self.expire_at(now) == 0
+        {
+            return Err(failed("too many incomplete messages"));
+        }
+        let done = {
+            let partial = self.partial.entry(key).or_insert_with(|| {
+                Partial {
+                    started: now,
+                    count,
+                    length,
+                    room: None,
+                    fragments: BTreeMap::new(),
+                    size: 0,
+                }
+            });
+            if !partial.fits(index, count, length, data) {
+                None
+            } else {
+                if index + 1 < count {
+                    partial.room = Some(data.len());
+                }
+                if !partial.fragments.contains_key(&index) {
+                    partial.size += data.len();
+                    partial.fragments.insert(index, Bytes::from(data));
+                }
+                Some(partial.fragments.len() == count)
+            }
+        };
+        match done {
+            None => {
+                self.partial.remove(&key);
+                Err(failed("fragments don't add up"))
+            }
+            Some(false) => Ok(None),
+            Some(true) => {
+                let partial = self.partial.remove(&key).expect("Message was just updated");
+                let mut message = BytesMut::with_capacity(partial.size);
+                for fragment in partial.fragments.values() {
+                    message.extend_from_slice(fragment);
+                }
+                Ok(Some(message.freeze()))
+            }
+        }
+    }
+
+    fn expire_at(&mut self, now: Instant) -> usize {
+        let before = self.partial.len();
+        let timeout = self.timeout;
+        self.partial.retain(|_, partial| now.duration_since(partial.started) < timeout);
+        before - self.partial.len()
+    }
+}
+
+fn failed(reason: &str) -> WhisperError { WhisperError::Fragmentation(reason.to_string()) }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use frame::HEADER_SIZE;
+    use session::test::handshake;
+
+    #[test]
+    fn fragments_reassemble() {
+        let (client, server) = handshake();
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let mut frames = client.make_request_fragmented(&data, 200).unwrap();
+        assert!(frames.len() > 1);
+        assert!(frames.iter().all(|frame| frame.length() <= 200));
+        frames.reverse();
+        let duplicate = frames[1].clone();
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        let mut message = None;
+        for frame in &frames {
+            let payload = server.read_msg(frame).unwrap();
+            if let Some(whole) = reassembler.push(&frame.id, &payload).unwrap() {
+                message = Some(whole);
+            }
+        }
+        assert_eq!(message.unwrap().as_ref(), &data[..]);
+        assert!(server.read_msg(&duplicate).is_err());
+        assert!(client.make_request_fragmented(&data, HEADER_SIZE + 24).is_err());
+
+        // Message with missing fragment times out.
+        let frames = client.make_request_fragmented(&data, 200).unwrap();
+        let start = Instant::now();
+        let payload = server.read_msg(&frames[0]).unwrap();
+        assert!(reassembler.push_at(&frames[0].id, &payload, start).unwrap().is_none());
+        assert_eq!(reassembler.expire_at(start + Duration::from_secs(1)), 0);
+        assert_eq!(reassembler.expire_at(start + Duration::from_secs(6)), 1);
+        assert_eq!(reassembler.pending(), 0);
+
+        reassembler.set_max_message(150);
+        let payload = server.read_msg(&frames[1]).unwrap();
+        assert!(reassembler.push(&frames[1].id, &payload).is_err());
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    fn fragment(id: u32, index: u16, count: u16, length: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(FRAGMENT_HEADER_SIZE + data.len());
+        buf.put_u32_be(id);
+        buf.put_u16_be(index);
+        buf.put_u16_be(count);
+        buf.put_u32_be(length);
+        buf.extend_from_slice(data);
+        buf.to_vec()
+    }
+
+    #[test]
+    fn fragments_must_add_up() {
+        let alice = PublicKey([1; 32]);
+        let bob = PublicKey([2; 32]);
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+
+        // Count the data can't fill.
+        assert!(reassembler.push(&alice, &fragment(1, 0, u16::MAX, 1, b"a")).is_err());
+        assert!(reassembler.push(&alice, &fragment(1, 0, 2, 10, b"a")).is_err());
+        assert_eq!(reassembler.pending(), 0);
+
+        // Same id from two peers are two messages.
+        assert!(reassembler.push(&alice, &fragment(2, 0, 2, 5, b"abc")).unwrap().is_none());
+        assert!(reassembler.push(&bob, &fragment(2, 1, 2, 4, b"yz")).unwrap().is_none());
+        assert_eq!(reassembler.push(&bob, &fragment(2, 0, 2, 4, b"wx")).unwrap().unwrap().as_ref(),
+                   b"wxyz");
+        // Fragment of other message with the same id and peer.
+        assert!(reassembler.push(&alice, &fragment(2, 1, 2, 6, b"def")).is_err());
+        assert!(reassembler.push(&alice, &fragment(3, 0, 3, 7, b"abc")).unwrap().is_none());
+        assert!(reassembler.push(&alice, &fragment(3, 2, 3, 7, b"gh")).is_err());
+        assert_eq!(reassembler.pending(), 0);
+
+        reassembler.set_max_pending(2);
+        let start = Instant::now();
+        for id in 0..2 {
+            let first = fragment(id, 0, 2, 4, b"ab");
+            assert!(reassembler.push_at(&alice, &first, start).unwrap().is_none());
+        }
+        let third = fragment(2, 0, 2, 4, b"ab");
+        assert!(reassembler.push_at(&alice, &third, start).is_err());
+        assert_eq!(reassembler.pending(), 2);
+        let rest = fragment(0, 1, 2, 4, b"cd");
+        assert!(reassembler.push_at(&alice, &rest, start).unwrap().is_some());
+        // Room again, also once the others time out.
+        assert!(reassembler.push_at(&alice, &third, start).unwrap().is_none());
+        let fourth = fragment(3, 0, 2, 4, b"ab");
+        let later = start + Duration::from_secs(6);
+        assert!(reassembler.push_at(&alice, &fourth, later).unwrap().is_none());
+        assert_eq!(reassembler.pending(), 1);
+    }
+}
@@ -1,20 +1,56 @@
 //! This is how frames look on the wire. This module doesn't handle Frame
 //! generation — generation is done in session module.
+//!
+//! ### Wire versions
+//! Version 1 is the original header with no version field. Version 2 adds
+//! version byte right after session id and marks it by setting the top bit
+//! of the last id byte. Curve25519 public keys never have that bit set, so
+//! `from_slice_any` can tell versions apart without guessing. Gateways use
+//! it to accept both and `pack_as` to talk to each side in its own version.
+//!
+//! ### Stream framing
+//! Frame doesn't carry its own length. On byte streams every frame is
+//! preceded by its length as u32 BigEndian (`LENGTH_PREFIX_SIZE` bytes).
+//! Length counts header and payload, not the prefix itself, and is at most
+//! `MAX_FRAME_SIZE`. `pack_with_length` and `from_slice_with_length` speak
+//! it, `FrameDecoder` reassembles it from partial reads.
 
+use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, Bytes, BytesMut};
+use std::io::{self, IoSlice, Write};
 
 use errors::{WhisperError, WhisperResult};
 use nom::{IResult, rest};
-use sodiumoxide::crypto::box_::{Nonce, PublicKey};
+use resumption::MIN_RESUME_SIZE;
+use session::{self, INITIATE_BOX_SIZE, READY_PAYLOAD};
+use sodiumoxide::crypto::box_::{MACBYTES, Nonce, PublicKey};
+use transport::{self, LENGTH_PREFIX_SIZE, MAX_FRAME_SIZE};
 
 
 /// How many bytes of overhead each frame has. Header consist of:
 /// - Session identificator. 32 bytes.
 /// - Nonce used to encrypt payload. 24 bytes.
 /// - Message type as u8 BigEndian. 1 byte.
-pub static HEADER_SIZE: usize = 57;
+pub static HEADER_SIZE: usize = HEADER_LEN;
+const HEADER_LEN: usize = 57;
 
 
+const PUBLIC_KEY_SIZE: usize = 32;
+
+/// Size of version 2 header: version 1 header plus version byte.
+pub static HEADER_SIZE_V2: usize = HEADER_LEN + 1;
+/// Bit of the last session id byte that marks versioned header.
+pub static VERSIONED_HEADER_BIT: u8 = 0x80;
+
+/// Wire format version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WireVersion {
+    /// Original header without version field.
+    V1 = 1,
+    /// Header with explicit version byte.
+    V2 = 2,
+}
+
 /// Frame type. Frame kind takes 1 byte.
 #[derive(Debug, Clone, PartialEq, Copy, Eq, Hash)]
 pub enum FrameKind {
@@ -34,11 +70,30 @@ pub enum FrameKind {
     Response,
     /// A message that doesn't require response. Can be sent from either side.
     Notification,
+    /// Session control message (e.g. parameter renegotiation). Encrypted
+    /// like Notification, but meant for the library, not the application.
+    /// Can be sent from either side.
+    Control,
+    /// Resumption of earlier session with a ticket, instead of Hello. Sent
+    /// from client, answered with Ready. See `resumption` module.
+    Resume,
     /// Termination frame. Usually used to indicate handshake error or session
     /// termination. Can be sent from either side.
-    Termination,
+    Termination = 255,
 }
 
+/// Every frame kind known to this library in wire order.
+pub static FRAME_KINDS: [FrameKind; 10] = [FrameKind::Hello,
+                                           FrameKind::Welcome,
+                                           FrameKind::Initiate,
+                                           FrameKind::Ready,
+                                           FrameKind::Request,
+                                           FrameKind::Response,
+                                           FrameKind::Notification,
+                                           FrameKind::Control,
+                                           FrameKind::Resume,
+                                           FrameKind::Termination];
+
 /// Each frame has it's kind. Meant to be expandable.
 impl FrameKind {
     /// Since we don't have TryFrom...
@@ -51,6 +106,8 @@ impl FrameKind {
             5 => Some(FrameKind::Request),
             6 => Some(FrameKind::Response),
             7 => Some(FrameKind::Notification),
+            8 => Some(FrameKind::Control),
+            9 => Some(FrameKind::Resume),
             255 => Some(FrameKind::Termination),
             _ => None,
         }
@@ -63,6 +120,21 @@ impl FrameKind {
         }
         FrameKind::from(kind[0])
     }
+    /// Lowercase name of the kind. Used by schema generators.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FrameKind::Hello => "hello",
+            FrameKind::Welcome => "welcome",
+            FrameKind::Initiate => "initiate",
+            FrameKind::Ready => "ready",
+            FrameKind::Request => "request",
+            FrameKind::Response => "response",
+            FrameKind::Notification => "notification",
+            FrameKind::Control => "control",
+            FrameKind::Resume => "resume",
+            FrameKind::Termination => "termination",
+        }
+    }
 }
 
 /// The main unit of information passed from client to server and vice versa.
@@ -74,8 +146,9 @@ impl FrameKind {
 pub struct Frame {
     /// Session identificator. 32 bytes
     pub id: PublicKey,
-    /// Nonce used to encrypt payload. Nonce is also used as Request ID in
-    /// multiplexing. 24 bytes
+    /// Nonce used to encrypt payload. Random in handshake, counter after
+    /// (see `session::nonce_counter`), so it can't tie Response to
+    /// Request: `tracker` puts request id into payload for that. 24 bytes
     pub nonce: Nonce,
     /// Message type as u8 BigEndian. 1 byte
     pub kind: FrameKind,
@@ -98,6 +171,32 @@ impl Frame {
         buf.extend_from_slice(&self.payload);
     }
 
+    /// Packed header alone. Together with payload it's the whole frame, so
+    /// the two can be handed to vectored IO without copying payload.
+    pub fn header(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[..32].copy_from_slice(&self.id.0);
+        header[32..56].copy_from_slice(&self.nonce.0);
+        header[56] = self.kind as u8;
+        header
+    }
+
+    /// Write packed frame using vectored write. Payload is never copied.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let header = self.header();
+        let written = writer.write_vectored(&[IoSlice::new(&header), IoSlice::new(&self.payload)])?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        // Writer is allowed to take only a part.
+        if written < HEADER_LEN {
+            writer.write_all(&header[written..])?;
+            writer.write_all(&self.payload)
+        } else {
+            writer.write_all(&self.payload[written - HEADER_LEN..])
+        }
+    }
+
     /// Pack frame header and its payload into Vec<u8>.
     pub fn pack(&self) -> Bytes {
         let mut frame = BytesMut::with_capacity(self.length());
@@ -105,6 +204,101 @@ impl Frame {
         frame.freeze()
     }
 
+    /// Pack frame behind its length, for byte streams. See "Stream framing"
+    /// above.
+    pub fn pack_with_length(&self) -> Bytes { transport::pack_prefixed(self) }
+
+    /// Pack frame using given wire version.
+    pub fn pack_as(&self, version: WireVersion) -> Bytes {
+        match version {
+            WireVersion::V1 => self.pack(),
+            WireVersion::V2 => {
+                let mut frame = BytesMut::with_capacity(self.length() + 1);
+                let mut id = self.id.0;
+                id[31] |= VERSIONED_HEADER_BIT;
+                frame.extend_from_slice(&id);
+                frame.put_u8(version as u8);
+                frame.extend_from_slice(&self.nonce.0);
+                frame.put_u8(self.kind as u8);
+                frame.extend_from_slice(&self.payload);
+                frame.freeze()
+            }
+        }
+    }
+
+    /// Parse packed frame of any known wire version. Returns version it was
+    /// packed with.
+    pub fn from_slice_any(i: &[u8]) -> WhisperResult<(WireVersion, Frame)> {
+        if i.len() < 32 {
+            return Err(WhisperError::IncompleteFrame);
+        }
+        if i[31] & VERSIONED_HEADER_BIT == 0 {
+            return Frame::from_slice(i).map(|frame| (WireVersion::V1, frame));
+        }
+        if i.len() < HEADER_SIZE_V2 {
+            return Err(WhisperError::IncompleteFrame);
+        }
+        match i[32] {
+            2 => {
+                let mut v1 = BytesMut::with_capacity(i.len() - 1);
+                v1.extend_from_slice(&i[..32]);
+                v1[31] &= !VERSIONED_HEADER_BIT;
+                v1.extend_from_slice(&i[33..]);
+                Frame::from_slice(&v1).map(|frame| (WireVersion::V2, frame))
+            }
+            _ => Err(WhisperError::BadFrame),
+        }
+    }
+
+    /// Cheap checks of what parser doesn't look at: payload size of each
+    /// kind. Frames that pass can still fail to open, but frames that don't
+    /// never will, so servers can drop them before looking up or creating
+    /// session. Handshake kinds fail with their own error
+    /// (`InvalidHelloFrame`...), the rest with `BadFrame`.
+    ///
+    /// - Hello: null box of valid padding, optionally followed by service
+    ///   name hint.
+    /// - Welcome: at least boxed server session key.
+    /// - Initiate: at least boxed mandatory fields.
+    /// - Ready: at least boxed `READY_PAYLOAD`.
+    /// - Messages: at least MAC. Empty payloads never open.
+    /// - Resume: at least empty ticket and binder.
+    /// - Termination: anything, even empty, up to `MAX_FRAME_SIZE`.
+    pub fn validate(&self) -> WhisperResult<()> {
+        let len = self.payload.len();
+        if len > MAX_FRAME_SIZE {
+            return Err(WhisperError::BadFrame);
+        }
+        let valid = match self.kind {
+            FrameKind::Hello => session::hello_box_size(len).is_some(),
+            FrameKind::Welcome => len >= PUBLIC_KEY_SIZE + MACBYTES,
+            FrameKind::Initiate => len >= INITIATE_BOX_SIZE + MACBYTES,
+            FrameKind::Ready => len >= READY_PAYLOAD.len() + MACBYTES,
+            FrameKind::Request | FrameKind::Response | FrameKind::Notification |
+            FrameKind::Control => len >= MACBYTES,
+            FrameKind::Resume => len >= MIN_RESUME_SIZE,
+            FrameKind::Termination => true,
+        };
+        if valid {
+            return Ok(());
+        }
+        Err(match self.kind {
+                FrameKind::Hello => WhisperError::InvalidHelloFrame,
+                FrameKind::Welcome => WhisperError::InvalidWelcomeFrame,
+                FrameKind::Initiate => WhisperError::InvalidInitiateFrame,
+                FrameKind::Ready => WhisperError::InvalidReadyFrame,
+                _ => WhisperError::BadFrame,
+            })
+    }
+
+    /// Parse frame behind its length at the start of the buffer. Returns
+    /// frame and number of bytes it took, prefix included. Fails with
+    /// `IncompleteFrame` if buffer doesn't hold all of it yet and with
+    /// `BadFrame` if length is over `MAX_FRAME_SIZE`.
+    pub fn from_slice_with_length(i: &[u8]) -> WhisperResult<(Frame, usize)> {
+        FrameDecoder::new().decode_one(i)?.ok_or(WhisperError::IncompleteFrame)
+    }
+
     /// Parse packed frame.
     pub fn from_slice(i: &[u8]) -> WhisperResult<Frame> {
         match parse_frame(i) {
@@ -115,6 +309,33 @@ impl Frame {
     }
 }
 
+/// Any known kind.
+#[cfg(feature = "arbitrary")]
+impl<'a> ::arbitrary::Arbitrary<'a> for FrameKind {
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<FrameKind> {
+        u.choose(&FRAME_KINDS).map(|kind| *kind)
+    }
+}
+
+/// Frame that `from_slice` parses back from `pack`. Top bit of the last id
+/// byte is clear, like in real session keys, so `from_slice_any` reads it
+/// as version 1. Payload is random bytes: sessions won't open it and it
+/// may not pass `validate`.
+#[cfg(feature = "arbitrary")]
+impl<'a> ::arbitrary::Arbitrary<'a> for Frame {
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Frame> {
+        let mut id: [u8; PUBLIC_KEY_SIZE] = u.arbitrary()?;
+        id[PUBLIC_KEY_SIZE - 1] &= !VERSIONED_HEADER_BIT;
+        let payload: Vec<u8> = u.arbitrary()?;
+        Ok(Frame {
+               id: PublicKey(id),
+               nonce: Nonce(u.arbitrary()?),
+               kind: u.arbitrary()?,
+               payload: payload.into(),
+           })
+    }
+}
+
 named!(parse_frame < &[u8], Frame >,
        do_parse!(
            pk:          map_opt!(take!(32), PublicKey::from_slice)  >>
@@ -134,6 +355,78 @@ named!(parse_frame < &[u8], Frame >,
            )
       );
 
+/// Reassembles stream mode frames (behind `transport::LENGTH_PREFIX_SIZE`
+/// length prefix, see `transport::pack_prefixed`) from reads of any size.
+/// Partial frame stays buffered until the rest of it arrives.
+///
+/// Stream is unusable after an error: there is no way to find the next
+/// frame boundary.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buf: BytesMut,
+    max_frame_size: usize,
+}
+
+impl FrameDecoder {
+    /// Decoder that refuses frames longer than `MAX_FRAME_SIZE`.
+    pub fn new() -> FrameDecoder { FrameDecoder::with_max_frame_size(MAX_FRAME_SIZE) }
+
+    /// Decoder that refuses frames longer than `max_frame_size` with
+    /// `BadFrame` before buffering them.
+    pub fn with_max_frame_size(max_frame_size: usize) -> FrameDecoder {
+        FrameDecoder {
+            buf: BytesMut::new(),
+            max_frame_size,
+        }
+    }
+
+    /// Buffer chunk read from the stream. Returns every frame it completed.
+    pub fn feed(&mut self, chunk: &[u8]) -> WhisperResult<Vec<Frame>> {
+        self.buf.extend_from_slice(chunk);
+        let mut buf = self.buf.take();
+        let frames = self.decode(&mut buf);
+        self.buf = buf;
+        frames
+    }
+
+    /// Take complete frames out of caller's buffer, leaving partial one in
+    /// it. For transports that read into `BytesMut` themselves.
+    pub fn decode(&self, buf: &mut BytesMut) -> WhisperResult<Vec<Frame>> {
+        let mut frames = Vec::new();
+        while let Some((frame, end)) = self.decode_one(buf)? {
+            buf.split_to(end);
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    // Frame at the start of `i` and bytes it took, prefix included. None
+    // until all of it is there.
+    fn decode_one(&self, i: &[u8]) -> WhisperResult<Option<(Frame, usize)>> {
+        if i.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+        let len = BigEndian::read_u32(&i[..LENGTH_PREFIX_SIZE]) as usize;
+        if len > self.max_frame_size {
+            return Err(WhisperError::BadFrame);
+        }
+        // Length isn't authenticated, so buffer grows only as data
+        // arrives, not up front.
+        let end = LENGTH_PREFIX_SIZE + len;
+        if i.len() < end {
+            return Ok(None);
+        }
+        Ok(Some((Frame::from_slice(&i[LENGTH_PREFIX_SIZE..end])?, end)))
+    }
+
+    /// Bytes of incomplete frame buffered by `feed`.
+    pub fn buffered(&self) -> usize { self.buf.len() }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> FrameDecoder { FrameDecoder::new() }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -151,6 +444,84 @@ mod test {
 
         assert_eq!(frame, parsed_frame.unwrap());
     }
+    #[test]
+    fn vectored_write() {
+        // Writer that takes at most 10 bytes at a time.
+        struct Trickle(Vec<u8>);
+        impl Write for Trickle {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let len = buf.len().min(10);
+                self.0.extend_from_slice(&buf[..len]);
+                Ok(len)
+            }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let mut frame = make_frame();
+        frame.payload = vec![7; 100].into();
+        let mut vec = Vec::new();
+        frame.write_to(&mut vec).unwrap();
+        assert_eq!(&vec[..], &frame.pack()[..]);
+        assert_eq!(&frame.header()[..], &frame.pack()[..HEADER_SIZE]);
+
+        let mut trickle = Trickle(Vec::new());
+        frame.write_to(&mut trickle).unwrap();
+        assert_eq!(&trickle.0[..], &frame.pack()[..]);
+    }
+
+    #[test]
+    fn cross_version() {
+        let frame = make_frame();
+        let v1 = frame.pack_as(WireVersion::V1);
+        let v2 = frame.pack_as(WireVersion::V2);
+        assert_eq!(v2.len(), v1.len() + 1);
+        assert_eq!(Frame::from_slice_any(&v1).unwrap(), (WireVersion::V1, frame.clone()));
+        assert_eq!(Frame::from_slice_any(&v2).unwrap(), (WireVersion::V2, frame.clone()));
+        // Gateway bridging versions.
+        let (_, bridged) = Frame::from_slice_any(&v2).unwrap();
+        assert_eq!(bridged.pack_as(WireVersion::V1), v1);
+
+        let mut unknown = v2.to_vec();
+        unknown[32] = 9;
+        assert!(Frame::from_slice_any(&unknown).is_err());
+        assert!(Frame::from_slice_any(&v2[..40]).is_err());
+    }
+
+    #[test]
+    fn validate_sizes() {
+        use session::{ClientSession, ServerSession};
+        use crypto::KeyPair;
+
+        let server_identity = KeyPair::new().unwrap();
+        let mut client =
+            ClientSession::new(KeyPair::new().unwrap(), server_identity.public_key).unwrap();
+        let hello = client.make_hello().unwrap();
+        let mut server = ServerSession::new(server_identity, hello.id).unwrap();
+        let welcome = server.make_welcome(&hello).unwrap();
+        let initiate = client.make_initiate(&welcome).unwrap();
+        let client_key = server.validate_initiate(&initiate).unwrap();
+        let (session, ready) = server.make_ready(&initiate, &client_key).unwrap();
+        let ping = session.make_notification(b"").unwrap();
+        for frame in &[&hello, &welcome, &initiate, &ready, &ping] {
+            assert!(frame.validate().is_ok(), "{:?} should be valid", frame.kind);
+        }
+
+        let mut short = hello.clone();
+        short.payload.truncate(session::HELLO_BOX_SIZE - 1);
+        match short.validate() {
+            Err(WhisperError::InvalidHelloFrame) => {}
+            other => panic!("Expected InvalidHelloFrame, got {:?}", other),
+        }
+        let mut short = initiate.clone();
+        short.payload.truncate(INITIATE_BOX_SIZE);
+        assert!(short.validate().is_err());
+        let mut empty = ping.clone();
+        empty.payload.clear();
+        assert!(empty.validate().is_err());
+        empty.kind = FrameKind::Termination;
+        assert!(empty.validate().is_ok());
+    }
+
     #[test]
     fn frame_kind_from_slice() {
         let hello = FrameKind::from_slice(&[1]).unwrap();
@@ -160,6 +531,8 @@ mod test {
         let request = FrameKind::from_slice(&[5]).unwrap();
         let response = FrameKind::from_slice(&[6]).unwrap();
         let notification = FrameKind::from_slice(&[7]).unwrap();
+        let control = FrameKind::from_slice(&[8]).unwrap();
+        let resume = FrameKind::from_slice(&[9]).unwrap();
         let termination = FrameKind::from_slice(&[255]).unwrap();
         let bad = FrameKind::from_slice(&[100]);
         let none = FrameKind::from_slice(&[]);
@@ -171,6 +544,8 @@ mod test {
         assert_eq!(request, FrameKind::Request);
         assert_eq!(response, FrameKind::Response);
         assert_eq!(notification, FrameKind::Notification);
+        assert_eq!(control, FrameKind::Control);
+        assert_eq!(resume, FrameKind::Resume);
         assert_eq!(termination, FrameKind::Termination);
         assert!(bad.is_none());
         assert!(none.is_none());
@@ -209,6 +584,67 @@ mod test {
         assert!(is_bad);
     }
 
+    #[test]
+    fn length_prefixed() {
+        let frame = make_frame();
+        let mut packed = frame.pack_with_length().to_vec();
+        assert_eq!(&packed[..LENGTH_PREFIX_SIZE], &[0, 0, 0, 60]);
+        packed.extend_from_slice(b"next");
+        assert_eq!(Frame::from_slice_with_length(&packed).unwrap(), (frame, 64));
+        match Frame::from_slice_with_length(&packed[..63]) {
+            Err(WhisperError::IncompleteFrame) => {}
+            other => panic!("Expected IncompleteFrame, got {:?}", other),
+        }
+        assert!(Frame::from_slice_with_length(&[0xff, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn decoder_reassembles_chunks() {
+        use transport::pack_prefixed;
+
+        let first = make_frame();
+        let mut second = make_frame();
+        second.kind = FrameKind::Termination;
+        second.payload = vec![1; 300].into();
+        let mut stream = pack_prefixed(&first).to_vec();
+        stream.extend_from_slice(&pack_prefixed(&second));
+        stream.extend_from_slice(&pack_prefixed(&first)[..10]);
+
+        for chunk_size in &[1, 7, 64, stream.len()] {
+            let mut decoder = FrameDecoder::new();
+            let mut frames = Vec::new();
+            for chunk in stream.chunks(*chunk_size) {
+                frames.extend(decoder.feed(chunk).unwrap());
+            }
+            assert_eq!(frames, vec![first.clone(), second.clone()]);
+            assert_eq!(decoder.buffered(), 10);
+        }
+
+        let mut buf = BytesMut::from(&pack_prefixed(&second)[..]);
+        assert!(FrameDecoder::with_max_frame_size(100).decode(&mut buf).is_err());
+
+        // Prefix alone doesn't make it allocate the whole frame.
+        let mut buf = BytesMut::from(&[0x00, 0xff, 0xff, 0xff, 0x01][..]);
+        assert!(FrameDecoder::new().decode(&mut buf).unwrap().is_empty());
+        assert!(buf.capacity() < 1024);
+    }
+
+    #[test]
+    #[cfg(feature = "arbitrary")]
+    fn arbitrary_frames_round_trip() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        let noise: Vec<u8> = (0..2048u32).map(|i| (i * 131 % 251) as u8 | 0x80).collect();
+        let mut u = Unstructured::new(&noise);
+        while let Ok(frame) = Frame::arbitrary(&mut u) {
+            assert_eq!(Frame::from_slice(&frame.pack()).unwrap(), frame);
+            assert_eq!(Frame::from_slice_any(&frame.pack()).unwrap().0, WireVersion::V1);
+            if u.is_empty() {
+                break;
+            }
+        }
+    }
+
     fn make_frame() -> Frame {
         let (pk, _) = gen_keypair();
         let payload = vec![0, 0, 0];
@@ -0,0 +1,105 @@
+//! Freshness timestamps for time sensitive payloads ("unlock door"). Replay
+//! window only remembers recent nonces and stream mode has none, so a
+//! recorded frame can be played again as long as the session lives.
+//! Stamped payload is prefixed with sender's clock as i64 BigEndian
+//! milliseconds since epoch inside the encrypted payload, and receiver
+//! refuses ones older than max age with `StaleFrame`.
+//!
+//! Like content type hints, peers have to agree to use it. Devices without
+//! RTC should stamp with `wrap_at(Utc::now() + offset, ..)`, where offset
+//! is `ClientSession::clock_offset` remembered from handshake.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{DateTime, Duration};
+use chrono::offset::{TimeZone, Utc};
+use errors::{WhisperError, WhisperResult};
+
+/// Size of timestamp prefix.
+pub const STAMP_SIZE: usize = 8;
+
+/// Prefix data with current time. Pass result to `make_request` and friends.
+pub fn wrap(data: &[u8]) -> BytesMut { wrap_at(Utc::now(), data) }
+
+/// Prefix data with given time.
+pub fn wrap_at(sent_at: DateTime<Utc>, data: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(STAMP_SIZE + data.len());
+    buf.put_i64_be(sent_at.timestamp_millis());
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// Receiver side staleness check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Freshness {
+    max_age: Duration,
+    skew_tolerance: Duration,
+}
+
+impl Freshness {
+    /// Refuse payloads stamped more than `max_age` ago.
+    pub fn new(max_age: Duration) -> Freshness {
+        Freshness {
+            max_age,
+            skew_tolerance: Duration::zero(),
+        }
+    }
+
+    /// Allow sender's clock to be this far off ours in either direction.
+    pub fn set_skew_tolerance(&mut self, tolerance: Duration) { self.skew_tolerance = tolerance; }
+
+    /// Split opened payload into timestamp and data. Fails with
+    /// `StaleFrame` if it's too old or from the future.
+    pub fn unwrap(&self, payload: &Bytes) -> WhisperResult<(DateTime<Utc>, Bytes)> {
+        self.unwrap_at(payload, Utc::now())
+    }
+
+    fn unwrap_at(&self,
+                 payload: &Bytes,
+                 now: DateTime<Utc>)
+                 -> WhisperResult<(DateTime<Utc>, Bytes)> {
+        if payload.len() < STAMP_SIZE {
+            return Err(WhisperError::BadFrame);
+        }
+        let sent_at = Utc.timestamp_millis_opt(BigEndian::read_i64(&payload[..STAMP_SIZE]))
+                          .single()
+                          .ok_or(WhisperError::BadFrame)?;
+        let age = now.signed_duration_since(sent_at);
+        if age > self.max_age + self.skew_tolerance || -age > self.skew_tolerance {
+            return Err(WhisperError::StaleFrame);
+        }
+        Ok((sent_at, payload.slice_from(STAMP_SIZE)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::test::handshake;
+
+    #[test]
+    fn stale_payloads_are_refused() {
+        let (client, server) = handshake();
+        let freshness = Freshness::new(Duration::seconds(30));
+        let frame = client.make_request(&wrap(b"unlock")).unwrap();
+        let payload = server.read_msg(&frame).unwrap();
+        let (_, data) = freshness.unwrap(&payload).unwrap();
+        assert_eq!(data.as_ref(), b"unlock");
+
+        // Same frame replayed an hour later.
+        let later = Utc::now() + Duration::hours(1);
+        match freshness.unwrap_at(&payload, later) {
+            Err(WhisperError::StaleFrame) => {}
+            other => panic!("Expected StaleFrame, got {:?}", other),
+        }
+
+        // Sender's clock is a bit ahead.
+        let ahead = wrap_at(Utc::now() + Duration::seconds(5), b"unlock").freeze();
+        assert!(freshness.unwrap(&ahead).is_err());
+        let mut tolerant = freshness;
+        tolerant.set_skew_tolerance(Duration::seconds(10));
+        assert!(tolerant.unwrap(&ahead).is_ok());
+
+        assert!(freshness.unwrap(&Bytes::from(&b"short"[..])).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+//! Minimal request/response service on top of an established session.
+//! Implement `Handler` (or pass a closure) and let `serve` run it over a
+//! blocking stream in stream transport mode. `serve_frame` is the same
+//! thing for a single frame, for those who do IO themselves.
+
+use bytes::Bytes;
+use errors::WhisperResult;
+use frame::{Frame, FrameKind};
+use session::EstablishedSession;
+use std::io::{Read, Write};
+use transport::{self, read_prefixed};
+
+/// Turns request payload into response payload.
+pub trait Handler {
+    /// Handle one request.
+    fn handle(&self, request: Bytes) -> Bytes;
+}
+
+impl<F: Fn(Bytes) -> Bytes> Handler for F {
+    fn handle(&self, request: Bytes) -> Bytes { self(request) }
+}
+
+/// Handle one incoming frame. Requests are answered with Response frame,
+/// everything else is ignored.
+pub fn serve_frame<H: Handler>(session: &EstablishedSession,
+                               handler: &H,
+                               frame: &Frame)
+                               -> WhisperResult<Option<Frame>> {
+    if frame.kind != FrameKind::Request {
+        return Ok(None);
+    }
+    let request = session.read_msg(frame)?;
+    session.make_response(&handler.handle(request)).map(Some)
+}
+
+/// Read length prefixed frames until peer closes the stream or sends
+/// Termination, and answer every Request. Session must be in stream mode.
+/// Termination isn't opened, since peer may send it unauthenticated, e.g.
+/// from drop sink.
+pub fn serve<R: Read, W: Write, H: Handler>(session: &mut EstablishedSession,
+                                            mut reader: R,
+                                            mut writer: W,
+                                            handler: &H)
+                                            -> WhisperResult<()> {
+    while let Some(packet) = read_prefixed(&mut reader)? {
+        let frame = transport::unpack_prefixed(&packet)?;
+        match frame.kind {
+            FrameKind::Request => {
+                let request = session.read_msg(&frame)?;
+                let response = session.make_response(&handler.handle(request))?;
+                writer.write_all(&session.pack(&response))?;
+                writer.flush()?;
+            }
+            FrameKind::Termination => return Ok(()),
+            _ => {
+                session.read_msg(&frame)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use byteorder::{BigEndian, ByteOrder};
+    use session::test::handshake;
+    use termination::TerminationReason;
+    use transport::{LENGTH_PREFIX_SIZE, pack_prefixed};
+
+    fn shout(request: Bytes) -> Bytes { request.to_ascii_uppercase().into() }
+
+    #[test]
+    fn serve_answers_requests() {
+        let (mut client, mut server) = handshake();
+        let mut input = Vec::new();
+        input.extend_from_slice(&client.pack(&client.make_request(b"ping").unwrap()));
+        input.extend_from_slice(&client.pack(&client.make_notification(b"ignored").unwrap()));
+        input.extend_from_slice(&client.pack(&client.make_request(b"pong").unwrap()));
+        let mut output = Vec::new();
+        serve(&mut server, &input[..], &mut output, &shout).unwrap();
+
+        let first_len = LENGTH_PREFIX_SIZE + BigEndian::read_u32(&output) as usize;
+        let (frame, first) = client.read_packet(&output[..first_len]).unwrap();
+        assert_eq!(frame.kind, FrameKind::Response);
+        assert_eq!(first.as_ref(), b"PING");
+        let (_, second) = client.read_packet(&output[first_len..]).unwrap();
+        assert_eq!(second.as_ref(), b"PONG");
+    }
+
+    #[test]
+    fn serve_stops_at_unauthenticated_termination() {
+        let (client, mut server) = handshake();
+        let bye = TerminationReason::Unspecified.to_frame(client.info().id).unwrap();
+        let mut input = Vec::new();
+        input.extend_from_slice(&pack_prefixed(&bye));
+        input.extend_from_slice(&client.pack(&client.make_request(b"late").unwrap()));
+        let mut output = Vec::new();
+        serve(&mut server, &input[..], &mut output, &shout).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn serve_frame_skips_non_requests() {
+        let (client, server) = handshake();
+        let notification = client.make_notification(b"hi").unwrap();
+        assert!(serve_frame(&server, &shout, &notification).unwrap().is_none());
+        let request = client.make_request(b"hi").unwrap();
+        let response = serve_frame(&server, &|_| Bytes::from(&b"ok"[..]), &request).unwrap().unwrap();
+        assert_eq!(client.read_msg(&response).unwrap().as_ref(), b"ok");
+    }
+}
@@ -0,0 +1,139 @@
+//! Hardened handshake failures. Distinct errors (and the time it took to
+//! reach them) tell an active attacker how far a forged frame got. In
+//! hardened mode server answers every failed handshake with the same empty
+//! Termination frame, sent a fixed time after the offending frame arrived.
+//! Real reason goes only to local `AuditHook`.
+//!
+//! Library doesn't do IO, so it can't sleep for you: wait for
+//! `Rejection::delay` before sending `Rejection::frame`.
+
+use errors::WhisperError;
+use frame::{Frame, FrameKind};
+use sodiumoxide::crypto::box_::{PublicKey, gen_nonce};
+use sodiumoxide::randombytes::randombytes_into;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long after arrival of the offending frame Termination is sent,
+/// unless set otherwise.
+pub static DEFAULT_DELAY_MS: u64 = 1000;
+
+/// Local sink for handshake failure details.
+pub trait AuditHook: Send + Sync {
+    /// Called for every rejected handshake.
+    fn handshake_failed(&self, session_id: &PublicKey, error: &WhisperError);
+}
+
+type SharedAuditHook = Arc<dyn AuditHook>;
+
+/// Generic reply to a failed handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    /// Termination frame to send.
+    pub frame: Frame,
+    /// How long to wait before sending it.
+    pub delay: Duration,
+}
+
+/// Hardened mode settings.
+#[derive(DebugStub, Clone)]
+pub struct Hardening {
+    #[debug_stub = "AuditHook"]
+    audit: SharedAuditHook,
+    delay: Duration,
+    jitter: Duration,
+}
+
+impl Hardening {
+    /// Hardened mode reporting to given hook. Replies are sent
+    /// `DEFAULT_DELAY_MS` after arrival with up to 10% jitter.
+    pub fn new(audit: Arc<dyn AuditHook>) -> Hardening {
+        Hardening::with_delay(audit, Duration::from_millis(DEFAULT_DELAY_MS))
+    }
+
+    /// Same as above, but with custom delay. Delay must be longer than the
+    /// slowest handshake step, otherwise timing still leaks.
+    pub fn with_delay(audit: Arc<dyn AuditHook>, delay: Duration) -> Hardening {
+        Hardening {
+            audit,
+            delay,
+            jitter: delay / 10,
+        }
+    }
+
+    /// Report failure to audit hook and make generic reply. `received_at` is
+    /// when the offending frame arrived.
+    pub fn reject(&self, frame: &Frame, error: &WhisperError, received_at: Instant) -> Rejection {
+        self.audit.handshake_failed(&frame.id, error);
+        let deadline = received_at + self.delay + self.random_jitter();
+        Rejection {
+            frame: Frame {
+                id: frame.id,
+                nonce: gen_nonce(),
+                kind: FrameKind::Termination,
+                payload: Default::default(),
+            },
+            delay: deadline.saturating_duration_since(Instant::now()),
+        }
+    }
+
+    fn random_jitter(&self) -> Duration {
+        let mut buf = [0; 4];
+        randombytes_into(&mut buf);
+        let fraction = f64::from(u32::from_le_bytes(buf)) / f64::from(u32::MAX);
+        self.jitter.mul_f64(fraction)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::KeyPair;
+    use session::{ClientSession, ServerSession};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl AuditHook for Recorder {
+        fn handshake_failed(&self, _: &PublicKey, error: &WhisperError) {
+            self.0.lock().unwrap().push(format!("{:?}", error));
+        }
+    }
+
+    #[test]
+    fn failures_look_the_same() {
+        let recorder = Arc::new(Recorder::default());
+        let hardening = Hardening::with_delay(recorder.clone(), Duration::from_millis(100));
+        let server_identity_keypair = KeyPair::new().unwrap();
+
+        // Hello for someone else.
+        let mut client = ClientSession::new(KeyPair::new().unwrap(),
+                                            KeyPair::new().unwrap().public_key)
+            .unwrap();
+        let hello = client.make_hello().unwrap();
+        let mut server = ServerSession::new(server_identity_keypair.clone(), hello.id).unwrap();
+        let received_at = Instant::now();
+        let error = server.make_welcome(&hello).unwrap_err();
+        let first = hardening.reject(&hello, &error, received_at);
+
+        // Wrong frame kind.
+        let mut client = ClientSession::new(KeyPair::new().unwrap(),
+                                            server_identity_keypair.public_key)
+            .unwrap();
+        let hello = client.make_hello().unwrap();
+        let mut server = ServerSession::new(server_identity_keypair, hello.id).unwrap();
+        let mut initiate = hello.clone();
+        initiate.kind = FrameKind::Initiate;
+        let error = server.make_welcome(&initiate).unwrap_err();
+        let second = hardening.reject(&initiate, &error, received_at);
+
+        assert_eq!(first.frame.kind, FrameKind::Termination);
+        assert_eq!(first.frame.kind, second.frame.kind);
+        assert_eq!(first.frame.payload, second.frame.payload);
+        assert!(first.delay <= Duration::from_millis(110));
+        assert!(second.delay > Duration::from_millis(50));
+        let log = recorder.0.lock().unwrap();
+        assert_eq!(log.as_slice(), &["DecryptionFailed", "InvalidSessionState"]);
+    }
+}
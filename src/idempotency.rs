@@ -0,0 +1,186 @@
+//! Idempotency keys, so requests whose responses got lost can be sent again
+//! without doing the work twice.
+//!
+//! Keyed Request payload is prefixed with key length (1 byte, zero for no
+//! key) and the key inside the encrypted payload. Server keeps responses in
+//! bounded `ResultCache` by peer identity and key, and answers repeated
+//! request from there. Key is only unique per client identity, so random
+//! `new_key` is good enough. Like content type hints, peers have to agree
+//! to use it.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use crypto;
+use digest::{self, Fingerprint};
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use handler::Handler;
+use session::EstablishedSession;
+use sodiumoxide::randombytes::randombytes;
+use std::collections::{HashMap, VecDeque};
+
+/// Longest key in bytes.
+pub const KEY_MAX: usize = 255;
+/// Size of keys made by `new_key`.
+pub const KEY_SIZE: usize = 16;
+
+/// Random key for a new request. Reuse it for every retry of the request.
+/// Fails with `InitializationFailed` if libsodium can't be initialized.
+pub fn new_key() -> WhisperResult<Bytes> {
+    crypto::init()?;
+    Ok(randombytes(KEY_SIZE).into())
+}
+
+/// Prefix data with key. Fails with `BadFrame` if key is longer than
+/// `KEY_MAX`.
+pub fn wrap(key: Option<&[u8]>, data: &[u8]) -> WhisperResult<BytesMut> {
+    let key = key.unwrap_or(&[]);
+    if key.len() > KEY_MAX {
+        return Err(WhisperError::BadFrame);
+    }
+    let mut buf = BytesMut::with_capacity(1 + key.len() + data.len());
+    buf.put_u8(key.len() as u8);
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(data);
+    Ok(buf)
+}
+
+/// Split opened payload into key and data.
+pub fn unwrap(payload: &Bytes) -> WhisperResult<(Option<Bytes>, Bytes)> {
+    let len = *payload.first().ok_or(WhisperError::BadFrame)? as usize;
+    if payload.len() < 1 + len {
+        return Err(WhisperError::BadFrame);
+    }
+    let key = if len == 0 { None } else { Some(payload.slice(1, 1 + len)) };
+    Ok((key, payload.slice_from(1 + len)))
+}
+
+type CacheKey = (Fingerprint, Bytes);
+
+/// Responses to keyed requests. Oldest ones are forgotten once cache is
+/// full.
+#[derive(Debug, Clone)]
+pub struct ResultCache {
+    capacity: usize,
+    order: VecDeque<CacheKey>,
+    results: HashMap<CacheKey, Bytes>,
+}
+
+impl ResultCache {
+    /// Cache that remembers up to `capacity` responses.
+    pub fn new(capacity: usize) -> ResultCache {
+        ResultCache {
+            capacity,
+            order: VecDeque::new(),
+            results: HashMap::new(),
+        }
+    }
+
+    /// Number of cached responses.
+    pub fn len(&self) -> usize { self.results.len() }
+
+    /// Returns true if nothing is cached.
+    pub fn is_empty(&self) -> bool { self.results.is_empty() }
+
+    /// Cached response to request with this key from this session's peer.
+    pub fn get(&self, session: &EstablishedSession, key: &[u8]) -> Option<&Bytes> {
+        self.results.get(&(owner(session), Bytes::from(key)))
+    }
+
+    /// Remember response to request with this key from this session's peer.
+    pub fn insert(&mut self, session: &EstablishedSession, key: &[u8], response: Bytes) {
+        if self.capacity == 0 {
+            return;
+        }
+        let cache_key = (owner(session), Bytes::from(key));
+        if self.results.insert(cache_key.clone(), response).is_none() {
+            self.order.push_back(cache_key);
+        }
+        while self.results.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.results.remove(&oldest);
+            }
+        }
+    }
+
+    /// Same as `handler::serve_frame`, but for keyed requests: handler only
+    /// runs for the first request with given key, retries get cached
+    /// response. Requests without key always run handler.
+    pub fn serve_frame<H: Handler>(&mut self,
+                                   session: &EstablishedSession,
+                                   handler: &H,
+                                   frame: &Frame)
+                                   -> WhisperResult<Option<Frame>> {
+        if frame.kind != FrameKind::Request {
+            return Ok(None);
+        }
+        let (key, request) = unwrap(&session.read_msg(frame)?)?;
+        let response = match key {
+            Some(ref key) => match self.get(session, key).cloned() {
+                Some(response) => response,
+                None => {
+                    let response = handler.handle(request);
+                    self.insert(session, key, response.clone());
+                    response
+                }
+            },
+            None => handler.handle(request),
+        };
+        session.make_response(&response).map(Some)
+    }
+}
+
+// Same client gets the same owner on every session.
+fn owner(session: &EstablishedSession) -> Fingerprint {
+    let info = session.info();
+    info.peer_fingerprint.unwrap_or_else(|| digest::fingerprint(&info.id))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::KeyPair;
+    use session::test::handshake_with;
+    use std::cell::Cell;
+
+    #[test]
+    fn retries_are_answered_from_cache() {
+        let client_identity = KeyPair::new().unwrap();
+        let server_identity = KeyPair::new().unwrap();
+        let (client, server) = handshake_with(client_identity.clone(), server_identity.clone());
+        let mut cache = ResultCache::new(1);
+        let runs = Cell::new(0);
+        let handler = |request: Bytes| {
+            runs.set(runs.get() + 1);
+            Bytes::from(format!("{} {}", String::from_utf8_lossy(&request), runs.get()))
+        };
+
+        let key = new_key().unwrap();
+        let request = client.make_request(&wrap(Some(&key), b"unlock").unwrap()).unwrap();
+        let first = cache.serve_frame(&server, &handler, &request).unwrap().unwrap();
+        assert_eq!(client.read_msg(&first).unwrap().as_ref(), b"unlock 1");
+        // Response got lost, client reconnects and retries.
+        let (client, server) = handshake_with(client_identity, server_identity);
+        let retry = client.make_request(&wrap(Some(&key), b"unlock").unwrap()).unwrap();
+        let second = cache.serve_frame(&server, &handler, &retry).unwrap().unwrap();
+        assert_eq!(client.read_msg(&second).unwrap().as_ref(), b"unlock 1");
+
+        let plain = client.make_request(&wrap(None, b"status").unwrap()).unwrap();
+        let third = cache.serve_frame(&server, &handler, &plain).unwrap().unwrap();
+        assert_eq!(client.read_msg(&third).unwrap().as_ref(), b"status 2");
+
+        // Other client with the same key is somebody else.
+        let (other, other_server) =
+            handshake_with(KeyPair::new().unwrap(), KeyPair::new().unwrap());
+        let request = other.make_request(&wrap(Some(&key), b"unlock").unwrap()).unwrap();
+        let response = cache.serve_frame(&other_server, &handler, &request).unwrap().unwrap();
+        assert_eq!(other.read_msg(&response).unwrap().as_ref(), b"unlock 3");
+        // Cache holds one response only.
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&server, &key).is_none());
+
+        match wrap(Some(&[0; KEY_MAX + 1]), b"unlock") {
+            Err(WhisperError::BadFrame) => {}
+            other => panic!("Expected BadFrame, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Storing identity keys between runs. `KeyEncoding` turns keys into raw
+//! bytes, hex or base64 and back, `save` and `load` keep keypair in a file.
+//!
+//! File is PEM-like text: `-----BEGIN WHISPER IDENTITY-----`, base64 of
+//! secret key followed by public key, `-----END WHISPER IDENTITY-----`.
+//! `import::keypair` reads it too. On Unix `save` makes the file readable
+//! by its owner only. It never overwrites: losing identity key means
+//! re-enrolling the device.
+//!
+//! Buffers holding secret key on the way are wiped. Returned text isn't:
+//! it's yours to keep or wipe.
+
+use crypto::{KeyPair, SecureBytes};
+use encoding;
+use errors::{WhisperError, WhisperResult};
+use import;
+use sodiumoxide::crypto::box_::{PUBLICKEYBYTES, PublicKey, SECRETKEYBYTES};
+use sodiumoxide::utils::memzero;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str;
+
+/// First line of identity file.
+pub const BEGIN: &str = "-----BEGIN WHISPER IDENTITY-----";
+/// Last line of identity file.
+pub const END: &str = "-----END WHISPER IDENTITY-----";
+
+const LINE_WIDTH: usize = 64;
+
+/// Encodings of keys.
+pub trait KeyEncoding: Sized {
+    /// Raw bytes. Keypair is secret key followed by public key, so they
+    /// are wiped once dropped.
+    fn to_bytes(&self) -> SecureBytes;
+
+    /// Inverse of `to_bytes`. Wrong size is `MalformedKey`, so is keypair
+    /// whose public key doesn't belong to its secret key.
+    fn from_bytes(bytes: &[u8]) -> WhisperResult<Self>;
+
+    /// Lowercase hex of `to_bytes`.
+    fn to_hex(&self) -> String { encoding::hex(&self.to_bytes()) }
+
+    /// Inverse of `to_hex`, either case.
+    fn from_hex(text: &str) -> WhisperResult<Self> {
+        Self::from_bytes(&SecureBytes::from(encoding::from_hex(text.trim())?))
+    }
+
+    /// Base64 of `to_bytes`, standard alphabet with padding.
+    fn to_base64(&self) -> String { encoding::base64(&self.to_bytes()) }
+
+    /// Inverse of `to_base64`. Padding is optional.
+    fn from_base64(text: &str) -> WhisperResult<Self> {
+        Self::from_bytes(&SecureBytes::from(encoding::from_base64(text)?))
+    }
+}
+
+impl KeyEncoding for PublicKey {
+    fn to_bytes(&self) -> SecureBytes { SecureBytes::from(self.0.to_vec()) }
+
+    fn from_bytes(bytes: &[u8]) -> WhisperResult<PublicKey> {
+        PublicKey::from_slice(bytes).ok_or_else(|| malformed("public key must be 32 bytes"))
+    }
+}
+
+impl KeyEncoding for KeyPair {
+    fn to_bytes(&self) -> SecureBytes {
+        let mut bytes = Vec::with_capacity(SECRETKEYBYTES + PUBLICKEYBYTES);
+        bytes.extend_from_slice(&self.secret_key.0);
+        bytes.extend_from_slice(&self.public_key.0);
+        SecureBytes::from(bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> WhisperResult<KeyPair> {
+        if bytes.len() != SECRETKEYBYTES + PUBLICKEYBYTES {
+            return Err(malformed("keypair must be 64 bytes"));
+        }
+        let mut secret_key = [0; SECRETKEYBYTES];
+        secret_key.copy_from_slice(&bytes[..SECRETKEYBYTES]);
+        let keypair = import::x25519_keypair(&secret_key);
+        memzero(&mut secret_key);
+        if keypair.public_key.0[..] != bytes[SECRETKEYBYTES..] {
+            return Err(malformed("public key doesn't match secret key"));
+        }
+        Ok(keypair)
+    }
+}
+
+/// Identity file contents for keypair.
+pub fn encode(keypair: &KeyPair) -> String {
+    let encoded = SecureBytes::from(keypair.to_base64().into_bytes());
+    let lines = encoded.len().div_ceil(LINE_WIDTH);
+    let mut text = String::with_capacity(BEGIN.len() + encoded.len() + lines + END.len() + 2);
+    text.push_str(BEGIN);
+    text.push('\n');
+    for line in encoded.chunks(LINE_WIDTH) {
+        // Base64 is ASCII, so this never replaces anything.
+        text.push_str(&String::from_utf8_lossy(line));
+        text.push('\n');
+    }
+    text.push_str(END);
+    text.push('\n');
+    text
+}
+
+/// Keypair from identity file contents.
+pub fn decode(text: &str) -> WhisperResult<KeyPair> {
+    let text = text.trim();
+    if !text.starts_with(BEGIN) || !text.ends_with(END) || text.len() < BEGIN.len() + END.len() {
+        return Err(malformed("not a whisper identity"));
+    }
+    KeyPair::from_base64(&text[BEGIN.len()..text.len() - END.len()])
+}
+
+/// Write keypair to a new file. Existing file is left alone and the error
+/// is `Io` with `AlreadyExists`.
+pub fn save<P: AsRef<Path>>(path: P, keypair: &KeyPair) -> WhisperResult<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    let text = SecureBytes::from(encode(keypair).into_bytes());
+    file.write_all(&text)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Read keypair written by `save`.
+pub fn load<P: AsRef<Path>>(path: P) -> WhisperResult<KeyPair> {
+    let text = SecureBytes::from(fs::read(path)?);
+    decode(str::from_utf8(&text).map_err(|_| malformed("not a whisper identity"))?)
+}
+
+fn malformed(reason: &str) -> WhisperError { WhisperError::MalformedKey(reason.to_string()) }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn keys_survive_round_trips() {
+        let keypair = KeyPair::new().unwrap();
+        let restored = KeyPair::from_bytes(&keypair.to_bytes()).unwrap();
+        assert_eq!(restored.secret_key, keypair.secret_key);
+        assert_eq!(KeyPair::from_hex(&keypair.to_hex()).unwrap().public_key, keypair.public_key);
+        assert_eq!(KeyPair::from_base64(&keypair.to_base64()).unwrap().public_key,
+                   keypair.public_key);
+        assert_eq!(PublicKey::from_hex(&keypair.public_key.to_hex()).unwrap(), keypair.public_key);
+
+        let mut bytes = keypair.to_bytes().to_vec();
+        bytes[63] ^= 1;
+        assert!(KeyPair::from_bytes(&bytes).is_err());
+        assert!(PublicKey::from_hex("abc").is_err());
+
+        let name = format!("whisper-{}.key", KeyPair::new().unwrap().public_key.to_hex());
+        let path = env::temp_dir().join(name);
+        save(&path, &keypair).unwrap();
+        assert!(save(&path, &KeyPair::new().unwrap()).is_err());
+        assert_eq!(load(&path).unwrap().public_key, keypair.public_key);
+        assert_eq!(import::keypair(&encode(&keypair)).unwrap().public_key, keypair.public_key);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
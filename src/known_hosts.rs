@@ -0,0 +1,201 @@
+//! Pinned server identity keys per host name, in a file like SSH's
+//! `known_hosts`. Protocol never shows server identity key: client needs it
+//! to seal Hello, so it always comes from elsewhere (config, provisioning,
+//! DNS record...). This store remembers the key that source gave for a host
+//! the first time, and refuses a different one for that host afterwards
+//! with `KeyMismatch`. Check the key here before handing it to
+//! `ClientSession`.
+//!
+//! File is text, one host per line: logical name, whitespace, hex of the
+//! identity key. Blank lines and lines starting with `#` are skipped, so is
+//! everything after the key. Names can't contain whitespace or repeat.
+//! Store opened with `KnownHosts::open` rewrites its file after every
+//! change; file is written next to the old one under a name of its own and
+//! renamed over it, so a crash leaves either old or new pins. Change that
+//! couldn't be written is undone.
+
+use errors::{WhisperError, WhisperResult};
+use keyfile::KeyEncoding;
+use sodiumoxide::crypto::box_::PublicKey;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Temp files of this process, so writers never share one.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Outcome of `KnownHosts::verify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trust {
+    /// Host wasn't pinned, its key is now.
+    FirstUse,
+    /// Key matches the pinned one.
+    Known,
+}
+
+/// Identity keys pinned per host name.
+#[derive(Debug, Clone, Default)]
+pub struct KnownHosts {
+    hosts: BTreeMap<String, PublicKey>,
+    path: Option<PathBuf>,
+}
+
+impl KnownHosts {
+    /// Empty store kept in memory only.
+    pub fn new() -> KnownHosts { KnownHosts::default() }
+
+    /// Store backed by file. Missing file is an empty store; it is created
+    /// on the first pin.
+    pub fn open<P: AsRef<Path>>(path: P) -> WhisperResult<KnownHosts> {
+        let mut known_hosts = match fs::read_to_string(&path) {
+            Ok(text) => KnownHosts::decode(&text)?,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => KnownHosts::new(),
+            Err(err) => return Err(err.into()),
+        };
+        known_hosts.path = Some(path.as_ref().to_path_buf());
+        Ok(known_hosts)
+    }
+
+    /// Key pinned for this host.
+    pub fn get(&self, host: &str) -> Option<&PublicKey> { self.hosts.get(host) }
+
+    /// Check identity key we were given for this host. Unknown host gets
+    /// the key pinned. Different key than the pinned one is `KeyMismatch`
+    /// and the pin stays as it was.
+    pub fn verify(&mut self, host: &str, key: &PublicKey) -> WhisperResult<Trust> {
+        match self.hosts.get(host) {
+            Some(pinned) if pinned == key => Ok(Trust::Known),
+            Some(_) => Err(WhisperError::KeyMismatch(host.to_string())),
+            None => {
+                self.pin(host, *key)?;
+                Ok(Trust::FirstUse)
+            }
+        }
+    }
+
+    /// Pin key for host, replacing the old pin. For keys that rotated on
+    /// purpose. Bad host name is `Io` with `InvalidInput`.
+    pub fn pin(&mut self, host: &str, key: PublicKey) -> WhisperResult<()> {
+        if host.is_empty() || host.contains(char::is_whitespace) {
+            let reason = "host name can't be empty or contain whitespace";
+            return Err(io::Error::new(ErrorKind::InvalidInput, reason).into());
+        }
+        let old = self.hosts.insert(host.to_string(), key);
+        let result = self.persist();
+        if result.is_err() {
+            match old {
+                Some(old) => self.hosts.insert(host.to_string(), old),
+                None => self.hosts.remove(host),
+            };
+        }
+        result
+    }
+
+    /// Drop pin of this host. Next key it presents is first use again.
+    pub fn forget(&mut self, host: &str) -> WhisperResult<()> {
+        if let Some(old) = self.hosts.remove(host) {
+            if let Err(err) = self.persist() {
+                self.hosts.insert(host.to_string(), old);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// File contents for this store.
+    pub fn encode(&self) -> String {
+        self.hosts
+            .iter()
+            .map(|(host, key)| format!("{} {}\n", host, key.to_hex()))
+            .collect()
+    }
+
+    /// Store from file contents. Not backed by any file. Host listed twice
+    /// is `MalformedKey`.
+    pub fn decode(text: &str) -> WhisperResult<KnownHosts> {
+        let mut known_hosts = KnownHosts::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(host), Some(key)) => {
+                    let key = PublicKey::from_hex(key)?;
+                    if known_hosts.hosts.insert(host.to_string(), key).is_some() {
+                        return Err(WhisperError::MalformedKey(format!("duplicate host: {}", host)));
+                    }
+                }
+                _ => return Err(WhisperError::MalformedKey(format!("bad line: {}", line))),
+            }
+        }
+        Ok(known_hosts)
+    }
+
+    fn persist(&self) -> WhisperResult<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut temp = path.clone().into_os_string();
+        temp.push(format!(".{}.{}.new", process::id(), TEMP_FILES.fetch_add(1, Ordering::Relaxed)));
+        let result = OpenOptions::new().write(true)
+                                       .create_new(true)
+                                       .open(&temp)
+                                       .and_then(|mut file| {
+                                                     file.write_all(self.encode().as_bytes())?;
+                                                     file.sync_all()?;
+                                                     fs::rename(&temp, path)
+                                                 });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result.map_err(WhisperError::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::KeyPair;
+    use std::env;
+
+    #[test]
+    fn pins_survive_restart() {
+        let (server, impostor) =
+            (KeyPair::new().unwrap().public_key, KeyPair::new().unwrap().public_key);
+        let name = format!("whisper-{}.hosts", KeyPair::new().unwrap().public_key.to_hex());
+        let path = env::temp_dir().join(name);
+
+        let mut known_hosts = KnownHosts::open(&path).unwrap();
+        assert_eq!(known_hosts.verify("db.internal", &server).unwrap(), Trust::FirstUse);
+        assert_eq!(known_hosts.verify("db.internal", &server).unwrap(), Trust::Known);
+        assert!(known_hosts.pin("db internal", server).is_err());
+
+        let mut known_hosts = KnownHosts::open(&path).unwrap();
+        assert_eq!(known_hosts.get("db.internal"), Some(&server));
+        match known_hosts.verify("db.internal", &impostor) {
+            Err(WhisperError::KeyMismatch(ref host)) if host == "db.internal" => {}
+            other => panic!("Expected KeyMismatch, got {:?}", other),
+        }
+        assert_eq!(known_hosts.get("db.internal"), Some(&server));
+        known_hosts.forget("db.internal").unwrap();
+        assert_eq!(KnownHosts::open(&path).unwrap().get("db.internal"), None);
+        fs::remove_file(&path).unwrap();
+
+        let text = format!("# pinned\n\ncache {} first seen monday\n", server.to_hex());
+        assert_eq!(KnownHosts::decode(&text).unwrap().get("cache"), Some(&server));
+        assert!(KnownHosts::decode("cache").is_err());
+        let twice = format!("{}cache {}\n", text, impostor.to_hex());
+        assert!(KnownHosts::decode(&twice).is_err());
+
+        // Pin that couldn't be written is forgotten.
+        let mut known_hosts = KnownHosts::open(env::temp_dir().join("no-such-dir").join("hosts"))
+            .unwrap();
+        assert!(known_hosts.verify("db.internal", &server).is_err());
+        assert_eq!(known_hosts.get("db.internal"), None);
+    }
+}
@@ -29,16 +29,115 @@
 //!
 //! ## Usage
 //! TODO: Write usage instructions here
+//!
+//! ## Debugging
+//! `null-cipher` feature turns encryption of session messages into no-op,
+//! so traffic can be read in Wireshark. Headers, sizes and handshake stay
+//! the same. Both sides must have it. Release builds refuse to compile
+//! with it. **Never use it in production.**
 
+extern crate byteorder;
 extern crate chrono;
 extern crate sodiumoxide;
+extern crate libsodium_sys;
+#[cfg(feature = "arbitrary")]
+extern crate arbitrary;
+#[cfg(feature = "opaque")]
+extern crate argon2;
 extern crate bytes;
+#[cfg(feature = "pake")]
+extern crate spake2;
+#[macro_use]
+extern crate debug_stub_derive;
 #[macro_use]
 extern crate quick_error;
 #[macro_use]
 extern crate nom;
+#[cfg(feature = "opaque")]
+extern crate opaque_ke;
+#[cfg(feature = "cbor")]
+extern crate ciborium;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "compression")]
+extern crate zstd;
+#[cfg(feature = "quic")]
+extern crate quinn;
+#[cfg(feature = "quic")]
+extern crate bytes1;
 
+#[cfg(all(feature = "null-cipher", not(debug_assertions)))]
+compile_error!("null-cipher feature sends messages in the clear, it is for debug builds only");
+
+pub mod attestation;
+pub mod auth;
 pub mod session;
+pub mod clock;
+pub mod codec;
+pub mod control;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod content;
+pub mod crc;
+pub mod fragment;
 pub mod frame;
+pub mod freshness;
+pub mod devices;
+pub mod digest;
+pub mod elligator;
+mod encoding;
 pub mod errors;
+pub mod facade;
+#[cfg(feature = "faults")]
+pub mod faults;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod enrollment;
+pub mod handler;
+pub mod hardening;
+pub mod idempotency;
+pub mod import;
+pub mod keyfile;
+pub mod known_hosts;
+pub mod limits;
+pub mod liveness;
 pub mod crypto;
+pub mod metadata;
+pub mod metrics;
+#[cfg(test)]
+mod model;
+pub mod net;
+#[cfg(feature = "opaque")]
+pub mod opaque;
+pub mod pacing;
+#[cfg(feature = "pake")]
+pub mod pairing;
+pub mod pubsub;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod quota;
+pub mod redirect;
+pub mod renegotiation;
+pub mod resumption;
+pub mod routing;
+pub mod schema;
+#[cfg(feature = "serde")]
+pub mod serialization;
+pub mod server;
+pub mod status;
+pub mod store;
+pub mod stream;
+pub mod suite;
+pub mod termination;
+pub mod tickets;
+pub mod trace;
+pub mod tracker;
+pub mod transport;
+#[cfg(not(feature = "null-cipher"))]
+pub mod vectors;
+pub mod vouch;
+pub mod sim;
+
+pub use facade::{Client, Connection, Server};
@@ -0,0 +1,271 @@
+//! Per-identity limits for authenticated peers. Pacing and handshake
+//! hardening don't help against a client with valid keys that floods us or
+//! keeps failing requests on purpose, so `IdentityLimiter` keeps counters
+//! by identity key:
+//! - messages: token bucket per identity. Message over the rate is refused
+//!   with `RateLimited` and counts as a failure.
+//! - failures: whatever application considers abuse (`record_failure`),
+//!   counted in a sliding window.
+//!
+//! Identity that gets `max_failures` within `failure_window` is banned for
+//! `ban_for`. Banned identity gets `Banned` error with time left, and new
+//! handshakes from it should be answered with `ban_termination` — a
+//! `Banned` Termination telling when to come back. `facade::Server` does
+//! it for you with `set_limiter`.
+
+use errors::{WhisperError, WhisperResult};
+use frame::Frame;
+use pacing::TokenBucket;
+use sodiumoxide::crypto::box_::PublicKey;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use termination::TerminationReason;
+
+/// Thresholds of `IdentityLimiter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdentityLimits {
+    /// Messages per second each identity can send.
+    pub messages_per_sec: u64,
+    /// Burst of messages above the rate.
+    pub burst: u64,
+    /// Failures that get identity banned.
+    pub max_failures: usize,
+    /// Window failures are counted in.
+    pub failure_window: Duration,
+    /// How long ban lasts.
+    pub ban_for: Duration,
+}
+
+impl Default for IdentityLimits {
+    fn default() -> IdentityLimits {
+        IdentityLimits {
+            messages_per_sec: 100,
+            burst: 200,
+            max_failures: 10,
+            failure_window: Duration::from_secs(60),
+            ban_for: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    messages: TokenBucket,
+    failures: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+    last_seen: Instant,
+}
+
+#[derive(Debug)]
+struct State {
+    limits: IdentityLimits,
+    // Full bucket every new identity starts with.
+    bucket: TokenBucket,
+    entries: HashMap<PublicKey, Entry>,
+}
+
+/// Counters and bans by identity key. Cheap to clone, clones share state.
+#[derive(Debug, Clone)]
+pub struct IdentityLimiter {
+    state: Arc<Mutex<State>>,
+}
+
+impl IdentityLimiter {
+    /// Limiter without any history. Fails with `InvalidLimit` if message
+    /// rate is zero.
+    pub fn new(limits: IdentityLimits) -> WhisperResult<IdentityLimiter> {
+        let bucket = TokenBucket::new(limits.messages_per_sec, limits.burst)?;
+        Ok(IdentityLimiter {
+               state: Arc::new(Mutex::new(State {
+                                              limits,
+                                              bucket,
+                                              entries: HashMap::new(),
+                                          })),
+           })
+    }
+
+    /// Fails with `Banned` if identity is banned. Call before accepting
+    /// its handshake.
+    pub fn check(&self, identity: &PublicKey) -> WhisperResult<()> {
+        self.check_at(identity, Instant::now())
+    }
+
+    /// Account for one message from identity. Fails with `Banned` if it is
+    /// banned, `RateLimited` if it sends too fast.
+    pub fn message(&self, identity: &PublicKey) -> WhisperResult<()> {
+        self.message_at(identity, Instant::now())
+    }
+
+    /// Count a failure of identity. Fails with `Banned` if identity is
+    /// banned now, maybe because of this failure.
+    pub fn record_failure(&self, identity: &PublicKey) -> WhisperResult<()> {
+        self.failure_at(identity, Instant::now())
+    }
+
+    /// Ban identity right away, e.g. by operator's decision.
+    pub fn ban(&self, identity: &PublicKey, ban_for: Duration) {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("Limiter lock poisoned");
+        state.entry(identity, now).banned_until = Some(now + ban_for);
+    }
+
+    /// Lift ban and forget failures of identity.
+    pub fn unban(&self, identity: &PublicKey) {
+        self.state.lock().expect("Limiter lock poisoned").entries.remove(identity);
+    }
+
+    /// Forget identities that weren't seen for a failure window and aren't
+    /// banned. Call from time to time, so map doesn't grow forever.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("Limiter lock poisoned");
+        let window = state.limits.failure_window;
+        state.entries.retain(|_, entry| {
+            entry.banned_until.is_some_and(|until| until > now) ||
+            now.saturating_duration_since(entry.last_seen) < window
+        });
+    }
+
+    /// Number of identities limiter knows about.
+    pub fn len(&self) -> usize { self.state.lock().expect("Limiter lock poisoned").entries.len() }
+
+    /// Returns true if limiter doesn't know anyone.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    fn check_at(&self, identity: &PublicKey, now: Instant) -> WhisperResult<()> {
+        let state = self.state.lock().expect("Limiter lock poisoned");
+        match state.entries.get(identity) {
+            Some(entry) => entry.check(now),
+            None => Ok(()),
+        }
+    }
+
+    fn message_at(&self, identity: &PublicKey, now: Instant) -> WhisperResult<()> {
+        let mut state = self.state.lock().expect("Limiter lock poisoned");
+        let limits = state.limits;
+        let entry = state.entry(identity, now);
+        entry.check(now)?;
+        let wait = entry.messages.wait(1, now);
+        if wait > Duration::from_secs(0) {
+            entry.fail(now, &limits);
+            entry.check(now)?;
+            return Err(WhisperError::RateLimited(wait));
+        }
+        entry.messages.take(1);
+        Ok(())
+    }
+
+    fn failure_at(&self, identity: &PublicKey, now: Instant) -> WhisperResult<()> {
+        let mut state = self.state.lock().expect("Limiter lock poisoned");
+        let limits = state.limits;
+        let entry = state.entry(identity, now);
+        entry.fail(now, &limits);
+        entry.check(now)
+    }
+}
+
+impl State {
+    fn entry(&mut self, identity: &PublicKey, now: Instant) -> &mut Entry {
+        let bucket = &self.bucket;
+        let entry = self.entries.entry(*identity).or_insert_with(|| {
+            Entry {
+                messages: bucket.clone(),
+                failures: VecDeque::new(),
+                banned_until: None,
+                last_seen: now,
+            }
+        });
+        entry.last_seen = now;
+        entry
+    }
+}
+
+impl Entry {
+    fn check(&self, now: Instant) -> WhisperResult<()> {
+        match self.banned_until {
+            Some(until) if until > now => Err(WhisperError::Banned(until - now)),
+            _ => Ok(()),
+        }
+    }
+
+    fn fail(&mut self, now: Instant, limits: &IdentityLimits) {
+        self.failures.push_back(now);
+        while self.failures
+                  .front()
+                  .is_some_and(|&at| now.saturating_duration_since(at) >= limits.failure_window)
+        {
+            self.failures.pop_front();
+        }
+        if self.failures.len() >= limits.max_failures {
+            self.failures.clear();
+            self.banned_until = Some(now + limits.ban_for);
+        }
+    }
+}
+
+/// `Banned` Termination for session of banned identity, telling when to
+/// retry.
+pub fn ban_termination(session_id: PublicKey, retry_in: Duration) -> WhisperResult<Frame> {
+    TerminationReason::Banned.to_frame_with_retry(session_id, retry_in)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::KeyPair;
+
+    #[test]
+    fn flood_gets_banned() {
+        let limiter = IdentityLimiter::new(IdentityLimits {
+                                               messages_per_sec: 10,
+                                               burst: 2,
+                                               max_failures: 3,
+                                               failure_window: Duration::from_secs(10),
+                                               ban_for: Duration::from_secs(60),
+                                           })
+            .unwrap();
+        let flooder = KeyPair::new().unwrap().public_key;
+        let neighbour = KeyPair::new().unwrap().public_key;
+        let start = Instant::now();
+        assert!(limiter.message_at(&flooder, start).is_ok());
+        assert!(limiter.message_at(&flooder, start).is_ok());
+        for _ in 0..2 {
+            match limiter.message_at(&flooder, start) {
+                Err(WhisperError::RateLimited(_)) => {}
+                other => panic!("Expected RateLimited, got {:?}", other),
+            }
+        }
+        // Third violation is a ban.
+        match limiter.message_at(&flooder, start) {
+            Err(WhisperError::Banned(left)) => assert_eq!(left, Duration::from_secs(60)),
+            other => panic!("Expected Banned, got {:?}", other),
+        }
+        let later = start + Duration::from_secs(30);
+        assert!(limiter.check_at(&flooder, later).is_err());
+        assert!(limiter.check_at(&neighbour, later).is_ok());
+        assert!(limiter.message_at(&neighbour, later).is_ok());
+        assert!(limiter.check_at(&flooder, start + Duration::from_secs(61)).is_ok());
+
+        // Failures outside of window don't add up.
+        assert!(limiter.failure_at(&neighbour, later).is_ok());
+        assert!(limiter.failure_at(&neighbour, later + Duration::from_secs(11)).is_ok());
+        assert!(limiter.failure_at(&neighbour, later + Duration::from_secs(12)).is_ok());
+        assert!(limiter.failure_at(&neighbour, later + Duration::from_secs(13)).is_err());
+        limiter.unban(&neighbour);
+        assert!(limiter.check(&neighbour).is_ok());
+
+        let termination = ban_termination(flooder, Duration::from_secs(42)).unwrap();
+        assert_eq!(TerminationReason::from_frame(&termination), Some(TerminationReason::Banned));
+        assert_eq!(TerminationReason::retry_after(&termination), Some(Duration::from_secs(42)));
+
+        let stuck = IdentityLimits {
+            messages_per_sec: 0,
+            ..Default::default()
+        };
+        match IdentityLimiter::new(stuck) {
+            Err(WhisperError::InvalidLimit) => {}
+            other => panic!("Expected InvalidLimit, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Dead peer detection. Session expires only after `SESSION_DURATION`, so a
+//! device that dropped off the network keeps its server-side session for
+//! most of an hour. `LivenessMonitor` sends heartbeat every interval and
+//! counts heartbeats peer didn't acknowledge before the next one is due:
+//! after `suspect_after` of them peer is `Suspect`, after `dead_after` —
+//! `Dead`, and stays dead. Every change is reported to `LivenessHook`.
+//!
+//! Heartbeats are Control frames with op byte 11 (heartbeat) or 12 (ack)
+//! followed by u64 BigEndian sequence number. Both sides can run a monitor,
+//! and each side must answer heartbeats, so pass every Control frame to
+//! `read`. Library doesn't do IO, so call `poll` when `next_poll` says so
+//! and send what it returns.
+//!
+//! Sessions that don't need a monitor can `answer` heartbeats and check
+//! `EstablishedSession::is_alive` instead, pinging peer with
+//! `EstablishedSession::make_ping` when it's been quiet for a while.
+//! Facade `Connection` does its own IO, so it answers heartbeats by itself.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+use control::{HEARTBEAT, HEARTBEAT_ACK};
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use session::EstablishedSession;
+use sodiumoxide::crypto::box_::PublicKey;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const HEARTBEAT_SIZE: usize = 9;
+
+/// What monitor thinks about peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liveness {
+    /// Peer answers heartbeats.
+    Alive,
+    /// Peer missed a few heartbeats.
+    Suspect,
+    /// Peer missed too many heartbeats. Reap the session.
+    Dead,
+}
+
+/// Gets told when liveness of a peer changes.
+pub trait LivenessHook: Send + Sync {
+    /// Called on every change.
+    fn changed(&self, session_id: &PublicKey, liveness: Liveness);
+}
+
+impl<F: Fn(&PublicKey, Liveness) + Send + Sync> LivenessHook for F {
+    fn changed(&self, session_id: &PublicKey, liveness: Liveness) { self(session_id, liveness) }
+}
+
+type SharedLivenessHook = Arc<dyn LivenessHook>;
+
+/// How often to check and how much silence to tolerate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LivenessConfig {
+    /// Time between heartbeats. Also how long peer has to answer one.
+    pub interval: Duration,
+    /// Missed heartbeats before peer is `Suspect`.
+    pub suspect_after: u32,
+    /// Missed heartbeats before peer is `Dead`.
+    pub dead_after: u32,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> LivenessConfig {
+        LivenessConfig {
+            interval: Duration::from_secs(30),
+            suspect_after: 2,
+            dead_after: 4,
+        }
+    }
+}
+
+/// Liveness of one session.
+#[derive(DebugStub, Clone)]
+pub struct LivenessMonitor {
+    id: PublicKey,
+    config: LivenessConfig,
+    liveness: Liveness,
+    sequence: u64,
+    // Sequence of unanswered heartbeat.
+    awaiting: Option<u64>,
+    missed: u32,
+    next_poll: Instant,
+    #[debug_stub(some = "LivenessHook")]
+    hook: Option<SharedLivenessHook>,
+}
+
+impl LivenessMonitor {
+    /// Start monitoring. First heartbeat is due one interval from now.
+    pub fn new(session: &EstablishedSession, config: LivenessConfig) -> LivenessMonitor {
+        LivenessMonitor::starting_at(session, config, Instant::now())
+    }
+
+    fn starting_at(session: &EstablishedSession,
+                   config: LivenessConfig,
+                   now: Instant)
+                   -> LivenessMonitor {
+        LivenessMonitor {
+            id: session.info().id,
+            config,
+            liveness: Liveness::Alive,
+            sequence: 0,
+            awaiting: None,
+            missed: 0,
+            next_poll: now + config.interval,
+            hook: None,
+        }
+    }
+
+    /// Report changes to this hook. Replaces hook set earlier.
+    pub fn set_hook(&mut self, hook: Arc<dyn LivenessHook>) { self.hook = Some(hook); }
+
+    /// Current verdict.
+    pub fn liveness(&self) -> Liveness { self.liveness }
+
+    /// When `poll` has to be called next.
+    pub fn next_poll(&self) -> Instant { self.next_poll }
+
+    /// Count missed heartbeat if it's time and make next one. None if it's
+    /// too early or peer is dead.
+    pub fn poll(&mut self, session: &EstablishedSession) -> WhisperResult<Option<Frame>> {
+        self.poll_at(session, Instant::now())
+    }
+
+    fn poll_at(&mut self,
+               session: &EstablishedSession,
+               now: Instant)
+               -> WhisperResult<Option<Frame>> {
+        if self.liveness == Liveness::Dead || now < self.next_poll {
+            return Ok(None);
+        }
+        if self.awaiting.is_some() {
+            self.missed += 1;
+            if self.missed >= self.config.dead_after {
+                self.change(Liveness::Dead);
+                return Ok(None);
+            }
+            if self.missed >= self.config.suspect_after {
+                self.change(Liveness::Suspect);
+            }
+        }
+        self.sequence += 1;
+        let frame = heartbeat(session, HEARTBEAT, self.sequence)?;
+        self.awaiting = Some(self.sequence);
+        self.next_poll = now + self.config.interval;
+        Ok(Some(frame))
+    }
+
+    /// Handle incoming frame. Returns ack to send if peer asked for it.
+    /// Frames that aren't heartbeats are ignored, so every frame can be
+    /// passed through here.
+    pub fn read(&mut self,
+                session: &EstablishedSession,
+                frame: &Frame)
+                -> WhisperResult<Option<Frame>> {
+        let (op, sequence) = match read_heartbeat(session, frame)? {
+            Some(heartbeat) => heartbeat,
+            None => return Ok(None),
+        };
+        if op == HEARTBEAT {
+            return heartbeat(session, HEARTBEAT_ACK, sequence).map(Some);
+        }
+        // Late ack of older heartbeat proves peer is alive just as well.
+        if self.liveness != Liveness::Dead && sequence <= self.sequence {
+            self.awaiting = None;
+            self.missed = 0;
+            self.change(Liveness::Alive);
+        }
+        Ok(None)
+    }
+
+    fn change(&mut self, liveness: Liveness) {
+        if self.liveness == liveness {
+            return;
+        }
+        self.liveness = liveness;
+        if let Some(ref hook) = self.hook {
+            hook.changed(&self.id, liveness);
+        }
+    }
+}
+
+/// Heartbeat made outside of monitor. Its sequence number is zero.
+pub fn ping(session: &EstablishedSession) -> WhisperResult<Frame> {
+    heartbeat(session, HEARTBEAT, 0)
+}
+
+/// Answer heartbeat for session without monitor. Returns ack to send for
+/// heartbeat, acks are taken and dropped. Other frames are left for the
+/// next handler.
+pub fn answer(session: &EstablishedSession, frame: &Frame) -> WhisperResult<Option<Frame>> {
+    match read_heartbeat(session, frame)? {
+        Some((HEARTBEAT, sequence)) => heartbeat(session, HEARTBEAT_ACK, sequence).map(Some),
+        _ => Ok(None),
+    }
+}
+
+// `answer` for Control payload opened already. None if it isn't heartbeat
+// or ack, otherwise ack to send, if any.
+pub(crate) fn answer_payload(session: &EstablishedSession,
+                             payload: &[u8])
+                             -> WhisperResult<Option<Option<Frame>>> {
+    match parse(payload)? {
+        Some((HEARTBEAT, sequence)) => {
+            heartbeat(session, HEARTBEAT_ACK, sequence).map(|ack| Some(Some(ack)))
+        }
+        Some(_) => Ok(Some(None)),
+        None => Ok(None),
+    }
+}
+
+// Op and sequence of heartbeat or ack, None for other frames.
+fn read_heartbeat(session: &EstablishedSession, frame: &Frame) -> WhisperResult<Option<(u8, u64)>> {
+    if frame.kind != FrameKind::Control {
+        return Ok(None);
+    }
+    let payload = session.peek_msg(frame)?;
+    let heartbeat = parse(&payload)?;
+    if heartbeat.is_some() {
+        session.claim(frame)?;
+    }
+    Ok(heartbeat)
+}
+
+fn parse(payload: &[u8]) -> WhisperResult<Option<(u8, u64)>> {
+    match payload.first() {
+        Some(&HEARTBEAT) | Some(&HEARTBEAT_ACK) if payload.len() == HEARTBEAT_SIZE => {}
+        Some(&HEARTBEAT) | Some(&HEARTBEAT_ACK) => return Err(WhisperError::BadFrame),
+        _ => return Ok(None),
+    }
+    Ok(Some((payload[0], BigEndian::read_u64(&payload[1..]))))
+}
+
+fn heartbeat(session: &EstablishedSession, op: u8, sequence: u64) -> WhisperResult<Frame> {
+    let mut payload = BytesMut::with_capacity(HEARTBEAT_SIZE);
+    payload.put_u8(op);
+    payload.put_u64_be(sequence);
+    session.make_control(&payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{self, TimeZone, Utc};
+    use clock::MockClock;
+    use session::test::{configured_handshake, handshake};
+    use std::sync::Mutex;
+
+    #[test]
+    fn alive_suspect_dead() {
+        let (client, server) = handshake();
+        let start = Instant::now();
+        let mut monitor = LivenessMonitor::starting_at(&server, LivenessConfig::default(), start);
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let log = changes.clone();
+        monitor.set_hook(Arc::new(move |_: &PublicKey, liveness| {
+                                      log.lock().unwrap().push(liveness)
+                                  }));
+        let mut peer = LivenessMonitor::new(&client, LivenessConfig::default());
+        let interval = LivenessConfig::default().interval;
+
+        assert!(monitor.poll_at(&server, start).unwrap().is_none());
+        // Answered heartbeat.
+        let ping = monitor.poll_at(&server, start + interval).unwrap().unwrap();
+        let ack = peer.read(&client, &ping).unwrap().unwrap();
+        assert!(monitor.read(&server, &ack).unwrap().is_none());
+
+        // Peer went quiet.
+        let mut unanswered = Vec::new();
+        for i in 2..6 {
+            unanswered.extend(monitor.poll_at(&server, start + interval * i).unwrap());
+        }
+        assert_eq!(monitor.liveness(), Liveness::Suspect);
+        // Late ack brings it back.
+        let late = peer.read(&client, &unanswered[0]).unwrap().unwrap();
+        monitor.read(&server, &late).unwrap();
+        assert_eq!(monitor.liveness(), Liveness::Alive);
+
+        for i in 6..11 {
+            monitor.poll_at(&server, start + interval * i).unwrap();
+        }
+        assert_eq!(monitor.liveness(), Liveness::Dead);
+        assert!(monitor.poll_at(&server, start + interval * 20).unwrap().is_none());
+        assert_eq!(*changes.lock().unwrap(),
+                   vec![Liveness::Suspect, Liveness::Alive, Liveness::Suspect, Liveness::Dead]);
+    }
+
+    #[test]
+    fn ping_without_monitor() {
+        let clock = MockClock::new(Utc.timestamp_opt(1_000_000_000, 0).unwrap());
+        let (client, server) = configured_handshake(|client, server| {
+            client.set_clock(Arc::new(clock.clone()));
+            server.set_clock(Arc::new(clock.clone()));
+        });
+        let created = client.last_seen();
+        let timeout = chrono::Duration::seconds(5);
+        assert!(client.is_alive(timeout));
+        clock.advance(chrono::Duration::seconds(10));
+        assert!(!client.is_alive(timeout));
+        let ping = client.make_ping().unwrap();
+        let ack = answer(&server, &ping).unwrap().unwrap();
+        assert!(answer(&client, &ack).unwrap().is_none());
+        assert_eq!(client.last_seen(), created + chrono::Duration::seconds(10));
+        assert!(client.is_alive(timeout));
+
+        let notification = client.make_notification(b"hi").unwrap();
+        assert!(answer(&server, &notification).unwrap().is_none());
+        assert_eq!(server.read_msg(&notification).unwrap().as_ref(), b"hi");
+    }
+}
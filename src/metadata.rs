@@ -0,0 +1,129 @@
+//! Handshake metadata. Initiate (and Welcome) boxes can carry a list of
+//! tagged values after the mandatory fields. Each entry is encoded as:
+//! - Tag. 1 byte.
+//! - Length of value as u16 BigEndian. 2 bytes.
+//! - Value.
+//!
+//! Unknown tags are kept as is, so peers can add new entries without breaking
+//! old implementations.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use errors::{WhisperError, WhisperResult};
+
+/// Identity key validity. Value is `crypto::KeyValidity`.
+pub const VALIDITY: u8 = 1;
+/// Device attestation blob. See `attestation` module.
+pub const ATTESTATION: u8 = 2;
+/// OPAQUE login messages. See `opaque` module.
+pub const OPAQUE: u8 = 3;
+/// Transport mode declared by client. See `transport` module.
+pub const TRANSPORT: u8 = 4;
+/// Server clock in Welcome and client clock in Initiate, milliseconds
+/// since epoch as i64 BigEndian.
+pub const TIMESTAMP: u8 = 5;
+/// Compression offered by client in Initiate and accepted by server in
+/// Ready. Value is dictionary id as u32 BigEndian (zero for none) for
+/// streaming compression, or one byte algorithm flag for per payload
+/// compression. See `compression` module.
+pub const COMPRESSION: u8 = 6;
+/// Ticket from `Redirect` presented by client in Initiate. See `redirect`
+/// module.
+pub const TICKET: u8 = 7;
+/// Routing hint assigned by server in Ready. See `routing` module.
+pub const ROUTING: u8 = 8;
+/// Device certificate signed by master identity, sent by client in
+/// Initiate. See `devices` module.
+pub const DEVICE: u8 = 9;
+/// Cipher suite of established session offered by client in Initiate and
+/// accepted by server in Ready. Value is one byte, suite id. See `suite`
+/// module.
+pub const CIPHER_SUITE: u8 = 11;
+/// Ed25519 signature of handshake keys sent by client in Initiate. See
+/// `vouch` module.
+pub const SIGNED_VOUCH: u8 = 12;
+
+/// List of tagged values carried in handshake.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Metadata {
+    entries: Vec<(u8, Bytes)>,
+}
+
+impl Metadata {
+    /// Create empty metadata.
+    pub fn new() -> Metadata { Metadata::default() }
+
+    /// Set value for a tag. Replaces previous value.
+    pub fn insert<B: Into<Bytes>>(&mut self, tag: u8, value: B) {
+        let value = value.into();
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.0 == tag) {
+            entry.1 = value;
+            return;
+        }
+        self.entries.push((tag, value));
+    }
+
+    /// Get value of a tag.
+    pub fn get(&self, tag: u8) -> Option<&Bytes> {
+        self.entries.iter().find(|entry| entry.0 == tag).map(|entry| &entry.1)
+    }
+
+    /// Returns true if there is nothing to encode.
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    /// Encode entries to the buffer.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        for &(tag, ref value) in &self.entries {
+            buf.reserve(3 + value.len());
+            buf.put_u8(tag);
+            buf.put_u16_be(value.len() as u16);
+            buf.extend_from_slice(value);
+        }
+    }
+
+    /// Decode entries. Whole slice must be made of entries.
+    pub fn decode(mut i: &[u8]) -> WhisperResult<Metadata> {
+        let mut metadata = Metadata::new();
+        while !i.is_empty() {
+            if i.len() < 3 {
+                return Err(WhisperError::BadFrame);
+            }
+            let len = BigEndian::read_u16(&i[1..3]) as usize;
+            if i.len() < 3 + len {
+                return Err(WhisperError::BadFrame);
+            }
+            metadata.insert(i[0], Bytes::from(&i[3..3 + len]));
+            i = &i[3 + len..];
+        }
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        let mut metadata = Metadata::new();
+        metadata.insert(VALIDITY, vec![1, 2, 3]);
+        metadata.insert(42, vec![]);
+        metadata.insert(VALIDITY, vec![4]);
+
+        let mut buf = BytesMut::new();
+        metadata.encode(&mut buf);
+        assert_eq!(buf.as_ref(), &[1, 0, 1, 4, 42, 0, 0]);
+
+        let decoded = Metadata::decode(&buf).unwrap();
+        assert_eq!(decoded, metadata);
+        assert_eq!(decoded.get(VALIDITY).unwrap().as_ref(), &[4]);
+        assert!(decoded.get(7).is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(Metadata::decode(&[1, 0]).is_err());
+        assert!(Metadata::decode(&[1, 0, 2, 0]).is_err());
+        assert!(Metadata::decode(&[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+//! Aggregate counters for a server. `SessionMetrics` is fed by whatever
+//! owns the session table and can be read at any time with `snapshot`, so
+//! operators can chart fleet health without walking the table.
+//!
+//! It implements `AuditHook`, so it can be handed to `hardening::Hardening`
+//! to count handshake failures by reason.
+
+use errors::WhisperError;
+use hardening::AuditHook;
+use sodiumoxide::crypto::box_::PublicKey;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Point in time copy of the counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Sessions that finished handshake and weren't closed or evicted yet.
+    pub active_sessions: u64,
+    /// Handshakes that started but neither finished nor failed yet.
+    pub pending_handshakes: u64,
+    /// Handshakes started since creation.
+    pub handshakes_started: u64,
+    /// Handshakes that ended with established session.
+    pub handshakes_completed: u64,
+    /// Handshakes that failed.
+    pub handshakes_failed: u64,
+    /// Sessions closed by either side.
+    pub sessions_closed: u64,
+    /// Sessions and handshakes dropped because they expired.
+    pub evictions: u64,
+    /// Failed handshakes by error.
+    pub failure_reasons: BTreeMap<String, u64>,
+}
+
+/// Thread safe counters. Cheap to clone, clones share counters.
+#[derive(Debug, Clone, Default)]
+pub struct SessionMetrics {
+    inner: Arc<Mutex<MetricsSnapshot>>,
+}
+
+impl SessionMetrics {
+    /// Counters starting at zero.
+    pub fn new() -> SessionMetrics { SessionMetrics::default() }
+
+    fn update<F: FnOnce(&mut MetricsSnapshot)>(&self, f: F) {
+        f(&mut self.inner.lock().expect("Metrics lock poisoned"))
+    }
+
+    /// New handshake began (i.e. Hello arrived).
+    pub fn handshake_started(&self) {
+        self.update(|m| {
+                        m.handshakes_started += 1;
+                        m.pending_handshakes += 1;
+                    })
+    }
+
+    /// Handshake produced established session.
+    pub fn handshake_completed(&self) {
+        self.update(|m| {
+                        m.handshakes_completed += 1;
+                        m.pending_handshakes = m.pending_handshakes.saturating_sub(1);
+                        m.active_sessions += 1;
+                    })
+    }
+
+    /// Handshake failed.
+    pub fn handshake_failed(&self, error: &WhisperError) {
+        let reason = format!("{:?}", error);
+        self.update(|m| {
+                        m.handshakes_failed += 1;
+                        m.pending_handshakes = m.pending_handshakes.saturating_sub(1);
+                        *m.failure_reasons.entry(reason).or_insert(0) += 1;
+                    })
+    }
+
+    /// Established session was closed.
+    pub fn session_closed(&self) {
+        self.update(|m| {
+                        m.sessions_closed += 1;
+                        m.active_sessions = m.active_sessions.saturating_sub(1);
+                    })
+    }
+
+    /// Expired entry was dropped. `established` tells if it was a session
+    /// or a pending handshake.
+    pub fn evicted(&self, established: bool) {
+        self.update(|m| {
+                        m.evictions += 1;
+                        if established {
+                            m.active_sessions = m.active_sessions.saturating_sub(1);
+                        } else {
+                            m.pending_handshakes = m.pending_handshakes.saturating_sub(1);
+                        }
+                    })
+    }
+
+    /// Copy of current counters.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.inner.lock().expect("Metrics lock poisoned").clone()
+    }
+}
+
+impl AuditHook for SessionMetrics {
+    fn handshake_failed(&self, _: &PublicKey, error: &WhisperError) {
+        SessionMetrics::handshake_failed(self, error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counters_add_up() {
+        let metrics = SessionMetrics::new();
+        for _ in 0..5 {
+            metrics.handshake_started();
+        }
+        metrics.handshake_completed();
+        metrics.handshake_completed();
+        metrics.handshake_failed(&WhisperError::DecryptionFailed);
+        let hook: &dyn AuditHook = &metrics;
+        hook.handshake_failed(&PublicKey([0; 32]), &WhisperError::DecryptionFailed);
+        metrics.evicted(false);
+        metrics.session_closed();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.handshakes_started, 5);
+        assert_eq!(snapshot.pending_handshakes, 0);
+        assert_eq!(snapshot.active_sessions, 1);
+        assert_eq!(snapshot.handshakes_failed, 2);
+        assert_eq!(snapshot.evictions, 1);
+        assert_eq!(snapshot.failure_reasons.get("DecryptionFailed"), Some(&2));
+    }
+}
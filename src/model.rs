@@ -0,0 +1,497 @@
+//! Model-based test of the handshake and message rules. Client and server
+//! talk over simulated network (`sim`) that loses, duplicates, reorders and
+//! corrupts packets. Seed drives random interleaving of sends,
+//! retransmissions and deliveries, and after every step real sessions are
+//! checked against what the protocol allows:
+//! - Handshake: authentic frame takes exactly the step the model expects,
+//!   corrupted or stale one either changes nothing or fails the handshake.
+//! - Messages: nothing peer didn't send is delivered, each message is
+//!   delivered at most once, and authentic packet is only refused as a
+//!   replay.
+//! - Once both sides are established, they have the same secrets: each
+//!   opens what the other seals.
+//!
+//! Seed drives the network and the order of events only. Keys and nonces
+//! still come from libsodium, so packets differ between runs and a failing
+//! seed is a lead, not an exact reproduction.
+
+use bytes::Bytes;
+use crypto::KeyPair;
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use session::{ClientSession, EstablishedSession, ServerSession, SessionState};
+use sim::{LinkConfig, Network, SimRng};
+use sodiumoxide::crypto::box_::PublicKey;
+use std::collections::{HashMap, HashSet};
+use transport::TransportMode;
+
+// Ticks without progress before client sends last handshake frame again.
+const RETRANSMIT_AFTER: u32 = 6;
+// Ticks without progress before client gives up and starts over.
+const RESTART_AFTER: u32 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Handshake,
+    Established,
+    Failed,
+}
+
+fn phase(state: SessionState) -> Phase {
+    match state {
+        SessionState::Fresh | SessionState::Initiated => Phase::Handshake,
+        SessionState::Ready => Phase::Established,
+        _ => Phase::Failed,
+    }
+}
+
+// Real state must be one of the allowed ones. Model follows it.
+fn follow(model: &mut Phase, state: SessionState, allowed: &[Phase]) {
+    let real = phase(state);
+    assert!(allowed.contains(&real),
+            "{:?} after {:?}, only {:?} allowed",
+            real,
+            model,
+            allowed);
+    *model = real;
+}
+
+// `sent` is message of authentic packet, None for corrupted ones.
+fn check_delivery(result: WhisperResult<Bytes>,
+                  sent: Option<&Bytes>,
+                  peer_sent: &HashSet<Bytes>,
+                  got: &mut HashSet<Bytes>) {
+    match result {
+        Ok(msg) => {
+            assert!(peer_sent.contains(&msg), "Delivered message peer never sent");
+            assert!(got.insert(msg), "Message delivered twice");
+        }
+        Err(err) => {
+            if let Some(msg) = sent {
+                // Frame header isn't authenticated, so copy with corrupted
+                // kind can get in first. Payload can't be changed.
+                assert!(got.contains(msg), "Authentic message was refused: {:?}", err);
+                match err {
+                    WhisperError::ReplayedFrame => {}
+                    other => panic!("Expected ReplayedFrame, got {:?}", other),
+                }
+            }
+        }
+    }
+}
+
+struct Client {
+    identity: KeyPair,
+    server_key: PublicKey,
+    session: ClientSession,
+    model: Phase,
+    established: Option<EstablishedSession>,
+    last_sent: Bytes,
+    idle: u32,
+}
+
+impl Client {
+    fn new(identity: KeyPair, server_key: PublicKey) -> Client {
+        let mut session = ClientSession::new(identity.clone(), server_key).unwrap();
+        session.set_transport_mode(TransportMode::Datagram);
+        let last_sent = session.make_hello().unwrap().pack();
+        Client {
+            identity,
+            server_key,
+            session,
+            model: Phase::Handshake,
+            established: None,
+            last_sent,
+            idle: 0,
+        }
+    }
+
+    fn id(&self) -> PublicKey { self.session.info().id }
+}
+
+struct Handshake {
+    session: ServerSession,
+    model: Phase,
+    hello: Bytes,
+    welcome: Bytes,
+    ready: Option<Bytes>,
+}
+
+struct World {
+    rng: SimRng,
+    net: Network,
+    server_identity: KeyPair,
+    client: Client,
+    // Server side, by client session id.
+    handshakes: HashMap<PublicKey, Handshake>,
+    established: HashMap<PublicKey, EstablishedSession>,
+    // Every packet each side put on the wire, to tell corrupted ones apart.
+    from_client: HashSet<Bytes>,
+    from_server: HashSet<Bytes>,
+    // Message of each message packet.
+    messages: HashMap<Bytes, Bytes>,
+    client_sent: HashSet<Bytes>,
+    server_sent: HashSet<Bytes>,
+    client_got: HashSet<Bytes>,
+    server_got: HashSet<Bytes>,
+    next_message: u32,
+}
+
+impl World {
+    fn new(config: LinkConfig, seed: u64) -> World {
+        let server_identity = KeyPair::new().unwrap();
+        let client = Client::new(KeyPair::new().unwrap(), server_identity.public_key);
+        let mut world = World {
+            rng: SimRng::new(seed ^ 0x5DEE_CE66_D1CE_4E5B),
+            net: Network::new(config, seed),
+            server_identity,
+            client,
+            handshakes: HashMap::new(),
+            established: HashMap::new(),
+            from_client: HashSet::new(),
+            from_server: HashSet::new(),
+            messages: HashMap::new(),
+            client_sent: HashSet::new(),
+            server_sent: HashSet::new(),
+            client_got: HashSet::new(),
+            server_got: HashSet::new(),
+            next_message: 0,
+        };
+        let hello = world.client.last_sent.clone();
+        world.send_to_server(hello);
+        world
+    }
+
+    fn run(&mut self, steps: usize) {
+        for _ in 0..steps {
+            match self.rng.below(4) {
+                0 => self.client_sends(),
+                1 => self.server_sends(),
+                _ => self.tick(),
+            }
+            self.check_states();
+        }
+        self.check_secrets();
+    }
+
+    fn send_to_server(&mut self, packet: Bytes) {
+        self.from_client.insert(packet.clone());
+        self.net.to_server.send(packet);
+    }
+
+    fn send_to_client(&mut self, packet: Bytes) {
+        self.from_server.insert(packet.clone());
+        self.net.to_client.send(packet);
+    }
+
+    fn next_message(&mut self, side: &str) -> Bytes {
+        self.next_message += 1;
+        format!("{} {}", side, self.next_message).into()
+    }
+
+    fn tick(&mut self) {
+        for packet in self.net.to_server.tick() {
+            self.server_receives(packet);
+        }
+        for packet in self.net.to_client.tick() {
+            self.client_receives(packet);
+        }
+        self.client.idle += 1;
+        let stuck = self.client.model == Phase::Handshake && self.client.idle > RESTART_AFTER;
+        if self.client.model == Phase::Failed || stuck {
+            self.client = Client::new(self.client.identity.clone(), self.client.server_key);
+            let hello = self.client.last_sent.clone();
+            self.send_to_server(hello);
+        } else if self.client.model == Phase::Handshake && self.client.idle % RETRANSMIT_AFTER == 0 {
+            let packet = self.client.last_sent.clone();
+            self.send_to_server(packet);
+        }
+    }
+
+    fn client_sends(&mut self) {
+        if self.client.established.is_none() {
+            return;
+        }
+        let msg = self.next_message("client");
+        let packet = {
+            let session = self.client.established.as_ref().expect("Checked above");
+            session.pack(&session.make_notification(&msg).expect("Established session can send"))
+        };
+        self.client_sent.insert(msg.clone());
+        self.messages.insert(packet.clone(), msg);
+        self.send_to_server(packet);
+    }
+
+    fn server_sends(&mut self) {
+        let id = self.client.id();
+        if !self.established.contains_key(&id) {
+            return;
+        }
+        let msg = self.next_message("server");
+        let packet = {
+            let session = &self.established[&id];
+            session.pack(&session.make_notification(&msg).expect("Established session can send"))
+        };
+        self.server_sent.insert(msg.clone());
+        self.messages.insert(packet.clone(), msg);
+        self.send_to_client(packet);
+    }
+
+    fn server_receives(&mut self, packet: Bytes) {
+        let authentic = self.from_client.contains(&packet);
+        let frame = match Frame::from_slice(&packet) {
+            Ok(frame) => frame,
+            Err(_) => {
+                assert!(!authentic, "Authentic packet didn't parse");
+                return;
+            }
+        };
+        match frame.kind {
+            FrameKind::Hello => self.server_hello(&packet, &frame, authentic),
+            FrameKind::Initiate => self.server_initiate(&frame, authentic),
+            _ => {
+                let session = match self.established.get_mut(&frame.id) {
+                    Some(session) => session,
+                    None => {
+                        assert!(!authentic, "Message before session was established");
+                        return;
+                    }
+                };
+                let result = session.read_packet(&packet).map(|(_, msg)| msg);
+                check_delivery(result,
+                               self.messages.get(&packet),
+                               &self.client_sent,
+                               &mut self.server_got);
+            }
+        }
+    }
+
+    fn server_hello(&mut self, packet: &Bytes, hello: &Frame, authentic: bool) {
+        let reply = match self.handshakes.get_mut(&hello.id) {
+            Some(handshake) => {
+                assert!(handshake.session.make_welcome(hello).is_err(), "Second Hello was accepted");
+                let before = handshake.model;
+                follow(&mut handshake.model, handshake.session.info().state, &[before]);
+                // Retransmitted Hello gets the same Welcome.
+                if handshake.hello == *packet {
+                    Some(handshake.welcome.clone())
+                } else {
+                    None
+                }
+            }
+            None => {
+                let mut session =
+                    ServerSession::new(self.server_identity.clone(), hello.id).unwrap();
+                match session.make_welcome(hello) {
+                    Ok(welcome) => {
+                        let mut model = Phase::Handshake;
+                        follow(&mut model, session.info().state, &[Phase::Handshake]);
+                        let welcome = welcome.pack();
+                        self.handshakes.insert(hello.id,
+                                               Handshake {
+                                                   session,
+                                                   model,
+                                                   hello: packet.clone(),
+                                                   welcome: welcome.clone(),
+                                                   ready: None,
+                                               });
+                        Some(welcome)
+                    }
+                    Err(err) => {
+                        assert!(!authentic, "Authentic Hello was refused: {:?}", err);
+                        None
+                    }
+                }
+            }
+        };
+        if let Some(welcome) = reply {
+            self.send_to_client(welcome);
+        }
+    }
+
+    fn server_initiate(&mut self, initiate: &Frame, authentic: bool) {
+        let reply = {
+            let handshake = match self.handshakes.get_mut(&initiate.id) {
+                Some(handshake) => handshake,
+                None => {
+                    assert!(!authentic, "Initiate without Hello");
+                    return;
+                }
+            };
+            let before = handshake.model;
+            if before == Phase::Handshake {
+                let result = handshake.session
+                                      .validate_initiate(initiate)
+                                      .and_then(|key| handshake.session.make_ready(initiate, &key));
+                match result {
+                    Ok((session, ready)) => {
+                        follow(&mut handshake.model,
+                               handshake.session.info().state,
+                               &[Phase::Established]);
+                        let ready = ready.pack();
+                        handshake.ready = Some(ready.clone());
+                        self.established.insert(initiate.id, session);
+                        Some(ready)
+                    }
+                    Err(err) => {
+                        assert!(!authentic, "Authentic Initiate was refused: {:?}", err);
+                        follow(&mut handshake.model,
+                               handshake.session.info().state,
+                               &[before, Phase::Failed]);
+                        None
+                    }
+                }
+            } else {
+                // Any valid Initiate is a retransmission: client may have
+                // made several of them from duplicated Welcomes.
+                let valid = match handshake.session.validate_initiate(initiate) {
+                    Ok(key) => {
+                        assert!(handshake.session.make_ready(initiate, &key).is_err(),
+                                "Second Ready was made");
+                        true
+                    }
+                    Err(_) => false,
+                };
+                follow(&mut handshake.model, handshake.session.info().state, &[before]);
+                if valid { handshake.ready.clone() } else { None }
+            }
+        };
+        if self.handshakes[&initiate.id].model == Phase::Failed {
+            self.handshakes.remove(&initiate.id);
+        }
+        if let Some(ready) = reply {
+            self.send_to_client(ready);
+        }
+    }
+
+    fn client_receives(&mut self, packet: Bytes) {
+        let authentic = self.from_server.contains(&packet);
+        let frame = match Frame::from_slice(&packet) {
+            Ok(frame) => frame,
+            Err(_) => {
+                assert!(!authentic, "Authentic packet didn't parse");
+                return;
+            }
+        };
+        match frame.kind {
+            FrameKind::Welcome | FrameKind::Ready => {
+                // Frames of session client gave up on are just noise now.
+                let current = frame.id == self.client.id();
+                self.client_handshake(&frame, authentic && current);
+            }
+            _ => {
+                let current = self.client.session.server_session_key() == Some(frame.id);
+                let sent = if authentic && current { self.messages.get(&packet) } else { None };
+                // Ready may be lost while messages get through.
+                let session = match self.client.established {
+                    Some(ref mut session) => session,
+                    None => return,
+                };
+                let result = session.read_packet(&packet).map(|(_, msg)| msg);
+                check_delivery(result, sent, &self.server_sent, &mut self.client_got);
+            }
+        }
+    }
+
+    fn client_handshake(&mut self, frame: &Frame, authentic: bool) {
+        let before = self.client.model;
+        let result = if frame.kind == FrameKind::Welcome {
+            self.client.session.make_initiate(frame).map(|initiate| Some(initiate.pack()))
+        } else {
+            match self.client.session.read_ready(frame) {
+                Ok(session) => {
+                    self.client.established = Some(session);
+                    Ok(None)
+                }
+                Err(err) => Err(err),
+            }
+        };
+        if authentic {
+            assert_eq!(result.is_ok(),
+                       before == Phase::Handshake,
+                       "Authentic {:?} in {:?}: {:?}",
+                       frame.kind,
+                       before,
+                       result);
+        }
+        let allowed = match result {
+            Ok(_) if frame.kind == FrameKind::Welcome => vec![Phase::Handshake],
+            Ok(_) => vec![Phase::Established],
+            Err(_) if authentic => vec![before],
+            Err(_) => vec![before, Phase::Failed],
+        };
+        follow(&mut self.client.model, self.client.session.info().state, &allowed);
+        if let Ok(initiate) = result {
+            self.client.idle = 0;
+            if let Some(initiate) = initiate {
+                self.client.last_sent = initiate.clone();
+                self.send_to_server(initiate);
+            }
+        }
+    }
+
+    fn check_states(&self) {
+        assert_eq!(phase(self.client.session.info().state), self.client.model);
+        assert_eq!(self.client.established.is_some(),
+                   self.client.model == Phase::Established);
+        for handshake in self.handshakes.values() {
+            assert_eq!(phase(handshake.session.info().state), handshake.model);
+        }
+    }
+
+    // Both sides derived the same secrets.
+    fn check_secrets(&self) {
+        let server = match self.established.get(&self.client.id()) {
+            Some(server) => server,
+            None => return,
+        };
+        if let Some(ref client) = self.client.established {
+            let ping = client.make_notification(b"ping").unwrap();
+            assert_eq!(server.read_msg(&ping).unwrap().as_ref(), b"ping");
+            let pong = server.make_notification(b"pong").unwrap();
+            assert_eq!(client.read_msg(&pong).unwrap().as_ref(), b"pong");
+        }
+    }
+
+    fn is_established(&self) -> bool {
+        self.client.established.is_some() && self.established.contains_key(&self.client.id())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn invariants_hold_on_bad_network() {
+        // With `null-cipher` nothing authenticates payloads, so corrupted
+        // messages would be delivered.
+        let config = LinkConfig {
+            drop: 0.2,
+            duplicate: 0.2,
+            corrupt: if cfg!(feature = "null-cipher") { 0.0 } else { 0.05 },
+            latency: 1,
+            jitter: 3,
+        };
+        for seed in 0..32 {
+            World::new(config.clone(), seed).run(600);
+        }
+    }
+
+    #[test]
+    fn handshake_completes_despite_loss() {
+        let config = LinkConfig {
+            drop: 0.2,
+            duplicate: 0.2,
+            corrupt: 0.0,
+            latency: 1,
+            jitter: 4,
+        };
+        for seed in 0..32 {
+            let mut world = World::new(config.clone(), seed);
+            world.run(2000);
+            assert!(world.is_established(), "Seed {} didn't finish handshake", seed);
+            assert!(!world.server_got.is_empty() && !world.client_got.is_empty());
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Blocking `std::net` (and Unix socket) helpers for tools that just want
+//! to talk to a server: connect, do handshake, get `Connection`. No async
+//! runtime needed.
+//!
+//! Every socket gets read and write timeouts from `Timeouts` before the
+//! handshake starts, and keeps them afterwards. Operation that runs out of
+//! time fails with `WhisperError::Io` of kind `WouldBlock` or `TimedOut`
+//! (depends on platform); `io_kind` of such error is never `InvalidData`.
+
+use errors::{WhisperError, WhisperResult};
+use facade::{Client, Connection, Server};
+use std::io;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
+
+/// Socket timeouts. None waits forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Establishing TCP connection. Not used for Unix sockets.
+    pub connect: Option<Duration>,
+    /// Each read from the socket.
+    pub read: Option<Duration>,
+    /// Each write to the socket.
+    pub write: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: Some(Duration::from_secs(10)),
+            read: Some(Duration::from_secs(30)),
+            write: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Connect to server over TCP and do handshake. Addresses are tried in
+/// order, error of the last one is returned.
+pub fn connect_tcp<A: ToSocketAddrs>(client: &Client,
+                                     addr: A,
+                                     timeouts: Timeouts)
+                                     -> WhisperResult<Connection<TcpStream>> {
+    let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "No addresses to connect to");
+    for addr in addr.to_socket_addrs()? {
+        let stream = match timeouts.connect {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match stream {
+            Ok(stream) => {
+                prepare_tcp(&stream, timeouts)?;
+                return client.connect(stream);
+            }
+            Err(err) => last_err = err,
+        }
+    }
+    Err(WhisperError::Io(last_err))
+}
+
+/// Accept next client on the listener and do handshake. Only waiting for
+/// the client to show up isn't limited by timeouts.
+pub fn accept_tcp(server: &Server,
+                  listener: &TcpListener,
+                  timeouts: Timeouts)
+                  -> WhisperResult<Connection<TcpStream>> {
+    let (stream, _) = listener.accept()?;
+    prepare_tcp(&stream, timeouts)?;
+    server.accept(stream)
+}
+
+/// Connect to server listening on Unix socket and do handshake.
+#[cfg(unix)]
+pub fn connect_unix<P: AsRef<Path>>(client: &Client,
+                                    path: P,
+                                    timeouts: Timeouts)
+                                    -> WhisperResult<Connection<UnixStream>> {
+    let stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(timeouts.read)?;
+    stream.set_write_timeout(timeouts.write)?;
+    client.connect(stream)
+}
+
+/// Accept next client on Unix socket and do handshake.
+#[cfg(unix)]
+pub fn accept_unix(server: &Server,
+                   listener: &UnixListener,
+                   timeouts: Timeouts)
+                   -> WhisperResult<Connection<UnixStream>> {
+    let (stream, _) = listener.accept()?;
+    stream.set_read_timeout(timeouts.read)?;
+    stream.set_write_timeout(timeouts.write)?;
+    server.accept(stream)
+}
+
+// Frames are small and latency matters more than packet count.
+fn prepare_tcp(stream: &TcpStream, timeouts: Timeouts) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(timeouts.read)?;
+    stream.set_write_timeout(timeouts.write)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use frame::FrameKind;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn tcp_ping_and_timeout() {
+        let server = Server::generate().unwrap();
+        let client = Client::generate(server.public_key()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (done, wait) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut connection = accept_tcp(&server, &listener, Timeouts::default()).unwrap();
+            let (kind, data) = connection.recv().unwrap().unwrap();
+            assert_eq!(kind, FrameKind::Request);
+            assert_eq!(data.as_ref(), b"ping");
+            connection.respond(b"pong").unwrap();
+            // Keep connection open without answering, so client times out.
+            wait.recv().unwrap();
+        });
+
+        let timeouts = Timeouts {
+            read: Some(Duration::from_millis(200)),
+            ..Timeouts::default()
+        };
+        let mut connection = connect_tcp(&client, addr, timeouts).unwrap();
+        assert_eq!(connection.request(b"ping").unwrap().as_ref(), b"pong");
+        match connection.request(b"anyone?") {
+            Err(WhisperError::Io(ref err)) if err.kind() == io::ErrorKind::WouldBlock ||
+                                              err.kind() == io::ErrorKind::TimedOut => {}
+            other => panic!("Expected timeout, got {:?}", other),
+        }
+        done.send(()).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_ping() {
+        let server = Server::generate().unwrap();
+        let client = Client::generate(server.public_key()).unwrap();
+        let path = ::std::env::temp_dir().join(format!("whisper-{}.sock", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let handle = thread::spawn(move || {
+            let mut connection = accept_unix(&server, &listener, Timeouts::default()).unwrap();
+            let (_, data) = connection.recv().unwrap().unwrap();
+            connection.respond(&data).unwrap();
+        });
+        let mut connection = connect_unix(&client, &path, Timeouts::default()).unwrap();
+        assert_eq!(connection.request(b"echo").unwrap().as_ref(), b"echo");
+        handle.join().unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+    }
+}
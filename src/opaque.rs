@@ -0,0 +1,269 @@
+//! OPAQUE password authenticated sessions. Client proves knowledge of a
+//! password without ever sending it to the server, and the result is bound
+//! into the session secret. Meant for user-facing services that can't
+//! distribute client keys ahead of time.
+//!
+//! ### Registration
+//! Done once, over an already secure channel:
+//! 1. Client: `PasswordRegistration::start` and send request.
+//! 2. Server: `registration_response` and send response.
+//! 3. Client: `PasswordRegistration::finish` and send upload.
+//! 4. Server: `registration_finish` and store returned password file.
+//!
+//! ### Login
+//! Piggybacks on the handshake:
+//! 1. Client: `PasswordLogin::start`, attach request to Initiate with
+//!    `ClientSession::set_initiate_metadata(metadata::OPAQUE, ..)`.
+//! 2. Server: `PasswordLoginServer::start` with request from
+//!    `ServerSession::initiate_metadata`, attach response to Ready with
+//!    `ServerSession::set_ready_metadata(metadata::OPAQUE, ..)`.
+//! 3. Client: `PasswordLogin::finish` with response from
+//!    `ClientSession::ready_metadata`. Send returned frame — it must be the
+//!    first frame of the session.
+//! 4. Server: `PasswordLoginServer::finish` with that frame.
+//!
+//! After that both sessions are bound to the password. Session secret of a
+//! client that used wrong password doesn't match the server's one.
+
+use argon2::Argon2;
+use crypto;
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use opaque_ke::{ClientLogin, ClientLoginFinishParameters, ClientRegistration,
+                ClientRegistrationFinishParameters, CredentialFinalization, CredentialRequest,
+                CredentialResponse, RegistrationRequest, RegistrationResponse, RegistrationUpload,
+                ServerLogin, ServerLoginStartParameters, ServerRegistration, ServerSetup};
+use opaque_ke::ciphersuite::CipherSuite;
+use opaque_ke::rand::{CryptoRng, Error, RngCore};
+use session::EstablishedSession;
+use sodiumoxide::randombytes::randombytes_into;
+
+/// Cipher suite used by this library.
+pub struct Suite;
+
+impl CipherSuite for Suite {
+    type OprfCs = opaque_ke::Ristretto255;
+    type KeGroup = opaque_ke::Ristretto255;
+    type KeyExchange = opaque_ke::key_exchange::tripledh::TripleDh;
+    type Ksf = Argon2<'static>;
+}
+
+// libsodium as randomness source, so there is no second one. Made by `rng`
+// only, so libsodium is initialized by the time it's used.
+struct SodiumRng;
+
+fn rng() -> WhisperResult<SodiumRng> {
+    crypto::init()?;
+    Ok(SodiumRng)
+}
+
+impl RngCore for SodiumRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut buf = [0; 4];
+        self.fill_bytes(&mut buf);
+        u32::from_le_bytes(buf)
+    }
+    fn next_u64(&mut self) -> u64 {
+        let mut buf = [0; 8];
+        self.fill_bytes(&mut buf);
+        u64::from_le_bytes(buf)
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) { randombytes_into(dest); }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for SodiumRng {}
+
+fn failed<E>(_: E) -> WhisperError { WhisperError::PasswordAuthFailed }
+
+/// Server long term OPAQUE setup. Must be persisted: password files are
+/// useless without it.
+pub struct PasswordServerSetup(ServerSetup<Suite>);
+
+impl PasswordServerSetup {
+    /// Generate new setup. Fails with `InitializationFailed` if libsodium
+    /// can't be initialized.
+    pub fn new() -> WhisperResult<PasswordServerSetup> {
+        Ok(PasswordServerSetup(ServerSetup::new(&mut rng()?)))
+    }
+
+    /// Serialize setup for storage.
+    pub fn to_bytes(&self) -> Vec<u8> { self.0.serialize().to_vec() }
+
+    /// Restore setup from storage.
+    pub fn from_bytes(bytes: &[u8]) -> WhisperResult<PasswordServerSetup> {
+        ServerSetup::deserialize(bytes).map(PasswordServerSetup).map_err(failed)
+    }
+}
+
+/// Client side of registration.
+pub struct PasswordRegistration(ClientRegistration<Suite>);
+
+impl PasswordRegistration {
+    /// Start registration. Returns request for the server.
+    pub fn start(password: &[u8]) -> WhisperResult<(PasswordRegistration, Vec<u8>)> {
+        let result = ClientRegistration::<Suite>::start(&mut rng()?, password).map_err(failed)?;
+        Ok((PasswordRegistration(result.state), result.message.serialize().to_vec()))
+    }
+
+    /// Finish registration with server's response. Returns upload for the
+    /// server.
+    pub fn finish(self, password: &[u8], response: &[u8]) -> WhisperResult<Vec<u8>> {
+        let response = RegistrationResponse::deserialize(response).map_err(failed)?;
+        let result = self.0
+                         .finish(&mut rng()?,
+                                 password,
+                                 response,
+                                 ClientRegistrationFinishParameters::default())
+                         .map_err(failed)?;
+        Ok(result.message.serialize().to_vec())
+    }
+}
+
+/// Server side of registration. Reply to client's registration request.
+/// `username` is whatever identifies the account.
+pub fn registration_response(setup: &PasswordServerSetup,
+                             request: &[u8],
+                             username: &[u8])
+                             -> WhisperResult<Vec<u8>> {
+    let request = RegistrationRequest::deserialize(request).map_err(failed)?;
+    let result = ServerRegistration::<Suite>::start(&setup.0, request, username).map_err(failed)?;
+    Ok(result.message.serialize().to_vec())
+}
+
+/// Server side of registration. Turns client's upload into password file.
+pub fn registration_finish(upload: &[u8]) -> WhisperResult<Vec<u8>> {
+    let upload = RegistrationUpload::<Suite>::deserialize(upload).map_err(failed)?;
+    Ok(ServerRegistration::finish(upload).serialize().to_vec())
+}
+
+/// Client side of login.
+pub struct PasswordLogin(ClientLogin<Suite>);
+
+impl PasswordLogin {
+    /// Start login. Returns request to attach to Initiate.
+    pub fn start(password: &[u8]) -> WhisperResult<(PasswordLogin, Vec<u8>)> {
+        let result = ClientLogin::<Suite>::start(&mut rng()?, password).map_err(failed)?;
+        Ok((PasswordLogin(result.state), result.message.serialize().to_vec()))
+    }
+
+    /// Finish login with response from Ready. Binds session to the password
+    /// and returns frame that must be sent to the server.
+    pub fn finish(self,
+                  session: &mut EstablishedSession,
+                  password: &[u8],
+                  response: &[u8])
+                  -> WhisperResult<Frame> {
+        let response = CredentialResponse::deserialize(response).map_err(failed)?;
+        let result = self.0
+                         .finish(password, response, ClientLoginFinishParameters::default())
+                         .map_err(failed)?;
+        // Sent with the old secret: server needs it to compute the new one.
+        let frame = session.make_notification(&result.message.serialize())?;
+        session.bind_secret(&result.session_key);
+        Ok(frame)
+    }
+}
+
+/// Server side of login.
+pub struct PasswordLoginServer(ServerLogin<Suite>);
+
+impl PasswordLoginServer {
+    /// Start login with request from Initiate. Pass `None` as password file
+    /// for unknown users — it produces indistinguishable response, so users
+    /// can't be enumerated. Returns response to attach to Ready.
+    pub fn start(setup: &PasswordServerSetup,
+                 password_file: Option<&[u8]>,
+                 request: &[u8],
+                 username: &[u8])
+                 -> WhisperResult<(PasswordLoginServer, Vec<u8>)> {
+        let password_file = match password_file {
+            Some(file) => Some(ServerRegistration::deserialize(file).map_err(failed)?),
+            None => None,
+        };
+        let request = CredentialRequest::deserialize(request).map_err(failed)?;
+        let result = ServerLogin::start(&mut rng()?,
+                                        &setup.0,
+                                        password_file,
+                                        request,
+                                        username,
+                                        ServerLoginStartParameters::default())
+                .map_err(failed)?;
+        Ok((PasswordLoginServer(result.state), result.message.serialize().to_vec()))
+    }
+
+    /// Finish login with first frame client sent. Binds session to the
+    /// password.
+    pub fn finish(self, session: &mut EstablishedSession, frame: &Frame) -> WhisperResult<()> {
+        if frame.kind != FrameKind::Notification {
+            return Err(WhisperError::PasswordAuthFailed);
+        }
+        let payload = session.read_msg(frame)?;
+        let finalization = CredentialFinalization::deserialize(&payload).map_err(failed)?;
+        let result = self.0.finish(finalization).map_err(failed)?;
+        session.bind_secret(&result.session_key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::KeyPair;
+    use metadata;
+    use session::{ClientSession, ServerSession};
+
+    fn register(setup: &PasswordServerSetup, password: &[u8]) -> Vec<u8> {
+        let (registration, request) = PasswordRegistration::start(password).unwrap();
+        let response = registration_response(setup, &request, b"alice").unwrap();
+        let upload = registration.finish(password, &response).unwrap();
+        registration_finish(&upload).unwrap()
+    }
+
+    fn login(setup: &PasswordServerSetup, password_file: &[u8], password: &[u8]) -> bool {
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                    server_identity_keypair.public_key).unwrap();
+        let (login, request) = PasswordLogin::start(password).unwrap();
+        client_session.set_initiate_metadata(metadata::OPAQUE, request);
+
+        let hello_frame = client_session.make_hello().unwrap();
+        let mut server_session =
+            ServerSession::new(server_identity_keypair, hello_frame.id).unwrap();
+        let welcome_frame = server_session.make_welcome(&hello_frame).unwrap();
+        let initiate_frame = client_session.make_initiate(&welcome_frame).unwrap();
+        let client_identity_key = server_session.validate_initiate(&initiate_frame).unwrap();
+
+        let initiate_metadata = server_session.initiate_metadata(&initiate_frame).unwrap();
+        let request = initiate_metadata.get(metadata::OPAQUE).unwrap();
+        let (server_login, response) =
+            PasswordLoginServer::start(setup, Some(password_file), request, b"alice").unwrap();
+        server_session.set_ready_metadata(metadata::OPAQUE, response);
+        let (mut server, ready_frame) = server_session.make_ready(&initiate_frame, &client_identity_key)
+                                                      .unwrap();
+        let mut client = client_session.read_ready(&ready_frame).unwrap();
+
+        let response = client_session.ready_metadata().get(metadata::OPAQUE).unwrap().clone();
+        let finalization = match login.finish(&mut client, password, &response) {
+            Ok(frame) => frame,
+            Err(_) => return false,
+        };
+        if server_login.finish(&mut server, &finalization).is_err() {
+            return false;
+        }
+        let ping = client.make_request(b"ping").unwrap();
+        server.read_msg(&ping).is_ok()
+    }
+
+    #[test]
+    fn register_and_login() {
+        let setup = PasswordServerSetup::new().unwrap();
+        let setup = PasswordServerSetup::from_bytes(&setup.to_bytes()).unwrap();
+        let password_file = register(&setup, b"hunter2");
+        assert!(login(&setup, &password_file, b"hunter2"));
+        assert!(!login(&setup, &password_file, b"hunter3"));
+    }
+}
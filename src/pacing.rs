@@ -0,0 +1,164 @@
+//! Outbound pacing. Token buckets on frames and bytes cap how fast a session
+//! can send, e.g. so gateway doesn't push device's uplink past carrier
+//! limits. Set `Pacer` with `EstablishedSession::set_pacer`; it's consulted
+//! whenever a message frame is made and shared by all clones and halves.
+//!
+//! Frame that doesn't fit is not made at all: `make_*` fails with
+//! `RateLimited` telling how long to wait before trying again. Waiting is
+//! up to you, `facade` hands the error back too.
+
+use errors::{WhisperError, WhisperResult};
+use frame::HEADER_SIZE;
+use sodiumoxide::crypto::box_::MACBYTES;
+use std::time::{Duration, Instant};
+
+/// Refills at `rate` tokens per second up to `burst`.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Full bucket. Fails with `InvalidLimit` if rate is zero: such
+    /// bucket would never refill.
+    pub fn new(rate: u64, burst: u64) -> WhisperResult<TokenBucket> {
+        if rate == 0 {
+            return Err(WhisperError::InvalidLimit);
+        }
+        Ok(TokenBucket {
+               rate: rate as f64,
+               burst: burst as f64,
+               tokens: burst as f64,
+               refilled_at: Instant::now(),
+           })
+    }
+
+    /// How long until `amount` tokens are available. Zero if they are now.
+    /// Amount larger than burst only has to wait for full bucket and then
+    /// leaves it in debt.
+    pub(crate) fn wait(&mut self, amount: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.refilled_at = now;
+        let missing = (amount as f64).min(self.burst) - self.tokens;
+        if missing > 0.0 {
+            Duration::from_secs_f64(missing / self.rate)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    pub(crate) fn take(&mut self, amount: u64) { self.tokens -= amount as f64; }
+}
+
+/// Limits on frames and bytes of one session. Unlimited unless told
+/// otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct Pacer {
+    frames: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Pacer {
+    /// Pacer without limits.
+    pub fn new() -> Pacer { Pacer::default() }
+
+    /// Allow `rate` frames per second with bursts of up to `burst` frames.
+    /// Fails with `InvalidLimit` if rate is zero.
+    pub fn limit_frames(&mut self, rate: u64, burst: u64) -> WhisperResult<()> {
+        self.frames = Some(TokenBucket::new(rate, burst)?);
+        Ok(())
+    }
+
+    /// Allow `rate` bytes per second with bursts of up to `burst` bytes.
+    /// Counts whole frames, headers included. Fails with `InvalidLimit` if
+    /// rate is zero.
+    pub fn limit_bytes(&mut self, rate: u64, burst: u64) -> WhisperResult<()> {
+        self.bytes = Some(TokenBucket::new(rate, burst)?);
+        Ok(())
+    }
+
+    /// Account for frame of `length` bytes. Err with time to wait if it
+    /// doesn't fit; nothing is taken then.
+    pub fn check(&mut self, length: usize) -> Result<(), Duration> {
+        self.check_at(length, Instant::now())
+    }
+
+    /// Same as `check` for frame carrying payload of this size.
+    pub fn check_payload(&mut self, payload: usize) -> Result<(), Duration> {
+        self.check(HEADER_SIZE + MACBYTES + payload)
+    }
+
+    fn check_at(&mut self, length: usize, now: Instant) -> Result<(), Duration> {
+        let mut wait = Duration::from_secs(0);
+        if let Some(ref mut frames) = self.frames {
+            wait = wait.max(frames.wait(1, now));
+        }
+        if let Some(ref mut bytes) = self.bytes {
+            wait = wait.max(bytes.wait(length as u64, now));
+        }
+        if wait > Duration::from_secs(0) {
+            return Err(wait);
+        }
+        if let Some(ref mut frames) = self.frames {
+            frames.take(1);
+        }
+        if let Some(ref mut bytes) = self.bytes {
+            bytes.take(length as u64);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn millis(wait: Result<(), Duration>) -> u64 {
+        (wait.unwrap_err().as_secs_f64() * 1000.0).round() as u64
+    }
+
+    #[test]
+    fn frames_and_bytes() {
+        let mut pacer = Pacer::new();
+        pacer.limit_frames(10, 2).unwrap();
+        pacer.limit_bytes(1000, 1000).unwrap();
+        let start = Instant::now();
+        assert!(pacer.check_at(100, start).is_ok());
+        assert!(pacer.check_at(100, start).is_ok());
+        // Out of frames, next one in 100ms.
+        assert_eq!(millis(pacer.check_at(100, start)), 100);
+        let later = start + Duration::from_millis(100);
+        assert!(pacer.check_at(100, later).is_ok());
+        let much_later = later + Duration::from_secs(1);
+        assert!(pacer.check_at(700, much_later).is_ok());
+        // 300 bytes left, 1000 needed.
+        assert_eq!(millis(pacer.check_at(1000, much_later)), 700);
+        // Oversized frame waits for full bucket and goes into debt.
+        let full = much_later + Duration::from_millis(700);
+        assert!(pacer.check_at(5000, full).is_ok());
+        assert!(pacer.check_at(1, full + Duration::from_secs(3)).is_err());
+    }
+
+    #[test]
+    fn zero_rate() {
+        let mut pacer = Pacer::new();
+        match pacer.limit_frames(0, 10) {
+            Err(WhisperError::InvalidLimit) => {}
+            other => panic!("Expected InvalidLimit, got {:?}", other),
+        }
+        assert!(pacer.limit_bytes(0, 10).is_err());
+        assert!(pacer.check(1 << 20).is_ok());
+    }
+
+    #[test]
+    fn unlimited() {
+        let mut pacer = Pacer::new();
+        for _ in 0..1000 {
+            assert!(pacer.check(1 << 20).is_ok());
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! PIN based pairing for devices that don't know each other's public keys
+//! yet, e.g. a phone and a headless device with a code printed on the box.
+//!
+//! Both sides run SPAKE2 keyed with the PIN, then exchange identity public
+//! keys sealed with the resulting key. An attacker who doesn't know the PIN
+//! gets one online guess per pairing attempt.
+//!
+//! ### Flow
+//! 1. Both sides call `Pairing::start` and send returned message to the
+//!    other side.
+//! 2. Both sides call `finish` with received message and send returned
+//!    confirmation.
+//! 3. Both sides call `read_confirmation` and learn the other's identity key.
+//!
+//! Messages are not frames, they can be carried by anything.
+
+use errors::{WhisperError, WhisperResult};
+use sodiumoxide::crypto::box_::PublicKey;
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::secretbox;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+/// Side of pairing. Two sides must have different roles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingRole {
+    /// Side that starts pairing, usually a phone.
+    Initiator,
+    /// Side that waits for pairing, usually a device with PIN printed on it.
+    Responder,
+}
+
+static INITIATOR_ID: &[u8] = b"angel-whisper pairing initiator";
+static RESPONDER_ID: &[u8] = b"angel-whisper pairing responder";
+
+/// One side of pairing exchange.
+pub struct Pairing {
+    role: PairingRole,
+    identity_key: PublicKey,
+    spake: Option<Spake2<Ed25519Group>>,
+    // (our key, their key)
+    keys: Option<(secretbox::Key, secretbox::Key)>,
+}
+
+impl Pairing {
+    /// Start pairing. Returns message that must be sent to the other side.
+    pub fn start(role: PairingRole, pin: &[u8], identity_key: PublicKey) -> (Pairing, Vec<u8>) {
+        let password = Password::new(pin);
+        let initiator = Identity::new(INITIATOR_ID);
+        let responder = Identity::new(RESPONDER_ID);
+        let (spake, msg) = match role {
+            PairingRole::Initiator => Spake2::<Ed25519Group>::start_a(&password, &initiator, &responder),
+            PairingRole::Responder => Spake2::<Ed25519Group>::start_b(&password, &initiator, &responder),
+        };
+        let pairing = Pairing {
+            role,
+            identity_key,
+            spake: Some(spake),
+            keys: None,
+        };
+        (pairing, msg)
+    }
+
+    /// Process message from the other side. Returns confirmation — our
+    /// identity key sealed with shared key — that must be sent to the other
+    /// side.
+    pub fn finish(&mut self, msg: &[u8]) -> WhisperResult<Vec<u8>> {
+        let spake = self.spake.take().ok_or(WhisperError::InvalidSessionState)?;
+        let shared = spake.finish(msg).map_err(|_| WhisperError::PairingFailed)?;
+        // Different key per direction, so confirmation can't be reflected.
+        let initiator_key = direction_key(&shared, INITIATOR_ID);
+        let responder_key = direction_key(&shared, RESPONDER_ID);
+        let keys = match self.role {
+            PairingRole::Initiator => (initiator_key, responder_key),
+            PairingRole::Responder => (responder_key, initiator_key),
+        };
+        let nonce = secretbox::gen_nonce();
+        let mut confirmation = Vec::with_capacity(secretbox::NONCEBYTES + 32 + secretbox::MACBYTES);
+        confirmation.extend_from_slice(&nonce.0);
+        confirmation.extend(secretbox::seal(&self.identity_key.0, &nonce, &keys.0));
+        self.keys = Some(keys);
+        Ok(confirmation)
+    }
+
+    /// Open confirmation from the other side. Fails if PINs didn't match.
+    pub fn read_confirmation(&self, confirmation: &[u8]) -> WhisperResult<PublicKey> {
+        let keys = self.keys.as_ref().ok_or(WhisperError::InvalidSessionState)?;
+        if confirmation.len() < secretbox::NONCEBYTES {
+            return Err(WhisperError::PairingFailed);
+        }
+        let (nonce, sealed) = confirmation.split_at(secretbox::NONCEBYTES);
+        let nonce = secretbox::Nonce::from_slice(nonce).ok_or(WhisperError::PairingFailed)?;
+        secretbox::open(sealed, &nonce, &keys.1)
+            .ok()
+            .and_then(|key| PublicKey::from_slice(&key))
+            .ok_or(WhisperError::PairingFailed)
+    }
+}
+
+fn direction_key(shared: &[u8], label: &[u8]) -> secretbox::Key {
+    let mut input = Vec::with_capacity(shared.len() + label.len());
+    input.extend_from_slice(shared);
+    input.extend_from_slice(label);
+    secretbox::Key(sha256::hash(&input).0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::KeyPair;
+
+    fn pair(phone_pin: &[u8], device_pin: &[u8]) -> (WhisperResult<PublicKey>, WhisperResult<PublicKey>) {
+        let phone_identity = KeyPair::new().unwrap();
+        let device_identity = KeyPair::new().unwrap();
+        let (mut phone, phone_msg) = Pairing::start(PairingRole::Initiator,
+                                                    phone_pin,
+                                                    phone_identity.public_key);
+        let (mut device, device_msg) = Pairing::start(PairingRole::Responder,
+                                                      device_pin,
+                                                      device_identity.public_key);
+        let phone_confirmation = phone.finish(&device_msg).unwrap();
+        let device_confirmation = device.finish(&phone_msg).unwrap();
+        let learned_by_phone = phone.read_confirmation(&device_confirmation);
+        let learned_by_device = device.read_confirmation(&phone_confirmation);
+        if let Ok(key) = learned_by_phone {
+            assert_eq!(key, device_identity.public_key);
+        }
+        if let Ok(key) = learned_by_device {
+            assert_eq!(key, phone_identity.public_key);
+        }
+        (learned_by_phone, learned_by_device)
+    }
+
+    #[test]
+    fn same_pin() {
+        let (phone, device) = pair(b"123456", b"123456");
+        assert!(phone.is_ok());
+        assert!(device.is_ok());
+    }
+
+    #[test]
+    fn wrong_pin() {
+        let (phone, device) = pair(b"123456", b"654321");
+        assert!(phone.is_err());
+        assert!(device.is_err());
+    }
+}
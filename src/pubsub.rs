@@ -0,0 +1,304 @@
+//! Topics and subscriptions on top of Notifications.
+//!
+//! Notification published to a topic carries topic in its encrypted
+//! payload: topic length (1 byte), topic as UTF-8, then data. Client asks
+//! for a topic with `subscribe`, which is a Control frame with op byte 6
+//! (7 for `unsubscribe`) followed by the topic. Server passes every frame
+//! through `SubscriptionRegistry::read` and uses `publish` to make
+//! Notifications only for sessions that asked for the topic.
+//!
+//! Registry keeps at most `max_topics` topics and `max_topics_per_session`
+//! topics for one session. Subscription over either cap is
+//! `ResourceExhausted`.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use control::{SUBSCRIBE, UNSUBSCRIBE};
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use session::EstablishedSession;
+use sodiumoxide::crypto::box_::PublicKey;
+use std::collections::{HashMap, HashSet};
+
+/// Longest topic in bytes.
+pub const TOPIC_MAX: usize = 255;
+
+/// Default cap on topics with at least one subscriber.
+pub const DEFAULT_MAX_TOPICS: usize = 4096;
+
+/// Default cap on topics one session can subscribe to.
+pub const DEFAULT_MAX_TOPICS_PER_SESSION: usize = 64;
+
+fn check_topic(topic: &[u8]) -> WhisperResult<()> {
+    if topic.is_empty() || topic.len() > TOPIC_MAX {
+        return Err(WhisperError::BadFrame);
+    }
+    Ok(())
+}
+
+/// Prefix data with topic. Fails with `BadFrame` if topic is empty or
+/// longer than `TOPIC_MAX`.
+pub fn wrap(topic: &str, data: &[u8]) -> WhisperResult<BytesMut> {
+    check_topic(topic.as_bytes())?;
+    let mut buf = BytesMut::with_capacity(1 + topic.len() + data.len());
+    buf.put_u8(topic.len() as u8);
+    buf.extend_from_slice(topic.as_bytes());
+    buf.extend_from_slice(data);
+    Ok(buf)
+}
+
+/// Split opened Notification payload into topic and data.
+pub fn unwrap(payload: &Bytes) -> WhisperResult<(String, Bytes)> {
+    let len = *payload.first().ok_or(WhisperError::BadFrame)? as usize;
+    if payload.len() < 1 + len {
+        return Err(WhisperError::BadFrame);
+    }
+    let topic = &payload[1..1 + len];
+    check_topic(topic)?;
+    let topic = String::from_utf8(topic.to_vec()).map_err(|_| WhisperError::BadFrame)?;
+    Ok((topic, payload.slice_from(1 + len)))
+}
+
+/// Ask peer to send Notifications of this topic.
+pub fn subscribe(session: &EstablishedSession, topic: &str) -> WhisperResult<Frame> {
+    control(session, SUBSCRIBE, topic)
+}
+
+/// Ask peer to stop sending Notifications of this topic.
+pub fn unsubscribe(session: &EstablishedSession, topic: &str) -> WhisperResult<Frame> {
+    control(session, UNSUBSCRIBE, topic)
+}
+
+fn control(session: &EstablishedSession, op: u8, topic: &str) -> WhisperResult<Frame> {
+    check_topic(topic.as_bytes())?;
+    let mut payload = BytesMut::with_capacity(1 + topic.len());
+    payload.put_u8(op);
+    payload.extend_from_slice(topic.as_bytes());
+    session.make_control(&payload)
+}
+
+/// What peer asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubscriptionEvent {
+    /// Peer subscribed to topic.
+    Subscribed(String),
+    /// Peer unsubscribed from topic.
+    Unsubscribed(String),
+}
+
+/// Topics each session asked for. Sessions are known by `SessionInfo::id`.
+#[derive(Debug, Clone)]
+pub struct SubscriptionRegistry {
+    topics: HashMap<String, HashSet<PublicKey>>,
+    sessions: HashMap<PublicKey, HashSet<String>>,
+    max_topics: usize,
+    max_topics_per_session: usize,
+}
+
+impl Default for SubscriptionRegistry {
+    fn default() -> SubscriptionRegistry {
+        SubscriptionRegistry {
+            topics: HashMap::new(),
+            sessions: HashMap::new(),
+            max_topics: DEFAULT_MAX_TOPICS,
+            max_topics_per_session: DEFAULT_MAX_TOPICS_PER_SESSION,
+        }
+    }
+}
+
+impl SubscriptionRegistry {
+    /// Empty registry.
+    pub fn new() -> SubscriptionRegistry { SubscriptionRegistry::default() }
+
+    /// Keep at most this many topics. Subscriptions to topics that already
+    /// have subscribers aren't limited by it.
+    pub fn set_max_topics(&mut self, max_topics: usize) { self.max_topics = max_topics; }
+
+    /// Let one session subscribe to at most this many topics.
+    pub fn set_max_topics_per_session(&mut self, max_topics: usize) {
+        self.max_topics_per_session = max_topics;
+    }
+
+    /// Handle incoming frame. Frames that aren't subscriptions are ignored,
+    /// so every frame can be passed through here. Subscription over a cap
+    /// is `ResourceExhausted`.
+    pub fn read(&mut self,
+                session: &EstablishedSession,
+                frame: &Frame)
+                -> WhisperResult<Option<SubscriptionEvent>> {
+        if frame.kind != FrameKind::Control {
+            return Ok(None);
+        }
+        let payload = session.peek_msg(frame)?;
+        let op = match payload.first() {
+            Some(&op) if op == SUBSCRIBE || op == UNSUBSCRIBE => op,
+            _ => return Ok(None),
+        };
+        session.claim(frame)?;
+        check_topic(&payload[1..])?;
+        let topic = String::from_utf8(payload[1..].to_vec()).map_err(|_| WhisperError::BadFrame)?;
+        let id = session.info().id;
+        if op == SUBSCRIBE {
+            self.add(id, &topic)?;
+            Ok(Some(SubscriptionEvent::Subscribed(topic)))
+        } else {
+            self.remove(&id, &topic);
+            Ok(Some(SubscriptionEvent::Unsubscribed(topic)))
+        }
+    }
+
+    /// Subscribe session to topic on its behalf. Fails with
+    /// `ResourceExhausted` if that's over a cap; subscribing again to the
+    /// same topic always works.
+    pub fn add(&mut self, session_id: PublicKey, topic: &str) -> WhisperResult<()> {
+        if self.is_subscribed(&session_id, topic) {
+            return Ok(());
+        }
+        let session_topics = self.sessions.get(&session_id).map_or(0, |topics| topics.len());
+        if session_topics >= self.max_topics_per_session ||
+           (!self.topics.contains_key(topic) && self.topics.len() >= self.max_topics)
+        {
+            return Err(WhisperError::ResourceExhausted);
+        }
+        self.topics.entry(topic.to_owned()).or_default().insert(session_id);
+        self.sessions.entry(session_id).or_default().insert(topic.to_owned());
+        Ok(())
+    }
+
+    /// Unsubscribe session from topic.
+    pub fn remove(&mut self, session_id: &PublicKey, topic: &str) {
+        if let Some(subscribers) = self.topics.get_mut(topic) {
+            subscribers.remove(session_id);
+            if subscribers.is_empty() {
+                self.topics.remove(topic);
+            }
+        }
+        if let Some(topics) = self.sessions.get_mut(session_id) {
+            topics.remove(topic);
+            if topics.is_empty() {
+                self.sessions.remove(session_id);
+            }
+        }
+    }
+
+    /// Forget session, e.g. once it's terminated or expired.
+    pub fn remove_session(&mut self, session_id: &PublicKey) {
+        for topic in self.sessions.remove(session_id).unwrap_or_default() {
+            if let Some(subscribers) = self.topics.get_mut(&topic) {
+                subscribers.remove(session_id);
+                if subscribers.is_empty() {
+                    self.topics.remove(&topic);
+                }
+            }
+        }
+    }
+
+    /// Returns true if session asked for topic.
+    pub fn is_subscribed(&self, session_id: &PublicKey, topic: &str) -> bool {
+        self.topics.get(topic).is_some_and(|subscribers| subscribers.contains(session_id))
+    }
+
+    /// Sessions subscribed to topic.
+    pub fn subscribers(&self, topic: &str) -> Vec<PublicKey> {
+        self.topics.get(topic).map(|s| s.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Topics session is subscribed to.
+    pub fn topics(&self, session_id: &PublicKey) -> Vec<String> {
+        self.sessions.get(session_id).map(|t| t.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Make Notification for every session out of these that is subscribed
+    /// to topic.
+    pub fn publish<'a, I>(&self,
+                          topic: &str,
+                          data: &[u8],
+                          sessions: I)
+                          -> WhisperResult<Vec<(PublicKey, Frame)>>
+        where I: IntoIterator<Item = &'a EstablishedSession>
+    {
+        let payload = wrap(topic, data)?;
+        let mut frames = Vec::new();
+        for session in sessions {
+            let id = session.info().id;
+            if self.is_subscribed(&id, topic) {
+                frames.push((id, session.make_notification(&payload)?));
+            }
+        }
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::test::handshake;
+
+    #[test]
+    fn only_subscribers_get_notifications() {
+        let (kitchen, kitchen_server) = handshake();
+        let (garage, garage_server) = handshake();
+        let mut registry = SubscriptionRegistry::new();
+
+        let frame = subscribe(&kitchen, "weather").unwrap();
+        assert_eq!(registry.read(&kitchen_server, &frame).unwrap(),
+                   Some(SubscriptionEvent::Subscribed("weather".to_owned())));
+        let frame = subscribe(&garage, "doors").unwrap();
+        registry.read(&garage_server, &frame).unwrap();
+
+        let sessions = vec![kitchen_server.clone(), garage_server.clone()];
+        let frames = registry.publish("weather", b"rain", &sessions).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0, kitchen_server.info().id);
+        let (topic, data) = unwrap(&kitchen.read_msg(&frames[0].1).unwrap()).unwrap();
+        assert_eq!(topic, "weather");
+        assert_eq!(data.as_ref(), b"rain");
+
+        let frame = unsubscribe(&kitchen, "weather").unwrap();
+        assert_eq!(registry.read(&kitchen_server, &frame).unwrap(),
+                   Some(SubscriptionEvent::Unsubscribed("weather".to_owned())));
+        assert!(registry.publish("weather", b"sun", &sessions).unwrap().is_empty());
+
+        registry.remove_session(&garage_server.info().id);
+        assert!(registry.subscribers("doors").is_empty());
+        assert!(registry.topics(&garage_server.info().id).is_empty());
+
+        // Other messages are ignored.
+        let notification = kitchen.make_notification(b"hi").unwrap();
+        assert_eq!(registry.read(&kitchen_server, &notification).unwrap(), None);
+        assert!(subscribe(&kitchen, "").is_err());
+        assert!(unwrap(&Bytes::from(&b"\x05abc"[..])).is_err());
+    }
+
+    #[test]
+    fn topics_are_capped() {
+        let (kitchen, kitchen_server) = handshake();
+        let (_, garage_server) = handshake();
+        let mut registry = SubscriptionRegistry::new();
+        registry.set_max_topics(3);
+        registry.set_max_topics_per_session(2);
+
+        for topic in &["weather", "doors"] {
+            let frame = subscribe(&kitchen, topic).unwrap();
+            registry.read(&kitchen_server, &frame).unwrap();
+        }
+        let frame = subscribe(&kitchen, "lights").unwrap();
+        match registry.read(&kitchen_server, &frame) {
+            Err(WhisperError::ResourceExhausted) => {}
+            other => panic!("Expected ResourceExhausted, got {:?}", other),
+        }
+        // Again to the same topic is fine.
+        let frame = subscribe(&kitchen, "doors").unwrap();
+        assert!(registry.read(&kitchen_server, &frame).is_ok());
+
+        let garage_id = garage_server.info().id;
+        registry.add(garage_id, "weather").unwrap();
+        registry.add(garage_id, "lights").unwrap();
+        let (_, other_server) = handshake();
+        assert!(registry.add(other_server.info().id, "weather").is_ok());
+        match registry.add(other_server.info().id, "heating") {
+            Err(WhisperError::ResourceExhausted) => {}
+            other => panic!("Expected ResourceExhausted, got {:?}", other),
+        }
+        assert_eq!(registry.topics(&kitchen_server.info().id).len(), 2);
+    }
+}
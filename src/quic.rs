@@ -0,0 +1,107 @@
+//! Whisper frames over QUIC DATAGRAM frames (RFC 9221), using quinn.
+//! QUIC takes care of connectivity: NAT rebinding, migration, congestion
+//! control. Its TLS only authenticates the endpoint we talk to, which can be
+//! a relay or a gateway; identity of the peer and secrecy of payloads are
+//! still whisper's, end to end.
+//!
+//! One whisper frame goes into one QUIC datagram, packed without length
+//! prefix. Datagrams are unreliable like UDP, so session must be in
+//! datagram transport mode (`ClientSession::set_transport_mode`), and lost
+//! requests are up to the caller to retry. Doing handshake over datagrams
+//! works too (`send_frame` and `read_frame`), but it's simpler to do it
+//! over a QUIC stream with `transport::pack_prefixed`.
+//!
+//! This crate doesn't depend on an async runtime: receiving is awaiting
+//! `quinn::Connection::read_datagram` and handing result to
+//! `QuicDatagrams::read`.
+
+use bytes::Bytes;
+use bytes1;
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, HEADER_SIZE};
+use quinn::{Connection, SendDatagramError};
+use session::EstablishedSession;
+use sodiumoxide::crypto::box_::MACBYTES;
+use std::io;
+use transport::TransportMode;
+
+/// Established session bound to QUIC connection.
+pub struct QuicDatagrams {
+    connection: Connection,
+    session: EstablishedSession,
+}
+
+impl QuicDatagrams {
+    /// Bind session to connection. Session has to be in datagram mode,
+    /// otherwise `InvalidSessionState`.
+    pub fn new(connection: Connection,
+               session: EstablishedSession)
+               -> WhisperResult<QuicDatagrams> {
+        if session.mode() != TransportMode::Datagram {
+            return Err(WhisperError::InvalidSessionState);
+        }
+        Ok(QuicDatagrams {
+               connection,
+               session,
+           })
+    }
+
+    /// Session used to make and open frames.
+    pub fn session(&self) -> &EstablishedSession { &self.session }
+
+    /// Underlying QUIC connection.
+    pub fn connection(&self) -> &Connection { &self.connection }
+
+    /// Send frame made by our session in one datagram.
+    pub fn send(&self, frame: &Frame) -> WhisperResult<()> {
+        send_packet(&self.connection, &self.session.pack(frame))
+    }
+
+    /// Make Request and send it.
+    pub fn request(&self, data: &[u8]) -> WhisperResult<Frame> {
+        let frame = self.session.make_request(data)?;
+        self.send(&frame)?;
+        Ok(frame)
+    }
+
+    /// Make Notification and send it.
+    pub fn notify(&self, data: &[u8]) -> WhisperResult<()> {
+        self.send(&self.session.make_notification(data)?)
+    }
+
+    /// Open datagram received from the connection. Replayed frames are
+    /// refused with `ReplayedFrame`.
+    pub fn read(&mut self, datagram: &[u8]) -> WhisperResult<(Frame, Bytes)> {
+        self.session.read_packet(datagram)
+    }
+
+    /// Largest message that still fits into one datagram right now. None
+    /// if peer doesn't accept datagrams.
+    pub fn max_payload(&self) -> Option<usize> {
+        self.connection
+            .max_datagram_size()
+            .map(|size| size.saturating_sub(HEADER_SIZE + MACBYTES))
+    }
+}
+
+/// Send any frame, e.g. handshake one, in one datagram.
+pub fn send_frame(connection: &Connection, frame: &Frame) -> WhisperResult<()> {
+    send_packet(connection, &frame.pack())
+}
+
+/// Parse frame from received datagram.
+pub fn read_frame(datagram: &[u8]) -> WhisperResult<Frame> { Frame::from_slice(datagram) }
+
+fn send_packet(connection: &Connection, packet: &[u8]) -> WhisperResult<()> {
+    connection.send_datagram(bytes1::Bytes::copy_from_slice(packet))
+              .map_err(datagram_error)
+}
+
+fn datagram_error(err: SendDatagramError) -> WhisperError {
+    let kind = match err {
+        SendDatagramError::TooLarge => io::ErrorKind::InvalidInput,
+        SendDatagramError::ConnectionLost(_) => io::ErrorKind::ConnectionAborted,
+        _ => io::ErrorKind::Unsupported,
+    };
+    WhisperError::Io(io::Error::new(kind, err))
+}
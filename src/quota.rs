@@ -0,0 +1,174 @@
+//! Memory quotas, so one misbehaving peer can't make a gateway with
+//! thousands of sessions run out of memory. `MemoryQuota` is the global
+//! budget. Each session takes `SESSION_COST` out of it, plus whatever its
+//! buffers hold right now, up to the session's own limit.
+//!
+//! Buffers charge quota with `SessionQuota::reserve` and get `Reservation`
+//! back. Memory is returned to both quotas when reservation is dropped, so
+//! keep it next to the bytes it accounts for. Going over either limit is
+//! `ResourceExhausted`. In this crate quotas are charged by:
+//! - `StreamMux::queue` for queued outbound messages (`StreamMux::set_quota`).
+//! - facade `Connection` for messages that arrived while it waited for a
+//!   response (`Server::set_memory_quota`). Quota is charged before such
+//!   message is read, so message that doesn't fit stays in the stream.
+
+use errors::{WhisperError, WhisperResult};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Memory charged to global quota for each session, on top of its buffers.
+/// Rough size of established session with its keys and bookkeeping.
+pub const SESSION_COST: usize = 1024;
+
+#[derive(Debug)]
+struct Counter {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl Counter {
+    fn new(limit: usize) -> Counter {
+        Counter {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    fn reserve(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: usize) { self.used.fetch_sub(bytes, Ordering::SeqCst); }
+}
+
+/// Global memory budget. Cheap to clone, clones share it.
+#[derive(Debug, Clone)]
+pub struct MemoryQuota {
+    global: Arc<Counter>,
+}
+
+impl MemoryQuota {
+    /// Budget of `limit` bytes.
+    pub fn new(limit: usize) -> MemoryQuota {
+        MemoryQuota { global: Arc::new(Counter::new(limit)) }
+    }
+
+    /// Quota for new session that may hold up to `limit` bytes in buffers.
+    /// Fails with `ResourceExhausted` if global budget can't fit one more
+    /// session.
+    pub fn session(&self, limit: usize) -> WhisperResult<SessionQuota> {
+        if !self.global.reserve(SESSION_COST) {
+            return Err(WhisperError::ResourceExhausted);
+        }
+        Ok(SessionQuota {
+               inner: Arc::new(SessionInner {
+                                   counter: Counter::new(limit),
+                                   global: self.global.clone(),
+                               }),
+           })
+    }
+
+    /// Bytes used by sessions and their buffers.
+    pub fn used(&self) -> usize { self.global.used.load(Ordering::SeqCst) }
+
+    /// Budget size.
+    pub fn limit(&self) -> usize { self.global.limit }
+}
+
+#[derive(Debug)]
+struct SessionInner {
+    counter: Counter,
+    global: Arc<Counter>,
+}
+
+impl Drop for SessionInner {
+    fn drop(&mut self) { self.global.release(SESSION_COST); }
+}
+
+/// Quota of one session. Clones share it, session's `SESSION_COST` is
+/// returned once the last of them and of its reservations is gone.
+#[derive(Debug, Clone)]
+pub struct SessionQuota {
+    inner: Arc<SessionInner>,
+}
+
+impl SessionQuota {
+    /// Charge `bytes` to session and global quota. Fails with
+    /// `ResourceExhausted` if either of them is over the limit then.
+    pub fn reserve(&self, bytes: usize) -> WhisperResult<Reservation> {
+        if !self.inner.counter.reserve(bytes) {
+            return Err(WhisperError::ResourceExhausted);
+        }
+        if !self.inner.global.reserve(bytes) {
+            self.inner.counter.release(bytes);
+            return Err(WhisperError::ResourceExhausted);
+        }
+        Ok(Reservation {
+               quota: self.clone(),
+               bytes,
+           })
+    }
+
+    /// Bytes held by session's buffers.
+    pub fn used(&self) -> usize { self.inner.counter.used.load(Ordering::SeqCst) }
+
+    /// Session limit.
+    pub fn limit(&self) -> usize { self.inner.counter.limit }
+}
+
+/// Memory charged to quota. Returned when dropped. Not `Clone`: copy
+/// would hold memory nobody reserved.
+#[derive(Debug)]
+pub struct Reservation {
+    quota: SessionQuota,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Bytes reserved.
+    pub fn bytes(&self) -> usize { self.bytes }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.quota.inner.counter.release(self.bytes);
+        self.quota.inner.global.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn session_and_global_limits() {
+        let quota = MemoryQuota::new(2 * SESSION_COST + 1500);
+        let first = quota.session(1000).unwrap();
+        let second = quota.session(1000).unwrap();
+        // No room for third session.
+        match quota.session(1000) {
+            Err(WhisperError::ResourceExhausted) => {}
+            other => panic!("Expected ResourceExhausted, got {:?}", other),
+        }
+
+        let held = first.reserve(800).unwrap();
+        assert!(first.reserve(300).is_err());
+        // Session has room, but global budget doesn't.
+        assert!(second.reserve(800).is_err());
+        assert_eq!(second.used(), 0);
+        let small = second.reserve(700).unwrap();
+        assert_eq!(quota.used(), 2 * SESSION_COST + 1500);
+
+        drop(held);
+        assert_eq!(first.used(), 0);
+        assert!(second.reserve(300).is_ok());
+        drop(small);
+        drop(first);
+        drop(second);
+        assert_eq!(quota.used(), 0);
+    }
+}
@@ -0,0 +1,118 @@
+//! Handoff of a session to another server, for load shedding and rolling
+//! restarts. Server sends Redirect and stops serving the session; client
+//! connects to the endpoint it names, presenting the ticket if there is
+//! one.
+//!
+//! Redirect is a Control frame with op byte 9, endpoint length (1 byte),
+//! endpoint as UTF-8 and the rest is ticket. Endpoint format (host:port,
+//! URL...) and what ticket means is up to the application. `facade`
+//! handles it for you: `Connection::recv` ends with `redirect` set.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use control::REDIRECT;
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use session::EstablishedSession;
+
+/// Longest endpoint in bytes.
+pub const ENDPOINT_MAX: usize = 255;
+
+/// Where to reconnect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    /// Server to connect to.
+    pub endpoint: String,
+    /// Opaque ticket to present there, e.g. for resumption.
+    pub ticket: Option<Bytes>,
+}
+
+impl Redirect {
+    /// Redirect without ticket.
+    pub fn new(endpoint: &str) -> Redirect {
+        Redirect {
+            endpoint: endpoint.to_owned(),
+            ticket: None,
+        }
+    }
+
+    /// Same as above, but with ticket.
+    pub fn with_ticket<B: Into<Bytes>>(endpoint: &str, ticket: B) -> Redirect {
+        Redirect {
+            endpoint: endpoint.to_owned(),
+            ticket: Some(ticket.into()),
+        }
+    }
+
+    /// Control frame to send. Fails with `BadFrame` if endpoint is empty or
+    /// longer than `ENDPOINT_MAX`.
+    pub fn to_frame(&self, session: &EstablishedSession) -> WhisperResult<Frame> {
+        session.make_control(&self.encode()?)
+    }
+
+    fn encode(&self) -> WhisperResult<BytesMut> {
+        let endpoint = self.endpoint.as_bytes();
+        if endpoint.is_empty() || endpoint.len() > ENDPOINT_MAX {
+            return Err(WhisperError::BadFrame);
+        }
+        let ticket = self.ticket.as_ref().map_or(&[][..], |ticket| ticket.as_ref());
+        let mut payload = BytesMut::with_capacity(2 + endpoint.len() + ticket.len());
+        payload.put_u8(REDIRECT);
+        payload.put_u8(endpoint.len() as u8);
+        payload.extend_from_slice(endpoint);
+        payload.extend_from_slice(ticket);
+        Ok(payload)
+    }
+
+    /// Redirect carried by this frame. None for anything else, so every
+    /// frame can be passed through here.
+    pub fn from_frame(session: &EstablishedSession,
+                      frame: &Frame)
+                      -> WhisperResult<Option<Redirect>> {
+        if frame.kind != FrameKind::Control {
+            return Ok(None);
+        }
+        let redirect = Redirect::decode(&session.peek_msg(frame)?)?;
+        if redirect.is_some() {
+            session.claim(frame)?;
+        }
+        Ok(redirect)
+    }
+
+    /// Same as above for already opened Control payload.
+    pub fn decode(payload: &Bytes) -> WhisperResult<Option<Redirect>> {
+        if payload.first() != Some(&REDIRECT) {
+            return Ok(None);
+        }
+        let len = *payload.get(1).ok_or(WhisperError::BadFrame)? as usize;
+        if len == 0 || payload.len() < 2 + len {
+            return Err(WhisperError::BadFrame);
+        }
+        let endpoint = String::from_utf8(payload[2..2 + len].to_vec())
+            .map_err(|_| WhisperError::BadFrame)?;
+        let ticket = payload.slice_from(2 + len);
+        Ok(Some(Redirect {
+                    endpoint,
+                    ticket: if ticket.is_empty() { None } else { Some(ticket) },
+                }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::test::handshake;
+
+    #[test]
+    fn redirect_round_trip() {
+        let (client, server) = handshake();
+        let redirect = Redirect::with_ticket("edge-2.example.com:7777", &b"ticket"[..]);
+        let frame = redirect.to_frame(&server).unwrap();
+        assert_eq!(Redirect::from_frame(&client, &frame).unwrap(), Some(redirect));
+        let plain = Redirect::new("10.0.0.2:7777").to_frame(&server).unwrap();
+        assert_eq!(Redirect::from_frame(&client, &plain).unwrap().unwrap().ticket, None);
+
+        let notification = server.make_notification(b"hi").unwrap();
+        assert_eq!(Redirect::from_frame(&client, &notification).unwrap(), None);
+        assert!(Redirect::new("").to_frame(&server).is_err());
+    }
+}
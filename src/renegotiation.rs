@@ -0,0 +1,377 @@
+//! Changing session parameters on established session without rekeying.
+//!
+//! Exchange is carried in Control frames. Payload is one op byte followed
+//! by parameters encoded as `Metadata`:
+//! 1. One side sends Propose with parameters it wants.
+//! 2. Other side decides and answers with Accept or Reject, echoing them.
+//! 3. Proposing side answers Accept with Confirm and switches right after.
+//!    Accepting side switches once it reads Confirm.
+//!
+//! Frames sent before the switch still use old parameters, so the proposing
+//! side keeps allowing payloads up to the old limit until peer is heard
+//! from again. Only one proposal per side can be in flight. If both sides
+//! propose at the same time, client's proposal wins and server's one is
+//! dropped.
+//!
+//! Parameters are not applied to the session, pass payloads through
+//! `check_outgoing` and `check_incoming` to enforce `max_payload`.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+use control::{ACCEPT, CONFIRM, PROPOSE, REJECT};
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use metadata::Metadata;
+use session::{EstablishedSession, Side};
+use std::time::Duration;
+use transport::MAX_FRAME_SIZE;
+
+/// Keepalive interval in milliseconds as u32 BigEndian.
+pub const KEEPALIVE: u8 = 1;
+/// Largest payload peer is willing to receive as u32 BigEndian.
+pub const MAX_PAYLOAD: u8 = 2;
+/// Compression flag, one byte.
+pub const COMPRESSION: u8 = 3;
+
+/// Parameters that can be changed mid-session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionParams {
+    /// How often to send keepalive when idle.
+    pub keepalive: Duration,
+    /// Largest payload to send.
+    pub max_payload: u32,
+    /// Whether payloads are compressed.
+    pub compression: bool,
+}
+
+impl Default for SessionParams {
+    fn default() -> SessionParams {
+        SessionParams {
+            keepalive: Duration::from_secs(30),
+            max_payload: MAX_FRAME_SIZE as u32,
+            compression: false,
+        }
+    }
+}
+
+impl SessionParams {
+    // Zero keepalive or payload limit would stall the session.
+    fn is_valid(&self) -> bool {
+        self.keepalive > Duration::from_millis(0) && self.max_payload > 0 &&
+        self.max_payload as usize <= MAX_FRAME_SIZE
+    }
+
+    fn encode(&self, buf: &mut BytesMut) {
+        let mut metadata = Metadata::new();
+        let mut value = [0; 4];
+        let keepalive = self.keepalive.as_secs() * 1000 + u64::from(self.keepalive.subsec_millis());
+        BigEndian::write_u32(&mut value, keepalive.min(u64::from(u32::MAX)) as u32);
+        metadata.insert(KEEPALIVE, &value[..]);
+        BigEndian::write_u32(&mut value, self.max_payload);
+        metadata.insert(MAX_PAYLOAD, &value[..]);
+        metadata.insert(COMPRESSION, vec![self.compression as u8]);
+        metadata.encode(buf);
+    }
+
+    // Missing entries keep default value.
+    fn decode(i: &[u8]) -> WhisperResult<SessionParams> {
+        let metadata = Metadata::decode(i)?;
+        let mut params = SessionParams::default();
+        if let Some(value) = metadata.get(KEEPALIVE) {
+            params.keepalive = Duration::from_millis(u64::from(read_u32(value)?));
+        }
+        if let Some(value) = metadata.get(MAX_PAYLOAD) {
+            params.max_payload = read_u32(value)?;
+        }
+        if let Some(value) = metadata.get(COMPRESSION) {
+            params.compression = match value.as_ref() {
+                [0] => false,
+                [1] => true,
+                _ => return Err(WhisperError::BadFrame),
+            };
+        }
+        if !params.is_valid() {
+            return Err(WhisperError::BadFrame);
+        }
+        Ok(params)
+    }
+}
+
+fn read_u32(value: &[u8]) -> WhisperResult<u32> {
+    if value.len() != 4 {
+        return Err(WhisperError::BadFrame);
+    }
+    Ok(BigEndian::read_u32(value))
+}
+
+/// What happened to the exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenegotiationEvent {
+    /// Peer wants these. Answer with `accept` or `reject`.
+    Proposed(SessionParams),
+    /// Peer accepted our proposal. Send frame from `confirm` before
+    /// anything else.
+    Accepted(SessionParams),
+    /// Peer rejected our proposal. Nothing changed.
+    Rejected(SessionParams),
+    /// Peer confirmed proposal we accepted. These are current now.
+    Confirmed(SessionParams),
+}
+
+/// Renegotiation state of one session.
+#[derive(Debug, Clone)]
+pub struct Renegotiation {
+    side: Side,
+    current: SessionParams,
+    // Replaced by the last switch, while peer may still use them.
+    previous: Option<SessionParams>,
+    // Ours, waiting for answer.
+    proposed: Option<SessionParams>,
+    // Ours, accepted and waiting for us to confirm.
+    confirming: Option<SessionParams>,
+    // Theirs, waiting for our decision.
+    offered: Option<SessionParams>,
+    // Theirs, accepted and waiting for their confirmation.
+    accepted: Option<SessionParams>,
+}
+
+impl Renegotiation {
+    /// Start with parameters both sides already agreed on.
+    pub fn new(session: &EstablishedSession, current: SessionParams) -> Renegotiation {
+        Renegotiation {
+            side: session.info().side,
+            current,
+            previous: None,
+            proposed: None,
+            confirming: None,
+            offered: None,
+            accepted: None,
+        }
+    }
+
+    /// Parameters in effect.
+    pub fn current(&self) -> &SessionParams { &self.current }
+
+    /// Returns true if our proposal is waiting for answer or confirmation.
+    pub fn is_pending(&self) -> bool { self.proposed.is_some() || self.confirming.is_some() }
+
+    /// `ResourceExhausted` if payload is over the limit in effect.
+    pub fn check_outgoing(&self, data: &[u8]) -> WhisperResult<()> {
+        if data.len() > self.current.max_payload as usize {
+            return Err(WhisperError::ResourceExhausted);
+        }
+        Ok(())
+    }
+
+    /// `BadFrame` if peer sent payload over the limit, old limit included
+    /// while peer may not have switched yet.
+    pub fn check_incoming(&self, msg: &[u8]) -> WhisperResult<()> {
+        let limit = self.previous
+                        .map_or(0, |previous| previous.max_payload)
+                        .max(self.current.max_payload);
+        if msg.len() > limit as usize {
+            return Err(WhisperError::BadFrame);
+        }
+        Ok(())
+    }
+
+    /// Ask peer to switch to these parameters. `InvalidLimit` if zero
+    /// keepalive or payload limit, or payload limit over `MAX_FRAME_SIZE`.
+    pub fn propose(&mut self,
+                   session: &EstablishedSession,
+                   params: SessionParams)
+                   -> WhisperResult<Frame> {
+        if !params.is_valid() {
+            return Err(WhisperError::InvalidLimit);
+        }
+        if self.is_pending() || self.accepted.is_some() {
+            return Err(WhisperError::InvalidSessionState);
+        }
+        let frame = control(session, PROPOSE, &params)?;
+        self.proposed = Some(params);
+        Ok(frame)
+    }
+
+    /// Accept peer's proposal. New parameters are in effect once peer
+    /// confirms.
+    pub fn accept(&mut self, session: &EstablishedSession) -> WhisperResult<Frame> {
+        let params = self.offered.ok_or(WhisperError::InvalidSessionState)?;
+        let frame = control(session, ACCEPT, &params)?;
+        self.offered = None;
+        self.accepted = Some(params);
+        Ok(frame)
+    }
+
+    /// Confirm proposal peer accepted. New parameters are in effect for
+    /// everything sent after returned frame.
+    pub fn confirm(&mut self, session: &EstablishedSession) -> WhisperResult<Frame> {
+        let params = self.confirming.ok_or(WhisperError::InvalidSessionState)?;
+        let frame = control(session, CONFIRM, &params)?;
+        self.confirming = None;
+        self.previous = Some(self.current);
+        self.current = params;
+        Ok(frame)
+    }
+
+    /// Reject peer's proposal.
+    pub fn reject(&mut self, session: &EstablishedSession) -> WhisperResult<Frame> {
+        let params = self.offered.ok_or(WhisperError::InvalidSessionState)?;
+        let frame = control(session, REJECT, &params)?;
+        self.offered = None;
+        Ok(frame)
+    }
+
+    /// Handle incoming frame. Frames that aren't renegotiation are ignored,
+    /// so every frame can be passed through here. None also means proposal
+    /// lost a collision and was dropped.
+    pub fn read(&mut self,
+                session: &EstablishedSession,
+                frame: &Frame)
+                -> WhisperResult<Option<RenegotiationEvent>> {
+        if frame.kind != FrameKind::Control {
+            return Ok(None);
+        }
+        let payload = session.peek_msg(frame)?;
+        match payload.first() {
+            Some(&PROPOSE) | Some(&ACCEPT) | Some(&REJECT) | Some(&CONFIRM) => {}
+            // Control frame of someone else, e.g. heartbeat.
+            Some(_) => return Ok(None),
+            None => return Err(WhisperError::BadFrame),
+        }
+        session.claim(frame)?;
+        let params = SessionParams::decode(&payload[1..])?;
+        // Peer answers only after it read our last Confirm, so it switched.
+        self.previous = None;
+        match payload[0] {
+            PROPOSE if self.accepted.is_none() => {
+                if self.proposed.is_some() {
+                    if self.side == Side::Client {
+                        return Ok(None);
+                    }
+                    self.proposed = None;
+                }
+                self.offered = Some(params);
+                Ok(Some(RenegotiationEvent::Proposed(params)))
+            }
+            ACCEPT if self.proposed == Some(params) => {
+                self.proposed = None;
+                self.confirming = Some(params);
+                Ok(Some(RenegotiationEvent::Accepted(params)))
+            }
+            REJECT if self.proposed == Some(params) => {
+                self.proposed = None;
+                Ok(Some(RenegotiationEvent::Rejected(params)))
+            }
+            CONFIRM if self.accepted == Some(params) => {
+                self.accepted = None;
+                self.current = params;
+                Ok(Some(RenegotiationEvent::Confirmed(params)))
+            }
+            _ => Err(WhisperError::InvalidSessionState),
+        }
+    }
+}
+
+fn control(session: &EstablishedSession, op: u8, params: &SessionParams) -> WhisperResult<Frame> {
+    let mut payload = BytesMut::with_capacity(32);
+    payload.put_u8(op);
+    params.encode(&mut payload);
+    session.make_control(&payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::test::handshake;
+
+    fn faster() -> SessionParams {
+        SessionParams {
+            keepalive: Duration::from_millis(1500),
+            max_payload: 1024,
+            compression: true,
+        }
+    }
+
+    #[test]
+    fn propose_and_accept() {
+        let (client, server) = handshake();
+        let mut ours = Renegotiation::new(&client, SessionParams::default());
+        let mut theirs = Renegotiation::new(&server, SessionParams::default());
+
+        let propose = ours.propose(&client, faster()).unwrap();
+        assert!(ours.propose(&client, faster()).is_err());
+        assert_eq!(theirs.read(&server, &propose).unwrap(),
+                   Some(RenegotiationEvent::Proposed(faster())));
+        // Nothing changes until both agreed.
+        assert_eq!(ours.current(), &SessionParams::default());
+        let accept = theirs.accept(&server).unwrap();
+        assert_eq!(theirs.current(), &SessionParams::default());
+        assert_eq!(ours.read(&client, &accept).unwrap(),
+                   Some(RenegotiationEvent::Accepted(faster())));
+        assert!(ours.is_pending());
+        let confirm = ours.confirm(&client).unwrap();
+        assert_eq!(ours.current(), &faster());
+        assert!(!ours.is_pending());
+        // Frame server sent before it read Confirm is still fine.
+        let big = vec![0; 2048];
+        assert!(ours.check_outgoing(&big).is_err());
+        ours.check_incoming(&big).unwrap();
+        assert_eq!(theirs.read(&server, &confirm).unwrap(),
+                   Some(RenegotiationEvent::Confirmed(faster())));
+        assert_eq!(theirs.current(), &faster());
+        assert!(theirs.check_incoming(&big).is_err());
+
+        // Rejected proposal changes nothing.
+        let propose = theirs.propose(&server, SessionParams::default()).unwrap();
+        ours.read(&client, &propose).unwrap();
+        let reject = ours.reject(&client).unwrap();
+        assert_eq!(theirs.read(&server, &reject).unwrap(),
+                   Some(RenegotiationEvent::Rejected(SessionParams::default())));
+        assert_eq!(theirs.current(), &faster());
+        assert!(ours.check_incoming(&big).is_err());
+    }
+
+    #[test]
+    fn params_are_checked() {
+        let (client, server) = handshake();
+        let mut ours = Renegotiation::new(&client, SessionParams::default());
+        let mut theirs = Renegotiation::new(&server, SessionParams::default());
+        let too_big = MAX_FRAME_SIZE as u32 + 1;
+        for &(keepalive, max_payload) in &[(0, 1024), (1000, 0), (1000, too_big)] {
+            let params = SessionParams {
+                keepalive: Duration::from_millis(keepalive),
+                max_payload,
+                compression: false,
+            };
+            match ours.propose(&client, params) {
+                Err(WhisperError::InvalidLimit) => {}
+                other => panic!("Expected InvalidLimit, got {:?}", other.map(|_| ())),
+            }
+            let forged = control(&client, PROPOSE, &params).unwrap();
+            match theirs.read(&server, &forged) {
+                Err(WhisperError::BadFrame) => {}
+                other => panic!("Expected BadFrame, got {:?}", other),
+            }
+        }
+        // Confirm without Accept.
+        let forged = control(&client, CONFIRM, &faster()).unwrap();
+        assert!(theirs.read(&server, &forged).is_err());
+        assert_eq!(theirs.current(), &SessionParams::default());
+    }
+
+    #[test]
+    fn client_wins_collision() {
+        let (client, server) = handshake();
+        let mut ours = Renegotiation::new(&client, SessionParams::default());
+        let mut theirs = Renegotiation::new(&server, SessionParams::default());
+        let from_client = ours.propose(&client, faster()).unwrap();
+        let from_server = theirs.propose(&server, SessionParams::default()).unwrap();
+
+        assert_eq!(ours.read(&client, &from_server).unwrap(), None);
+        assert_eq!(theirs.read(&server, &from_client).unwrap(),
+                   Some(RenegotiationEvent::Proposed(faster())));
+        assert!(!theirs.is_pending());
+        let notification = client.make_notification(b"not for us").unwrap();
+        assert_eq!(theirs.read(&server, &notification).unwrap(), None);
+    }
+}
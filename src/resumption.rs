@@ -0,0 +1,376 @@
+//! Session resumption: reconnect in one round trip instead of full
+//! handshake.
+//!
+//! After handshake server hands client a ticket with `issue`: Control frame
+//! with op byte 10, expiry as i64 BigEndian unix time and the ticket. Ticket
+//! is sealed with `TicketKeys` and holds client identity key, expiry,
+//! transport mode and resumption secret both sides derive from session keys.
+//! Client keeps it as `ResumptionTicket`.
+//!
+//! To reconnect, client makes new short term key and sends Resume frame with
+//! it as session id: ticket length as u16 BigEndian, ticket and HMAC-SHA256
+//! of session id, nonce and ticket keyed with the resumption secret. Server
+//! opens ticket, checks HMAC and replies with Ready: its new short term key
+//! followed by `READY_PAYLOAD` sealed by the new session. New session keys
+//! come from new short term keys with resumption secret bound on top
+//! (`EstablishedSession::bind_secret`), so stolen ticket key alone doesn't
+//! open resumed sessions.
+//!
+//! Resume can be replayed, but that only gets Ready for a session nobody can
+//! use, so it carries no early data. Ticket works until it expires; issue
+//! new one after every resumption, so connections can't be linked by it.
+//! Compression and routing hint of original session are not carried over.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{DateTime, Duration};
+use chrono::offset::{TimeZone, Utc};
+use clock::{self, Clock, SharedClock};
+use control::RESUMPTION_TICKET;
+use crypto::{KeyPair, constant_time_eq};
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use session::{EstablishedSession, READY_PAYLOAD, Side};
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::box_::{self, Nonce, PublicKey};
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::utils::memzero;
+use std::sync::Arc;
+use termination::TerminationReason;
+use tickets::{TICKET_HEADER_SIZE, TicketKeys};
+use transport::TransportMode;
+
+const SECRET_SIZE: usize = 32;
+// Client identity key, secret, expiry and transport mode.
+const STATE_SIZE: usize = box_::PUBLICKEYBYTES + SECRET_SIZE + 8 + 1;
+
+/// Size of Resume payload with ticket issued by this library.
+pub const MIN_RESUME_SIZE: usize = 2 + TICKET_HEADER_SIZE + secretbox::MACBYTES + STATE_SIZE +
+                                   hmacsha256::TAGBYTES;
+
+#[derive(Clone)]
+struct Secret([u8; SECRET_SIZE]);
+
+impl Drop for Secret {
+    fn drop(&mut self) { memzero(&mut self.0) }
+}
+
+/// Server side. Control frame with ticket to resume this session with, valid
+/// for `lifetime`. Client sessions can't issue tickets: `InvalidSessionState`.
+pub fn issue(keys: &TicketKeys,
+             session: &EstablishedSession,
+             lifetime: Duration)
+             -> WhisperResult<Frame> {
+    let client_identity_key = match (session.info().side, session.peer_identity()) {
+        (Side::Server, Some(key)) => key,
+        _ => return Err(WhisperError::InvalidSessionState),
+    };
+    let now = session.clock().now();
+    let expires_at = (now + lifetime).timestamp();
+    let secret = Secret(session.resumption_secret());
+    let mut state = Vec::with_capacity(STATE_SIZE);
+    state.extend_from_slice(&client_identity_key.0);
+    state.extend_from_slice(&secret.0);
+    state.put_i64_be(expires_at);
+    state.push(session.mode() as u8);
+    let ticket = keys.seal_at(now, &state);
+    memzero(&mut state);
+
+    let mut payload = BytesMut::with_capacity(1 + 8 + ticket.len());
+    payload.put_u8(RESUMPTION_TICKET);
+    payload.put_i64_be(expires_at);
+    payload.extend_from_slice(&ticket);
+    session.make_control(&payload)
+}
+
+/// Client side. Ticket from server, good for `make_resume` until it expires.
+#[derive(DebugStub, Clone)]
+pub struct ResumptionTicket {
+    server_identity_key: PublicKey,
+    expires_at: DateTime<Utc>,
+    mode: TransportMode,
+    ticket: Bytes,
+    #[debug_stub = "Secret"]
+    secret: Secret,
+    #[debug_stub = "Clock"]
+    clock: SharedClock,
+}
+
+impl ResumptionTicket {
+    /// Ticket carried by this frame. None for anything else, so every frame
+    /// can be passed through here.
+    pub fn from_frame(session: &EstablishedSession,
+                      frame: &Frame)
+                      -> WhisperResult<Option<ResumptionTicket>> {
+        if frame.kind != FrameKind::Control {
+            return Ok(None);
+        }
+        let payload = session.peek_msg(frame)?;
+        if payload.first() != Some(&RESUMPTION_TICKET) {
+            return Ok(None);
+        }
+        session.claim(frame)?;
+        ResumptionTicket::decode(session, &payload)
+    }
+
+    /// Same as `from_frame`, but for payload of Control frame that was
+    /// already opened. None if it's not a ticket. Ticket that doesn't fit
+    /// into Resume (longer than 65535 bytes) is `BadFrame`.
+    pub fn decode(session: &EstablishedSession,
+                  payload: &Bytes)
+                  -> WhisperResult<Option<ResumptionTicket>> {
+        if payload.first() != Some(&RESUMPTION_TICKET) {
+            return Ok(None);
+        }
+        let server_identity_key = match (session.info().side, session.peer_identity()) {
+            (Side::Client, Some(key)) => key,
+            _ => return Err(WhisperError::InvalidSessionState),
+        };
+        if payload.len() < 9 + TICKET_HEADER_SIZE || payload.len() - 9 > u16::MAX as usize {
+            return Err(WhisperError::BadFrame);
+        }
+        let expires_at = Utc.timestamp_opt(BigEndian::read_i64(&payload[1..9]), 0)
+                            .single()
+                            .ok_or(WhisperError::BadFrame)?;
+        Ok(Some(ResumptionTicket {
+                    server_identity_key,
+                    expires_at,
+                    mode: session.mode(),
+                    ticket: payload.slice_from(9),
+                    secret: Secret(session.resumption_secret()),
+                    clock: session.clock(),
+                }))
+    }
+
+    /// Identity key of server that issued the ticket.
+    pub fn server_identity_key(&self) -> PublicKey { self.server_identity_key }
+
+    /// When server stops accepting the ticket.
+    pub fn expires_at(&self) -> DateTime<Utc> { self.expires_at }
+
+    /// Resume frame to send and session waiting for reply to it. Expired
+    /// ticket is `InvalidTicket`, do full handshake instead. Expiry is
+    /// checked on clock of session ticket came with.
+    pub fn make_resume(&self) -> WhisperResult<(ResumingSession, Frame)> {
+        if self.expires_at <= self.clock.now() {
+            return Err(WhisperError::InvalidTicket);
+        }
+        let local_session_keypair = KeyPair::new()?;
+        let nonce = box_::gen_nonce();
+        let tag = binder(&self.secret, &local_session_keypair.public_key, &nonce, &self.ticket);
+        // `decode` made sure it fits.
+        let mut payload = BytesMut::with_capacity(2 + self.ticket.len() + hmacsha256::TAGBYTES);
+        payload.put_u16_be(self.ticket.len() as u16);
+        payload.extend_from_slice(&self.ticket);
+        payload.extend_from_slice(&tag.0);
+        let frame = Frame {
+            id: local_session_keypair.public_key,
+            nonce,
+            kind: FrameKind::Resume,
+            payload: payload.freeze(),
+        };
+        let session = ResumingSession {
+            local_session_keypair,
+            server_identity_key: self.server_identity_key,
+            mode: self.mode,
+            secret: self.secret.clone(),
+            clock: self.clock.clone(),
+        };
+        Ok((session, frame))
+    }
+}
+
+/// Client side. Sent Resume, waits for Ready.
+#[derive(DebugStub)]
+pub struct ResumingSession {
+    local_session_keypair: KeyPair,
+    server_identity_key: PublicKey,
+    mode: TransportMode,
+    #[debug_stub = "Secret"]
+    secret: Secret,
+    #[debug_stub = "Clock"]
+    clock: SharedClock,
+}
+
+impl ResumingSession {
+    /// Check Ready and get resumed session. Termination from server is
+    /// `Terminated`, e.g. because it no longer accepts the ticket.
+    pub fn read_ready(&self, ready: &Frame) -> WhisperResult<EstablishedSession> {
+        let id = self.local_session_keypair.public_key;
+        if ready.id == id {
+            if let Some(reason) = TerminationReason::from_frame(ready) {
+                return Err(WhisperError::Terminated(reason));
+            }
+        }
+        if ready.kind != FrameKind::Ready || ready.payload.len() < box_::PUBLICKEYBYTES {
+            return Err(WhisperError::InvalidReadyFrame);
+        }
+        let server_session_key = PublicKey::from_slice(&ready.payload[..box_::PUBLICKEYBYTES])
+            .ok_or(WhisperError::InvalidReadyFrame)?;
+        let mut session = EstablishedSession::with_clock(server_session_key,
+                                                         self.local_session_keypair.clone(),
+                                                         Side::Client,
+                                                         self.clock.clone());
+        session.bind_secret(&self.secret.0);
+        session.set_mode(self.mode);
+        session.set_peer_identity(self.server_identity_key);
+        let sealed = Frame {
+            id: ready.id,
+            nonce: ready.nonce,
+            kind: ready.kind,
+            payload: ready.payload.slice_from(box_::PUBLICKEYBYTES),
+        };
+        if !constant_time_eq(&session.open_as(&sealed, &id)?, READY_PAYLOAD) {
+            return Err(WhisperError::InvalidReadyFrame);
+        }
+        Ok(session)
+    }
+}
+
+/// Server side. Resume with ticket of ours from client that knows its
+/// secret.
+#[derive(DebugStub)]
+pub struct ResumeRequest {
+    client_session_key: PublicKey,
+    client_identity_key: PublicKey,
+    mode: TransportMode,
+    #[debug_stub = "Secret"]
+    secret: Secret,
+    #[debug_stub = "Clock"]
+    clock: SharedClock,
+}
+
+impl ResumeRequest {
+    /// Open ticket in Resume and check client holds its secret. Expired,
+    /// foreign and forged tickets are `InvalidTicket`.
+    pub fn open(keys: &TicketKeys, resume: &Frame) -> WhisperResult<ResumeRequest> {
+        ResumeRequest::open_with_clock(keys, resume, clock::system())
+    }
+
+    /// Same as `open`, but expiry is checked on given clock, and resumed
+    /// session runs on it too.
+    pub fn open_with_clock(keys: &TicketKeys,
+                           resume: &Frame,
+                           clock: Arc<dyn Clock>)
+                           -> WhisperResult<ResumeRequest> {
+        if resume.kind != FrameKind::Resume {
+            return Err(WhisperError::WrongKind(FrameKind::Resume, resume.kind));
+        }
+        resume.validate()?;
+        let len = BigEndian::read_u16(&resume.payload[..2]) as usize;
+        if resume.payload.len() != 2 + len + hmacsha256::TAGBYTES {
+            return Err(WhisperError::InvalidTicket);
+        }
+        let ticket = &resume.payload[2..2 + len];
+        let tag = hmacsha256::Tag::from_slice(&resume.payload[2 + len..])
+            .ok_or(WhisperError::InvalidTicket)?;
+        let now = clock.now();
+        let state = keys.open_at(now, ticket)?;
+        if state.len() != STATE_SIZE {
+            return Err(WhisperError::InvalidTicket);
+        }
+        let (key, rest) = state.split_at(box_::PUBLICKEYBYTES);
+        let mut secret = Secret([0; SECRET_SIZE]);
+        secret.0.copy_from_slice(&rest[..SECRET_SIZE]);
+        let expires_at = BigEndian::read_i64(&rest[SECRET_SIZE..SECRET_SIZE + 8]);
+        if expires_at <= now.timestamp() ||
+           !constant_time_eq(&binder(&secret, &resume.id, &resume.nonce, ticket).0, &tag.0)
+        {
+            return Err(WhisperError::InvalidTicket);
+        }
+        Ok(ResumeRequest {
+               client_session_key: resume.id,
+               client_identity_key: PublicKey::from_slice(key).ok_or(WhisperError::InvalidTicket)?,
+               mode: TransportMode::from_slice(&rest[SECRET_SIZE + 8..])
+                   .ok_or(WhisperError::InvalidTicket)?,
+               secret,
+               clock,
+           })
+    }
+
+    /// Permanent key of client resuming. Decide whether it's still welcome
+    /// before `make_ready`.
+    pub fn client_identity_key(&self) -> PublicKey { self.client_identity_key }
+
+    /// Resumed session and Ready to send back.
+    pub fn make_ready(&self) -> WhisperResult<(EstablishedSession, Frame)> {
+        let local_session_keypair = KeyPair::new()?;
+        let local_key = local_session_keypair.public_key;
+        let mut session = EstablishedSession::with_clock(self.client_session_key,
+                                                         local_session_keypair,
+                                                         Side::Server,
+                                                         self.clock.clone());
+        session.bind_secret(&self.secret.0);
+        session.set_mode(self.mode);
+        session.set_peer_identity(self.client_identity_key);
+        let (nonce, sealed) = session.seal_msg(READY_PAYLOAD);
+        let mut payload = BytesMut::with_capacity(box_::PUBLICKEYBYTES + sealed.len());
+        payload.extend_from_slice(&local_key.0);
+        payload.extend_from_slice(&sealed);
+        let frame = Frame {
+            id: self.client_session_key,
+            nonce,
+            kind: FrameKind::Ready,
+            payload: payload.freeze(),
+        };
+        Ok((session, frame))
+    }
+}
+
+// HMAC of session id, nonce and ticket, keyed with resumption secret.
+fn binder(secret: &Secret, id: &PublicKey, nonce: &Nonce, ticket: &[u8]) -> hmacsha256::Tag {
+    let mut input = Vec::with_capacity(box_::PUBLICKEYBYTES + box_::NONCEBYTES + ticket.len());
+    input.extend_from_slice(&id.0);
+    input.extend_from_slice(&nonce.0);
+    input.extend_from_slice(ticket);
+    hmacsha256::authenticate(&input, &hmacsha256::Key(secret.0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::test::handshake_with;
+
+    #[test]
+    fn resume_in_one_round_trip() {
+        let client_identity = KeyPair::new().unwrap();
+        let server_identity = KeyPair::new().unwrap();
+        let (client, server) = handshake_with(client_identity.clone(), server_identity.clone());
+        let keys = TicketKeys::new(Duration::hours(1), Duration::hours(1)).unwrap();
+        let frame = issue(&keys, &server, Duration::hours(1)).unwrap();
+        assert!(issue(&keys, &client, Duration::hours(1)).is_err());
+        let ticket = ResumptionTicket::from_frame(&client, &frame).unwrap().unwrap();
+        assert_eq!(ticket.server_identity_key(), server_identity.public_key);
+
+        let (resuming, resume) = ticket.make_resume().unwrap();
+        let request = ResumeRequest::open(&keys, &resume).unwrap();
+        assert_eq!(request.client_identity_key(), client_identity.public_key);
+        let (server, ready) = request.make_ready().unwrap();
+        let client = resuming.read_ready(&ready).unwrap();
+        let ping = client.make_request(b"ping").unwrap();
+        assert_eq!(server.read_msg(&ping).unwrap().as_ref(), b"ping");
+
+        // Binder covers session id.
+        let mut forged = resume.clone();
+        forged.id = KeyPair::new().unwrap().public_key;
+        match ResumeRequest::open(&keys, &forged) {
+            Err(WhisperError::InvalidTicket) => {}
+            other => panic!("Expected InvalidTicket, got {:?}", other),
+        }
+        keys.retire_all();
+        assert!(ResumeRequest::open(&keys, &resume).is_err());
+    }
+
+    #[test]
+    fn ticket_must_fit_into_resume() {
+        let (client, server) = handshake_with(KeyPair::new().unwrap(), KeyPair::new().unwrap());
+        let mut payload = vec![RESUMPTION_TICKET];
+        payload.extend_from_slice(&[0x7f; 8]);
+        payload.resize(9 + u16::MAX as usize + 1, 0);
+        let frame = server.make_control(&payload).unwrap();
+        match ResumptionTicket::from_frame(&client, &frame) {
+            Err(WhisperError::BadFrame) => {}
+            other => panic!("Expected BadFrame, got {:?}", other.map(|_| ())),
+        }
+    }
+}
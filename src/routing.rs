@@ -0,0 +1,107 @@
+//! Routing hints for load balancers. Front end sees nothing but session id
+//! and ciphertext, so it can't tell which backend holds a session after
+//! the client reconnects or changes address. Server can hand client a
+//! short opaque hint in Ready (`ServerSession::set_routing_hint`), and
+//! from then on client puts it in the clear in front of every payload it
+//! sends: hint length (1 byte), then hint. Balancer reads it with `hint`.
+//!
+//! Server checks the hint against the one it assigned before opening the
+//! payload, so a frame with rewritten hint is refused with `BadFrame`.
+//!
+//! Hint is visible to anyone on the path and stays the same for the whole
+//! session. Don't put anything identifying the client in it — use backend
+//! id (ideally encrypted with key shared by balancer and backends) and
+//! pick new one for each session. Only client to server frames carry it.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+
+/// Longest hint in bytes.
+pub const HINT_MAX: usize = 16;
+
+/// Routing hint of a frame client sent in session with hints. None for
+/// handshake frames and frames without hint. Balancer has to know whether
+/// backends use hints: payload of other frames is ciphertext and may look
+/// like one.
+pub fn hint(frame: &Frame) -> Option<&[u8]> {
+    match frame.kind {
+        FrameKind::Hello | FrameKind::Welcome | FrameKind::Initiate | FrameKind::Ready |
+        FrameKind::Resume => None,
+        _ => split(&frame.payload).map(|(hint, _)| hint),
+    }
+}
+
+pub(crate) fn check(hint: &[u8]) -> WhisperResult<()> {
+    if hint.is_empty() || hint.len() > HINT_MAX {
+        return Err(WhisperError::BadFrame);
+    }
+    Ok(())
+}
+
+// Put hint in front of sealed payload.
+pub(crate) fn prefix(hint: &[u8], payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(1 + hint.len() + payload.len());
+    buf.put_u8(hint.len() as u8);
+    buf.extend_from_slice(hint);
+    buf.extend_from_slice(payload);
+    buf.freeze()
+}
+
+// Sealed payload behind expected hint.
+pub(crate) fn strip<'a>(expected: &[u8], payload: &'a [u8]) -> WhisperResult<&'a [u8]> {
+    match split(payload) {
+        Some((hint, rest)) if hint == expected => Ok(rest),
+        _ => Err(WhisperError::BadFrame),
+    }
+}
+
+fn split(payload: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = *payload.first()? as usize;
+    if len == 0 || len > HINT_MAX || payload.len() < 1 + len {
+        return None;
+    }
+    Some((&payload[1..1 + len], &payload[1 + len..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::KeyPair;
+    use session::{ClientSession, ServerSession, Session};
+
+    #[test]
+    fn hint_echoed_by_client() {
+        let server_identity = KeyPair::new().unwrap();
+        let mut client =
+            ClientSession::new(KeyPair::new().unwrap(), server_identity.public_key).unwrap();
+        let mut server = ServerSession::new(server_identity, client.id()).unwrap();
+        assert!(server.set_routing_hint(vec![0u8; HINT_MAX + 1]).is_err());
+        server.set_routing_hint(&b"backend-7"[..]).unwrap();
+        let welcome = server.make_welcome(&client.make_hello().unwrap()).unwrap();
+        let initiate = client.make_initiate(&welcome).unwrap();
+        let client_key = server.validate_initiate(&initiate).unwrap();
+        let (server, ready) = server.make_ready(&initiate, &client_key).unwrap();
+        let client = client.read_ready(&ready).unwrap();
+        assert_eq!(hint(&ready), None);
+        assert_eq!(client.routing_hint(), Some(&b"backend-7"[..]));
+        assert_eq!(server.routing_hint(), Some(&b"backend-7"[..]));
+
+        let request = client.make_request(b"ping").unwrap();
+        assert_eq!(hint(&request), Some(&b"backend-7"[..]));
+        assert_eq!(server.read_msg(&request).unwrap().as_ref(), b"ping");
+        // Server doesn't echo it.
+        let response = server.make_response(b"pong").unwrap();
+        assert_eq!(client.read_msg(&response).unwrap().as_ref(), b"pong");
+
+        let mut rewritten = request.clone();
+        let mut payload = rewritten.payload.to_vec();
+        payload[1] = b'B';
+        rewritten.payload = payload.into();
+        assert!(hint(&rewritten).is_some());
+        match server.read_msg(&rewritten) {
+            Err(WhisperError::BadFrame) => {}
+            other => panic!("Expected BadFrame, got {:?}", other),
+        }
+    }
+}
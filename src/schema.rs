@@ -0,0 +1,186 @@
+//! This module generates machine readable description of the wire format.
+//! Description is built from the same constants frame module uses, so it
+//! can't drift from the code. Meant for implementers in other languages and
+//! analysis tools like Wireshark.
+
+use frame::{FRAME_KINDS, HEADER_SIZE};
+use session::{CLIENT_TRANSITIONS, SERVER_TRANSITIONS, Transition};
+use sodiumoxide::crypto::box_::{NONCEBYTES, PUBLICKEYBYTES};
+use std::fmt::Write;
+
+/// Name of the protocol in Wireshark filters.
+pub static WIRESHARK_PROTO: &str = "whisper";
+
+/// Kaitai Struct id of the frame type.
+pub static KAITAI_ID: &str = "angel_whisper_frame";
+
+/// Emit [Kaitai Struct](http://kaitai.io) description of a single frame.
+pub fn kaitai() -> String {
+    let mut ksy = String::new();
+    ksy.push_str("meta:\n");
+    let _ = writeln!(ksy, "  id: {}", KAITAI_ID);
+    ksy.push_str("  title: Angel Whisper frame\n");
+    ksy.push_str("  endian: be\n");
+    let _ = writeln!(ksy, "doc: Frame header is {} bytes followed by payload.", HEADER_SIZE);
+    ksy.push_str("seq:\n");
+    ksy.push_str("  - id: session_id\n");
+    let _ = writeln!(ksy, "    size: {}", PUBLICKEYBYTES);
+    ksy.push_str("    doc: Client short term public key.\n");
+    ksy.push_str("  - id: nonce\n");
+    let _ = writeln!(ksy, "    size: {}", NONCEBYTES);
+    ksy.push_str("  - id: kind\n");
+    ksy.push_str("    type: u1\n");
+    ksy.push_str("    enum: frame_kind\n");
+    ksy.push_str("  - id: payload\n");
+    ksy.push_str("    size-eos: true\n");
+    ksy.push_str("enums:\n");
+    ksy.push_str("  frame_kind:\n");
+    for kind in FRAME_KINDS.iter() {
+        let _ = writeln!(ksy, "    {}: {}", *kind as u8, kind.name());
+    }
+    ksy
+}
+
+/// Emit Lua dissector for Wireshark. Dissector decodes frame header (id,
+/// nonce, kind and payload length) and registers itself on given UDP and TCP
+/// port. It expects one frame per packet. Payload is shown as is — decryption
+/// isn't done since there is no key log to take session secrets from.
+pub fn wireshark_dissector(port: u16) -> String {
+    let id_end = PUBLICKEYBYTES;
+    let nonce_end = id_end + NONCEBYTES;
+    let mut lua = String::new();
+    lua.push_str("-- Angel Whisper dissector. Generated by libwhisper, do not edit.\n");
+    let _ = writeln!(lua,
+                     "local whisper = Proto(\"{}\", \"Angel Whisper\")",
+                     WIRESHARK_PROTO);
+    lua.push_str("local kinds = {\n");
+    for kind in FRAME_KINDS.iter() {
+        let _ = writeln!(lua, "  [{}] = \"{:?}\",", *kind as u8, kind);
+    }
+    lua.push_str("}\n");
+    let _ = writeln!(lua,
+                     "local f_id = ProtoField.bytes(\"{}.id\", \"Session ID\")",
+                     WIRESHARK_PROTO);
+    let _ = writeln!(lua,
+                     "local f_nonce = ProtoField.bytes(\"{}.nonce\", \"Nonce\")",
+                     WIRESHARK_PROTO);
+    let _ = writeln!(lua,
+                     "local f_kind = ProtoField.uint8(\"{}.kind\", \"Kind\", base.DEC, kinds)",
+                     WIRESHARK_PROTO);
+    let _ = writeln!(lua,
+                     "local f_len = ProtoField.uint32(\"{}.payload_length\", \"Payload length\")",
+                     WIRESHARK_PROTO);
+    let _ = writeln!(lua,
+                     "local f_payload = ProtoField.bytes(\"{}.payload\", \"Payload\")",
+                     WIRESHARK_PROTO);
+    lua.push_str("whisper.fields = { f_id, f_nonce, f_kind, f_len, f_payload }\n\n");
+    lua.push_str("function whisper.dissector(buffer, pinfo, tree)\n");
+    let _ = writeln!(lua, "  if buffer:len() < {} then return 0 end", HEADER_SIZE);
+    lua.push_str("  pinfo.cols.protocol = \"WHISPER\"\n");
+    lua.push_str("  local subtree = tree:add(whisper, buffer(), \"Angel Whisper\")\n");
+    let _ = writeln!(lua, "  subtree:add(f_id, buffer(0, {}))", id_end);
+    let _ = writeln!(lua, "  subtree:add(f_nonce, buffer({}, {}))", id_end, NONCEBYTES);
+    let _ = writeln!(lua, "  local kind = buffer({}, 1):uint()", nonce_end);
+    let _ = writeln!(lua, "  subtree:add(f_kind, buffer({}, 1))", nonce_end);
+    let _ = writeln!(lua, "  local payload_len = buffer:len() - {}", HEADER_SIZE);
+    lua.push_str("  subtree:add(f_len, payload_len)\n");
+    let _ = writeln!(lua,
+                     "  if payload_len > 0 then subtree:add(f_payload, buffer({})) end",
+                     HEADER_SIZE);
+    lua.push_str("  pinfo.cols.info = kinds[kind] or \"Unknown\"\n");
+    lua.push_str("  return buffer:len()\n");
+    lua.push_str("end\n\n");
+    let _ = writeln!(lua, "DissectorTable.get(\"udp.port\"):add({}, whisper)", port);
+    let _ = writeln!(lua, "DissectorTable.get(\"tcp.port\"):add({}, whisper)", port);
+    lua
+}
+
+/// Emit client and server handshake state machines as Graphviz DOT. Graph
+/// is built from the transition tables session methods check their input
+/// against. Dashed red edges lead to the state session ends up in when a
+/// step fails. Render with `dot -Tsvg`.
+pub fn state_machine_dot() -> String {
+    let mut dot = String::new();
+    dot.push_str("// Angel Whisper handshake. Generated by libwhisper, do not edit.\n");
+    dot.push_str("digraph whisper {\n");
+    dot.push_str("  rankdir=LR;\n");
+    write_cluster(&mut dot, "client", &CLIENT_TRANSITIONS);
+    write_cluster(&mut dot, "server", &SERVER_TRANSITIONS);
+    dot.push_str("}\n");
+    dot
+}
+
+fn write_cluster(dot: &mut String, side: &str, transitions: &[&Transition]) {
+    let _ = writeln!(dot, "  subgraph cluster_{} {{", side);
+    let _ = writeln!(dot, "    label=\"{}\";", side);
+    for transition in transitions {
+        let mut label = transition.method.to_string();
+        if let Some(input) = transition.input {
+            let _ = write!(label, "\\nin: {}", input.name());
+        }
+        if let Some(output) = transition.output {
+            let _ = write!(label, "\\nout: {}", output.name());
+        }
+        let _ = writeln!(dot,
+                         "    {side}_{:?} -> {side}_{:?} [label=\"{}\"];",
+                         transition.from,
+                         transition.to,
+                         label,
+                         side = side);
+        if let Some(on_error) = transition.on_error {
+            let _ = writeln!(dot,
+                             "    {side}_{:?} -> {side}_{:?} [label=\"{}\", style=dashed, color=red];",
+                             transition.from,
+                             on_error,
+                             transition.method,
+                             side = side);
+        }
+    }
+    dot.push_str("  }\n");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use frame::FrameKind;
+
+    #[test]
+    fn header_matches_frame() {
+        assert_eq!(PUBLICKEYBYTES + NONCEBYTES + 1, HEADER_SIZE);
+    }
+
+    #[test]
+    fn kaitai_has_every_kind() {
+        let ksy = kaitai();
+        assert!(ksy.contains("  id: angel_whisper_frame\n"));
+        assert!(ksy.contains("    size: 32\n"));
+        assert!(ksy.contains("    size: 24\n"));
+        for kind in FRAME_KINDS.iter() {
+            let line = format!("    {}: {}\n", *kind as u8, kind.name());
+            assert!(ksy.contains(&line));
+            assert_eq!(FrameKind::from(*kind as u8), Some(*kind));
+        }
+        assert!(ksy.contains("    255: termination\n"));
+    }
+
+    #[test]
+    fn dissector_has_header_offsets() {
+        let lua = wireshark_dissector(4242);
+        assert!(lua.contains("  if buffer:len() < 57 then return 0 end\n"));
+        assert!(lua.contains("  subtree:add(f_nonce, buffer(32, 24))\n"));
+        assert!(lua.contains("  local kind = buffer(56, 1):uint()\n"));
+        assert!(lua.contains("  [255] = \"Termination\",\n"));
+        assert!(lua.contains("DissectorTable.get(\"udp.port\"):add(4242, whisper)\n"));
+    }
+
+    #[test]
+    fn dot_has_handshake_edges() {
+        let dot = state_machine_dot();
+        assert!(dot.starts_with("// Angel Whisper"));
+        assert!(dot.contains("    client_Fresh -> client_Initiated [label=\"make_hello\\nout: hello\"];\n"));
+        assert!(dot.contains("    server_Initiated -> server_Ready [label=\"make_ready\\nin: initiate\\nout: ready\"];\n"));
+        assert!(dot.contains("    server_Initiated -> server_Error [label=\"make_ready\", style=dashed, color=red];\n"));
+        assert_eq!(dot.matches(" -> ").count(),
+                   CLIENT_TRANSITIONS.len() + SERVER_TRANSITIONS.len() + 3);
+    }
+}
@@ -0,0 +1,207 @@
+//! serde support (behind `serde` feature) for `Frame`, `FrameKind` and
+//! `KeyPair`, for logging frames, keeping them in queues and debugging
+//! tools.
+//!
+//! Byte fields are written with `serialize_bytes`, so binary formats (CBOR,
+//! bincode) store them as they are and JSON gets array of numbers. Both are
+//! accepted back. `FrameKind` is its wire byte. `Frame` is a struct of
+//! `id`, `nonce`, `kind` and `payload`, session id and nonce as raw bytes;
+//! its payload is whatever is on the wire, so sealed frames stay sealed.
+//!
+//! `KeyPair` is serialized as its public key only, so it's safe to log, and
+//! can't be deserialized. Wrap it in `RevealedKeyPair` to write the secret
+//! key too (secret key followed by public key, same as
+//! `KeyEncoding::to_bytes`) and read it back.
+
+use bytes::Bytes;
+use crypto::KeyPair;
+use frame::{Frame, FrameKind};
+use keyfile::KeyEncoding;
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use sodiumoxide::crypto::box_::{Nonce, PublicKey};
+use sodiumoxide::utils::memzero;
+use std::fmt;
+
+const FRAME_FIELDS: &[&str] = &["id", "nonce", "kind", "payload"];
+
+// Byte slice that goes out with `serialize_bytes`.
+struct RawBytes<'a>(&'a [u8]);
+
+impl<'a> Serialize for RawBytes<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+// Bytes that came in as byte string or sequence of numbers.
+struct ByteBuf(Vec<u8>);
+
+struct ByteBufVisitor;
+
+impl<'de> Visitor<'de> for ByteBufVisitor {
+    type Value = ByteBuf;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("bytes") }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<ByteBuf, E> {
+        Ok(ByteBuf(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<ByteBuf, E> {
+        Ok(ByteBuf(bytes))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ByteBuf, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(ByteBuf(bytes))
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ByteBuf, D::Error> {
+        deserializer.deserialize_bytes(ByteBufVisitor)
+    }
+}
+
+impl Serialize for FrameKind {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> Deserialize<'de> for FrameKind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<FrameKind, D::Error> {
+        let kind = u8::deserialize(deserializer)?;
+        FrameKind::from(kind).ok_or_else(|| {
+            de::Error::invalid_value(de::Unexpected::Unsigned(u64::from(kind)), &"frame kind")
+        })
+    }
+}
+
+impl Serialize for Frame {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut frame = serializer.serialize_struct("Frame", FRAME_FIELDS.len())?;
+        frame.serialize_field("id", &RawBytes(&self.id.0))?;
+        frame.serialize_field("nonce", &RawBytes(&self.nonce.0))?;
+        frame.serialize_field("kind", &self.kind)?;
+        frame.serialize_field("payload", &RawBytes(&self.payload))?;
+        frame.end()
+    }
+}
+
+struct FrameVisitor;
+
+impl FrameVisitor {
+    fn build<E: de::Error>(id: ByteBuf,
+                           nonce: ByteBuf,
+                           kind: FrameKind,
+                           payload: ByteBuf)
+                           -> Result<Frame, E> {
+        let id = PublicKey::from_slice(&id.0)
+            .ok_or_else(|| E::invalid_length(id.0.len(), &"32 bytes"))?;
+        let nonce = Nonce::from_slice(&nonce.0)
+            .ok_or_else(|| E::invalid_length(nonce.0.len(), &"24 bytes"))?;
+        Ok(Frame {
+               id,
+               nonce,
+               kind,
+               payload: Bytes::from(payload.0),
+           })
+    }
+}
+
+impl<'de> Visitor<'de> for FrameVisitor {
+    type Value = Frame;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("struct Frame") }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Frame, A::Error> {
+        let id = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let nonce = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        let kind = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(2, &self))?;
+        let payload = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(3, &self))?;
+        FrameVisitor::build(id, nonce, kind, payload)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Frame, A::Error> {
+        let (mut id, mut nonce, mut kind, mut payload) = (None, None, None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "id" => id = Some(map.next_value()?),
+                "nonce" => nonce = Some(map.next_value()?),
+                "kind" => kind = Some(map.next_value()?),
+                "payload" => payload = Some(map.next_value()?),
+                other => return Err(de::Error::unknown_field(other, FRAME_FIELDS)),
+            }
+        }
+        FrameVisitor::build(id.ok_or_else(|| de::Error::missing_field("id"))?,
+                            nonce.ok_or_else(|| de::Error::missing_field("nonce"))?,
+                            kind.ok_or_else(|| de::Error::missing_field("kind"))?,
+                            payload.ok_or_else(|| de::Error::missing_field("payload"))?)
+    }
+}
+
+impl<'de> Deserialize<'de> for Frame {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Frame, D::Error> {
+        deserializer.deserialize_struct("Frame", FRAME_FIELDS, FrameVisitor)
+    }
+}
+
+impl Serialize for KeyPair {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.public_key.0)
+    }
+}
+
+/// `KeyPair` that serializes with its secret key, for key stores. Never
+/// log it.
+pub struct RevealedKeyPair(pub KeyPair);
+
+impl Serialize for RevealedKeyPair {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0.to_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for RevealedKeyPair {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<RevealedKeyPair, D::Error> {
+        let mut bytes = ByteBuf::deserialize(deserializer)?;
+        let keypair = KeyPair::from_bytes(&bytes.0).map_err(de::Error::custom);
+        memzero(&mut bytes.0);
+        keypair.map(RevealedKeyPair)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::test::handshake;
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn frames_survive_json() {
+        let (client, server) = handshake();
+        let request = client.make_request(b"to the log").unwrap();
+        let json = ::serde_json::to_string(&request).unwrap();
+        let restored: Frame = ::serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, request);
+        assert_eq!(server.read_msg(&restored).unwrap().as_ref(), b"to the log");
+
+        assert_eq!(::serde_json::to_string(&FrameKind::Request).unwrap(), "5");
+        assert!(::serde_json::from_str::<FrameKind>("42").is_err());
+        let long_id = json.replacen("\"id\":[", "\"id\":[1,", 1);
+        assert!(::serde_json::from_str::<Frame>(&long_id).is_err());
+
+        let keypair = KeyPair::new().unwrap();
+        let public: Vec<u8> = ::serde_json::from_str(&::serde_json::to_string(&keypair).unwrap())
+            .unwrap();
+        assert_eq!(&public[..], &keypair.public_key.0[..]);
+        let json = ::serde_json::to_string(&RevealedKeyPair(keypair.clone())).unwrap();
+        let restored: RevealedKeyPair = ::serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.0.secret_key, keypair.secret_key);
+    }
+}
@@ -0,0 +1,276 @@
+//! Server wide state shared by all server sessions of one process.
+//!
+//! ### Identity rotation
+//! `ServerIdentity` holds identity keypair behind a lock, so it can be
+//! swapped at runtime. Every `ServerSession` takes a copy of the keypair
+//! when it's created, so handshakes that already passed Hello finish under
+//! the key they started with. Previous key is kept around until
+//! `retire_previous` is called, and Hello frames sealed to it still get a
+//! session — this covers clients that sent Hello right before rotation or
+//! haven't picked up new key yet.
+//!
+//! ### Tenants
+//! One listener can serve several identities. `Tenants` keeps them by
+//! service name and picks the right one from the name hint client sealed
+//! to the outer key (see `ClientSession::set_service_name`).
+//!
+//! ### Draining
+//! `DrainSwitch` lets gateway stop taking new clients before restart. While
+//! draining, frames that would start a session are answered with
+//! `RetryLater` Termination. Established sessions aren't touched and run
+//! until they expire.
+
+use crypto::KeyPair;
+use errors::{WhisperError, WhisperResult};
+use frame::Frame;
+use session::{self, ServerSession, SessionConfig};
+use sodiumoxide::crypto::box_::PublicKey;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use termination::TerminationReason;
+
+#[derive(Debug)]
+struct Keys {
+    current: KeyPair,
+    previous: Option<KeyPair>,
+}
+
+/// Identity keypair of a server that can be rotated without dropping
+/// clients. Cheap to clone, clones share keys.
+#[derive(Debug, Clone)]
+pub struct ServerIdentity {
+    keys: Arc<RwLock<Keys>>,
+}
+
+impl ServerIdentity {
+    /// Start with given keypair.
+    pub fn new(keypair: KeyPair) -> ServerIdentity {
+        ServerIdentity {
+            keys: Arc::new(RwLock::new(Keys {
+                                           current: keypair,
+                                           previous: None,
+                                       })),
+        }
+    }
+
+    /// Current keypair.
+    pub fn keypair(&self) -> KeyPair {
+        self.keys.read().expect("Identity lock poisoned").current.clone()
+    }
+
+    /// Current public key. This is what new clients should be given.
+    pub fn public_key(&self) -> PublicKey { self.keypair().public_key }
+
+    /// Replace identity keypair. Old one becomes previous and keeps working
+    /// for Hello frames until `retire_previous`. Key that was previous before
+    /// this call is forgotten.
+    pub fn rotate(&self, keypair: KeyPair) {
+        let mut keys = self.keys.write().expect("Identity lock poisoned");
+        let old = ::std::mem::replace(&mut keys.current, keypair);
+        keys.previous = Some(old);
+    }
+
+    /// Stop accepting Hello frames sealed to previous key.
+    pub fn retire_previous(&self) {
+        self.keys.write().expect("Identity lock poisoned").previous = None;
+    }
+
+    /// Create session for the client that sent this Hello. Session uses the
+    /// key Hello was sealed to: current one, or previous one if it's still
+    /// around. Hello is opened once per key tried, and fails the same way
+    /// `ServerSession::from_hello` does if neither opens it.
+    pub fn session_for_hello(&self, hello: &Frame) -> WhisperResult<ServerSession> {
+        self.session_for_hello_with_config(hello, SessionConfig::default())
+    }
+
+    /// Same as `session_for_hello`, but with tuned protocol parameters.
+    pub fn session_for_hello_with_config(&self,
+                                         hello: &Frame,
+                                         config: SessionConfig)
+                                         -> WhisperResult<ServerSession> {
+        let keys = self.keys.read().expect("Identity lock poisoned");
+        match (ServerSession::from_hello_with_config(keys.current.clone(), hello, config),
+               &keys.previous) {
+            (Err(WhisperError::DecryptionFailed), &Some(ref previous)) => {
+                ServerSession::from_hello_with_config(previous.clone(), hello, config)
+            }
+            (result, _) => result,
+        }
+    }
+}
+
+/// Identities of all tenants served by one listener, by service name.
+#[derive(Debug, Clone)]
+pub struct Tenants {
+    outer: KeyPair,
+    identities: HashMap<String, ServerIdentity>,
+}
+
+impl Tenants {
+    /// No tenants yet. Outer keypair is only used to open service name
+    /// hints; its public key is given to clients with tenant keys.
+    pub fn new(outer: KeyPair) -> Tenants {
+        Tenants {
+            outer,
+            identities: HashMap::new(),
+        }
+    }
+
+    /// Outer public key.
+    pub fn outer_key(&self) -> PublicKey { self.outer.public_key }
+
+    /// Serve tenant under this name. Replaces identity added under the same
+    /// name before.
+    pub fn add<S: Into<String>>(&mut self, name: S, identity: ServerIdentity) {
+        self.identities.insert(name.into(), identity);
+    }
+
+    /// Tenant identity by name.
+    pub fn get(&self, name: &str) -> Option<&ServerIdentity> { self.identities.get(name) }
+
+    /// Create session for the client that sent this Hello using identity of
+    /// tenant named in it. Hello without hint or with unknown name is
+    /// `InvalidHelloFrame`.
+    pub fn session_for_hello(&self, hello: &Frame) -> WhisperResult<ServerSession> {
+        self.session_for_hello_with_config(hello, SessionConfig::default())
+    }
+
+    /// Same as `session_for_hello`, but with tuned protocol parameters.
+    pub fn session_for_hello_with_config(&self,
+                                         hello: &Frame,
+                                         config: SessionConfig)
+                                         -> WhisperResult<ServerSession> {
+        let name = session::read_service_name(hello, &self.outer)?
+            .ok_or(WhisperError::InvalidHelloFrame)?;
+        let identity = self.identities.get(&name).ok_or(WhisperError::InvalidHelloFrame)?;
+        identity.session_for_hello_with_config(hello, config)
+    }
+}
+
+/// Server wide switch that refuses new sessions. Cheap to clone, clones
+/// share state.
+#[derive(Debug, Clone, Default)]
+pub struct DrainSwitch {
+    draining: Arc<AtomicBool>,
+}
+
+impl DrainSwitch {
+    /// Switch that isn't draining.
+    pub fn new() -> DrainSwitch { DrainSwitch::default() }
+
+    /// Stop accepting new sessions.
+    pub fn drain(&self) { self.draining.store(true, Ordering::SeqCst) }
+
+    /// Accept new sessions again.
+    pub fn resume(&self) { self.draining.store(false, Ordering::SeqCst) }
+
+    /// Returns true if new sessions are refused.
+    pub fn is_draining(&self) -> bool { self.draining.load(Ordering::SeqCst) }
+
+    /// Call with every frame that would create a new server session (Hello,
+    /// or Initiate for abbreviated handshake). Returns Termination to send
+    /// back instead of creating the session if draining.
+    pub fn refuse(&self, frame: &Frame) -> WhisperResult<Option<Frame>> {
+        if self.is_draining() {
+            TerminationReason::RetryLater.to_frame(frame.id).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use frame::FrameKind;
+    use session::ClientSession;
+    use session::test::handshake;
+
+    #[test]
+    fn rotation_keeps_handshakes() {
+        let old = KeyPair::new().unwrap();
+        let identity = ServerIdentity::new(old.clone());
+
+        // Handshake in flight while key is rotated.
+        let mut in_flight =
+            ClientSession::new(KeyPair::new().unwrap(), identity.public_key()).unwrap();
+        let hello = in_flight.make_hello().unwrap();
+        let mut server_session = identity.session_for_hello(&hello).unwrap();
+        identity.rotate(KeyPair::new().unwrap());
+        let welcome = server_session.make_welcome(&hello).unwrap();
+        assert!(in_flight.make_initiate(&welcome).is_ok());
+
+        // Client that still has old key.
+        let mut late = ClientSession::new(KeyPair::new().unwrap(), old.public_key).unwrap();
+        let hello = late.make_hello().unwrap();
+        let welcome = identity.session_for_hello(&hello).unwrap().make_welcome(&hello).unwrap();
+        assert!(late.make_initiate(&welcome).is_ok());
+
+        // New clients use new key.
+        assert!(identity.public_key() != old.public_key);
+        let mut fresh = ClientSession::new(KeyPair::new().unwrap(), identity.public_key()).unwrap();
+        let hello = fresh.make_hello().unwrap();
+        let welcome = identity.session_for_hello(&hello).unwrap().make_welcome(&hello).unwrap();
+        assert!(fresh.make_initiate(&welcome).is_ok());
+
+        // Until old key is retired.
+        identity.retire_previous();
+        let mut late = ClientSession::new(KeyPair::new().unwrap(), old.public_key).unwrap();
+        let hello = late.make_hello().unwrap();
+        match identity.session_for_hello(&hello) {
+            Err(WhisperError::DecryptionFailed) => {}
+            other => panic!("Expected DecryptionFailed, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn tenant_picked_by_hint() {
+        let mut tenants = Tenants::new(KeyPair::new().unwrap());
+        let alpha = KeyPair::new().unwrap();
+        let beta = KeyPair::new().unwrap();
+        tenants.add("alpha", ServerIdentity::new(alpha.clone()));
+        tenants.add("beta", ServerIdentity::new(beta.clone()));
+
+        let mut client = ClientSession::new(KeyPair::new().unwrap(), beta.public_key).unwrap();
+        client.set_service_name(tenants.outer_key(), "beta").unwrap();
+        let hello = client.make_hello().unwrap();
+        let welcome = tenants.session_for_hello(&hello).unwrap().make_welcome(&hello).unwrap();
+        assert!(client.make_initiate(&welcome).is_ok());
+
+        // Hint length doesn't depend on the name.
+        let mut other = ClientSession::new(KeyPair::new().unwrap(), alpha.public_key).unwrap();
+        other.set_service_name(tenants.outer_key(), "a").unwrap();
+        assert_eq!(other.make_hello().unwrap().payload.len(), hello.payload.len());
+
+        let mut unknown = ClientSession::new(KeyPair::new().unwrap(), beta.public_key).unwrap();
+        unknown.set_service_name(tenants.outer_key(), "gamma").unwrap();
+        assert!(tenants.session_for_hello(&unknown.make_hello().unwrap()).is_err());
+        let mut plain = ClientSession::new(KeyPair::new().unwrap(), beta.public_key).unwrap();
+        assert!(tenants.session_for_hello(&plain.make_hello().unwrap()).is_err());
+        assert!(client.set_service_name(tenants.outer_key(), &"x".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn drain_refuses_only_new_sessions() {
+        let switch = DrainSwitch::new();
+        let (client, server) = handshake();
+        let mut newcomer = ClientSession::new(KeyPair::new().unwrap(),
+                                              KeyPair::new().unwrap().public_key)
+            .unwrap();
+        let hello = newcomer.make_hello().unwrap();
+        assert!(switch.refuse(&hello).unwrap().is_none());
+
+        switch.clone().drain();
+        let termination = switch.refuse(&hello).unwrap().unwrap();
+        assert_eq!(termination.kind, FrameKind::Termination);
+        assert_eq!(termination.id, hello.id);
+        assert_eq!(TerminationReason::from_frame(&termination),
+                   Some(TerminationReason::RetryLater));
+        let ping = client.make_request(b"ping").unwrap();
+        assert!(server.read_msg(&ping).is_ok());
+
+        switch.resume();
+        assert!(switch.refuse(&hello).unwrap().is_none());
+    }
+}
@@ -16,32 +16,187 @@
 //! 4. Server verifies that client is allowed to talk to this server and
 //! replies with Ready or Terminate frame
 //!
+//! ### Service name hint
+//! Endpoint that serves several tenants (each with its own identity key)
+//! publishes one more key — outer key. Client that knows it can append
+//! service name sealed to the outer key to its Hello, so listener knows
+//! which tenant identity to use without trying all of them. Hint has fixed
+//! size, so name length doesn't leak. See `server::Tenants`.
+//!
 //! ### Messages
 //! The protocol allows bi-directorial message exchange. However,
 //! implementation of that is not part of the protocol.
 
 
-use bytes::Bytes;
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
 use chrono::{DateTime, Duration};
-use chrono::offset::Utc;
+use chrono::offset::{TimeZone, Utc};
 use errors::{WhisperError, WhisperResult};
+use sodiumoxide;
 use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::hash::sha256;
 use sodiumoxide::crypto::box_::{Nonce, PrecomputedKey, PublicKey};
+use sodiumoxide::crypto::sign;
+use std::fmt;
+use std::num::NonZeroU8;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
 
-use frame::{Frame, FrameKind};
-use crypto::KeyPair;
+use attestation::{self, AttestationVerifier, Attester};
+use auth::{AuthDecision, Authenticator};
+use clock::{self, Clock, SharedClock};
+#[cfg(feature = "compression")]
+use compression;
+use devices::DeviceCertificate;
+use digest::{self, Fingerprint};
+use elligator;
+#[cfg(feature = "faults")]
+use faults::Faults;
+use fragment;
+use liveness;
+use frame::{Frame, FrameKind, HEADER_SIZE};
+use crypto::{self, KeyPair, KeyValidity, SecureBytes};
+use metadata::{self, Metadata};
+use pacing::Pacer;
+use routing;
+use status::{self, Status};
+use suite::{self, CipherSuite};
+use termination::{SharedTerminationSink, TerminationReason, TerminationSink};
+use tracker::{self, RequestId};
+use transport::{self, ReplayWindow, TransportMode};
+use vouch::{SignedVouch, SigningIdentity, VouchKeys};
 
 /// Array of null bytes used in Hello package. Needs to be bigger than Welcome
 /// frame to prevent amplification attacks. Maybe, 256 is too much...who knows?
 pub static NULL_BYTES: [u8; 256] = [b'\x00'; 256];
-/// Payload "server" side supposed to send to client when.
+/// Payload "server" side supposed to send to client when. Followed by
+/// metadata.
 pub static READY_PAYLOAD: &'static [u8; 16] = b"My body is ready";
 
+/// Size of boxed null bytes in Hello frame with default padding.
+pub static HELLO_BOX_SIZE: usize = 272;
+/// Hello padding must be a multiple of this, so its size says nothing
+/// besides that.
+pub static HELLO_PADDING_STEP: usize = 64;
+/// Largest Hello padding. Smallest is `NULL_BYTES` length.
+pub static HELLO_PADDING_MAX: usize = 1024;
+/// Longest service name that fits into Hello.
+pub static SERVICE_NAME_MAX: usize = 64;
+/// Size of sealed service name appended to Hello: length byte, name padded
+/// to `SERVICE_NAME_MAX` and MAC.
+pub static SERVICE_HINT_SIZE: usize = 81;
+
+/// Size of Initiate box without metadata and early data: client identity key
+/// (32 bytes), vouch nonce (24 bytes), vouch box (48 bytes) and metadata length
+/// as u16 BigEndian (2 bytes).
+pub static INITIATE_BOX_SIZE: usize = 106;
+// Offsets of vouch nonce, vouch box and metadata length in Initiate box.
+const INITIATE_VOUCH_NONCE_AT: usize = 32;
+const INITIATE_VOUCH_AT: usize = 56;
+const INITIATE_METADATA_LEN_AT: usize = 104;
+
 /// How much time client and server have to agree on shared secret.
 pub static HANDSHAKE_DURATION: i64 = 3;
 /// How much time one shared secret can last.
 pub static SESSION_DURATION: i64 = 55;
 
+/// Protocol parameters for deployments where defaults don't fit: long
+/// handshakes over slow radio links, short sessions for rekeying often,
+/// bigger Hello against amplification on links with big Welcome. Both sides
+/// should use the same values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionConfig {
+    /// How long handshake may take. `HANDSHAKE_DURATION` minutes by default.
+    pub handshake_timeout: Duration,
+    /// How long established session lasts. `SESSION_DURATION` minutes by
+    /// default.
+    pub session_lifetime: Duration,
+    /// Null bytes client seals into Hello. Multiple of `HELLO_PADDING_STEP`
+    /// between `NULL_BYTES` length (the default) and `HELLO_PADDING_MAX`.
+    /// Server takes any valid padding.
+    pub hello_padding: usize,
+    /// What Ready starts with, `READY_PAYLOAD` by default. At least as long
+    /// as `READY_PAYLOAD`.
+    pub ready_payload: &'static [u8],
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig {
+            handshake_timeout: Duration::minutes(HANDSHAKE_DURATION),
+            session_lifetime: Duration::minutes(SESSION_DURATION),
+            hello_padding: NULL_BYTES.len(),
+            ready_payload: READY_PAYLOAD,
+        }
+    }
+}
+
+impl SessionConfig {
+    fn check(&self) -> WhisperResult<()> {
+        if !valid_hello_padding(self.hello_padding) {
+            return Err(WhisperError::InvalidHelloFrame);
+        }
+        if self.ready_payload.len() < READY_PAYLOAD.len() {
+            return Err(WhisperError::InvalidReadyFrame);
+        }
+        Ok(())
+    }
+}
+
+/// Handshake phase, named after the frame being awaited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakePhase {
+    /// Client sent Hello and waits for Welcome.
+    Welcome,
+    /// Server sent Welcome and waits for Initiate.
+    Initiate,
+    /// Client sent Initiate and waits for Ready.
+    Ready,
+}
+
+/// How long each handshake phase may take. Unless set, only whole handshake
+/// is limited by `HANDSHAKE_DURATION`. Slow lossy links may want longer
+/// phases that need retransmits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeDeadlines {
+    /// Deadline for Welcome.
+    pub welcome: Duration,
+    /// Deadline for Initiate.
+    pub initiate: Duration,
+    /// Deadline for Ready.
+    pub ready: Duration,
+}
+
+impl Default for HandshakeDeadlines {
+    fn default() -> HandshakeDeadlines {
+        HandshakeDeadlines {
+            welcome: Duration::minutes(HANDSHAKE_DURATION),
+            initiate: Duration::minutes(HANDSHAKE_DURATION),
+            ready: Duration::minutes(HANDSHAKE_DURATION),
+        }
+    }
+}
+
+impl HandshakeDeadlines {
+    fn check(&self,
+             phase: HandshakePhase,
+             started: DateTime<Utc>,
+             now: DateTime<Utc>)
+             -> WhisperResult<()> {
+        let deadline = match phase {
+            HandshakePhase::Welcome => self.welcome,
+            HandshakePhase::Initiate => self.initiate,
+            HandshakePhase::Ready => self.ready,
+        };
+        if now.signed_duration_since(started) > deadline {
+            Err(WhisperError::HandshakeTimeout(phase))
+        } else {
+            Ok(())
+        }
+    }
+}
+
 /// Enum representing session state.
 #[derive(Debug, Clone, PartialEq, Copy)]
 pub enum SessionState {
@@ -55,10 +210,243 @@ pub enum SessionState {
     /// This state means that session established, but can't be used at the
     /// time.
     Error,
+    /// Session was closed with `EstablishedSession::close`. Nothing can be
+    /// sent anymore.
+    Closed,
+    /// Peer sent Termination instead of next handshake frame.
+    Terminated,
+}
+
+/// Snapshot of session details for dashboards and admin APIs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionInfo {
+    /// Session id.
+    pub id: PublicKey,
+    /// Handshake state. Always `Ready` for established sessions.
+    pub state: SessionState,
+    /// Our end of the session.
+    pub side: Side,
+    /// Fingerprint of peer identity key, if known yet.
+    pub peer_fingerprint: Option<Fingerprint>,
+    /// When session was created.
+    pub created_at: DateTime<Utc>,
+    /// When session expires.
+    pub expires_at: DateTime<Utc>,
+    /// Messages sealed by established session. Zero during handshake.
+    pub frames_sent: u64,
+    /// Messages accepted by established session. Replays aren't counted.
+    /// Zero during handshake.
+    pub frames_received: u64,
+}
+
+/// Opened Request. See `EstablishedSession::read_request`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request(pub Bytes);
+
+/// Opened Response to the request we were waiting for. See
+/// `EstablishedSession::read_response`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response(pub Bytes);
+
+/// Opened Notification. See `EstablishedSession::read_notification`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification(pub Bytes);
+
+/// One step of the handshake. Session methods check their input against
+/// these and take both success and failure states from them, so the tables
+/// below are exactly what the code does. Used by
+/// `schema::state_machine_dot`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transition {
+    /// Method that performs the step.
+    pub method: &'static str,
+    /// State session must be in.
+    pub from: SessionState,
+    /// Frame kind method accepts. None if it doesn't take a frame.
+    pub input: Option<FrameKind>,
+    /// Frame kind method produces. None if it doesn't produce a frame.
+    pub output: Option<FrameKind>,
+    /// State after success.
+    pub to: SessionState,
+    /// State after failure. None if failure leaves state as is.
+    pub on_error: Option<SessionState>,
+}
+
+impl Transition {
+    /// Returns true if step can be taken from state with given frame.
+    pub fn accepts(&self, state: SessionState, kind: FrameKind) -> bool {
+        self.from == state && self.input.is_none_or(|input| input == kind)
+    }
+
+    // Move session to the state failed step leaves it in.
+    fn fail(&self, state: &mut SessionState) {
+        if let Some(on_error) = self.on_error {
+            *state = on_error;
+        }
+    }
+}
+
+static SERVER_WELCOME: Transition = Transition {
+    method: "make_welcome",
+    from: SessionState::Fresh,
+    input: Some(FrameKind::Hello),
+    output: Some(FrameKind::Welcome),
+    to: SessionState::Initiated,
+    on_error: Some(SessionState::Error),
+};
+static SERVER_ABBREVIATED: Transition = Transition {
+    method: "accept_abbreviated",
+    from: SessionState::Fresh,
+    input: Some(FrameKind::Initiate),
+    output: None,
+    to: SessionState::Initiated,
+    on_error: None,
+};
+static SERVER_FALLBACK: Transition = Transition {
+    method: "make_fallback_welcome",
+    from: SessionState::Fresh,
+    input: Some(FrameKind::Initiate),
+    output: Some(FrameKind::Welcome),
+    to: SessionState::Initiated,
+    on_error: None,
+};
+static SERVER_READY: Transition = Transition {
+    method: "make_ready",
+    from: SessionState::Initiated,
+    input: Some(FrameKind::Initiate),
+    output: Some(FrameKind::Ready),
+    to: SessionState::Ready,
+    on_error: Some(SessionState::Error),
+};
+static SERVER_EARLY_DATA: Transition = Transition {
+    method: "read_early_data",
+    from: SessionState::Ready,
+    input: Some(FrameKind::Initiate),
+    output: None,
+    to: SessionState::Ready,
+    on_error: None,
+};
+static CLIENT_HELLO: Transition = Transition {
+    method: "make_hello",
+    from: SessionState::Fresh,
+    input: None,
+    output: Some(FrameKind::Hello),
+    to: SessionState::Initiated,
+    on_error: None,
+};
+static CLIENT_ABBREVIATED: Transition = Transition {
+    method: "make_abbreviated_initiate",
+    from: SessionState::Fresh,
+    input: None,
+    output: Some(FrameKind::Initiate),
+    to: SessionState::Initiated,
+    on_error: None,
+};
+static CLIENT_INITIATE: Transition = Transition {
+    method: "make_initiate",
+    from: SessionState::Initiated,
+    input: Some(FrameKind::Welcome),
+    output: Some(FrameKind::Initiate),
+    to: SessionState::Initiated,
+    on_error: Some(SessionState::Error),
+};
+// Ready that doesn't open is left alone: anyone on the path can forge one.
+static CLIENT_READY: Transition = Transition {
+    method: "read_ready",
+    from: SessionState::Initiated,
+    input: Some(FrameKind::Ready),
+    output: None,
+    to: SessionState::Ready,
+    on_error: Some(SessionState::Error),
+};
+static CLIENT_WELCOME_TERMINATED: Transition = Transition {
+    method: "make_initiate",
+    from: SessionState::Initiated,
+    input: Some(FrameKind::Termination),
+    output: None,
+    to: SessionState::Terminated,
+    on_error: None,
+};
+static CLIENT_READY_TERMINATED: Transition = Transition {
+    method: "read_ready",
+    from: SessionState::Initiated,
+    input: Some(FrameKind::Termination),
+    output: None,
+    to: SessionState::Terminated,
+    on_error: None,
+};
+static SERVER_VALIDATE_TERMINATED: Transition = Transition {
+    method: "validate_initiate",
+    from: SessionState::Initiated,
+    input: Some(FrameKind::Termination),
+    output: None,
+    to: SessionState::Terminated,
+    on_error: None,
+};
+static SERVER_READY_TERMINATED: Transition = Transition {
+    method: "make_ready",
+    from: SessionState::Initiated,
+    input: Some(FrameKind::Termination),
+    output: None,
+    to: SessionState::Terminated,
+    on_error: None,
+};
+
+/// Every step ServerSession can take.
+pub static SERVER_TRANSITIONS: [&Transition; 7] = [&SERVER_WELCOME,
+                                                   &SERVER_ABBREVIATED,
+                                                   &SERVER_FALLBACK,
+                                                   &SERVER_READY,
+                                                   &SERVER_EARLY_DATA,
+                                                   &SERVER_VALIDATE_TERMINATED,
+                                                   &SERVER_READY_TERMINATED];
+/// Every step ClientSession can take.
+pub static CLIENT_TRANSITIONS: [&Transition; 6] = [&CLIENT_HELLO,
+                                                   &CLIENT_ABBREVIATED,
+                                                   &CLIENT_INITIATE,
+                                                   &CLIENT_READY,
+                                                   &CLIENT_WELCOME_TERMINATED,
+                                                   &CLIENT_READY_TERMINATED];
+
+// Reason of Termination peer sent instead of next handshake frame. It isn't
+// authenticated: anyone on the path can end handshake this way, same as by
+// dropping its packets.
+fn peer_termination(transition: &Transition,
+                    state: SessionState,
+                    id: &PublicKey,
+                    frame: &Frame)
+                    -> Option<TerminationReason> {
+    if frame.id != *id || !transition.accepts(state, frame.kind) {
+        return None;
+    }
+    TerminationReason::from_frame(frame)
 }
 
-/// Server-side session.
+/// Initiate frame opened by `ServerSession::open_initiate`, with everything
+/// in it checked.
 #[derive(Debug, Clone)]
+pub struct OpenedInitiate {
+    /// Client identity key that vouched for its short term key.
+    pub client_identity_key: PublicKey,
+    /// Metadata client attached.
+    pub metadata: Metadata,
+    /// Master key that certified client identity key, see `devices` module.
+    pub master_identity: Option<sign::PublicKey>,
+    /// Ed25519 key client signed its vouch with, see `vouch` module.
+    pub signed_identity: Option<sign::PublicKey>,
+    // Handed out by `read_early_data` only.
+    early_data: Bytes,
+    // Frame this was opened from.
+    nonce: Nonce,
+    payload: Bytes,
+}
+
+type SharedVerifier = Arc<dyn AttestationVerifier>;
+type SharedAuthenticator = Arc<dyn Authenticator>;
+
+/// Server-side session. `Debug` shows public keys, state and timestamps
+/// only. Not `Clone`: a copy dropped mid-handshake would send Termination
+/// for a handshake that is still going.
 pub struct ServerSession {
     expire_at: DateTime<Utc>,
     created_at: DateTime<Utc>,
@@ -67,89 +455,466 @@ pub struct ServerSession {
     remote_session_key: PublicKey,
     remote_identity_key: Option<PublicKey>,
     state: SessionState,
+    early_data_read: bool,
+    welcome_metadata: Metadata,
+    ready_metadata: Metadata,
+    attestation_verifier: Option<SharedVerifier>,
+    authenticator: Option<SharedAuthenticator>,
+    drop_sink: Option<SharedTerminationSink>,
+    skew_tolerance: Option<Duration>,
+    deadlines: Option<HandshakeDeadlines>,
+    phase_started: DateTime<Utc>,
+    compression_dictionaries: Vec<u32>,
+    payload_compression: bool,
+    cipher_suites: Vec<CipherSuite>,
+    welcome_suite: Option<CipherSuite>,
+    opened_hello: Option<(Nonce, Vec<u8>)>,
+    opened_initiate: Option<OpenedInitiate>,
+    require_validity: bool,
+    required_mode: Option<TransportMode>,
+    config: SessionConfig,
+    clock: SharedClock,
+}
+impl fmt::Debug for ServerSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerSession")
+         .field("id", &self.remote_session_key)
+         .field("state", &self.state)
+         .field("local_identity_key", &self.local_identity_keypair.public_key)
+         .field("local_session_key", &self.local_session_keypair.public_key)
+         .field("remote_identity_key", &self.remote_identity_key)
+         .field("created_at", &self.created_at)
+         .field("expire_at", &self.expire_at)
+         .finish()
+    }
 }
 impl ServerSession {
-    /// Server side session.
-    pub fn new(local_identity_keypair: KeyPair, remote_session_key: PublicKey) -> ServerSession {
-        let now = Utc::now();
+    /// Server side session. Fails with `InitializationFailed` if libsodium
+    /// can't be initialized.
+    pub fn new(local_identity_keypair: KeyPair,
+               remote_session_key: PublicKey)
+               -> WhisperResult<ServerSession> {
+        Ok(ServerSession::with_session_keypair(local_identity_keypair,
+                                               KeyPair::new()?,
+                                               remote_session_key))
+    }
+    /// Same as `new`, but with tuned protocol parameters. Config that
+    /// isn't valid fails the same way frames made with it would.
+    pub fn with_config(local_identity_keypair: KeyPair,
+                       remote_session_key: PublicKey,
+                       config: SessionConfig)
+                       -> WhisperResult<ServerSession> {
+        config.check()?;
+        let mut session = ServerSession::new(local_identity_keypair, remote_session_key)?;
+        session.expire_at = session.created_at + config.handshake_timeout;
+        session.config = config;
+        Ok(session)
+    }
+    /// Server side session for this Hello. Client's short term key is the
+    /// Hello's id. Fails the same way `make_welcome` would if Hello isn't
+    /// meant for our identity key. Opened Hello is kept, so `make_welcome`
+    /// with the same Hello doesn't open it again.
+    pub fn from_hello(local_identity_keypair: KeyPair, hello: &Frame) -> WhisperResult<ServerSession> {
+        ServerSession::from_hello_with_config(local_identity_keypair,
+                                              hello,
+                                              SessionConfig::default())
+    }
+    /// Same as `from_hello`, but with tuned protocol parameters.
+    pub fn from_hello_with_config(local_identity_keypair: KeyPair,
+                                  hello: &Frame,
+                                  config: SessionConfig)
+                                  -> WhisperResult<ServerSession> {
+        if hello.kind != FrameKind::Hello {
+            return Err(WhisperError::InvalidHelloFrame);
+        }
+        let payload = open_hello(hello, &local_identity_keypair)?;
+        let mut session = ServerSession::with_config(local_identity_keypair, hello.id, config)?;
+        session.opened_hello = Some((hello.nonce, payload));
+        Ok(session)
+    }
+    /// Same as `new`, but short term key has Elligator2 representative.
+    /// Server frames after Welcome carry this key as id, so without it
+    /// `elligator::hide` fails for about half of server sessions. See
+    /// `elligator` module.
+    pub fn new_hidden(local_identity_keypair: KeyPair,
+                      remote_session_key: PublicKey)
+                      -> WhisperResult<ServerSession> {
+        Ok(ServerSession::with_session_keypair(local_identity_keypair,
+                                               elligator::keypair()?.0,
+                                               remote_session_key))
+    }
+    /// Server side session that uses supplied short term keypair instead of
+    /// generating new one. Server that reuses short term keypair for a while
+    /// lets clients that cached it do abbreviated handshake.
+    pub fn with_session_keypair(local_identity_keypair: KeyPair,
+                                local_session_keypair: KeyPair,
+                                remote_session_key: PublicKey)
+                                -> ServerSession {
+        let clock = clock::system();
+        let now = clock.now();
         ServerSession {
             expire_at: now + Duration::minutes(HANDSHAKE_DURATION),
             created_at: now,
-            local_session_keypair: KeyPair::new(),
-            local_identity_keypair:
-                local_identity_keypair,
+            local_session_keypair: local_session_keypair,
+            local_identity_keypair: local_identity_keypair,
             remote_session_key: remote_session_key,
             remote_identity_key: None,
             state: SessionState::Fresh,
+            early_data_read: false,
+            welcome_metadata: Metadata::new(),
+            ready_metadata: Metadata::new(),
+            attestation_verifier: None,
+            authenticator: None,
+            drop_sink: None,
+            skew_tolerance: None,
+            deadlines: None,
+            phase_started: now,
+            compression_dictionaries: Vec::new(),
+            payload_compression: false,
+            cipher_suites: Vec::new(),
+            welcome_suite: None,
+            opened_hello: None,
+            opened_initiate: None,
+            require_validity: false,
+            required_mode: None,
+            config: SessionConfig::default(),
+            clock: clock,
+        }
+    }
+    /// Attach validity of our identity key to Welcome frame. Client will refuse
+    /// to talk to us outside of this period.
+    pub fn set_identity_validity(&mut self, validity: KeyValidity) {
+        self.welcome_metadata.insert(metadata::VALIDITY, &validity.to_bytes()[..]);
+    }
+    /// Refuse clients that don't attach validity of their identity key to
+    /// Initiate with `ExpiredIdentity`. For fleets with mandatory key
+    /// rollover.
+    pub fn require_identity_validity(&mut self) { self.require_validity = true; }
+    /// `make_ready` refuses clients that declare other transport mode (or
+    /// don't declare any, which is `Stream`) with `InvalidInitiateFrame`.
+    /// For listeners that only speak one of them.
+    pub fn require_transport_mode(&mut self, mode: TransportMode) {
+        self.required_mode = Some(mode);
+    }
+    /// Attach arbitrary metadata to Welcome frame.
+    pub fn set_welcome_metadata<B: Into<Bytes>>(&mut self, tag: u8, value: B) {
+        self.welcome_metadata.insert(tag, value);
+    }
+    /// Attach arbitrary metadata to Ready frame. Client can read it with
+    /// `ClientSession::ready_metadata`.
+    pub fn set_ready_metadata<B: Into<Bytes>>(&mut self, tag: u8, value: B) {
+        self.ready_metadata.insert(tag, value);
+    }
+    /// Refuse or give up on handshake: unauthenticated Termination with
+    /// reason for the client, see `TerminationReason::for_error`. Session
+    /// moves to `Error` state and its drop sink is disarmed.
+    pub fn make_termination(&mut self, reason: TerminationReason) -> WhisperResult<Frame> {
+        self.state = SessionState::Error;
+        self.drop_sink = None;
+        reason.to_frame(self.id())
+    }
+    /// Hand client routing hint in Ready. Client sends it with every frame
+    /// after that, and established session refuses frames without it. Fails
+    /// with `BadFrame` if hint is empty or longer than `routing::HINT_MAX`.
+    /// See `routing` module.
+    pub fn set_routing_hint<B: Into<Bytes>>(&mut self, hint: B) -> WhisperResult<()> {
+        let hint = hint.into();
+        routing::check(&hint)?;
+        self.ready_metadata.insert(metadata::ROUTING, hint);
+        Ok(())
+    }
+    /// Metadata client attached to Initiate frame.
+    pub fn initiate_metadata(&mut self, initiate: &Frame) -> WhisperResult<Metadata> {
+        self.open_initiate(initiate).map(|opened| opened.metadata)
+    }
+    /// Verifier consulted by `make_ready` with attestation blob client sent
+    /// in Initiate frame.
+    pub fn set_attestation_verifier(&mut self, verifier: Arc<dyn AttestationVerifier>) {
+        self.attestation_verifier = Some(verifier);
+    }
+    /// Authenticator consulted by `make_ready` with client identity key.
+    /// Without one every client that finishes handshake gets a session.
+    pub fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.authenticator = Some(authenticator);
+    }
+    /// Snapshot of session details.
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id(),
+            state: self.state,
+            side: Side::Server,
+            peer_fingerprint: self.remote_identity_key.as_ref().map(digest::fingerprint),
+            created_at: self.created_at,
+            expires_at: self.expire_at,
+            frames_sent: 0,
+            frames_received: 0,
         }
     }
+    /// Allow this much clock difference: identity validity periods are
+    /// widened by it, and handshake and session expire that much later.
+    /// Initiate with client clock further off than that fails with
+    /// `ClockSkew`.
+    pub fn set_skew_tolerance(&mut self, tolerance: Duration) {
+        self.skew_tolerance = Some(tolerance);
+    }
+    /// Limit how long we wait for Initiate after sending Welcome. Late
+    /// Initiate fails with `HandshakeTimeout` instead of `ExpiredSession`.
+    pub fn set_deadlines(&mut self, deadlines: HandshakeDeadlines) {
+        self.deadlines = Some(deadlines);
+    }
+    /// Take time from this clock instead of system one. Session is moved
+    /// to clock's present, so call it right after creating session, before
+    /// any frame is made or read. Established session inherits the clock.
+    /// See `clock` module.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        let now = clock.now();
+        self.expire_at = now + (self.expire_at - self.created_at);
+        self.created_at = now;
+        self.phase_started = now;
+        self.clock = clock;
+    }
+    /// Accept streaming compression with this dictionary (zero for none)
+    /// if client offers it. See `compression` module.
+    pub fn accept_compression(&mut self, dictionary_id: u32) {
+        self.compression_dictionaries.push(dictionary_id);
+    }
+    /// Accept per payload compression if client offers it. See
+    /// `compression` module.
+    #[cfg(feature = "compression")]
+    pub fn accept_payload_compression(&mut self) { self.payload_compression = true; }
+    /// Accept this cipher suite if client offers it, in Initiate or in the
+    /// list of its Hello. See `suite` module.
+    pub fn accept_cipher_suite(&mut self, suite: CipherSuite) {
+        self.cipher_suites.push(suite);
+    }
+    /// Hand Termination to this sink if session is dropped mid-handshake.
+    /// Established session made by `make_ready` inherits the sink.
+    pub fn set_drop_sink(&mut self, sink: Arc<dyn TerminationSink>) {
+        self.drop_sink = Some(sink);
+    }
     /// Helper to make a Welcome frame, a reply to Hello frame. Server worflow.
     pub fn make_welcome(&mut self, hello: &Frame) -> WhisperResult<Frame> {
-        if self.state != SessionState::Fresh || hello.kind != FrameKind::Hello {
+        if !SERVER_WELCOME.accepts(self.state, hello.kind) {
+            return Err(WhisperError::InvalidSessionState);
+        }
+        crypto::init()?;
+        // Hello opened by from_hello doesn't need to be opened again.
+        let id = self.remote_session_key;
+        let cached = self.opened_hello
+                         .take()
+                         .filter(|&(nonce, _)| nonce == hello.nonce && hello.id == id);
+        let opened = match cached {
+            Some((_, payload)) => Ok(payload),
+            None => open_hello(hello, &self.local_identity_keypair),
+        };
+        let hello_payload = match opened {
+            Ok(payload) => payload,
+            Err(e) => {
+                SERVER_WELCOME.fail(&mut self.state);
+                return Err(e);
+            }
+        };
+        // Client's favourite of the suites we accept.
+        self.welcome_suite = hello_suites(&hello_payload)
+            .into_iter()
+            .find(|suite| self.cipher_suites.contains(suite));
+        self.state = SERVER_WELCOME.to;
+        self.phase_started = self.clock.now();
+        Ok(self.seal_welcome(hello.id))
+    }
+    /// Abbreviated handshake: client skipped Hello/Welcome and sent Initiate
+    /// encrypted to cached short term key of this server. Returns client's
+    /// permanent public key if Initiate was made for our short term key. Reply
+    /// to the client with `make_ready` on success, otherwise use
+    /// `make_fallback_welcome` from a fresh session to do full handshake.
+    pub fn accept_abbreviated(&mut self, initiate: &Frame) -> WhisperResult<PublicKey> {
+        if !SERVER_ABBREVIATED.accepts(self.state, initiate.kind) {
             return Err(WhisperError::InvalidSessionState);
         }
-        // Verify content of the box
-        if let Ok(payload) = box_::open(&hello.payload,
-                                     &hello.nonce,
-                                     &hello.id,
-                                     &self.local_identity_keypair.secret_key)
+        let opened = self.open_initiate(initiate)?;
+        // Abbreviated Initiate can be replayed for as long as our short term key
+        // lives, so it is not allowed to carry early data.
+        if !opened.early_data.is_empty() {
+            return Err(WhisperError::InvalidInitiateFrame);
+        }
+        self.state = SERVER_ABBREVIATED.to;
+        self.phase_started = self.clock.now();
+        Ok(opened.client_identity_key)
+    }
+    /// Reply to abbreviated Initiate that server couldn't accept (i.e.
+    /// short term key client used is gone). Welcome makes client fall back to
+    /// full handshake. Welcome is smaller than any Initiate, so this can't be
+    /// used for amplification.
+    pub fn make_fallback_welcome(&mut self, initiate: &Frame) -> WhisperResult<Frame> {
+        if !SERVER_FALLBACK.accepts(self.state, initiate.kind) ||
+           initiate.payload.len() < INITIATE_BOX_SIZE
         {
-            // We're not going to verify that box content itself, but will verify it's
-            // length since
-            // that is what matters the most.
-            if payload.len() != 256 {
-                self.state = SessionState::Error;
-                return Err(WhisperError::InvalidHelloFrame);
-            }
-
-            self.state = SessionState::Initiated;
-
-            let nonce = box_::gen_nonce();
-            let welcome_box = box_::seal(self.local_session_keypair.public_key.as_ref(),
-                                         &nonce,
-                                         &hello.id,
-                                         &self.local_identity_keypair.secret_key);
-
-            let welcome_frame = Frame {
-                // Server uses client id in reply.
-                id: hello.id,
-                nonce: nonce,
-                kind: FrameKind::Welcome,
-                payload: welcome_box.into(),
-            };
-            Ok(welcome_frame)
-        } else {
-            self.state = SessionState::Error;
-            Err(WhisperError::DecryptionFailed)
+            return Err(WhisperError::InvalidSessionState);
         }
+        self.state = SERVER_FALLBACK.to;
+        self.phase_started = self.clock.now();
+        Ok(self.seal_welcome(initiate.id))
     }
     /// A helper to extract client's permamanet public key from initiate frame
     /// in order to
-    /// authenticate client. Authentication happens in another place.
-    pub fn validate_initiate(&self, initiate: &Frame) -> WhisperResult<PublicKey> {
+    /// authenticate client. `make_ready` asks authenticator about it, see
+    /// `set_authenticator`.
+    /// Device certificate in Initiate, if any, is checked here: it must be
+    /// signed by its master and name the key client authenticated with.
+    pub fn validate_initiate(&mut self, initiate: &Frame) -> WhisperResult<PublicKey> {
+        if let Some(reason) =
+            peer_termination(&SERVER_VALIDATE_TERMINATED, self.state, &self.id(), initiate)
+        {
+            self.state = SERVER_VALIDATE_TERMINATED.to;
+            return Err(WhisperError::Terminated(reason));
+        }
+        self.open_initiate(initiate).map(|opened| opened.client_identity_key)
+    }
+
+    /// Master key that certified client's identity key. None if client
+    /// didn't present a certificate. See `devices` module.
+    pub fn master_identity(&mut self, initiate: &Frame) -> WhisperResult<Option<sign::PublicKey>> {
+        self.open_initiate(initiate).map(|opened| opened.master_identity)
+    }
+
+    /// Ed25519 key client signed its vouch with. None if client didn't
+    /// sign. Signature is checked along with the rest of Initiate. See
+    /// `vouch` module.
+    pub fn signed_identity(&mut self, initiate: &Frame) -> WhisperResult<Option<sign::PublicKey>> {
+        self.open_initiate(initiate).map(|opened| opened.signed_identity)
+    }
+
+    /// Decrypt and check Initiate frame: vouch, validity, device certificate
+    /// and signed vouch. Result is kept, so `validate_initiate`,
+    /// `master_identity`, `signed_identity` and `make_ready` called with the
+    /// same frame don't open it again.
+    pub fn open_initiate(&mut self, initiate: &Frame) -> WhisperResult<OpenedInitiate> {
+        match self.opened_initiate {
+            Some(ref opened) if opened.nonce == initiate.nonce &&
+                                opened.payload == initiate.payload => return Ok(opened.clone()),
+            _ => {}
+        }
+        let opened = self.unseal_initiate(initiate)?;
+        self.opened_initiate = Some(opened.clone());
+        Ok(opened)
+    }
+
+    /// Returns early data client attached to Initiate frame (empty if none).
+    /// Only available once session is Ready, i.e. after client was
+    /// authenticated and `make_ready` succeeded, and only once per session.
+    ///
+    /// Early data is not protected from replay the same way regular messages
+    /// are: client sends it before it knows server accepted the session, so it
+    /// can be sent again on reconnect. Only use it for idempotent commands.
+    pub fn read_early_data(&mut self, initiate: &Frame) -> WhisperResult<Bytes> {
+        if !SERVER_EARLY_DATA.accepts(self.state, initiate.kind) || self.early_data_read {
+            return Err(WhisperError::InvalidSessionState);
+        }
+        let opened = self.open_initiate(initiate)?;
+        self.early_data_read = true;
+        Ok(opened.early_data)
+    }
+
+    // Welcome carries our short term key, our clock and metadata sealed with
+    // identity key.
+    fn seal_welcome(&self, client_session_key: PublicKey) -> Frame {
+        let mut welcome_metadata = self.welcome_metadata.clone();
+        let mut timestamp = [0; 8];
+        BigEndian::write_i64(&mut timestamp, self.clock.now().timestamp_millis());
+        welcome_metadata.insert(metadata::TIMESTAMP, &timestamp[..]);
+        if let Some(suite) = self.welcome_suite {
+            welcome_metadata.insert(metadata::CIPHER_SUITE, vec![suite as u8]);
+        }
+        let mut welcome_payload = BytesMut::with_capacity(32);
+        welcome_payload.extend_from_slice(self.local_session_keypair.public_key.as_ref());
+        welcome_metadata.encode(&mut welcome_payload);
+        let nonce = box_::gen_nonce();
+        let welcome_box = box_::seal(&welcome_payload,
+                                     &nonce,
+                                     &client_session_key,
+                                     &self.local_identity_keypair.secret_key);
+        Frame {
+            // Server uses client id in reply.
+            id: client_session_key,
+            nonce,
+            kind: FrameKind::Welcome,
+            payload: welcome_box.into(),
+        }
+    }
+
+    // Verified signer of signed vouch in Initiate metadata.
+    fn signed_identity_in(&self,
+                          metadata: &Metadata,
+                          client_identity_key: &PublicKey)
+                          -> WhisperResult<Option<sign::PublicKey>> {
+        match metadata.get(metadata::SIGNED_VOUCH) {
+            Some(vouch) => {
+                let vouch = SignedVouch::from_bytes(vouch)
+                    .map_err(|_| WhisperError::InvalidInitiateFrame)?;
+                let keys = VouchKeys {
+                    client_session_key: self.remote_session_key,
+                    server_session_key: self.local_session_keypair.public_key,
+                    server_identity_key: self.local_identity_keypair.public_key,
+                    client_identity_key: *client_identity_key,
+                };
+                if !vouch.verify(&keys) {
+                    return Err(WhisperError::InvalidInitiateFrame);
+                }
+                Ok(Some(vouch.identity))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Opens Initiate box and checks everything in it.
+    fn unseal_initiate(&self, initiate: &Frame) -> WhisperResult<OpenedInitiate> {
         if let Ok(initiate_payload) =
             box_::open(&initiate.payload,
                        &initiate.nonce,
                        &self.remote_session_key,
                        &self.local_session_keypair.secret_key)
         {
-            // TODO: change to != with proper size
-            if initiate_payload.len() < 60 {
+            if initiate_payload.len() < INITIATE_BOX_SIZE {
                 return Err(WhisperError::InvalidInitiateFrame);
             }
             // unwrapping here because they only panic when input is shorter than needed.
-            let pk = PublicKey::from_slice(&initiate_payload[0..32])
+            let pk = PublicKey::from_slice(&initiate_payload[..INITIATE_VOUCH_NONCE_AT])
                 .expect("Failed to slice pk from payload");
-            let v_nonce = Nonce::from_slice(&initiate_payload[32..56])
-                .expect("Failed to slice nonce from payload");
-            let v_box = &initiate_payload[56..initiate_payload.len()];
+            let v_nonce =
+                Nonce::from_slice(&initiate_payload[INITIATE_VOUCH_NONCE_AT..INITIATE_VOUCH_AT])
+                    .expect("Failed to slice nonce from payload");
+            let v_box = &initiate_payload[INITIATE_VOUCH_AT..INITIATE_METADATA_LEN_AT];
 
             if let Ok(vouch_payload) =
                 box_::open(v_box, &v_nonce, &pk, &self.local_session_keypair.secret_key)
             {
-                let v_pk = PublicKey::from_slice(&vouch_payload).expect("Wrong Size Key!!!");
-                if vouch_payload.len() == 32 || v_pk == self.remote_session_key {
-                    return Ok(pk);
+                // Vouch is attacker controlled, so it can be of any size.
+                if let Some(v_pk) = PublicKey::from_slice(&vouch_payload) {
+                    if crypto::constant_time_eq(&v_pk.0, &self.remote_session_key.0) {
+                        let metadata_len = &initiate_payload[INITIATE_METADATA_LEN_AT..];
+                        let metadata_len = BigEndian::read_u16(metadata_len) as usize;
+                        let metadata_end = INITIATE_BOX_SIZE + metadata_len;
+                        if initiate_payload.len() < metadata_end {
+                            return Err(WhisperError::InvalidInitiateFrame);
+                        }
+                        let metadata = Metadata::decode(&initiate_payload[INITIATE_BOX_SIZE..metadata_end])
+                            .map_err(|_| WhisperError::InvalidInitiateFrame)?;
+                        check_validity(&metadata,
+                                       self.require_validity,
+                                       self.clock.now(),
+                                       leeway(self.skew_tolerance))?;
+                        let master_identity = master_identity(&metadata, &pk)?;
+                        let signed_identity = self.signed_identity_in(&metadata, &pk)?;
+                        return Ok(OpenedInitiate {
+                                      client_identity_key: pk,
+                                      master_identity,
+                                      signed_identity,
+                                      early_data: Bytes::from(&initiate_payload[metadata_end..]),
+                                      metadata,
+                                      nonce: initiate.nonce,
+                                      payload: initiate.payload.clone(),
+                                  });
+                    }
                 }
             }
         }
@@ -162,21 +927,135 @@ impl ServerSession {
                       initiate: &Frame,
                       client_identity_key: &PublicKey)
                       -> WhisperResult<(EstablishedSession, Frame)> {
-        if self.state != SessionState::Initiated || initiate.kind != FrameKind::Initiate {
+        if let Some(reason) =
+            peer_termination(&SERVER_READY_TERMINATED, self.state, &self.id(), initiate)
+        {
+            self.state = SERVER_READY_TERMINATED.to;
+            return Err(WhisperError::Terminated(reason));
+        }
+        if !SERVER_READY.accepts(self.state, initiate.kind) {
             return Err(WhisperError::InvalidSessionState);
         }
 
+        if let Some(ref deadlines) = self.deadlines {
+            let now = self.clock.now();
+            if let Err(e) = deadlines.check(HandshakePhase::Initiate, self.phase_started, now) {
+                SERVER_READY.fail(&mut self.state);
+                return Err(e);
+            }
+        }
         // If client spend more than 3 minutes to come up with initiate - fuck him.
-        let duration_since = Utc::now().signed_duration_since(self.created_at);
-        if duration_since > Duration::minutes(HANDSHAKE_DURATION) {
+        let duration_since = self.clock.now().signed_duration_since(self.created_at);
+        if duration_since > self.config.handshake_timeout + leeway(self.skew_tolerance) {
+            SERVER_READY.fail(&mut self.state);
             return Err(WhisperError::ExpiredSession);
         }
-        self.state = SessionState::Ready;
+        let OpenedInitiate {
+            client_identity_key: pk,
+            metadata,
+            ..
+        } = match self.open_initiate(initiate) {
+            Ok(opened) => opened,
+            Err(e) => {
+                SERVER_READY.fail(&mut self.state);
+                return Err(e);
+            }
+        };
+        // Key the caller validated must be the one that vouched in this Initiate.
+        if !crypto::constant_time_eq(&pk.0, &client_identity_key.0) {
+            SERVER_READY.fail(&mut self.state);
+            return Err(WhisperError::InvalidPublicKey);
+        }
+        let client_identity_key = &pk;
+        if let Err(e) = check_skew(&metadata, self.skew_tolerance, self.clock.now()) {
+            SERVER_READY.fail(&mut self.state);
+            return Err(e);
+        }
+        let mode = match transport_mode(&metadata) {
+            Some(mode) if self.required_mode.is_none_or(|required| required == mode) => mode,
+            _ => {
+                SERVER_READY.fail(&mut self.state);
+                return Err(WhisperError::InvalidInitiateFrame);
+            }
+        };
+        let compression = match read_compression(&metadata) {
+            Some(Ok(Compression::Stream(id))) if mode == TransportMode::Stream &&
+                                                 self.compression_dictionaries.contains(&id) => {
+                Some(Compression::Stream(id))
+            }
+            Some(Ok(Compression::Payload)) if self.payload_compression => {
+                Some(Compression::Payload)
+            }
+            Some(Err(_)) => {
+                SERVER_READY.fail(&mut self.state);
+                return Err(WhisperError::InvalidInitiateFrame);
+            }
+            _ => None,
+        };
+        let suite = match metadata.get(metadata::CIPHER_SUITE) {
+            // Client that got our pick in Welcome has to offer exactly that.
+            Some(value) if value.len() == 1 &&
+                           self.welcome_suite.is_none_or(|pick| pick as u8 == value[0]) => {
+                // Suites we don't know or don't accept are declined.
+                CipherSuite::from(value[0]).filter(|s| self.cipher_suites.contains(s))
+            }
+            None if self.welcome_suite.is_none() => None,
+            _ => {
+                SERVER_READY.fail(&mut self.state);
+                return Err(WhisperError::InvalidInitiateFrame);
+            }
+        };
+        if let Some(ref authenticator) = self.authenticator {
+            if authenticator.authenticate(client_identity_key) == AuthDecision::Deny {
+                SERVER_READY.fail(&mut self.state);
+                return Err(WhisperError::InvalidPublicKey);
+            }
+        }
+        if let Some(ref verifier) = self.attestation_verifier {
+            let keys = VouchKeys {
+                client_session_key: self.remote_session_key,
+                server_session_key: self.local_session_keypair.public_key,
+                server_identity_key: self.local_identity_keypair.public_key,
+                client_identity_key: *client_identity_key,
+            };
+            let attestation = metadata.get(metadata::ATTESTATION).map(|blob| blob.as_ref());
+            if !verifier.verify(client_identity_key, &attestation::challenge(&keys), attestation) {
+                SERVER_READY.fail(&mut self.state);
+                return Err(WhisperError::AttestationFailed);
+            }
+        }
+        self.state = SERVER_READY.to;
         self.remote_identity_key = Some(*client_identity_key);
 
-        let session = EstablishedSession::new(self.remote_session_key.clone(),
-                                              self.local_session_keypair.clone());
-        let (nonce, payload) = session.seal_msg(READY_PAYLOAD);
+        let mut session = EstablishedSession::with_clock(self.remote_session_key,
+                                                         self.local_session_keypair.clone(),
+                                                         Side::Server,
+                                                         self.clock.clone());
+        session.set_mode(mode);
+        if let Some(compression) = compression {
+            session.set_compression(compression);
+            self.ready_metadata.insert(metadata::COMPRESSION, compression.value());
+        }
+        if let Some(suite) = suite {
+            self.ready_metadata.insert(metadata::CIPHER_SUITE, vec![suite as u8]);
+        }
+        if let Some(hint) = self.ready_metadata.get(metadata::ROUTING) {
+            session.set_routing_hint(hint.clone());
+        }
+        session.set_peer_identity(*client_identity_key);
+        session.set_lifetime(self.config.session_lifetime);
+        session.extend_expiry(leeway(self.skew_tolerance));
+        if let Some(ref sink) = self.drop_sink {
+            session.set_drop_sink(sink.clone());
+        }
+        let mut ready_payload = BytesMut::with_capacity(self.config.ready_payload.len());
+        ready_payload.extend_from_slice(self.config.ready_payload);
+        self.ready_metadata.encode(&mut ready_payload);
+        let (nonce, payload) = session.seal_msg(&ready_payload);
+        // Ready goes with the default suite, agreed one starts after it.
+        if let Some(suite) = suite {
+            session.set_cipher_suite(suite);
+        }
         let frame = Frame {
             id: initiate.id,
             nonce: nonce,
@@ -187,8 +1066,8 @@ impl ServerSession {
     }
 }
 
-/// Client-side session.
-#[derive(Debug, Clone)]
+/// Client-side session. `Debug` shows public keys, state and timestamps
+/// only. Not `Clone`, see `ServerSession`.
 pub struct ClientSession {
     expire_at: DateTime<Utc>,
     created_at: DateTime<Utc>,
@@ -197,103 +1076,513 @@ pub struct ClientSession {
     remote_session_key: Option<PublicKey>,
     remote_identity_key: PublicKey,
     state: SessionState,
+    initiate_metadata: Metadata,
+    ready_metadata: Metadata,
+    adopt_server_time: bool,
+    clock_offset: Duration,
+    drop_sink: Option<SharedTerminationSink>,
+    service_hint: Option<(PublicKey, String)>,
+    cipher_suites: Vec<CipherSuite>,
+    signing_identity: Option<SigningIdentity>,
+    attester: Option<Arc<dyn Attester>>,
+    require_validity: bool,
+    skew_tolerance: Option<Duration>,
+    deadlines: Option<HandshakeDeadlines>,
+    phase_started: DateTime<Utc>,
+    config: SessionConfig,
+    clock: SharedClock,
+}
+impl fmt::Debug for ClientSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientSession")
+         .field("id", &self.local_session_keypair.public_key)
+         .field("state", &self.state)
+         .field("local_identity_key", &self.local_identity_keypair.public_key)
+         .field("remote_session_key", &self.remote_session_key)
+         .field("remote_identity_key", &self.remote_identity_key)
+         .field("created_at", &self.created_at)
+         .field("expire_at", &self.expire_at)
+         .finish()
+    }
 }
 impl ClientSession {
-    /// Create new session. This method is private because it will create
-    /// session with a few missing values.
-    pub fn new(local_identity_keypair: KeyPair, remote_identity_key: PublicKey) -> ClientSession {
-        let now = Utc::now();
-        ClientSession {
-            expire_at: now + Duration::minutes(HANDSHAKE_DURATION),
-            created_at: now,
-            local_session_keypair: KeyPair::new(),
-            local_identity_keypair:
-                local_identity_keypair,
-            remote_session_key: None,
-            remote_identity_key: remote_identity_key,
-            state: SessionState::Fresh,
+    /// Create new session. Fails with `InitializationFailed` if libsodium
+    /// can't be initialized.
+    pub fn new(local_identity_keypair: KeyPair,
+               remote_identity_key: PublicKey)
+               -> WhisperResult<ClientSession> {
+        let clock = clock::system();
+        let now = clock.now();
+        Ok(ClientSession {
+               expire_at: now + Duration::minutes(HANDSHAKE_DURATION),
+               created_at: now,
+               local_session_keypair: KeyPair::new()?,
+               local_identity_keypair,
+               remote_session_key: None,
+               remote_identity_key,
+               state: SessionState::Fresh,
+               initiate_metadata: Metadata::new(),
+               ready_metadata: Metadata::new(),
+               adopt_server_time: false,
+               clock_offset: Duration::zero(),
+               drop_sink: None,
+               service_hint: None,
+               cipher_suites: Vec::new(),
+               signing_identity: None,
+               attester: None,
+               require_validity: false,
+               skew_tolerance: None,
+               deadlines: None,
+               phase_started: now,
+               config: SessionConfig::default(),
+               clock,
+           })
+    }
+    /// Same as `new`, but with tuned protocol parameters. Config that
+    /// isn't valid fails the same way frames made with it would.
+    pub fn with_config(local_identity_keypair: KeyPair,
+                       remote_identity_key: PublicKey,
+                       config: SessionConfig)
+                       -> WhisperResult<ClientSession> {
+        config.check()?;
+        let mut session = ClientSession::new(local_identity_keypair, remote_identity_key)?;
+        session.expire_at = session.created_at + config.handshake_timeout;
+        session.config = config;
+        Ok(session)
+    }
+    /// Same as `new`, but short term key has Elligator2 representative, so
+    /// frames of this session can go through `elligator::hide`. See
+    /// `elligator` module.
+    pub fn new_hidden(local_identity_keypair: KeyPair,
+                      remote_identity_key: PublicKey)
+                      -> WhisperResult<ClientSession> {
+        let mut session = ClientSession::new(local_identity_keypair, remote_identity_key)?;
+        session.local_session_keypair = elligator::keypair()?.0;
+        Ok(session)
+    }
+    /// Same as `new`, but with supplied short term keypair. Meant for
+    /// replaying known handshakes, e.g. test vectors.
+    pub fn with_session_keypair(local_identity_keypair: KeyPair,
+                                local_session_keypair: KeyPair,
+                                remote_identity_key: PublicKey)
+                                -> WhisperResult<ClientSession> {
+        let mut session = ClientSession::new(local_identity_keypair, remote_identity_key)?;
+        session.local_session_keypair = local_session_keypair;
+        Ok(session)
+    }
+    /// Give up on handshake: unauthenticated Termination with reason for
+    /// the server. Session moves to `Error` state and its drop sink is
+    /// disarmed, since server gets this Termination instead.
+    pub fn make_termination(&mut self, reason: TerminationReason) -> WhisperResult<Frame> {
+        self.state = SessionState::Error;
+        self.drop_sink = None;
+        reason.to_frame(self.id())
+    }
+    /// Use clock of the server from Welcome frame instead of our own. For
+    /// devices without RTC: server identity validity is checked against
+    /// server time. Welcome is authenticated with server identity key, so
+    /// only the server we're talking to can set our clock.
+    pub fn adopt_server_time(&mut self) { self.adopt_server_time = true; }
+    /// How far our clock is behind the server's one. Zero unless
+    /// `adopt_server_time` was called and Welcome was read. Expiry of this
+    /// session and of established session made from it is counted with
+    /// the offset applied.
+    pub fn clock_offset(&self) -> Duration { self.clock_offset }
+    /// Current time as seen by this session.
+    pub fn now(&self) -> DateTime<Utc> { self.clock.now() + self.clock_offset }
+    /// Attach validity of our identity key to Initiate frame. Server will
+    /// refuse handshake outside of this period.
+    pub fn set_identity_validity(&mut self, validity: KeyValidity) {
+        self.initiate_metadata.insert(metadata::VALIDITY, &validity.to_bytes()[..]);
+    }
+    /// Refuse servers that don't attach validity of their identity key to
+    /// Welcome with `ExpiredIdentity`.
+    pub fn require_identity_validity(&mut self) { self.require_validity = true; }
+    /// Attach device attestation blob made by this attester to Initiate
+    /// frame. Server passes it to its `AttestationVerifier`. See
+    /// `attestation` module.
+    pub fn set_attester(&mut self, attester: Arc<dyn Attester>) {
+        self.attester = Some(attester);
+    }
+    /// Present master's certificate of our identity key in Initiate. See
+    /// `devices` module.
+    pub fn set_device_certificate(&mut self, certificate: &DeviceCertificate) {
+        self.initiate_metadata.insert(metadata::DEVICE, certificate.to_bytes());
+    }
+    /// Sign our vouch with this Ed25519 key in Initiate, in addition to the
+    /// regular one. See `vouch` module.
+    pub fn set_signing_identity(&mut self, identity: SigningIdentity) {
+        self.signing_identity = Some(identity);
+    }
+    /// Declare transport this session runs over. Both sides of established
+    /// session will frame packets accordingly. See `transport` module.
+    pub fn set_transport_mode(&mut self, mode: TransportMode) {
+        self.initiate_metadata.insert(metadata::TRANSPORT, vec![mode as u8]);
+    }
+    /// Offer streaming compression with this dictionary (zero for none).
+    /// Server accepts it only in stream mode. Replaces offer of per payload
+    /// compression. See `compression` module.
+    pub fn offer_compression(&mut self, dictionary_id: u32) {
+        self.initiate_metadata
+            .insert(metadata::COMPRESSION, Compression::Stream(dictionary_id).value());
+    }
+    /// Offer per payload compression. Works in both transport modes.
+    /// Replaces offer of streaming compression. See `compression` module.
+    #[cfg(feature = "compression")]
+    pub fn offer_payload_compression(&mut self) {
+        self.initiate_metadata.insert(metadata::COMPRESSION, Compression::Payload.value());
+    }
+    /// Offer cipher suite for messages. Server that doesn't accept it keeps
+    /// the default. See `suite` module.
+    pub fn offer_cipher_suite(&mut self, suite: CipherSuite) {
+        self.initiate_metadata.insert(metadata::CIPHER_SUITE, vec![suite as u8]);
+    }
+    /// List cipher suites we support in Hello, most preferred first. Server
+    /// picks one in Welcome and it is offered in Initiate in place of
+    /// `offer_cipher_suite`. See `suite` module. More than 255 suites don't
+    /// fit into Hello: `InvalidHelloFrame`.
+    pub fn set_cipher_suites(&mut self, suites: &[CipherSuite]) -> WhisperResult<()> {
+        // Count is one byte, and even the smallest padding has that many
+        // slots after it.
+        if suites.len() > u8::MAX as usize {
+            return Err(WhisperError::InvalidHelloFrame);
+        }
+        self.cipher_suites = suites.to_vec();
+        Ok(())
+    }
+    /// Attach arbitrary metadata to Initiate frame. Server can read it with
+    /// `ServerSession::initiate_metadata`.
+    pub fn set_initiate_metadata<B: Into<Bytes>>(&mut self, tag: u8, value: B) {
+        self.initiate_metadata.insert(tag, value);
+    }
+    /// Metadata server attached to Ready frame. Empty until `read_ready`
+    /// succeeds.
+    pub fn ready_metadata(&self) -> &Metadata { &self.ready_metadata }
+    /// Snapshot of session details.
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id(),
+            state: self.state,
+            side: Side::Client,
+            peer_fingerprint: Some(digest::fingerprint(&self.remote_identity_key)),
+            created_at: self.created_at,
+            expires_at: self.expire_at,
+            frames_sent: 0,
+            frames_received: 0,
+        }
+    }
+    /// Allow this much clock difference: identity validity periods are
+    /// widened by it, and handshake and session expire that much later.
+    /// Also checks server time from Welcome: if it's further off than
+    /// this, `make_initiate` fails with `ClockSkew`. Ignored for the check
+    /// if we `adopt_server_time`.
+    pub fn set_skew_tolerance(&mut self, tolerance: Duration) {
+        self.skew_tolerance = Some(tolerance);
+    }
+    /// Limit how long we wait for Welcome after Hello and for Ready after
+    /// Initiate. Late frames fail with `HandshakeTimeout`.
+    pub fn set_deadlines(&mut self, deadlines: HandshakeDeadlines) {
+        self.deadlines = Some(deadlines);
+    }
+    /// Take time from this clock instead of system one. Session is moved
+    /// to clock's present, so call it right after creating session, before
+    /// any frame is made or read. Established session inherits the clock.
+    /// See `clock` module.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        let now = clock.now();
+        self.expire_at = now + (self.expire_at - self.created_at);
+        self.created_at = now;
+        self.phase_started = now;
+        self.clock = clock;
+    }
+    /// Hand Termination to this sink if session is dropped mid-handshake.
+    /// Established session made by `read_ready` inherits the sink.
+    pub fn set_drop_sink(&mut self, sink: Arc<dyn TerminationSink>) {
+        self.drop_sink = Some(sink);
+    }
+    /// Append service name sealed to endpoint's outer key to Hello. Name
+    /// must be at most `SERVICE_NAME_MAX` bytes long.
+    pub fn set_service_name(&mut self, outer_key: PublicKey, name: &str) -> WhisperResult<()> {
+        if name.len() > SERVICE_NAME_MAX {
+            return Err(WhisperError::InvalidHelloFrame);
         }
+        self.service_hint = Some((outer_key, name.to_owned()));
+        Ok(())
     }
     /// Helper to make Hello frame. Client workflow.
-    pub fn make_hello(&mut self) -> Frame {
-        self.state = SessionState::Initiated;
+    pub fn make_hello(&mut self) -> WhisperResult<Frame> {
+        if self.state != CLIENT_HELLO.from {
+            return Err(WhisperError::InvalidSessionState);
+        }
+        crypto::init()?;
+        self.state = CLIENT_HELLO.to;
+        self.phase_started = self.clock.now();
         let nonce = box_::gen_nonce();
-        let payload = box_::seal(&NULL_BYTES,
-                                 &nonce,
-                                 &self.remote_identity_key,
-                                 &self.local_session_keypair.secret_key);
-        Frame {
-            id: self.local_session_keypair.public_key,
-            nonce: nonce,
-            kind: FrameKind::Hello,
-            payload: payload.into(),
+        let mut hello_payload = vec![0; self.config.hello_padding];
+        hello_payload[0] = self.cipher_suites.len() as u8;
+        for (slot, suite) in hello_payload[1..].iter_mut().zip(&self.cipher_suites) {
+            *slot = *suite as u8;
+        }
+        let mut payload = box_::seal(&hello_payload,
+                                     &nonce,
+                                     &self.remote_identity_key,
+                                     &self.local_session_keypair.secret_key);
+        if let Some((ref outer_key, ref name)) = self.service_hint {
+            let mut padded = vec![0; 1 + SERVICE_NAME_MAX];
+            padded[0] = name.len() as u8;
+            padded[1..1 + name.len()].copy_from_slice(name.as_bytes());
+            payload.extend(box_::seal(&padded,
+                                      &hint_nonce(&nonce),
+                                      outer_key,
+                                      &self.local_session_keypair.secret_key));
         }
+        Ok(Frame {
+               id: self.local_session_keypair.public_key,
+               nonce,
+               kind: FrameKind::Hello,
+               payload: payload.into(),
+           })
     }
 
     /// Helper to make am Initiate frame, a reply to Welcome frame. Client
     /// workflow.
     pub fn make_initiate(&mut self, welcome: &Frame) -> WhisperResult<Frame> {
-        if self.state != SessionState::Initiated || welcome.kind != FrameKind::Welcome {
+        self.make_initiate_with_early_data(welcome, &[])
+    }
+
+    /// Same as `make_initiate`, but attaches application payload to Initiate
+    /// frame. Server can read it right after it authenticates client, which
+    /// saves a round trip. See `ServerSession::read_early_data` for caveats.
+    pub fn make_initiate_with_early_data(&mut self,
+                                         welcome: &Frame,
+                                         early_data: &[u8])
+                                         -> WhisperResult<Frame> {
+        if let Some(reason) =
+            peer_termination(&CLIENT_WELCOME_TERMINATED, self.state, &self.id(), welcome)
+        {
+            self.state = CLIENT_WELCOME_TERMINATED.to;
+            return Err(WhisperError::Terminated(reason));
+        }
+        if !CLIENT_INITIATE.accepts(self.state, welcome.kind) {
             return Err(WhisperError::InvalidSessionState);
         }
+        if let Some(ref deadlines) = self.deadlines {
+            let now = self.clock.now();
+            if let Err(e) = deadlines.check(HandshakePhase::Welcome, self.phase_started, now) {
+                CLIENT_INITIATE.fail(&mut self.state);
+                return Err(e);
+            }
+        }
         // Try to obtain server short public key from the box.
-        if let Ok(server_pk) = box_::open(&welcome.payload,
-                                       &welcome.nonce,
-                                       &self.remote_identity_key,
-                                       &self.local_session_keypair.secret_key)
+        if let Ok(welcome_payload) = box_::open(&welcome.payload,
+                                             &welcome.nonce,
+                                             &self.remote_identity_key,
+                                             &self.local_session_keypair.secret_key)
         {
-            if let Some(key) = PublicKey::from_slice(&server_pk) {
+            let key = if welcome_payload.len() >= 32 {
+                PublicKey::from_slice(&welcome_payload[0..32])
+            } else {
+                None
+            };
+            let metadata = Metadata::decode(&welcome_payload[32.min(welcome_payload.len())..]);
+            if let (Some(key), Ok(metadata)) = (key, metadata) {
+                if self.adopt_server_time {
+                    if let Some(server_time) = read_timestamp(&metadata) {
+                        // Handshake lifetime is counted in session time too.
+                        let offset = server_time.signed_duration_since(self.clock.now());
+                        self.created_at += offset - self.clock_offset;
+                        self.expire_at += offset - self.clock_offset;
+                        self.clock_offset = offset;
+                    }
+                } else if let Err(e) = check_skew(&metadata, self.skew_tolerance, self.now()) {
+                    CLIENT_INITIATE.fail(&mut self.state);
+                    return Err(e);
+                }
+                if let Err(e) = check_validity(&metadata,
+                                               self.require_validity,
+                                               self.now(),
+                                               leeway(self.skew_tolerance)) {
+                    CLIENT_INITIATE.fail(&mut self.state);
+                    return Err(e);
+                }
+                if let Some(value) = metadata.get(metadata::CIPHER_SUITE) {
+                    // Server may only pick from the list we sent.
+                    match value.first().and_then(|id| CipherSuite::from(*id)) {
+                        Some(suite) if value.len() == 1 && self.cipher_suites.contains(&suite) => {
+                            self.offer_cipher_suite(suite)
+                        }
+                        _ => {
+                            CLIENT_INITIATE.fail(&mut self.state);
+                            return Err(WhisperError::InvalidWelcomeFrame);
+                        }
+                    }
+                }
                 self.remote_session_key = Some(key);
-                let mut initiate_box = Vec::with_capacity(104);
-                initiate_box.extend_from_slice(&self.local_identity_keypair.public_key.0);
-                initiate_box.extend(self.make_vouch());
-                let nonce = box_::gen_nonce();
-                let payload = box_::seal(&initiate_box,
-                                         &nonce,
-                                         &self.remote_session_key.expect("Shit is on fire yo"),
-                                         &self.local_session_keypair.secret_key);
-                let frame = Frame {
-                    id: welcome.id,
-                    nonce: nonce,
-                    kind: FrameKind::Initiate,
-                    payload: payload.into(),
-                };
-                Ok(frame)
+                self.state = CLIENT_INITIATE.to;
+                self.phase_started = self.clock.now();
+                self.seal_initiate(early_data)
             } else {
-                self.state = SessionState::Error;
+                CLIENT_INITIATE.fail(&mut self.state);
 
                 return Err(WhisperError::InvalidWelcomeFrame);
             }
         } else {
-            self.state = SessionState::Error;
+            CLIENT_INITIATE.fail(&mut self.state);
             return Err(WhisperError::DecryptionFailed);
         }
     }
+    /// Abbreviated handshake: skip Hello/Welcome and send Initiate encrypted
+    /// directly to server short term key cached from previous session. Server
+    /// replies either with Ready (pass it to `read_ready`) or with Welcome if
+    /// it no longer has that key (pass it to `make_initiate` to fall back to
+    /// full handshake).
+    pub fn make_abbreviated_initiate(&mut self, server_session_key: PublicKey) -> WhisperResult<Frame> {
+        if self.state != CLIENT_ABBREVIATED.from {
+            return Err(WhisperError::InvalidSessionState);
+        }
+        self.state = CLIENT_ABBREVIATED.to;
+        self.phase_started = self.clock.now();
+        self.remote_session_key = Some(server_session_key);
+        self.seal_initiate(&[])
+    }
+    /// Server short term key learned during handshake. Cache it to do
+    /// abbreviated handshake next time.
+    pub fn server_session_key(&self) -> Option<PublicKey> { self.remote_session_key }
+    // Initiate box: our identity key, vouch and early data. Server session
+    // key must be known by now.
+    fn seal_initiate(&self, early_data: &[u8]) -> WhisperResult<Frame> {
+        let remote_session_key =
+            self.remote_session_key.ok_or(WhisperError::InvalidSessionState)?;
+        let mut initiate_metadata = self.initiate_metadata.clone();
+        let mut timestamp = [0; 8];
+        BigEndian::write_i64(&mut timestamp, self.now().timestamp_millis());
+        initiate_metadata.insert(metadata::TIMESTAMP, &timestamp[..]);
+        let keys = VouchKeys {
+            client_session_key: self.local_session_keypair.public_key,
+            server_session_key: remote_session_key,
+            server_identity_key: self.remote_identity_key,
+            client_identity_key: self.local_identity_keypair.public_key,
+        };
+        if let Some(ref identity) = self.signing_identity {
+            initiate_metadata.insert(metadata::SIGNED_VOUCH, identity.vouch(&keys).to_bytes());
+        }
+        if let Some(ref attester) = self.attester {
+            let attestation = attester.attest(&attestation::challenge(&keys));
+            initiate_metadata.insert(metadata::ATTESTATION, attestation);
+        }
+        let mut encoded_metadata = BytesMut::new();
+        initiate_metadata.encode(&mut encoded_metadata);
+
+        let mut initiate_box =
+            BytesMut::with_capacity(INITIATE_BOX_SIZE + encoded_metadata.len() + early_data.len());
+        initiate_box.extend_from_slice(&self.local_identity_keypair.public_key.0);
+        initiate_box.extend_from_slice(&self.make_vouch(&remote_session_key));
+        initiate_box.put_u16_be(encoded_metadata.len() as u16);
+        initiate_box.extend_from_slice(&encoded_metadata);
+        initiate_box.extend_from_slice(early_data);
+        let nonce = box_::gen_nonce();
+        let payload = box_::seal(&initiate_box,
+                                 &nonce,
+                                 &remote_session_key,
+                                 &self.local_session_keypair.secret_key);
+        Ok(Frame {
+               id: self.local_session_keypair.public_key,
+               nonce,
+               kind: FrameKind::Initiate,
+               payload: payload.into(),
+           })
+    }
     /// Verify that reply to initiate frame is correct ready frame. Changes
     /// session state if so.
     pub fn read_ready(&mut self, ready: &Frame) -> WhisperResult<EstablishedSession> {
-        if self.state != SessionState::Initiated || ready.kind != FrameKind::Ready {
+        if let Some(reason) =
+            peer_termination(&CLIENT_READY_TERMINATED, self.state, &self.id(), ready)
+        {
+            self.state = CLIENT_READY_TERMINATED.to;
+            return Err(WhisperError::Terminated(reason));
+        }
+        if !CLIENT_READY.accepts(self.state, ready.kind) {
             return Err(WhisperError::InvalidSessionState);
         }
-        // This can never fail when used properly.
-        let session = EstablishedSession::new(self.remote_session_key.unwrap().clone(),
-                                              self.local_session_keypair.clone());
-        let msg = session.read_msg(ready)?;
-        if msg.as_ref() == READY_PAYLOAD {
-            self.state = SessionState::Ready;
-            Ok(session)
-        } else {
-            Err(WhisperError::InvalidReadyFrame)
+        // Forged Ready doesn't end handshake, but timeout does.
+        if let Some(ref deadlines) = self.deadlines {
+            let now = self.clock.now();
+            if let Err(e) = deadlines.check(HandshakePhase::Ready, self.phase_started, now) {
+                CLIENT_READY.fail(&mut self.state);
+                return Err(e);
+            }
+        }
+        // Server can send Ready before we've seen Welcome.
+        let remote_session_key = match self.remote_session_key {
+            Some(key) => key,
+            None => return Err(WhisperError::InvalidSessionState),
+        };
+        let mut session = EstablishedSession::with_clock(remote_session_key,
+                                                         self.local_session_keypair.clone(),
+                                                         Side::Client,
+                                                         clock::offset(self.clock.clone(),
+                                                                       self.clock_offset));
+        session.set_mode(transport_mode(&self.initiate_metadata).unwrap_or_default());
+        session.set_peer_identity(self.remote_identity_key);
+        session.set_lifetime(self.config.session_lifetime);
+        session.extend_expiry(leeway(self.skew_tolerance));
+        // Ready carries our session key as id, not server's. Forged Ready
+        // doesn't end handshake, so it returns before the step fails.
+        let msg = session.reader.open(ready, &self.local_session_keypair.public_key)?;
+        if let Err(e) = self.apply_ready(&msg, &mut session) {
+            CLIENT_READY.fail(&mut self.state);
+            return Err(e);
+        }
+        self.state = CLIENT_READY.to;
+        if let Some(ref sink) = self.drop_sink {
+            session.set_drop_sink(sink.clone());
+        }
+        Ok(session)
+    }
+    // Check payload of Ready that opened and set up session with what
+    // server agreed to.
+    fn apply_ready(&mut self, msg: &[u8], session: &mut EstablishedSession) -> WhisperResult<()> {
+        let expected = self.config.ready_payload;
+        if msg.len() < expected.len() ||
+           !crypto::constant_time_eq(&msg[..expected.len()], expected)
+        {
+            return Err(WhisperError::InvalidReadyFrame);
+        }
+        self.ready_metadata = Metadata::decode(&msg[expected.len()..])
+            .map_err(|_| WhisperError::InvalidReadyFrame)?;
+        let offered = read_compression(&self.initiate_metadata).and_then(Result::ok);
+        match read_compression(&self.ready_metadata) {
+            None => {}
+            // Server can only accept what we've offered.
+            Some(Ok(compression)) if offered == Some(compression) => {
+                session.set_compression(compression)
+            }
+            _ => return Err(WhisperError::InvalidReadyFrame),
+        }
+        if let Some(value) = self.ready_metadata.get(metadata::CIPHER_SUITE) {
+            // Same here.
+            if self.initiate_metadata.get(metadata::CIPHER_SUITE) != Some(value) {
+                return Err(WhisperError::InvalidReadyFrame);
+            }
+            let suite = value.first()
+                             .and_then(|id| CipherSuite::from(*id))
+                             .ok_or(WhisperError::InvalidReadyFrame)?;
+            session.set_cipher_suite(suite);
+        }
+        if let Some(hint) = self.ready_metadata.get(metadata::ROUTING) {
+            routing::check(hint).map_err(|_| WhisperError::InvalidReadyFrame)?;
+            session.set_routing_hint(hint.clone());
         }
+        Ok(())
     }
     // Helper to make a vouch
-    fn make_vouch(&self) -> Vec<u8> {
+    fn make_vouch(&self, remote_session_key: &PublicKey) -> Vec<u8> {
         let nonce = box_::gen_nonce();
         let our_sk = &self.local_identity_keypair.secret_key;
         let pk = &self.local_session_keypair.public_key;
         let vouch_box = box_::seal(&pk.0,
                                    &nonce,
-                                   &self.remote_session_key.expect("Shit is on fire yo"),
+                                   remote_session_key,
                                    our_sk);
 
         let mut vouch = Vec::with_capacity(72);
@@ -303,54 +1592,1055 @@ impl ClientSession {
     }
 }
 
-/// This structure represent session that completed handshake.
-///
-/// Only way to create is to have ClientSession and ServerSession agree on
-/// shared secret a.k.a. session_key a.k.a. PrecomputedKey.
-/// ServerSession turns into EstablishedSession by verifying Initiate frame.
-/// ClientSession turns into EstablishedSession by verifying Ready frame.
-pub struct EstablishedSession {
-    id: PublicKey,
-    expire_at: DateTime<Utc>,
-    session_secret: PrecomputedKey,
+fn valid_hello_padding(padding: usize) -> bool {
+    (NULL_BYTES.len()..=HELLO_PADDING_MAX).contains(&padding) && padding % HELLO_PADDING_STEP == 0
 }
 
-impl EstablishedSession {
-    /// Create EstablishSession by precomputing shared secret. Don't use this
-    /// directly.
-    pub fn new(remote_session_key: PublicKey,
-               local_session_keypair: KeyPair)
-               -> EstablishedSession {
-        let now = Utc::now();
-        let our_precomputed_key = box_::precompute(&remote_session_key,
-                                                   &local_session_keypair.secret_key);
-        EstablishedSession {
-            id: local_session_keypair.public_key,
-            expire_at: now + Duration::minutes(SESSION_DURATION),
-            session_secret: our_precomputed_key,
-        }
+// Size of Hello box in Hello payload of this size, with or without service
+// hint. Hint size isn't a multiple of padding step, so it can't be both.
+pub(crate) fn hello_box_size(len: usize) -> Option<usize> {
+    let is_box = |len: usize| len >= box_::MACBYTES && valid_hello_padding(len - box_::MACBYTES);
+    if is_box(len) {
+        Some(len)
+    } else if len >= SERVICE_HINT_SIZE && is_box(len - SERVICE_HINT_SIZE) {
+        Some(len - SERVICE_HINT_SIZE)
+    } else {
+        None
     }
-    fn seal_msg(&self, data: &[u8]) -> (Nonce, Bytes) {
-        let nonce = box_::gen_nonce();
-        let payload = box_::seal_precomputed(data, &nonce, &self.session_secret);
-        (nonce, payload.into())
+}
+
+// Part of Hello sealed to identity key. None if Hello has wrong size.
+fn hello_box(hello: &Frame) -> Option<&[u8]> {
+    hello_box_size(hello.payload.len()).map(|size| &hello.payload[..size])
+}
+
+// Verify Hello box opens with our identity key and return its content. We're
+// not going to verify content of the box itself, but will verify its length
+// since that is what matters the most.
+fn open_hello(hello: &Frame, identity_keypair: &KeyPair) -> WhisperResult<Vec<u8>> {
+    let hello_box = hello_box(hello).ok_or(WhisperError::InvalidHelloFrame)?;
+    let payload = box_::open(hello_box, &hello.nonce, &hello.id, &identity_keypair.secret_key)
+        .map_err(|_| WhisperError::DecryptionFailed)?;
+    if !valid_hello_padding(payload.len()) {
+        return Err(WhisperError::InvalidHelloFrame);
     }
+    Ok(payload)
+}
 
-    /// Method use to open payload.
-    pub fn read_msg(&self, frame: &Frame) -> WhisperResult<Bytes> {
-        if let Ok(msg) = box_::open_precomputed(&frame.payload, &frame.nonce, &self.session_secret) {
-            Ok(msg.into())
-        } else {
-            Err(WhisperError::DecryptionFailed)
-        }
+// Cipher suites listed in Hello, most preferred first: count in the first
+// byte, ids after it. Hello from clients that list nothing is all zeros.
+// Ids we don't know are skipped.
+fn hello_suites(hello_payload: &[u8]) -> Vec<CipherSuite> {
+    let count = hello_payload[0] as usize;
+    hello_payload[1..].iter().take(count).filter_map(|id| CipherSuite::from(*id)).collect()
+}
+
+// Hint is sealed with Hello's key. If outer key is identity key, reusing
+// nonce would be fatal, so it's tweaked.
+fn hint_nonce(hello_nonce: &Nonce) -> Nonce {
+    let mut nonce = *hello_nonce;
+    nonce.0[box_::NONCEBYTES - 1] ^= 1;
+    nonce
+}
+
+/// Service name client put into Hello, opened with endpoint's outer
+/// keypair. None if Hello has no hint.
+pub fn read_service_name(hello: &Frame, outer_keypair: &KeyPair) -> WhisperResult<Option<String>> {
+    let box_size = match hello_box_size(hello.payload.len()) {
+        Some(box_size) if hello.kind == FrameKind::Hello => box_size,
+        _ => return Err(WhisperError::InvalidHelloFrame),
+    };
+    if hello.payload.len() == box_size {
+        return Ok(None);
     }
+    let padded = box_::open(&hello.payload[box_size..],
+                            &hint_nonce(&hello.nonce),
+                            &hello.id,
+                            &outer_keypair.secret_key)
+        .map_err(|_| WhisperError::DecryptionFailed)?;
+    let len = padded[0] as usize;
+    if len > SERVICE_NAME_MAX {
+        return Err(WhisperError::InvalidHelloFrame);
+    }
+    String::from_utf8(padded[1..1 + len].to_vec())
+        .map(Some)
+        .map_err(|_| WhisperError::InvalidHelloFrame)
+}
 
-    fn make_message(&self, data: &[u8], kind: FrameKind) -> WhisperResult<Frame> {
-        if self.is_expired() {
-            return Err(WhisperError::ExpiredSession);
+// Verified master of device certificate in metadata.
+fn master_identity(metadata: &Metadata,
+                   identity_key: &PublicKey)
+                   -> WhisperResult<Option<sign::PublicKey>> {
+    match metadata.get(metadata::DEVICE) {
+        Some(certificate) => {
+            let certificate = DeviceCertificate::from_bytes(certificate)
+                .map_err(|_| WhisperError::InvalidInitiateFrame)?;
+            certificate.verify_for(identity_key).map(Some)
         }
-        let (nonce, payload) = self.seal_msg(data);
-        let frame = Frame {
+        None => Ok(None),
+    }
+}
+
+// Transport mode declared in Initiate. None if value makes no sense.
+fn transport_mode(metadata: &Metadata) -> Option<TransportMode> {
+    match metadata.get(metadata::TRANSPORT) {
+        Some(value) => TransportMode::from_slice(value),
+        None => Some(TransportMode::default()),
+    }
+}
+
+// Compression in handshake metadata. One entry, so only one can be agreed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    // Streaming, with dictionary id.
+    Stream(u32),
+    // Per payload.
+    Payload,
+}
+
+impl Compression {
+    fn value(self) -> Vec<u8> {
+        match self {
+            Compression::Stream(dictionary_id) => {
+                let mut value = vec![0; 4];
+                BigEndian::write_u32(&mut value, dictionary_id);
+                value
+            }
+            Compression::Payload => vec![PAYLOAD_COMPRESSION_FLAG],
+        }
+    }
+}
+
+// Compression metadata entry. None if there is no entry.
+fn read_compression(metadata: &Metadata) -> Option<WhisperResult<Compression>> {
+    metadata.get(metadata::COMPRESSION).map(|value| match value.len() {
+        4 => Ok(Compression::Stream(BigEndian::read_u32(value))),
+        1 if value[0] == PAYLOAD_COMPRESSION_FLAG => Ok(Compression::Payload),
+        _ => Err(WhisperError::BadFrame),
+    })
+}
+
+// Server clock from Welcome.
+fn read_timestamp(metadata: &Metadata) -> Option<DateTime<Utc>> {
+    let value = metadata.get(metadata::TIMESTAMP)?;
+    if value.len() != 8 {
+        return None;
+    }
+    Utc.timestamp_millis_opt(BigEndian::read_i64(value)).single()
+}
+
+// Peer's clock in handshake metadata has to be within tolerance of ours.
+// Peers that don't send their clock aren't checked.
+fn check_skew(metadata: &Metadata,
+              tolerance: Option<Duration>,
+              now: DateTime<Utc>)
+              -> WhisperResult<()> {
+    if let (Some(tolerance), Some(peer_time)) = (tolerance, read_timestamp(metadata)) {
+        let skew = peer_time.signed_duration_since(now);
+        if skew > tolerance || -skew > tolerance {
+            return Err(WhisperError::ClockSkew);
+        }
+    }
+    Ok(())
+}
+
+// Extra time allowed by skew tolerance.
+fn leeway(tolerance: Option<Duration>) -> Duration { tolerance.unwrap_or_else(Duration::zero) }
+
+// Refuse identities outside of their validity period widened by leeway, and
+// identities without one if it's required.
+fn check_validity(metadata: &Metadata,
+                  required: bool,
+                  now: DateTime<Utc>,
+                  leeway: Duration)
+                  -> WhisperResult<()> {
+    match metadata.get(metadata::VALIDITY) {
+        Some(validity) => {
+            match KeyValidity::from_slice(validity) {
+                Some(validity) if validity.not_before - leeway <= now &&
+                                  now <= validity.not_after + leeway => Ok(()),
+                Some(_) => Err(WhisperError::ExpiredIdentity),
+                None => Err(WhisperError::BadFrame),
+            }
+        }
+        None if required => Err(WhisperError::ExpiredIdentity),
+        None => Ok(()),
+    }
+}
+
+/// This structure represent session that completed handshake.
+///
+/// Only way to create is to have ClientSession and ServerSession agree on
+/// shared secret a.k.a. session_key a.k.a. PrecomputedKey.
+/// ServerSession turns into EstablishedSession by verifying Initiate frame.
+/// ClientSession turns into EstablishedSession by verifying Ready frame.
+///
+/// Session can be split into `SessionReader` and `SessionWriter` halves in
+/// order to be used from two tasks at the same time without a lock.
+///
+/// Each direction has its own key, derived from the shared secret and both
+/// session keys. Frames we sent don't open with our read key, so a frame
+/// reflected back at its sender is refused. On top of that, top bit of the
+/// nonce tells direction, so reflected frames and mixed up session objects
+/// get `WrongDirection` without trying to decrypt.
+///
+/// Cloning is cheap: clones (and halves) share handles to the keys instead
+/// of copying them. Keys are zeroed when the last handle is dropped.
+#[derive(Clone)]
+pub struct EstablishedSession {
+    reader: SessionReader,
+    writer: SessionWriter,
+}
+
+/// End of the session. Decides which direction key is used for what.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// Side that sent Hello.
+    Client,
+    /// Side that answered it.
+    Server,
+}
+
+impl EstablishedSession {
+    /// Create EstablishSession by precomputing shared secret and deriving
+    /// direction keys from it. Don't use this directly.
+    pub fn new(remote_session_key: PublicKey,
+               local_session_keypair: KeyPair,
+               side: Side)
+               -> EstablishedSession {
+        EstablishedSession::with_clock(remote_session_key,
+                                       local_session_keypair,
+                                       side,
+                                       clock::system())
+    }
+
+    pub(crate) fn with_clock(remote_session_key: PublicKey,
+                             local_session_keypair: KeyPair,
+                             side: Side,
+                             clock: SharedClock)
+                             -> EstablishedSession {
+        let now = clock.now();
+        let shared = box_::precompute(&remote_session_key, &local_session_keypair.secret_key);
+        let local = local_session_keypair.public_key;
+        let (client, server) = match side {
+            Side::Client => (&local, &remote_session_key),
+            Side::Server => (&remote_session_key, &local),
+        };
+        let client_to_server = direction_key(&shared, client, server, CLIENT_TO_SERVER);
+        let server_to_client = direction_key(&shared, client, server, SERVER_TO_CLIENT);
+        let (rx, tx) = match side {
+            Side::Client => (server_to_client, client_to_server),
+            Side::Server => (client_to_server, server_to_client),
+        };
+        let id = local_session_keypair.public_key;
+        let expire_at = now + Duration::minutes(SESSION_DURATION);
+        let stats = Arc::new(SessionStats::new(now, None, clock));
+        EstablishedSession {
+            reader: SessionReader {
+                id,
+                peer_id: remote_session_key,
+                expire_at,
+                side,
+                stats: stats.clone(),
+                session_secret: Arc::new(rx),
+                suite: CipherSuite::default(),
+                mode: TransportMode::default(),
+                compression: None,
+                payload_compression: false,
+                replay_window: Some(Arc::new(Mutex::new(ReplayWindow::new()))),
+                highest_counter: None,
+                routing_hint: None,
+                drop_notice: None,
+                #[cfg(feature = "faults")]
+                faults: None,
+            },
+            writer: SessionWriter {
+                id,
+                expire_at,
+                side,
+                stats,
+                session_secret: Arc::new(tx),
+                suite: CipherSuite::default(),
+                mode: TransportMode::default(),
+                compression: None,
+                payload_compression: false,
+                pacer: None,
+                counter: Arc::new(AtomicU64::new(0)),
+                routing_hint: None,
+                drop_notice: None,
+                #[cfg(feature = "faults")]
+                faults: None,
+            },
+        }
+    }
+
+    /// Mix extra secret (e.g. result of password authentication) into both
+    /// direction keys. Both sides must bind the same secret in the same
+    /// order. Clones and halves made before binding keep using old keys.
+    pub fn bind_secret(&mut self, secret: &[u8]) {
+        self.reader.session_secret = Arc::new(bind(&self.reader.session_secret, secret));
+        self.writer.session_secret = Arc::new(bind(&self.writer.session_secret, secret));
+    }
+
+    /// Hand Termination to this sink when the last clone or half of this
+    /// session is dropped before it expires. Replaces sink set earlier.
+    pub fn set_drop_sink(&mut self, sink: Arc<dyn TerminationSink>) {
+        let notice = Arc::new(DropNotice {
+                                  id: self.writer.id,
+                                  expire_at: self.writer.expire_at,
+                                  clock: self.writer.stats.clock.clone(),
+                                  sink,
+                                  armed: AtomicBool::new(true),
+                              });
+        self.reader.drop_notice = Some(notice.clone());
+        self.writer.drop_notice = Some(notice);
+    }
+
+    /// Don't send Termination on drop, e.g. because we've sent one already.
+    /// Applies to all clones and halves.
+    pub fn disarm_drop_sink(&self) { self.writer.disarm_drop_sink() }
+
+    // Only called before session is handed out, so nobody shares stats yet.
+    pub(crate) fn set_peer_identity(&mut self, key: PublicKey) {
+        let stats = Arc::new(SessionStats::new(self.writer.stats.created_at,
+                                               Some(key),
+                                               self.writer.stats.clock.clone()));
+        self.reader.stats = stats.clone();
+        self.writer.stats = stats;
+    }
+
+    fn set_lifetime(&mut self, lifetime: Duration) {
+        let expire_at = self.writer.stats.created_at + lifetime;
+        self.reader.expire_at = expire_at;
+        self.writer.expire_at = expire_at;
+    }
+
+    fn extend_expiry(&mut self, leeway: Duration) {
+        self.reader.expire_at += leeway;
+        self.writer.expire_at += leeway;
+    }
+
+    /// Snapshot of session details. Counters are shared by all clones and
+    /// halves.
+    pub fn info(&self) -> SessionInfo { self.writer.info() }
+
+    pub(crate) fn set_mode(&mut self, mode: TransportMode) {
+        self.reader.mode = mode;
+        self.writer.mode = mode;
+    }
+
+    fn set_compression(&mut self, compression: Compression) {
+        match compression {
+            Compression::Stream(dictionary_id) => {
+                self.reader.compression = Some(dictionary_id);
+                self.writer.compression = Some(dictionary_id);
+            }
+            Compression::Payload => {
+                self.reader.payload_compression = true;
+                self.writer.payload_compression = true;
+            }
+        }
+    }
+
+    /// Dictionary id of streaming compression agreed on during handshake.
+    /// None if payloads are not compressed.
+    pub fn compression(&self) -> Option<u32> { self.writer.compression }
+
+    // Every suite gets its own keys, so no key is used with two ciphers.
+    // Default suite keeps the keys Ready was sealed with.
+    fn set_cipher_suite(&mut self, suite: CipherSuite) {
+        if suite != self.writer.suite {
+            let name = suite.name().as_bytes();
+            self.reader.session_secret = Arc::new(bind(&self.reader.session_secret, name));
+            self.writer.session_secret = Arc::new(bind(&self.writer.session_secret, name));
+        }
+        self.reader.suite = suite;
+        self.writer.suite = suite;
+    }
+
+    /// Cipher suite messages are sealed with. See `suite` module.
+    pub fn cipher_suite(&self) -> CipherSuite { self.writer.suite }
+
+    /// Returns true if per payload compression was agreed on.
+    pub fn payload_compression(&self) -> bool { self.writer.payload_compression }
+
+    /// Arm faults on this session. See `faults` module. Clones and halves
+    /// made after this share them.
+    #[cfg(feature = "faults")]
+    pub fn set_faults(&mut self, faults: Faults) {
+        self.reader.faults = Some(faults.clone());
+        self.writer.faults = Some(faults);
+    }
+
+    /// Refuse frames that were opened before, or that fell more than
+    /// `transport::REPLAY_WINDOW` behind the newest one, with
+    /// `ReplayedFrame`. Frames without counter nonce are `BadFrame`. On by
+    /// default. Window is shared by all clones and halves made after this.
+    /// See `transport::ReplayWindow`.
+    pub fn set_replay_protection(&mut self, enabled: bool) {
+        if !enabled {
+            self.reader.replay_window = None;
+        } else if self.reader.replay_window.is_none() {
+            self.reader.replay_window = Some(Arc::new(Mutex::new(ReplayWindow::new())));
+        }
+    }
+
+    /// Only open frames with counter nonces higher than any opened before,
+    /// old counters are refused with `ReplayedFrame`. Stricter than replay
+    /// window: over datagrams that drops reordered frames too. Clones and
+    /// halves made after this share the highest counter.
+    pub fn require_counter_nonces(&mut self) {
+        if self.reader.highest_counter.is_none() {
+            self.reader.highest_counter = Some(Arc::new(AtomicU64::new(0)));
+        }
+    }
+
+    /// Limit how fast this session sends. See `pacing` module. Clones and
+    /// halves made after this share the limits.
+    pub fn set_pacer(&mut self, pacer: Pacer) {
+        self.writer.pacer = Some(Arc::new(Mutex::new(pacer)));
+    }
+
+    // Client sends the hint, server expects it.
+    fn set_routing_hint(&mut self, hint: Bytes) {
+        match self.writer.side {
+            Side::Client => self.writer.routing_hint = Some(hint),
+            Side::Server => self.reader.routing_hint = Some(hint),
+        }
+    }
+
+    /// Routing hint server assigned in Ready, if any. See `routing` module.
+    pub fn routing_hint(&self) -> Option<&[u8]> {
+        self.writer
+            .routing_hint
+            .as_ref()
+            .or_else(|| self.reader.routing_hint.as_ref())
+            .map(|hint| hint.as_ref())
+    }
+
+    /// Transport mode agreed on during handshake.
+    pub fn mode(&self) -> TransportMode { self.writer.mode }
+
+    /// Pack frame for the wire according to transport mode.
+    pub fn pack(&self, frame: &Frame) -> Bytes { self.writer.pack(frame) }
+
+    /// Parse one packet received from the wire and open it. See
+    /// `SessionReader::read_packet`.
+    pub fn read_packet(&mut self, packet: &[u8]) -> WhisperResult<(Frame, Bytes)> {
+        self.reader.read_packet(packet)
+    }
+
+    /// Split session into read and write halves. Each half can be moved to
+    /// its own task, so reading and writing doesn't have to share a lock.
+    pub fn split(self) -> (SessionReader, SessionWriter) { (self.reader, self.writer) }
+
+    pub(crate) fn seal_msg(&self, data: &[u8]) -> (Nonce, Bytes) { self.writer.seal_msg(data) }
+
+    // Open frame that carries given id instead of peer's, i.e. Ready.
+    pub(crate) fn open_as(&self, frame: &Frame, id: &PublicKey) -> WhisperResult<Bytes> {
+        self.reader.open(frame, id)
+    }
+
+    pub(crate) fn peer_identity(&self) -> Option<PublicKey> { self.writer.stats.peer_identity }
+
+    // Clock session was made with, shifted to server time if client adopted
+    // it.
+    pub(crate) fn clock(&self) -> SharedClock { self.writer.stats.clock.clone() }
+
+    // Secret to resume session with: sha256(client to server key || server
+    // to client key || label). Changes with `bind_secret`.
+    pub(crate) fn resumption_secret(&self) -> [u8; 32] {
+        let (client_to_server, server_to_client) = match self.writer.side {
+            Side::Client => (&self.writer.session_secret, &self.reader.session_secret),
+            Side::Server => (&self.reader.session_secret, &self.writer.session_secret),
+        };
+        let mut input = Vec::with_capacity(2 * box_::PRECOMPUTEDKEYBYTES + RESUMPTION_LABEL.len());
+        input.extend_from_slice(&client_to_server.0);
+        input.extend_from_slice(&server_to_client.0);
+        input.extend_from_slice(RESUMPTION_LABEL);
+        let secret = sha256::hash(&input).0;
+        sodiumoxide::utils::memzero(&mut input);
+        secret
+    }
+
+    /// Method use to open payload. See `SessionReader::read_msg`.
+    pub fn read_msg(&self, frame: &Frame) -> WhisperResult<Bytes> { self.reader.read_msg(frame) }
+
+    /// See `SessionReader::read_msg_secure`.
+    pub fn read_msg_secure(&self, frame: &Frame) -> WhisperResult<SecureBytes> {
+        self.reader.read_msg_secure(frame)
+    }
+
+    pub(crate) fn peek_msg(&self, frame: &Frame) -> WhisperResult<Bytes> {
+        self.reader.peek_msg(frame)
+    }
+
+    pub(crate) fn claim(&self, frame: &Frame) -> WhisperResult<()> { self.reader.claim(frame) }
+
+    /// Open Request. Any other kind is `WrongKind`.
+    pub fn read_request(&self, frame: &Frame) -> WhisperResult<Request> {
+        self.reader.read_request(frame)
+    }
+
+    /// Open tracked Response to request with this id. See
+    /// `SessionReader::read_response`.
+    pub fn read_response(&self, frame: &Frame, request_id: RequestId) -> WhisperResult<Response> {
+        self.reader.read_response(frame, request_id)
+    }
+
+    /// Open Notification. Any other kind is `WrongKind`.
+    pub fn read_notification(&self, frame: &Frame) -> WhisperResult<Notification> {
+        self.reader.read_notification(frame)
+    }
+
+    /// Method used to create new requests.
+    pub fn make_request(&self, data: &[u8]) -> WhisperResult<Frame> {
+        self.writer.make_request(data)
+    }
+
+    /// Method used to create new responses.
+    pub fn make_response(&self, data: &[u8]) -> WhisperResult<Frame> {
+        self.writer.make_response(data)
+    }
+
+    /// Response with `Ok` status envelope. See `status` module.
+    pub fn make_ok_response(&self, body: &[u8]) -> WhisperResult<Frame> {
+        self.writer.make_ok_response(body)
+    }
+
+    /// Response with error status envelope. See `status` module.
+    pub fn make_error_response(&self, code: NonZeroU8, body: &[u8]) -> WhisperResult<Frame> {
+        self.writer.make_error_response(code, body)
+    }
+
+    /// Request split into frames of at most `max_fragment` bytes each
+    /// (`Frame::length`, transport length prefix not included). See
+    /// `fragment` module.
+    pub fn make_request_fragmented(&self,
+                                   data: &[u8],
+                                   max_fragment: usize)
+                                   -> WhisperResult<Vec<Frame>> {
+        self.make_fragmented(data, max_fragment, FrameKind::Request)
+    }
+
+    /// Notification split into frames like `make_request_fragmented` does.
+    pub fn make_notification_fragmented(&self,
+                                        data: &[u8],
+                                        max_fragment: usize)
+                                        -> WhisperResult<Vec<Frame>> {
+        self.make_fragmented(data, max_fragment, FrameKind::Notification)
+    }
+
+    fn make_fragmented(&self,
+                       data: &[u8],
+                       max_fragment: usize,
+                       kind: FrameKind)
+                       -> WhisperResult<Vec<Frame>> {
+        let hint_len = self.writer.routing_hint.as_ref().map_or(0, |hint| 1 + hint.len());
+        let flag_len = if self.writer.payload_compression { 1 } else { 0 };
+        let overhead = HEADER_SIZE + box_::MACBYTES + fragment::FRAGMENT_HEADER_SIZE + hint_len +
+                       flag_len;
+        fragment::split(data, max_fragment.saturating_sub(overhead))?
+            .iter()
+            .map(|piece| self.writer.make_message(piece, kind))
+            .collect()
+    }
+
+    /// Method used to create new notifications.
+    pub fn make_notification(&self, data: &[u8]) -> WhisperResult<Frame> {
+        self.writer.make_notification(data)
+    }
+
+    /// Method used to create session control messages.
+    pub fn make_control(&self, data: &[u8]) -> WhisperResult<Frame> {
+        self.writer.make_control(data)
+    }
+
+    /// Authenticated Termination frame with reason. Session stays open; see
+    /// `close` for the usual way to hang up.
+    pub fn make_termination(&self, reason: TerminationReason) -> WhisperResult<Frame> {
+        self.writer.make_termination(reason)
+    }
+
+    /// Hang up: make Termination to send to the peer and close session, so
+    /// every `make_*` of it, its clones and halves fails with
+    /// `SessionClosed` from now on. Drop sink is disarmed, since peer gets
+    /// this Termination instead. Only the first of racing callers gets the
+    /// Termination, the rest get `SessionClosed`.
+    pub fn close(&self, reason: TerminationReason) -> WhisperResult<Frame> {
+        self.writer
+            .stats
+            .closed
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .map_err(|_| WhisperError::SessionClosed)?;
+        self.disarm_drop_sink();
+        self.writer.make_frame(&[reason as u8], FrameKind::Termination)
+    }
+
+    /// Returns true once session was closed.
+    pub fn is_closed(&self) -> bool { self.writer.is_closed() }
+
+    /// Heartbeat Control frame. Peer that answers heartbeats (see
+    /// `liveness` module) sends ack back, which bumps `last_seen`.
+    pub fn make_ping(&self) -> WhisperResult<Frame> { liveness::ping(self) }
+
+    /// When peer's last frame was read by this session, its clones or read
+    /// half. Session creation time until then.
+    pub fn last_seen(&self) -> DateTime<Utc> { self.reader.last_seen() }
+
+    /// Returns true if peer was heard from within `timeout`. Peer that has
+    /// nothing to say should be pinged more often than that.
+    pub fn is_alive(&self, timeout: Duration) -> bool { self.reader.is_alive(timeout) }
+
+    /// Reason from Termination made by peer's `make_termination`. Anything
+    /// else is `WrongKind`.
+    pub fn read_termination(&self, frame: &Frame) -> WhisperResult<TerminationReason> {
+        self.reader.read_termination(frame)
+    }
+}
+
+// Details shared by both halves and all clones of established session.
+struct SessionStats {
+    created_at: DateTime<Utc>,
+    clock: SharedClock,
+    peer_identity: Option<PublicKey>,
+    sent: AtomicU64,
+    received: AtomicU64,
+    // Milliseconds since epoch.
+    last_seen: AtomicI64,
+    closed: AtomicBool,
+}
+
+impl SessionStats {
+    fn new(created_at: DateTime<Utc>,
+           peer_identity: Option<PublicKey>,
+           clock: SharedClock)
+           -> SessionStats {
+        SessionStats {
+            created_at,
+            clock,
+            peer_identity,
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            last_seen: AtomicI64::new(created_at.timestamp_millis()),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn seen(&self) {
+        self.last_seen.fetch_max(self.clock.now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    fn last_seen(&self) -> DateTime<Utc> {
+        let millis = self.last_seen.load(Ordering::Relaxed);
+        Utc.timestamp_millis_opt(millis).single().unwrap_or(self.created_at)
+    }
+
+    fn state(&self) -> SessionState {
+        if self.closed.load(Ordering::SeqCst) {
+            SessionState::Closed
+        } else {
+            SessionState::Ready
+        }
+    }
+
+    fn info(&self, id: PublicKey, side: Side, expires_at: DateTime<Utc>) -> SessionInfo {
+        SessionInfo {
+            id,
+            state: self.state(),
+            side,
+            peer_fingerprint: self.peer_identity.as_ref().map(digest::fingerprint),
+            created_at: self.created_at,
+            expires_at,
+            frames_sent: self.sent.load(Ordering::Relaxed),
+            frames_received: self.received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static CLIENT_TO_SERVER: u8 = 0;
+static SERVER_TO_CLIENT: u8 = 1;
+// Top bit of the first nonce byte carries direction of the frame.
+static NONCE_DIRECTION_BIT: u8 = 0x80;
+static RESUMPTION_LABEL: &[u8] = b"resumption";
+// Algorithm of per payload compression in handshake metadata. Same as
+// `compression::ZSTD`.
+static PAYLOAD_COMPRESSION_FLAG: u8 = 1;
+
+impl Side {
+    fn direction(self) -> u8 {
+        match self {
+            Side::Client => CLIENT_TO_SERVER,
+            Side::Server => SERVER_TO_CLIENT,
+        }
+    }
+
+    // Counter nonce: direction bit, zeros, counter in last 8 bytes.
+    fn counter_nonce(self, counter: u64) -> Nonce {
+        let mut nonce = Nonce([0; box_::NONCEBYTES]);
+        BigEndian::write_u64(&mut nonce.0[box_::NONCEBYTES - 8..], counter);
+        if self.direction() == SERVER_TO_CLIENT {
+            nonce.0[0] |= NONCE_DIRECTION_BIT;
+        }
+        nonce
+    }
+
+    // Returns true if nonce was made by this side.
+    fn made(self, nonce: &Nonce) -> bool {
+        let server_to_client = nonce.0[0] & NONCE_DIRECTION_BIT != 0;
+        server_to_client == (self == Side::Server)
+    }
+}
+
+/// Counter of nonce made by established session: frames are numbered 1,
+/// 2, 3... in order they were made, by all clones and halves together.
+/// None for other nonces. Counters of each direction start at 1 and are
+/// only unique within their direction.
+pub fn nonce_counter(nonce: &Nonce) -> Option<u64> {
+    let (head, counter) = nonce.0.split_at(box_::NONCEBYTES - 8);
+    if head[0] & !NONCE_DIRECTION_BIT != 0 || head[1..].iter().any(|&byte| byte != 0) {
+        return None;
+    }
+    Some(BigEndian::read_u64(counter))
+}
+
+// Key for one direction: sha256(shared || client key || server key || direction).
+fn direction_key(shared: &PrecomputedKey,
+                 client: &PublicKey,
+                 server: &PublicKey,
+                 direction: u8)
+                 -> PrecomputedKey {
+    let mut input = Vec::with_capacity(box_::PRECOMPUTEDKEYBYTES + 2 * box_::PUBLICKEYBYTES + 1);
+    input.extend_from_slice(&shared.0);
+    input.extend_from_slice(&client.0);
+    input.extend_from_slice(&server.0);
+    input.push(direction);
+    hash_key(input)
+}
+
+// With `null-cipher` feature payload is plaintext behind zeroed MAC, so
+// sizes and everything else stay the same.
+#[cfg(not(feature = "null-cipher"))]
+fn seal_payload(suite: CipherSuite, data: &[u8], nonce: &Nonce, key: &PrecomputedKey) -> Vec<u8> {
+    suite::seal(suite, data, nonce, key)
+}
+
+#[cfg(not(feature = "null-cipher"))]
+fn open_payload(suite: CipherSuite,
+                payload: &[u8],
+                nonce: &Nonce,
+                key: &PrecomputedKey)
+                -> Option<Vec<u8>> {
+    suite::open(suite, payload, nonce, key)
+}
+
+#[cfg(feature = "compression")]
+fn pack_payload(data: &[u8]) -> Bytes { compression::pack(data) }
+
+#[cfg(feature = "compression")]
+fn unpack_payload(payload: &[u8]) -> WhisperResult<Vec<u8>> { compression::unpack(payload) }
+
+// Payload compression can't be agreed on without the feature.
+#[cfg(not(feature = "compression"))]
+fn pack_payload(data: &[u8]) -> Bytes { Bytes::from(data) }
+
+#[cfg(not(feature = "compression"))]
+fn unpack_payload(payload: &[u8]) -> WhisperResult<Vec<u8>> { Ok(payload.to_vec()) }
+
+#[cfg(feature = "null-cipher")]
+fn seal_payload(_: CipherSuite, data: &[u8], _: &Nonce, _: &PrecomputedKey) -> Vec<u8> {
+    let mut payload = vec![0; box_::MACBYTES];
+    payload.extend_from_slice(data);
+    payload
+}
+
+#[cfg(feature = "null-cipher")]
+fn open_payload(_: CipherSuite,
+                payload: &[u8],
+                _: &Nonce,
+                _: &PrecomputedKey)
+                -> Option<Vec<u8>> {
+    if payload.len() < box_::MACBYTES || payload[..box_::MACBYTES].iter().any(|&b| b != 0) {
+        return None;
+    }
+    Some(payload[box_::MACBYTES..].to_vec())
+}
+
+// sha256(key || secret).
+fn bind(key: &PrecomputedKey, secret: &[u8]) -> PrecomputedKey {
+    let mut input = Vec::with_capacity(box_::PRECOMPUTEDKEYBYTES + secret.len());
+    input.extend_from_slice(&key.0);
+    input.extend_from_slice(secret);
+    hash_key(input)
+}
+
+// sha256 of input as key. Input and digest are wiped, so the key is the
+// only copy left.
+fn hash_key(mut input: Vec<u8>) -> PrecomputedKey {
+    let mut digest = sha256::hash(&input);
+    let key = PrecomputedKey(digest.0);
+    sodiumoxide::utils::memzero(&mut input);
+    sodiumoxide::utils::memzero(&mut digest.0);
+    key
+}
+
+/// Read half of EstablishedSession. Only opens incoming frames.
+#[derive(Clone)]
+pub struct SessionReader {
+    id: PublicKey,
+    peer_id: PublicKey,
+    expire_at: DateTime<Utc>,
+    side: Side,
+    stats: Arc<SessionStats>,
+    session_secret: Arc<PrecomputedKey>,
+    suite: CipherSuite,
+    mode: TransportMode,
+    compression: Option<u32>,
+    payload_compression: bool,
+    replay_window: Option<Arc<Mutex<ReplayWindow>>>,
+    highest_counter: Option<Arc<AtomicU64>>,
+    routing_hint: Option<Bytes>,
+    drop_notice: Option<Arc<DropNotice>>,
+    #[cfg(feature = "faults")]
+    faults: Option<Faults>,
+}
+
+impl SessionReader {
+    /// Snapshot of session details.
+    pub fn info(&self) -> SessionInfo { self.stats.info(self.id, self.side, self.expire_at) }
+
+    /// Dictionary id of agreed streaming compression, if any.
+    pub fn compression(&self) -> Option<u32> { self.compression }
+
+    /// Method use to open payload. Frames with nonce made by our own side
+    /// are refused with `WrongDirection` and frames of other sessions with
+    /// `WrongPeer`, both before decryption. If we've assigned routing hint,
+    /// frames without it get `BadFrame`. Frames opened before are refused
+    /// with `ReplayedFrame`, unless replay protection was turned off.
+    pub fn read_msg(&self, frame: &Frame) -> WhisperResult<Bytes> {
+        let msg = self.peek_msg(frame)?;
+        self.claim(frame)?;
+        Ok(msg)
+    }
+
+    /// Same as `read_msg`, but message is wiped from memory once dropped,
+    /// so is compressed message. Decompression isn't: zstd's own buffers
+    /// and buffers outgrown while inflating are freed as they are. Don't
+    /// agree on payload compression if that matters.
+    pub fn read_msg_secure(&self, frame: &Frame) -> WhisperResult<SecureBytes> {
+        let mut msg = SecureBytes::from(self.open_vec(frame, &self.peer_id)?);
+        if self.payload_compression {
+            msg = SecureBytes::from(unpack_payload(&msg)?);
+        }
+        self.claim(frame)?;
+        Ok(msg)
+    }
+
+    // Open without remembering the nonce. For handlers that every frame is
+    // passed through: they `claim` only frames that are theirs, so the next
+    // handler in line can still open the rest.
+    pub(crate) fn peek_msg(&self, frame: &Frame) -> WhisperResult<Bytes> {
+        let msg = self.open(frame, &self.peer_id)?;
+        if self.payload_compression {
+            return unpack_payload(&msg).map(Bytes::from);
+        }
+        Ok(msg)
+    }
+
+    // Remember counter of authentic frame. Only authentic frames move the
+    // window, so it can't be pushed ahead by forgeries. Frame is counted as
+    // received here, so replays and peeks don't add up.
+    pub(crate) fn claim(&self, frame: &Frame) -> WhisperResult<()> {
+        let counter = nonce_counter(&frame.nonce);
+        if let Some(ref window) = self.replay_window {
+            let counter = counter.ok_or(WhisperError::BadFrame)?;
+            if !window.lock().expect("Replay window lock poisoned").check(counter) {
+                return Err(WhisperError::ReplayedFrame);
+            }
+        }
+        if let Some(ref highest) = self.highest_counter {
+            let counter = counter.ok_or(WhisperError::BadFrame)?;
+            if highest.fetch_max(counter, Ordering::SeqCst) >= counter {
+                return Err(WhisperError::ReplayedFrame);
+            }
+        }
+        self.stats.received.fetch_add(1, Ordering::Relaxed);
+        self.stats.seen();
+        Ok(())
+    }
+
+    /// When peer's last frame was read. Session creation time until then.
+    pub fn last_seen(&self) -> DateTime<Utc> { self.stats.last_seen() }
+
+    /// Returns true if peer was heard from within `timeout`.
+    pub fn is_alive(&self, timeout: Duration) -> bool {
+        self.stats.clock.now().signed_duration_since(self.last_seen()) <= timeout
+    }
+
+    /// Open Request. Any other kind is `WrongKind`.
+    pub fn read_request(&self, frame: &Frame) -> WhisperResult<Request> {
+        self.read_kind(frame, FrameKind::Request).map(Request)
+    }
+
+    /// Open Response payload made with `tracker::wrap` and return what
+    /// follows request id. Any other kind is `WrongKind`, response to
+    /// another request is `WrongRequestId`.
+    pub fn read_response(&self, frame: &Frame, request_id: RequestId) -> WhisperResult<Response> {
+        let (id, body) = tracker::unwrap(&self.read_kind(frame, FrameKind::Response)?)?;
+        if id != request_id {
+            return Err(WhisperError::WrongRequestId(request_id, id));
+        }
+        Ok(Response(body))
+    }
+
+    /// Open Notification. Any other kind is `WrongKind`.
+    pub fn read_notification(&self, frame: &Frame) -> WhisperResult<Notification> {
+        self.read_kind(frame, FrameKind::Notification).map(Notification)
+    }
+
+    /// Reason from peer's authenticated Termination. Any other kind is
+    /// `WrongKind`.
+    pub fn read_termination(&self, frame: &Frame) -> WhisperResult<TerminationReason> {
+        let payload = self.read_kind(frame, FrameKind::Termination)?;
+        Ok(TerminationReason::from(payload.first().cloned().unwrap_or(0)))
+    }
+
+    // Kind is checked first, so wrong frames aren't even decrypted.
+    fn read_kind(&self, frame: &Frame, kind: FrameKind) -> WhisperResult<Bytes> {
+        if frame.kind != kind {
+            return Err(WhisperError::WrongKind(kind, frame.kind));
+        }
+        self.read_msg(frame)
+    }
+
+    // Open frame that must carry this id.
+    fn open(&self, frame: &Frame, id: &PublicKey) -> WhisperResult<Bytes> {
+        self.open_vec(frame, id).map(Bytes::from)
+    }
+
+    fn open_vec(&self, frame: &Frame, id: &PublicKey) -> WhisperResult<Vec<u8>> {
+        if self.side.made(&frame.nonce) {
+            return Err(WhisperError::WrongDirection);
+        }
+        if frame.id != *id {
+            return Err(WhisperError::WrongPeer);
+        }
+        let payload = match self.routing_hint {
+            Some(ref hint) => routing::strip(hint, &frame.payload)?,
+            None => &frame.payload[..],
+        };
+        if self.injected_decryption_failure() {
+            return Err(WhisperError::DecryptionFailed);
+        }
+        open_payload(self.suite, payload, &frame.nonce, &self.session_secret)
+            .ok_or(WhisperError::DecryptionFailed)
+    }
+
+    #[cfg(feature = "faults")]
+    fn injected_decryption_failure(&self) -> bool {
+        self.faults.as_ref().is_some_and(Faults::take_decryption_failure)
+    }
+
+    #[cfg(not(feature = "faults"))]
+    fn injected_decryption_failure(&self) -> bool { false }
+
+    /// Parse one packet received from the wire and open it. In stream mode
+    /// packet must be exactly one length prefixed frame. Replays are
+    /// refused the same way as in `read_msg`.
+    pub fn read_packet(&mut self, packet: &[u8]) -> WhisperResult<(Frame, Bytes)> {
+        let frame = match self.mode {
+            TransportMode::Stream => transport::unpack_prefixed(packet)?,
+            TransportMode::Datagram => Frame::from_slice(packet)?,
+        };
+        let msg = self.read_msg(&frame)?;
+        Ok((frame, msg))
+    }
+}
+
+/// Write half of EstablishedSession. Only makes outgoing frames.
+#[derive(Clone)]
+pub struct SessionWriter {
+    id: PublicKey,
+    expire_at: DateTime<Utc>,
+    side: Side,
+    stats: Arc<SessionStats>,
+    session_secret: Arc<PrecomputedKey>,
+    suite: CipherSuite,
+    mode: TransportMode,
+    compression: Option<u32>,
+    payload_compression: bool,
+    pacer: Option<Arc<Mutex<Pacer>>>,
+    counter: Arc<AtomicU64>,
+    routing_hint: Option<Bytes>,
+    drop_notice: Option<Arc<DropNotice>>,
+    #[cfg(feature = "faults")]
+    faults: Option<Faults>,
+}
+
+impl SessionWriter {
+    /// Snapshot of session details.
+    pub fn info(&self) -> SessionInfo { self.stats.info(self.id, self.side, self.expire_at) }
+
+    /// Dictionary id of agreed streaming compression, if any.
+    pub fn compression(&self) -> Option<u32> { self.compression }
+
+    /// See `EstablishedSession::disarm_drop_sink`.
+    pub fn disarm_drop_sink(&self) {
+        if let Some(ref notice) = self.drop_notice {
+            notice.armed.store(false, Ordering::SeqCst);
+        }
+    }
+
+    fn seal_msg(&self, data: &[u8]) -> (Nonce, Bytes) {
+        // Wraps after 2^64 frames, which is not going to happen.
+        let nonce = self.side.counter_nonce(self.counter.fetch_add(1, Ordering::SeqCst) + 1);
+        let payload = seal_payload(self.suite, data, &nonce, &self.session_secret);
+        self.stats.sent.fetch_add(1, Ordering::Relaxed);
+        (nonce, payload.into())
+    }
+
+    /// Pack frame for the wire according to transport mode.
+    pub fn pack(&self, frame: &Frame) -> Bytes {
+        match self.mode {
+            TransportMode::Stream => transport::pack_prefixed(frame),
+            TransportMode::Datagram => frame.pack(),
+        }
+    }
+
+    /// Returns true once session was closed.
+    pub fn is_closed(&self) -> bool { self.stats.closed.load(Ordering::SeqCst) }
+
+    fn make_message(&self, data: &[u8], kind: FrameKind) -> WhisperResult<Frame> {
+        if self.is_closed() {
+            return Err(WhisperError::SessionClosed);
+        }
+        self.make_frame(data, kind)
+    }
+
+    // Frame regardless of session being closed.
+    fn make_frame(&self, data: &[u8], kind: FrameKind) -> WhisperResult<Frame> {
+        if self.is_expired() {
+            return Err(WhisperError::ExpiredSession);
+        }
+        if let Some(ref pacer) = self.pacer {
+            let mut pacer = pacer.lock().expect("Pacer lock poisoned");
+            pacer.check_payload(data.len()).map_err(WhisperError::RateLimited)?;
+        }
+        let packed;
+        let data = if self.payload_compression {
+            packed = pack_payload(data);
+            &packed[..]
+        } else {
+            data
+        };
+        let (mut nonce, mut payload) = self.seal_msg(data);
+        if self.injected_nonce_corruption() {
+            nonce.0[box_::NONCEBYTES - 1] ^= 1;
+        }
+        if let Some(ref hint) = self.routing_hint {
+            payload = routing::prefix(hint, &payload);
+        }
+        let frame = Frame {
             id: self.id(),
             nonce: nonce,
             kind: kind,
@@ -369,106 +2659,532 @@ impl EstablishedSession {
         self.make_message(data, FrameKind::Response)
     }
 
+    /// Response with `Ok` status envelope. See `status` module.
+    pub fn make_ok_response(&self, body: &[u8]) -> WhisperResult<Frame> {
+        self.make_response(&status::wrap(Status::Ok, body))
+    }
+
+    /// Response with error status envelope. See `status` module.
+    pub fn make_error_response(&self, code: NonZeroU8, body: &[u8]) -> WhisperResult<Frame> {
+        self.make_response(&status::wrap(Status::Error(code), body))
+    }
+
     /// Method used to create new notifications.
     pub fn make_notification(&self, data: &[u8]) -> WhisperResult<Frame> {
         self.make_message(data, FrameKind::Notification)
     }
+
+    /// Method used to create session control messages.
+    pub fn make_control(&self, data: &[u8]) -> WhisperResult<Frame> {
+        self.make_message(data, FrameKind::Control)
+    }
+
+    /// See `EstablishedSession::make_termination`.
+    pub fn make_termination(&self, reason: TerminationReason) -> WhisperResult<Frame> {
+        self.make_message(&[reason as u8], FrameKind::Termination)
+    }
+
+    #[cfg(feature = "faults")]
+    fn injected_nonce_corruption(&self) -> bool {
+        self.faults.as_ref().is_some_and(Faults::take_nonce_corruption)
+    }
+
+    #[cfg(not(feature = "faults"))]
+    fn injected_nonce_corruption(&self) -> bool { false }
+
+    #[cfg(feature = "faults")]
+    fn injected_expiry(&self) -> bool { self.faults.as_ref().is_some_and(Faults::is_expired) }
+
+    #[cfg(not(feature = "faults"))]
+    fn injected_expiry(&self) -> bool { false }
+}
+
+// Shared by all clones and halves of one established session, so
+// Termination goes out once, when the last of them is gone.
+struct DropNotice {
+    id: PublicKey,
+    expire_at: DateTime<Utc>,
+    clock: SharedClock,
+    sink: SharedTerminationSink,
+    armed: AtomicBool,
 }
 
-/// Common session functions that apply to all session types.
-trait Session {
+impl Drop for DropNotice {
+    fn drop(&mut self) {
+        if self.armed.load(Ordering::SeqCst) && self.expire_at > self.clock.now() {
+            // libsodium is initialized by now, session keys were made with it.
+            if let Ok(frame) = TerminationReason::Unspecified.to_frame(self.id) {
+                self.sink.terminated(frame);
+            }
+        }
+    }
+}
+
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        if self.state == SessionState::Initiated && !self.is_expired() {
+            if let (Some(sink), Ok(frame)) =
+                (self.drop_sink.take(), TerminationReason::Unspecified.to_frame(self.id()))
+            {
+                sink.terminated(frame);
+            }
+        }
+    }
+}
+
+impl Drop for ServerSession {
+    fn drop(&mut self) {
+        if self.state == SessionState::Initiated && !self.is_expired() {
+            if let (Some(sink), Ok(frame)) =
+                (self.drop_sink.take(), TerminationReason::Unspecified.to_frame(self.id()))
+            {
+                sink.terminated(frame);
+            }
+        }
+    }
+}
+
+/// Common session functions that apply to all session types. Import it to
+/// write helpers generic over handshake and established sessions.
+pub trait Session {
     /// Returns true if session is expired.
     fn is_expired(&self) -> bool;
     /// Returns session state.
-    fn session_state(&self) -> SessionState;
+    fn state(&self) -> SessionState;
     /// Returns session id. This should always be client short term public key.
     fn id(&self) -> PublicKey;
+    /// When session was created. Halves and clones of established session
+    /// share it.
+    fn created_at(&self) -> DateTime<Utc>;
+    /// When session expires, not counting skew tolerance.
+    fn expire_at(&self) -> DateTime<Utc>;
+    /// Peer's identity key. Server learns it from Initiate, so it's None
+    /// before that.
+    fn remote_identity(&self) -> Option<PublicKey>;
 }
 
 impl Session for ClientSession {
-    fn is_expired(&self) -> bool { self.expire_at < Utc::now() }
-    fn session_state(&self) -> SessionState { self.state }
+    fn is_expired(&self) -> bool {
+        self.expire_at + leeway(self.skew_tolerance) < self.now()
+    }
+    fn state(&self) -> SessionState { self.state }
     fn id(&self) -> PublicKey { self.local_session_keypair.public_key }
+    fn created_at(&self) -> DateTime<Utc> { self.created_at }
+    fn expire_at(&self) -> DateTime<Utc> { self.expire_at }
+    fn remote_identity(&self) -> Option<PublicKey> { Some(self.remote_identity_key) }
 }
 
 impl Session for ServerSession {
-    fn is_expired(&self) -> bool { self.expire_at < Utc::now() }
-    fn session_state(&self) -> SessionState { self.state }
+    fn is_expired(&self) -> bool {
+        self.expire_at + leeway(self.skew_tolerance) < self.clock.now()
+    }
+    fn state(&self) -> SessionState { self.state }
     fn id(&self) -> PublicKey { self.remote_session_key }
+    fn created_at(&self) -> DateTime<Utc> { self.created_at }
+    fn expire_at(&self) -> DateTime<Utc> { self.expire_at }
+    fn remote_identity(&self) -> Option<PublicKey> { self.remote_identity_key }
 }
 
 impl Session for EstablishedSession {
-    fn is_expired(&self) -> bool { self.expire_at < Utc::now() }
-    fn session_state(&self) -> SessionState { SessionState::Ready }
+    fn is_expired(&self) -> bool { self.writer.is_expired() }
+    fn state(&self) -> SessionState { self.writer.state() }
+    fn id(&self) -> PublicKey { self.writer.id() }
+    fn created_at(&self) -> DateTime<Utc> { self.writer.created_at() }
+    fn expire_at(&self) -> DateTime<Utc> { self.writer.expire_at }
+    fn remote_identity(&self) -> Option<PublicKey> { self.writer.remote_identity() }
+}
+
+impl Session for SessionReader {
+    fn is_expired(&self) -> bool { self.expire_at < self.stats.clock.now() }
+    fn state(&self) -> SessionState { self.stats.state() }
+    fn id(&self) -> PublicKey { self.id }
+    fn created_at(&self) -> DateTime<Utc> { self.stats.created_at }
+    fn expire_at(&self) -> DateTime<Utc> { self.expire_at }
+    fn remote_identity(&self) -> Option<PublicKey> { self.stats.peer_identity }
+}
+
+impl Session for SessionWriter {
+    fn is_expired(&self) -> bool {
+        self.expire_at < self.stats.clock.now() || self.injected_expiry()
+    }
+    fn state(&self) -> SessionState { self.stats.state() }
     fn id(&self) -> PublicKey { self.id }
+    fn created_at(&self) -> DateTime<Utc> { self.stats.created_at }
+    fn expire_at(&self) -> DateTime<Utc> { self.expire_at }
+    fn remote_identity(&self) -> Option<PublicKey> { self.stats.peer_identity }
 }
 
 #[cfg(test)]
-mod test {
-    use frame::FrameKind;
-    use session::{ClientSession, EstablishedSession, KeyPair, ServerSession, Session, SessionState};
-    use crypto::init;
+pub(crate) mod test {
+    use frame::{Frame, FrameKind};
+    use session::{ClientSession, EstablishedSession, HANDSHAKE_DURATION, KeyPair,
+                  NONCE_DIRECTION_BIT, SERVICE_HINT_SIZE, SESSION_DURATION, ServerSession, Session,
+                  SessionConfig, SessionState, hello_suites, nonce_counter, read_service_name,
+                  seal_payload};
+    use chrono::Duration;
+    use chrono::offset::{TimeZone, Utc};
+    use attestation::{AttestationVerifier, RequireAttestation};
+    use clock::{Clock, MockClock};
+    use crypto::{KeyValidity, init};
+    use errors::{WhisperError, WhisperResult};
+    use metadata;
+    use pacing::Pacer;
+    use sodiumoxide::crypto::box_::{self, PublicKey};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use suite::CipherSuite;
+    use transport::{REPLAY_WINDOW, TransportMode};
 
     /// Helper to create two established sessions.
-    fn handshake() -> (EstablishedSession, EstablishedSession) {
-        let client_identity_keypair = KeyPair::new();
-        let server_identity_keypair = KeyPair::new();
+    pub fn handshake() -> (EstablishedSession, EstablishedSession) {
+        handshake_with(KeyPair::new().unwrap(), KeyPair::new().unwrap())
+    }
+
+    /// Same as above, but with supplied identities.
+    pub fn handshake_with(client_identity_keypair: KeyPair,
+                          server_identity_keypair: KeyPair)
+                          -> (EstablishedSession, EstablishedSession) {
+        configured_handshake_with(client_identity_keypair, server_identity_keypair, |_, _| {})
+    }
+
+    /// Same as `handshake`, but `configure` gets both sessions before Hello
+    /// to offer and accept options.
+    pub fn configured_handshake<F>(configure: F) -> (EstablishedSession, EstablishedSession)
+        where F: FnOnce(&mut ClientSession, &mut ServerSession)
+    {
+        configured_handshake_with(KeyPair::new().unwrap(), KeyPair::new().unwrap(), configure)
+    }
+
+    fn configured_handshake_with<F>(client_identity_keypair: KeyPair,
+                                    server_identity_keypair: KeyPair,
+                                    configure: F)
+                                    -> (EstablishedSession, EstablishedSession)
+        where F: FnOnce(&mut ClientSession, &mut ServerSession)
+    {
         let mut client_session =
             ClientSession::new(client_identity_keypair.clone(),
-                               server_identity_keypair.public_key.clone());
-        let mut server_session = ServerSession::new(server_identity_keypair, client_session.id().clone());
-        let hello_frame = client_session.make_hello();
-        let welcome_frame =
-            server_session.make_welcome(&hello_frame)
-                          .expect("Failed to create welcome!");
-        let initiate_frame =
-            client_session.make_initiate(&welcome_frame)
-                          .expect("Failed to create initiate!");
-        let client_identity_key =
-            server_session.validate_initiate(&initiate_frame)
-                          .expect("Failed to unpack PublicKey");
+                               server_identity_keypair.public_key).unwrap();
+        let mut server_session =
+            ServerSession::new(server_identity_keypair, client_session.id()).unwrap();
+        configure(&mut client_session, &mut server_session);
+        finish_handshake(&mut client_session, &mut server_session).expect("Handshake failed!")
+    }
+
+    /// Same as `configured_handshake`, but each side is built with its own
+    /// config and whatever step fails first is returned instead of panicking.
+    pub fn try_handshake<F>(client_config: SessionConfig,
+                            server_config: SessionConfig,
+                            configure: F)
+                            -> WhisperResult<(EstablishedSession, EstablishedSession)>
+        where F: FnOnce(&mut ClientSession, &mut ServerSession)
+    {
+        let server_identity_keypair = KeyPair::new()?;
+        let mut client_session = ClientSession::with_config(KeyPair::new()?,
+                                                            server_identity_keypair.public_key,
+                                                            client_config)?;
+        let mut server_session = ServerSession::with_config(server_identity_keypair,
+                                                            client_session.id(),
+                                                            server_config)?;
+        configure(&mut client_session, &mut server_session);
+        finish_handshake(&mut client_session, &mut server_session)
+    }
+
+    /// Walks fresh sessions from Hello to Ready.
+    fn finish_handshake(client_session: &mut ClientSession,
+                        server_session: &mut ServerSession)
+                        -> WhisperResult<(EstablishedSession, EstablishedSession)> {
+        let hello_frame = client_session.make_hello()?;
+        let welcome_frame = server_session.make_welcome(&hello_frame)?;
+        let initiate_frame = client_session.make_initiate(&welcome_frame)?;
+        let client_identity_key = server_session.validate_initiate(&initiate_frame)?;
         let (server_established_session, ready_frame) =
-            server_session.make_ready(&initiate_frame, &client_identity_key)
-                          .expect("Failed to create ready!");
-        let client_established_session =
-            client_session.read_ready(&ready_frame)
-                          .expect("Failed to read ready frame!");
-        (client_established_session, server_established_session)
+            server_session.make_ready(&initiate_frame, &client_identity_key)?;
+        let client_established_session = client_session.read_ready(&ready_frame)?;
+        Ok((client_established_session, server_established_session))
+    }
+
+    #[test]
+    fn test_session_trait() {
+        fn peer<S: Session>(session: &S) -> Option<PublicKey> {
+            assert!(session.created_at() <= Utc::now());
+            assert!(session.expire_at() > session.created_at());
+            session.remote_identity()
+        }
+        let client_identity = KeyPair::new().unwrap();
+        let server_identity = KeyPair::new().unwrap();
+        let (client, server) = handshake_with(client_identity.clone(), server_identity.clone());
+        assert_eq!(peer(&client), Some(server_identity.public_key));
+        assert_eq!(peer(&server), Some(client_identity.public_key));
+        assert_eq!(client.state(), SessionState::Ready);
+        let (reader, writer) = server.split();
+        assert_eq!(peer(&reader), peer(&writer));
+
+        let handshake = ClientSession::new(client_identity, server_identity.public_key).unwrap();
+        assert_eq!(peer(&handshake), Some(server_identity.public_key));
+        assert_eq!(peer(&ServerSession::new(server_identity, handshake.id()).unwrap()), None);
+    }
+
+    #[test]
+    fn test_session_config() {
+        let config = SessionConfig {
+            handshake_timeout: Duration::seconds(10),
+            session_lifetime: Duration::minutes(5),
+            hello_padding: 512,
+            ready_payload: b"Ready when you are",
+        };
+        let client_identity = KeyPair::new().unwrap();
+        let server_identity = KeyPair::new().unwrap();
+        let mut client_session =
+            ClientSession::with_config(client_identity.clone(), server_identity.public_key, config)
+                .unwrap();
+        assert_eq!(client_session.expire_at() - client_session.created_at(),
+                   Duration::seconds(10));
+        client_session.set_service_name(server_identity.public_key, "mail").unwrap();
+        let hello = client_session.make_hello().unwrap();
+        assert_eq!(hello.payload.len(), 512 + box_::MACBYTES + SERVICE_HINT_SIZE);
+        assert!(hello.validate().is_ok());
+        assert_eq!(read_service_name(&hello, &server_identity).unwrap(), Some("mail".into()));
+        let mut server_session =
+            ServerSession::from_hello_with_config(server_identity.clone(), &hello, config)
+                .unwrap();
+        let welcome = server_session.make_welcome(&hello).unwrap();
+        let initiate = client_session.make_initiate(&welcome).unwrap();
+        let key = server_session.validate_initiate(&initiate).unwrap();
+        let (server, ready) = server_session.make_ready(&initiate, &key).unwrap();
+        let client = client_session.read_ready(&ready).unwrap();
+        for session in &[client, server] {
+            assert_eq!(session.expire_at() - session.created_at(), Duration::minutes(5));
+        }
+
+        // Both sides have to agree on Ready payload.
+        match try_handshake(SessionConfig::default(), config, |_, _| {}) {
+            Err(WhisperError::InvalidReadyFrame) => {}
+            other => panic!("Expected InvalidReadyFrame, got {:?}", other.map(|_| ())),
+        }
+
+        for padding in &[0, 255, 300, 1088] {
+            let config = SessionConfig {
+                hello_padding: *padding,
+                ..SessionConfig::default()
+            };
+            let server_key = server_identity.public_key;
+            let client_identity = client_identity.clone();
+            assert!(ClientSession::with_config(client_identity, server_key, config).is_err());
+        }
+        let config = SessionConfig {
+            ready_payload: b"short",
+            ..SessionConfig::default()
+        };
+        let client_key = client_identity.public_key;
+        assert!(ServerSession::with_config(server_identity, client_key, config).is_err());
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(Utc.timestamp_opt(1_000_000_000, 0).unwrap());
+        let (client, server) = configured_handshake(|client, server| {
+            client.set_clock(Arc::new(clock.clone()));
+            server.set_clock(Arc::new(clock.clone()));
+        });
+        assert_eq!(client.created_at(), clock.now());
+
+        clock.advance(Duration::minutes(SESSION_DURATION) - Duration::seconds(1));
+        assert!(!client.is_expired());
+        assert!(client.make_request(b"still here").is_ok());
+        clock.advance(Duration::seconds(2));
+        assert!(client.is_expired() && server.is_expired());
+        assert!(client.make_request(b"too late").is_err());
+        assert!(!server.is_alive(Duration::minutes(1)));
+
+        let mut stale = ClientSession::new(KeyPair::new().unwrap(),
+                                           KeyPair::new().unwrap().public_key)
+            .unwrap();
+        stale.set_clock(Arc::new(clock.clone()));
+        assert_eq!(stale.info().created_at, clock.now());
+        let mut server = ServerSession::with_session_keypair(KeyPair::new().unwrap(),
+                                                             KeyPair::new().unwrap(),
+                                                             stale.info().id);
+        server.set_clock(Arc::new(clock.clone()));
+        assert_eq!(server.info().created_at, clock.now());
+        assert!(server.info().expires_at > clock.now());
+        clock.advance(Duration::minutes(HANDSHAKE_DURATION) + Duration::seconds(1));
+        assert!(stale.is_expired());
+    }
+
+    #[test]
+    fn test_cipher_suite() {
+        let agree = |offer: Option<CipherSuite>, accept: Option<CipherSuite>| {
+            let (client, server) = configured_handshake(|client, server| {
+                if let Some(suite) = offer {
+                    client.offer_cipher_suite(suite);
+                }
+                if let Some(suite) = accept {
+                    server.accept_cipher_suite(suite);
+                }
+            });
+            assert_eq!(client.cipher_suite(), server.cipher_suite());
+            // Keys have to line up even where null-cipher doesn't check them.
+            assert_eq!(client.writer.session_secret.0, server.reader.session_secret.0);
+            assert_eq!(client.reader.session_secret.0, server.writer.session_secret.0);
+            let request = client.make_request(b"ping").unwrap();
+            assert_eq!(server.read_msg(&request).unwrap().as_ref(), b"ping");
+            let response = server.make_response(b"pong").unwrap();
+            assert_eq!(client.read_msg(&response).unwrap().as_ref(), b"pong");
+            client.cipher_suite()
+        };
+        let xchacha = Some(CipherSuite::XChaCha20Poly1305);
+        assert_eq!(agree(None, None), CipherSuite::XSalsa20Poly1305);
+        assert_eq!(agree(xchacha, None), CipherSuite::XSalsa20Poly1305);
+        assert_eq!(agree(None, xchacha), CipherSuite::XSalsa20Poly1305);
+        assert_eq!(agree(xchacha, xchacha), CipherSuite::XChaCha20Poly1305);
+
+        let (mut client, _) = handshake();
+        let ready_key = client.writer.session_secret.0;
+        client.set_cipher_suite(CipherSuite::XChaCha20Poly1305);
+        assert_ne!(client.writer.session_secret.0, ready_key);
+    }
+
+    #[test]
+    fn test_suite_negotiation() {
+        let negotiate = |listed: &[CipherSuite], accepted: &[CipherSuite]| {
+            let (client, server) = configured_handshake(|client, server| {
+                client.set_cipher_suites(listed).unwrap();
+                for suite in accepted {
+                    server.accept_cipher_suite(*suite);
+                }
+            });
+            assert_eq!(client.cipher_suite(), server.cipher_suite());
+            let request = client.make_request(b"ping").unwrap();
+            assert_eq!(server.read_msg(&request).unwrap().as_ref(), b"ping");
+            client.cipher_suite()
+        };
+        let (xsalsa, xchacha) = (CipherSuite::XSalsa20Poly1305, CipherSuite::XChaCha20Poly1305);
+        assert_eq!(negotiate(&[], &[xchacha]), xsalsa);
+        assert_eq!(negotiate(&[xchacha, xsalsa], &[]), xsalsa);
+        assert_eq!(negotiate(&[xchacha, xsalsa], &[xsalsa, xchacha]), xchacha);
+        assert_eq!(negotiate(&[xsalsa, xchacha], &[xsalsa, xchacha]), xsalsa);
+        assert_eq!(negotiate(&[xsalsa, xchacha], &[xchacha]), xchacha);
+        assert_eq!(hello_suites(&[3, 1, 9, 0, 1]), vec![xchacha, xsalsa]);
+
+        let mut client =
+            ClientSession::new(KeyPair::new().unwrap(), KeyPair::new().unwrap().public_key)
+                .unwrap();
+        assert!(client.set_cipher_suites(&[xsalsa; 256]).is_err());
+        assert!(client.set_cipher_suites(&[xsalsa; 255]).is_ok());
+
+        // Initiate has to offer what Welcome picked. Client here got Welcome
+        // from a twin of the server that accepts other suites.
+        let server_identity = KeyPair::new().unwrap();
+        let twin_rejection = |listed: &[CipherSuite], twin_accepts: &[CipherSuite]| {
+            let server_session = KeyPair::new().unwrap();
+            let mut client =
+                ClientSession::new(KeyPair::new().unwrap(), server_identity.public_key).unwrap();
+            client.set_cipher_suites(listed).unwrap();
+            let hello = client.make_hello().unwrap();
+            let mut twin = ServerSession::with_session_keypair(server_identity.clone(),
+                                                               server_session.clone(),
+                                                               hello.id);
+            for suite in twin_accepts {
+                twin.accept_cipher_suite(*suite);
+            }
+            let welcome = twin.make_welcome(&hello).unwrap();
+            let initiate = client.make_initiate(&welcome).unwrap();
+            let mut server = ServerSession::with_session_keypair(server_identity.clone(),
+                                                                 server_session,
+                                                                 hello.id);
+            server.accept_cipher_suite(xchacha);
+            server.make_welcome(&hello).unwrap();
+            let key = server.validate_initiate(&initiate).unwrap();
+            match server.make_ready(&initiate, &key) {
+                Err(e) => (e, server.state()),
+                Ok(_) => panic!("Initiate without server's pick was accepted"),
+            }
+        };
+        // No pick offered.
+        match twin_rejection(&[xchacha], &[]) {
+            (WhisperError::InvalidInitiateFrame, SessionState::Error) => {}
+            other => panic!("Expected InvalidInitiateFrame and Error, got {:?}", other),
+        }
+        // Other pick offered.
+        match twin_rejection(&[xchacha, xsalsa], &[xsalsa]) {
+            (WhisperError::InvalidInitiateFrame, SessionState::Error) => {}
+            other => panic!("Expected InvalidInitiateFrame and Error, got {:?}", other),
+        }
+    }
+    }
+
+    #[test]
+    fn test_read_msg_secure() {
+        let (client, server) = handshake();
+        let request = client.make_request(b"pin 1234").unwrap();
+        let msg = server.read_msg_secure(&request).unwrap();
+        assert_eq!(&msg[..], b"pin 1234");
+        assert_eq!(format!("{:?}", msg), "SecureBytes(8 bytes)");
+        match server.read_msg_secure(&request) {
+            Err(WhisperError::ReplayedFrame) => {}
+            other => panic!("Expected ReplayedFrame, got {:?}", other),
+        }
     }
 
     #[test]
     fn test_expire_client() {
-        let local = KeyPair::new();
-        let remote = KeyPair::new();
+        let local = KeyPair::new().unwrap();
+        let remote = KeyPair::new().unwrap();
 
-        let client_session = ClientSession::new(local, remote.public_key.clone());
+        let client_session = ClientSession::new(local, remote.public_key).unwrap();
         assert!(!client_session.is_expired());
     }
 
     #[test]
     fn test_expire_server() {
-        let local = KeyPair::new();
-        let remote = KeyPair::new();
+        let local = KeyPair::new().unwrap();
+        let remote = KeyPair::new().unwrap();
 
-        let server_session = ServerSession::new(local, remote.public_key.clone());
+        let server_session = ServerSession::new(local, remote.public_key).unwrap();
         assert!(!server_session.is_expired());
     }
 
+    #[test]
+    fn test_from_hello() {
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                    server_identity_keypair.public_key).unwrap();
+        let hello = client_session.make_hello().unwrap();
+        let mut server_session = ServerSession::from_hello(server_identity_keypair.clone(), &hello)
+            .unwrap();
+        assert!(server_session.opened_hello.is_some());
+        let welcome = server_session.make_welcome(&hello).unwrap();
+        assert!(server_session.opened_hello.is_none());
+        assert!(client_session.make_initiate(&welcome).is_ok());
+
+        match ServerSession::from_hello(KeyPair::new().unwrap(), &hello) {
+            Err(WhisperError::DecryptionFailed) => {}
+            other => panic!("Expected DecryptionFailed, got {:?}", other.map(|_| ())),
+        }
+        let mut not_hello = hello.clone();
+        not_hello.kind = FrameKind::Welcome;
+        assert!(ServerSession::from_hello(server_identity_keypair.clone(), &not_hello).is_err());
+        let mut truncated = hello.clone();
+        truncated.payload.truncate(100);
+        assert!(ServerSession::from_hello(server_identity_keypair, &truncated).is_err());
+    }
+
     #[test]
     fn test_successful_hashshake() {
         init().unwrap();
-        let client_identity_keypair = KeyPair::new();
-        let server_identity_keypair = KeyPair::new();
+        let client_identity_keypair = KeyPair::new().unwrap();
+        let server_identity_keypair = KeyPair::new().unwrap();
 
         let mut client_session =
             ClientSession::new(client_identity_keypair.clone(),
-                               server_identity_keypair.public_key.clone());
-        let mut server_session = ServerSession::new(server_identity_keypair.clone(), client_session.id().clone());
+                               server_identity_keypair.public_key).unwrap();
+        let mut server_session =
+            ServerSession::new(server_identity_keypair.clone(), client_session.id()).unwrap();
         assert_eq!(client_session.state, SessionState::Fresh);
         assert_eq!(server_session.state, SessionState::Fresh);
         assert_eq!(client_session.id(), server_session.id());
 
-        let hello_frame = client_session.make_hello();
+        let hello_frame = client_session.make_hello().unwrap();
         assert_eq!(hello_frame.kind, FrameKind::Hello);
         assert_eq!(client_session.state, SessionState::Initiated);
 
@@ -489,16 +3205,355 @@ mod test {
         let (server_established_session, ready_frame) =
             server_session.make_ready(&initiate_frame, &client_identity_key)
                           .expect("Failed to create ready!");
-        assert_eq!(server_established_session.session_state(),
+        assert_eq!(server_established_session.state(),
                    SessionState::Ready);
-        assert_eq!(server_session.session_state(), SessionState::Ready);
+        assert_eq!(server_session.state(), SessionState::Ready);
 
         let client_established_session =
             client_session.read_ready(&ready_frame)
                           .expect("Failed to read ready frame!");
-        assert_eq!(client_established_session.session_state(),
+        assert_eq!(client_established_session.state(),
                    SessionState::Ready);
-        assert_eq!(client_session.session_state(), SessionState::Ready);
+        assert_eq!(client_session.state(), SessionState::Ready);
+    }
+
+    #[test]
+    fn test_ready_wrong_key() {
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                    server_identity_keypair.public_key).unwrap();
+        let mut server_session =
+            ServerSession::new(server_identity_keypair, client_session.id()).unwrap();
+        let welcome_frame =
+            server_session.make_welcome(&client_session.make_hello().unwrap()).unwrap();
+        let initiate_frame = client_session.make_initiate(&welcome_frame).unwrap();
+        // Key that didn't vouch in this Initiate can't be authenticated.
+        match server_session.make_ready(&initiate_frame, &KeyPair::new().unwrap().public_key) {
+            Err(WhisperError::InvalidPublicKey) => {}
+            other => panic!("Expected InvalidPublicKey, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(server_session.state(), SessionState::Error);
+    }
+
+    /// Handshake up to Initiate, set up by `configure` and spoiled by
+    /// `tamper`. Returns why `make_ready` refused it and state it left
+    /// server in.
+    fn ready_rejection<F, T>(configure: F, tamper: T) -> (WhisperError, SessionState)
+        where F: FnOnce(&mut ClientSession, &mut ServerSession),
+              T: FnOnce(&mut Frame)
+    {
+        let client_identity_keypair = KeyPair::new().unwrap();
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let mut client_session = ClientSession::new(client_identity_keypair.clone(),
+                                                    server_identity_keypair.public_key).unwrap();
+        let mut server_session =
+            ServerSession::new(server_identity_keypair, client_session.id()).unwrap();
+        configure(&mut client_session, &mut server_session);
+        let welcome_frame =
+            server_session.make_welcome(&client_session.make_hello().unwrap()).unwrap();
+        let mut initiate_frame = client_session.make_initiate(&welcome_frame).unwrap();
+        tamper(&mut initiate_frame);
+        match server_session.make_ready(&initiate_frame, &client_identity_keypair.public_key) {
+            Err(e) => (e, server_session.state()),
+            Ok(_) => panic!("Spoiled Initiate was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_ready_rejections() {
+        let late = |_: &mut ClientSession, server: &mut ServerSession| {
+            server.created_at = server.created_at - Duration::hours(1);
+        };
+        match ready_rejection(late, |_| {}) {
+            (WhisperError::ExpiredSession, SessionState::Error) => {}
+            other => panic!("Expected ExpiredSession and Error, got {:?}", other),
+        }
+        let flip = |initiate: &mut Frame| {
+            let mut payload = initiate.payload.to_vec();
+            payload[0] ^= 1;
+            initiate.payload = payload.into();
+        };
+        match ready_rejection(|_, _| {}, flip) {
+            (WhisperError::InvalidInitiateFrame, SessionState::Error) => {}
+            other => panic!("Expected InvalidInitiateFrame and Error, got {:?}", other),
+        }
+        let unknown_mode = |client: &mut ClientSession, _: &mut ServerSession| {
+            client.set_initiate_metadata(metadata::TRANSPORT, vec![99]);
+        };
+        match ready_rejection(unknown_mode, |_| {}) {
+            (WhisperError::InvalidInitiateFrame, SessionState::Error) => {}
+            other => panic!("Expected InvalidInitiateFrame and Error, got {:?}", other),
+        }
+        let datagram_only = |_: &mut ClientSession, server: &mut ServerSession| {
+            server.require_transport_mode(TransportMode::Datagram);
+        };
+        match ready_rejection(datagram_only, |_| {}) {
+            (WhisperError::InvalidInitiateFrame, SessionState::Error) => {}
+            other => panic!("Expected InvalidInitiateFrame and Error, got {:?}", other),
+        }
+        let broken_compression = |client: &mut ClientSession, _: &mut ServerSession| {
+            client.set_initiate_metadata(metadata::COMPRESSION, vec![7, 7]);
+        };
+        match ready_rejection(broken_compression, |_| {}) {
+            (WhisperError::InvalidInitiateFrame, SessionState::Error) => {}
+            other => panic!("Expected InvalidInitiateFrame and Error, got {:?}", other),
+        }
+        let broken_suite = |client: &mut ClientSession, _: &mut ServerSession| {
+            client.set_initiate_metadata(metadata::CIPHER_SUITE, vec![1, 2]);
+        };
+        match ready_rejection(broken_suite, |_| {}) {
+            (WhisperError::InvalidInitiateFrame, SessionState::Error) => {}
+            other => panic!("Expected InvalidInitiateFrame and Error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_early_data() {
+        let client_identity_keypair = KeyPair::new().unwrap();
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let mut client_session = ClientSession::new(client_identity_keypair.clone(),
+                                                    server_identity_keypair.public_key).unwrap();
+        let mut server_session =
+            ServerSession::new(server_identity_keypair, client_session.id()).unwrap();
+        let hello_frame = client_session.make_hello().unwrap();
+        let welcome_frame = server_session.make_welcome(&hello_frame).unwrap();
+        let initiate_frame =
+            client_session.make_initiate_with_early_data(&welcome_frame, b"open door")
+                          .unwrap();
+        let client_identity_key = server_session.validate_initiate(&initiate_frame).unwrap();
+        assert_eq!(client_identity_key, client_identity_keypair.public_key);
+
+        // Not authenticated yet.
+        assert!(server_session.read_early_data(&initiate_frame).is_err());
+        server_session.make_ready(&initiate_frame, &client_identity_key)
+                      .unwrap();
+        let early_data = server_session.read_early_data(&initiate_frame).unwrap();
+        assert_eq!(early_data.as_ref(), b"open door");
+        // Delivered only once.
+        assert!(server_session.read_early_data(&initiate_frame).is_err());
+    }
+
+    #[test]
+    fn test_abbreviated_handshake() {
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let server_session_keypair = KeyPair::new().unwrap();
+
+        // First connection does full handshake and caches server key.
+        let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                    server_identity_keypair.public_key).unwrap();
+        let mut server_session =
+            ServerSession::with_session_keypair(server_identity_keypair.clone(),
+                                                server_session_keypair.clone(),
+                                                client_session.id());
+        let hello_frame = client_session.make_hello().unwrap();
+        let welcome_frame = server_session.make_welcome(&hello_frame).unwrap();
+        client_session.make_initiate(&welcome_frame).unwrap();
+        let cached_key = client_session.server_session_key().unwrap();
+        assert_eq!(cached_key, server_session_keypair.public_key);
+
+        // Reconnect skips Hello and Welcome.
+        let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                    server_identity_keypair.public_key).unwrap();
+        let initiate_frame = client_session.make_abbreviated_initiate(cached_key).unwrap();
+        let mut server_session =
+            ServerSession::with_session_keypair(server_identity_keypair.clone(),
+                                                server_session_keypair,
+                                                initiate_frame.id);
+        let client_identity_key = server_session.accept_abbreviated(&initiate_frame).unwrap();
+        let (server, ready_frame) = server_session.make_ready(&initiate_frame, &client_identity_key)
+                                                  .unwrap();
+        let client = client_session.read_ready(&ready_frame).unwrap();
+        let ping = client.make_request(b"ping").unwrap();
+        assert_eq!(server.read_msg(&ping).unwrap().as_ref(), b"ping");
+    }
+
+    #[test]
+    fn test_abbreviated_handshake_fallback() {
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                    server_identity_keypair.public_key).unwrap();
+        // Server doesn't have this key anymore.
+        let initiate_frame =
+            client_session.make_abbreviated_initiate(KeyPair::new().unwrap().public_key).unwrap();
+        let mut server_session = ServerSession::new(server_identity_keypair.clone(),
+                                                    initiate_frame.id).unwrap();
+        assert!(server_session.accept_abbreviated(&initiate_frame).is_err());
+
+        let mut server_session =
+            ServerSession::new(server_identity_keypair, initiate_frame.id).unwrap();
+        let welcome_frame = server_session.make_fallback_welcome(&initiate_frame).unwrap();
+        let initiate_frame = client_session.make_initiate(&welcome_frame).unwrap();
+        let client_identity_key = server_session.validate_initiate(&initiate_frame).unwrap();
+        let (_, ready_frame) = server_session.make_ready(&initiate_frame, &client_identity_key)
+                                             .unwrap();
+        assert!(client_session.read_ready(&ready_frame).is_ok());
+    }
+
+    #[test]
+    fn test_identity_validity() {
+        let client_identity_keypair = KeyPair::new().unwrap();
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let now = Utc::now();
+        let valid = KeyValidity {
+            not_before: now - Duration::days(1),
+            not_after: now + Duration::days(1),
+        };
+        let expired = KeyValidity {
+            not_before: now - Duration::days(2),
+            not_after: now - Duration::days(1),
+        };
+
+        // Expired client identity.
+        let mut client_session = ClientSession::new(client_identity_keypair.clone(),
+                                                    server_identity_keypair.public_key).unwrap();
+        client_session.set_identity_validity(expired);
+        let mut server_session = ServerSession::new(server_identity_keypair.clone(),
+                                                    client_session.id()).unwrap();
+        server_session.set_identity_validity(valid);
+        let hello_frame = client_session.make_hello().unwrap();
+        let welcome_frame = server_session.make_welcome(&hello_frame).unwrap();
+        let initiate_frame = client_session.make_initiate(&welcome_frame).unwrap();
+        match server_session.validate_initiate(&initiate_frame) {
+            Err(WhisperError::ExpiredIdentity) => {}
+            other => panic!("Expected ExpiredIdentity, got {:?}", other),
+        }
+
+        // Expired server identity.
+        let mut client_session = ClientSession::new(client_identity_keypair,
+                                                    server_identity_keypair.public_key).unwrap();
+        client_session.set_identity_validity(valid);
+        let mut server_session =
+            ServerSession::new(server_identity_keypair, client_session.id()).unwrap();
+        server_session.set_identity_validity(expired);
+        let hello_frame = client_session.make_hello().unwrap();
+        let welcome_frame = server_session.make_welcome(&hello_frame).unwrap();
+        match client_session.make_initiate(&welcome_frame) {
+            Err(WhisperError::ExpiredIdentity) => {}
+            other => panic!("Expected ExpiredIdentity, got {:?}", other),
+        }
+
+        // Validity required, but not attached.
+        let (client_key, server_key) = (KeyPair::new().unwrap(), KeyPair::new().unwrap());
+        let mut client_session = ClientSession::new(client_key, server_key.public_key).unwrap();
+        client_session.require_identity_validity();
+        let mut server_session =
+            ServerSession::new(server_key.clone(), client_session.id()).unwrap();
+        let welcome_frame =
+            server_session.make_welcome(&client_session.make_hello().unwrap()).unwrap();
+        match client_session.make_initiate(&welcome_frame) {
+            Err(WhisperError::ExpiredIdentity) => {}
+            other => panic!("Expected ExpiredIdentity, got {:?}", other),
+        }
+        let mut client_session =
+            ClientSession::new(KeyPair::new().unwrap(), server_key.public_key).unwrap();
+        let mut server_session = ServerSession::new(server_key, client_session.id()).unwrap();
+        server_session.require_identity_validity();
+        let welcome_frame =
+            server_session.make_welcome(&client_session.make_hello().unwrap()).unwrap();
+        let initiate_frame = client_session.make_initiate(&welcome_frame).unwrap();
+        match server_session.validate_initiate(&initiate_frame) {
+            Err(WhisperError::ExpiredIdentity) => {}
+            other => panic!("Expected ExpiredIdentity, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_attestation() {
+        let default = SessionConfig::default();
+        for &attested in &[true, false] {
+            let result = try_handshake(default, default, |client, server| {
+                if attested {
+                    client.set_attester(Arc::new(|_: &[u8]| b"TPM quote".to_vec()));
+                }
+                server.set_attestation_verifier(Arc::new(RequireAttestation));
+            });
+            match result {
+                Ok(_) => assert!(attested),
+                Err(WhisperError::AttestationFailed) => assert!(!attested),
+                Err(e) => panic!("Unexpected error {:?}", e),
+            }
+        }
+
+        // Quote must cover this handshake, recorded one doesn't do.
+        struct EchoQuote;
+        impl AttestationVerifier for EchoQuote {
+            fn verify(&self, _: &PublicKey, challenge: &[u8], quote: Option<&[u8]>) -> bool {
+                quote == Some(challenge)
+            }
+        }
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        for &replayed in &[false, true] {
+            let recorded = recorded.clone();
+            let result = try_handshake(default, default, |client, server| {
+                client.set_attester(Arc::new(move |challenge: &[u8]| {
+                    let mut recorded = recorded.lock().unwrap();
+                    if recorded.is_empty() {
+                        recorded.extend_from_slice(challenge);
+                    }
+                    recorded.clone()
+                }));
+                server.set_attestation_verifier(Arc::new(EchoQuote));
+            });
+            assert_eq!(result.is_ok(), !replayed);
+        }
+    }
+
+    #[test]
+    fn test_forged_ready() {
+        let config = SessionConfig {
+            ready_payload: b"Ready when you are",
+            ..SessionConfig::default()
+        };
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                    server_identity_keypair.public_key).unwrap();
+        let mut server_session =
+            ServerSession::with_config(server_identity_keypair, client_session.id(), config)
+                .unwrap();
+        let welcome_frame =
+            server_session.make_welcome(&client_session.make_hello().unwrap()).unwrap();
+        let initiate_frame = client_session.make_initiate(&welcome_frame).unwrap();
+        let client_identity_key = server_session.validate_initiate(&initiate_frame).unwrap();
+        let (_, ready_frame) = server_session.make_ready(&initiate_frame, &client_identity_key)
+                                             .unwrap();
+
+        // Ready that doesn't open leaves handshake as it was.
+        let mut forged = ready_frame.clone();
+        let mut payload = forged.payload.to_vec();
+        payload[0] ^= 1;
+        forged.payload = payload.into();
+        assert!(client_session.read_ready(&forged).is_err());
+        assert_eq!(client_session.state(), SessionState::Initiated);
+
+        // Server's own Ready we can't agree with ends it.
+        match client_session.read_ready(&ready_frame) {
+            Err(WhisperError::InvalidReadyFrame) => {}
+            other => panic!("Expected InvalidReadyFrame, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(client_session.state(), SessionState::Error);
+    }
+
+    #[test]
+    fn test_ready_before_welcome() {
+        let client_identity_keypair = KeyPair::new().unwrap();
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let mut client_session = ClientSession::new(client_identity_keypair,
+                                                    server_identity_keypair.public_key).unwrap();
+        let mut ready_frame = client_session.make_hello().unwrap();
+        ready_frame.kind = FrameKind::Ready;
+        assert!(client_session.read_ready(&ready_frame).is_err());
+    }
+
+    #[test]
+    fn test_hello_only_once() {
+        let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                    KeyPair::new().unwrap().public_key).unwrap();
+        assert!(client_session.make_hello().is_ok());
+        match client_session.make_hello() {
+            Err(WhisperError::InvalidSessionState) => {}
+            other => panic!("Expected InvalidSessionState, got {:?}", other),
+        }
+        assert_eq!(client_session.state(), SessionState::Initiated);
     }
 
     #[test]
@@ -521,4 +3576,687 @@ mod test {
 
         assert_eq!(score.kind, FrameKind::Notification);
     }
+
+    #[test]
+    fn test_split_halves() {
+        let (client, server) = handshake();
+        let (client_reader, client_writer) = client.split();
+        let (server_reader, server_writer) = server.split();
+
+        let writer = thread::spawn(move || client_writer.make_request(b"ping").unwrap());
+        let ping = writer.join().unwrap();
+        assert_eq!(server_reader.read_msg(&ping).unwrap().as_ref(), b"ping");
+
+        let pong = server_writer.make_response(b"pong").unwrap();
+        let reader = thread::spawn(move || client_reader.read_msg(&pong).unwrap());
+        assert_eq!(reader.join().unwrap().as_ref(), b"pong");
+    }
+
+    #[test]
+    fn test_transport_mode() {
+        let (client, mut server) = handshake();
+        assert_eq!(client.mode(), TransportMode::Stream);
+        let ping = client.make_request(b"ping").unwrap();
+        let packet = client.pack(&ping);
+        assert_eq!(packet.len(), 4 + ping.length());
+        assert_eq!(server.read_packet(&packet).unwrap().1.as_ref(), b"ping");
+        // Stream transport doesn't duplicate, but whoever is on the path can.
+        assert!(server.read_packet(&packet).is_err());
+        assert!(server.read_packet(&packet[..10]).is_err());
+
+        let (mut client, mut server) = configured_handshake(|client, _| {
+            client.set_transport_mode(TransportMode::Datagram);
+        });
+        assert_eq!(client.mode(), TransportMode::Datagram);
+        assert_eq!(server.mode(), TransportMode::Datagram);
+
+        let ping = client.make_request(b"ping").unwrap();
+        let packet = client.pack(&ping);
+        assert_eq!(packet, ping.pack());
+        assert_eq!(server.read_packet(&packet).unwrap().1.as_ref(), b"ping");
+        match server.read_packet(&packet) {
+            Err(WhisperError::ReplayedFrame) => {}
+            other => panic!("Expected ReplayedFrame, got {:?}", other.map(|_| ())),
+        }
+        let pong = server.pack(&server.make_response(b"pong").unwrap());
+        assert!(client.read_packet(&pong).is_ok());
+
+        // Server that only speaks datagrams refuses stream clients.
+        let (_, server) = configured_handshake(|client, server| {
+            client.set_transport_mode(TransportMode::Datagram);
+            server.require_transport_mode(TransportMode::Datagram);
+        });
+        assert_eq!(server.mode(), TransportMode::Datagram);
+        let default = SessionConfig::default();
+        match try_handshake(default, default, |_, server| {
+            server.require_transport_mode(TransportMode::Datagram);
+        }) {
+            Err(WhisperError::InvalidInitiateFrame) => {}
+            other => panic!("Expected InvalidInitiateFrame, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_pacing() {
+        let (mut client, server) = handshake();
+        let mut pacer = Pacer::new();
+        pacer.limit_frames(1, 2).unwrap();
+        client.set_pacer(pacer);
+        let (_, writer) = client.clone().split();
+        assert!(client.make_request(b"one").is_ok());
+        assert!(writer.make_notification(b"two").is_ok());
+        match client.make_request(b"three") {
+            Err(WhisperError::RateLimited(wait)) => assert!(wait.as_secs() <= 1),
+            other => panic!("Expected RateLimited, got {:?}", other),
+        }
+        assert_eq!(client.info().frames_sent, 2);
+        // Other side is not limited.
+        assert!(server.make_response(b"one").is_ok());
+    }
+
+    #[test]
+    fn test_compression_negotiation() {
+        let agree = |offer: u32, mode: TransportMode| {
+            let (client, server) = configured_handshake(|client, server| {
+                client.set_transport_mode(mode);
+                client.offer_compression(offer);
+                server.accept_compression(0);
+                server.accept_compression(7);
+            });
+            assert_eq!(client.compression(), server.compression());
+            client.compression()
+        };
+        assert_eq!(agree(7, TransportMode::Stream), Some(7));
+        assert_eq!(agree(0, TransportMode::Stream), Some(0));
+        assert_eq!(agree(3, TransportMode::Stream), None);
+        assert_eq!(agree(7, TransportMode::Datagram), None);
+        // Nothing offered, nothing agreed.
+        let (client, server) = handshake();
+        assert_eq!(client.compression(), None);
+        assert_eq!(server.compression(), None);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_payload_compression() {
+        let agree = |offer: bool, accept: bool| {
+            let (client, server) = configured_handshake(|client, server| {
+                client.set_transport_mode(TransportMode::Datagram);
+                if offer {
+                    client.offer_payload_compression();
+                }
+                if accept {
+                    server.accept_payload_compression();
+                    server.accept_compression(0);
+                }
+            });
+            assert_eq!(client.payload_compression(), server.payload_compression());
+            (client, server)
+        };
+        assert!(!agree(true, false).0.payload_compression());
+        assert!(!agree(false, true).0.payload_compression());
+        // One offer at a time: the last one wins.
+        let (client, server) = configured_handshake(|client, server| {
+            client.offer_payload_compression();
+            client.offer_compression(0);
+            server.accept_payload_compression();
+            server.accept_compression(0);
+        });
+        assert_eq!(client.compression(), Some(0));
+        assert!(!client.payload_compression() && !server.payload_compression());
+        let (client, server) = agree(true, true);
+        assert!(client.payload_compression());
+        let json = b"{\"sensor\":\"kitchen\",\"temperature\":21.5}".repeat(8);
+        let request = client.make_request(&json).unwrap();
+        assert!(request.payload.len() < json.len());
+        assert_eq!(server.read_msg(&request).unwrap().as_ref(), &json[..]);
+        let response = server.make_response(b"ok").unwrap();
+        assert_eq!(client.read_msg(&response).unwrap().as_ref(), b"ok");
+    }
+
+    #[test]
+    fn test_adopt_server_time() {
+        // Device thinks it's 1970, server identity is valid for a day from now.
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let now = Utc::now();
+        let validity = KeyValidity {
+            not_before: now - Duration::hours(1),
+            not_after: now + Duration::days(1),
+        };
+        let handshake_until_welcome = |adopt: bool| {
+            let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                        server_identity_keypair.public_key)
+                .unwrap();
+            client_session.clock_offset = Utc.timestamp_opt(0, 0).unwrap() - Utc::now();
+            if adopt {
+                client_session.adopt_server_time();
+            }
+            let hello_frame = client_session.make_hello().unwrap();
+            let mut server_session = ServerSession::new(server_identity_keypair.clone(),
+                                                        hello_frame.id).unwrap();
+            server_session.set_identity_validity(validity);
+            let welcome_frame = server_session.make_welcome(&hello_frame).unwrap();
+            let result = client_session.make_initiate(&welcome_frame).map(|_| ());
+            (client_session, result)
+        };
+
+        let (_, result) = handshake_until_welcome(false);
+        match result {
+            Err(WhisperError::ExpiredIdentity) => {}
+            other => panic!("Expected ExpiredIdentity, got {:?}", other),
+        }
+        let (client_session, result) = handshake_until_welcome(true);
+        assert!(result.is_ok());
+        assert!((client_session.now() - Utc::now()).num_seconds().abs() < 5);
+
+        // Session lifetime is counted in server time as well.
+        let clock = MockClock::new(Utc.timestamp_opt(0, 0).unwrap());
+        let (client, _) = configured_handshake(|client, _| {
+            client.set_clock(Arc::new(clock.clone()));
+            client.adopt_server_time();
+        });
+        let expected = Utc::now() + Duration::minutes(SESSION_DURATION);
+        assert!((client.expire_at() - expected).num_seconds().abs() < 5);
+        clock.advance(Duration::minutes(SESSION_DURATION) + Duration::seconds(10));
+        assert!(client.is_expired());
+    }
+
+    #[test]
+    fn test_bind_secret() {
+        let (mut client, mut server) = handshake();
+        client.bind_secret(b"password");
+        let ping = client.make_request(b"ping").unwrap();
+        assert!(server.read_msg(&ping).is_err());
+        server.bind_secret(b"password");
+        assert_eq!(server.read_msg(&ping).unwrap().as_ref(), b"ping");
+    }
+
+    #[test]
+    fn test_clone_shares_secret() {
+        let (client, server) = handshake();
+        assert_eq!(Arc::strong_count(&client.writer.session_secret), 1);
+
+        let worker = client.clone();
+        assert_eq!(Arc::strong_count(&client.writer.session_secret), 2);
+        assert!(Arc::ptr_eq(&client.writer.session_secret, &worker.writer.session_secret));
+        assert!(Arc::ptr_eq(&client.reader.session_secret, &worker.reader.session_secret));
+
+        let ping = worker.make_request(b"ping").unwrap();
+        assert_eq!(server.read_msg(&ping).unwrap().as_ref(), b"ping");
+
+        drop(worker);
+        assert_eq!(Arc::strong_count(&client.writer.session_secret), 1);
+    }
+
+    #[test]
+    fn test_replay_protection() {
+        let (client, mut server) = handshake();
+        let ping = client.make_request(b"ping").unwrap();
+        let worker = server.clone();
+        assert_eq!(worker.read_msg(&ping).unwrap().as_ref(), b"ping");
+        assert_eq!(server.info().frames_received, 1);
+        match server.read_msg(&ping) {
+            Err(WhisperError::ReplayedFrame) => {}
+            other => panic!("Expected ReplayedFrame, got {:?}", other),
+        }
+        assert_eq!(server.info().frames_received, 1);
+        server.set_replay_protection(false);
+        assert!(server.read_msg(&ping).is_ok());
+        assert!(server.read_msg(&ping).is_ok());
+        assert_eq!(server.info().frames_received, 3);
+    }
+
+    #[test]
+    fn test_counter_nonces() {
+        let (client, mut server) = handshake();
+        server.require_counter_nonces();
+        let worker = client.clone();
+        let first = client.make_request(b"first").unwrap();
+        let second = worker.make_request(b"second").unwrap();
+        let third = client.make_request(b"third").unwrap();
+        assert_eq!(nonce_counter(&first.nonce), Some(1));
+        assert_eq!(nonce_counter(&third.nonce), Some(3));
+        assert_eq!(server.read_msg(&first).unwrap().as_ref(), b"first");
+        assert_eq!(server.read_msg(&third).unwrap().as_ref(), b"third");
+        // Overtaken frame is as good as replayed.
+        match server.read_msg(&second) {
+            Err(WhisperError::ReplayedFrame) => {}
+            other => panic!("Expected ReplayedFrame, got {:?}", other),
+        }
+
+        // Ready was the first frame of the server.
+        let pong = server.make_response(b"pong").unwrap();
+        assert_eq!(pong.nonce.0[0], NONCE_DIRECTION_BIT);
+        assert_eq!(nonce_counter(&pong.nonce), Some(2));
+        assert_eq!(client.read_msg(&pong).unwrap().as_ref(), b"pong");
+
+        // Frames without counter don't open, with or without replay window.
+        let (client, mut server) = handshake();
+        let mut ping = client.make_request(b"ping").unwrap();
+        ping.nonce = box_::gen_nonce();
+        ping.nonce.0[0] &= !NONCE_DIRECTION_BIT;
+        ping.payload = seal_payload(CipherSuite::default(),
+                                    b"ping",
+                                    &ping.nonce,
+                                    &client.writer.session_secret)
+            .into();
+        match server.read_msg(&ping) {
+            Err(WhisperError::BadFrame) => {}
+            other => panic!("Expected BadFrame, got {:?}", other),
+        }
+        server.set_replay_protection(false);
+        server.require_counter_nonces();
+        match server.read_msg(&ping) {
+            Err(WhisperError::BadFrame) => {}
+            other => panic!("Expected BadFrame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let (client, server) = handshake();
+        let frames: Vec<Frame> =
+            (0..REPLAY_WINDOW + 2).map(|_| client.make_notification(b"tick").unwrap()).collect();
+        // Reordered frames open once.
+        assert!(server.read_msg(&frames[1]).is_ok());
+        assert!(server.read_msg(&frames[0]).is_ok());
+        assert!(server.read_msg(&frames[0]).is_err());
+        for frame in &frames[2..] {
+            assert!(server.read_msg(frame).is_ok());
+        }
+        // Replay after more than a window's worth of frames is still refused.
+        for frame in &frames[..2] {
+            match server.read_msg(frame) {
+                Err(WhisperError::ReplayedFrame) => {}
+                other => panic!("Expected ReplayedFrame, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_direction_keys() {
+        let (client, server) = handshake();
+        assert!(client.reader.session_secret.0 != client.writer.session_secret.0);
+        assert_eq!(client.writer.session_secret.0, server.reader.session_secret.0);
+        assert_eq!(client.reader.session_secret.0, server.writer.session_secret.0);
+
+        let pong = server.make_response(b"pong").unwrap();
+        assert_eq!(client.read_msg(&pong).unwrap().as_ref(), b"pong");
+    }
+
+    #[test]
+    fn test_reflected_frame() {
+        // Reflected frames don't open, even with direction bit forged.
+        let (client, server) = handshake();
+        let mut ping = client.make_request(b"ping").unwrap();
+        ping.nonce.0[0] ^= 0x80;
+        match client.read_msg(&ping) {
+            Err(WhisperError::WrongPeer) => {}
+            other => panic!("Expected WrongPeer, got {:?}", other),
+        }
+        // Nor with session id forged.
+        ping.id = server.id();
+        match client.read_msg(&ping) {
+            Err(WhisperError::DecryptionFailed) => {}
+            other => panic!("Expected DecryptionFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "null-cipher")]
+    fn test_null_cipher() {
+        let (client, server) = handshake();
+        let ping = client.make_request(b"ping").unwrap();
+        assert_eq!(&ping.payload[16..], b"ping");
+        assert_eq!(server.read_msg(&ping).unwrap().as_ref(), b"ping");
+    }
+
+    #[test]
+    fn test_nonce_direction() {
+        let (client, server) = handshake();
+        for _ in 0..16 {
+            let ping = client.make_request(b"ping").unwrap();
+            assert_eq!(ping.nonce.0[0] & 0x80, 0);
+            let pong = server.make_response(b"pong").unwrap();
+            assert_eq!(pong.nonce.0[0] & 0x80, 0x80);
+        }
+        let ping = client.make_request(b"ping").unwrap();
+        match client.read_msg(&ping) {
+            Err(WhisperError::WrongDirection) => {}
+            other => panic!("Expected WrongDirection, got {:?}", other),
+        }
+        let pong = server.make_response(b"pong").unwrap();
+        match server.read_msg(&pong) {
+            Err(WhisperError::WrongDirection) => {}
+            other => panic!("Expected WrongDirection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_wrong_peer() {
+        let (client, server) = handshake();
+        let (stranger, _) = handshake();
+        let ping = stranger.make_request(b"ping").unwrap();
+        match server.read_msg(&ping) {
+            Err(WhisperError::WrongPeer) => {}
+            other => panic!("Expected WrongPeer, got {:?}", other),
+        }
+        let ping = client.make_request(b"ping").unwrap();
+        assert_eq!(server.read_msg(&ping).unwrap().as_ref(), b"ping");
+    }
+
+    #[test]
+    fn test_close() {
+        use termination::TerminationReason;
+
+        let (client, server) = handshake();
+        let worker = client.clone();
+        let bye = client.close(TerminationReason::Unspecified).unwrap();
+        assert_eq!(bye.kind, FrameKind::Termination);
+        assert!(worker.is_closed());
+        assert_eq!(client.state(), SessionState::Closed);
+        assert_eq!(client.info().state, SessionState::Closed);
+        match worker.make_request(b"ping") {
+            Err(WhisperError::SessionClosed) => {}
+            other => panic!("Expected SessionClosed, got {:?}", other),
+        }
+        assert!(client.close(TerminationReason::Unspecified).is_err());
+
+        let mut forged = bye.clone();
+        forged.payload = vec![1; forged.payload.len()].into();
+        assert!(server.read_termination(&forged).is_err());
+        assert_eq!(server.read_termination(&bye).unwrap(), TerminationReason::Unspecified);
+        assert!(!server.is_closed());
+
+        // Racing closers, one Termination.
+        let (client, _server) = handshake();
+        let close = |client: EstablishedSession| {
+            thread::spawn(move || client.close(TerminationReason::Unspecified).is_ok())
+        };
+        let closers: Vec<_> = (0..8).map(|_| close(client.clone())).collect();
+        let sent = closers.into_iter().filter(|closer| closer.join().unwrap()).count();
+        assert_eq!(sent, 1);
+    }
+
+    #[test]
+    fn test_typed_readers() {
+        use session::{Notification, Request, Response};
+        use tracker;
+
+        let (client, server) = handshake();
+        let ping = client.make_request(b"ping").unwrap();
+        assert_eq!(server.read_request(&ping).unwrap(), Request(b"ping"[..].into()));
+        let note = client.make_notification(b"note").unwrap();
+        assert_eq!(server.read_notification(&note).unwrap(),
+                   Notification(b"note"[..].into()));
+        match server.read_request(&note) {
+            Err(WhisperError::WrongKind(FrameKind::Request, FrameKind::Notification)) => {}
+            other => panic!("Expected WrongKind, got {:?}", other),
+        }
+
+        let pong = server.make_response(&tracker::wrap(7, b"pong")).unwrap();
+        assert_eq!(client.read_response(&pong, 7).unwrap(), Response(b"pong"[..].into()));
+        match client.read_response(&pong, 8) {
+            Err(WhisperError::WrongRequestId(8, 7)) => {}
+            other => panic!("Expected WrongRequestId, got {:?}", other),
+        }
+        let note = server.make_notification(b"not a response").unwrap();
+        assert!(client.read_response(&note, 7).is_err());
+    }
+
+    #[test]
+    fn test_drop_sink() {
+        use frame::Frame;
+        use termination::TerminationReason;
+
+        let sent = Arc::new(Mutex::new(Vec::<Frame>::new()));
+        let sink = {
+            let sent = sent.clone();
+            Arc::new(move |frame| sent.lock().unwrap().push(frame))
+        };
+        let server_keypair = KeyPair::new().unwrap();
+
+        // Not started yet - peer doesn't know about us.
+        let mut fresh =
+            ClientSession::new(KeyPair::new().unwrap(), server_keypair.public_key).unwrap();
+        fresh.set_drop_sink(sink.clone());
+        drop(fresh);
+        assert!(sent.lock().unwrap().is_empty());
+
+        // Dropped mid-handshake.
+        let mut client =
+            ClientSession::new(KeyPair::new().unwrap(), server_keypair.public_key).unwrap();
+        client.set_drop_sink(sink.clone());
+        let hello = client.make_hello().unwrap();
+        drop(client);
+        {
+            let sent = sent.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].id, hello.id);
+            assert_eq!(TerminationReason::from_frame(&sent[0]),
+                       Some(TerminationReason::Unspecified));
+        }
+
+        // Established session inherits the sink and fires once, after the
+        // last clone and half is gone.
+        let (established, _) = configured_handshake(|client, _| {
+            client.set_drop_sink(sink.clone());
+        });
+        let (reader, writer) = established.clone().split();
+        drop(established);
+        drop(reader);
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        drop(writer);
+        assert_eq!(sent.lock().unwrap().len(), 2);
+
+        // Disarmed sessions go quietly.
+        let (mut established, _) = handshake();
+        established.set_drop_sink(sink);
+        established.disarm_drop_sink();
+        drop(established);
+        assert_eq!(sent.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_handshake_termination() {
+        use termination::TerminationReason;
+
+        let server_keypair = KeyPair::new().unwrap();
+        let mut client =
+            ClientSession::new(KeyPair::new().unwrap(), server_keypair.public_key).unwrap();
+        let hello = client.make_hello().unwrap();
+        let mut server = ServerSession::from_hello(server_keypair, &hello).unwrap();
+        let reason = TerminationReason::for_error(&WhisperError::AttestationFailed);
+        let refusal = server.make_termination(reason).unwrap();
+        assert_eq!(refusal.id, hello.id);
+        assert_eq!(TerminationReason::from_frame(&refusal),
+                   Some(TerminationReason::Unauthorized));
+        assert!(server.make_welcome(&hello).is_err());
+
+        let bye = client.make_termination(TerminationReason::Timeout).unwrap();
+        assert_eq!(TerminationReason::from_frame(&bye), Some(TerminationReason::Timeout));
+        assert_eq!(client.info().state, SessionState::Error);
+    }
+
+    #[test]
+    fn test_peer_termination() {
+        use termination::TerminationReason;
+
+        let server_keypair = KeyPair::new().unwrap();
+        let mut client =
+            ClientSession::new(KeyPair::new().unwrap(), server_keypair.public_key).unwrap();
+        let hello = client.make_hello().unwrap();
+        let mut server = ServerSession::from_hello(server_keypair.clone(), &hello).unwrap();
+        let welcome = server.make_welcome(&hello).unwrap();
+        let mut other_server = ServerSession::from_hello(server_keypair, &hello).unwrap();
+        other_server.make_welcome(&hello).unwrap();
+
+        // Termination of another session is just a frame out of place.
+        let stranger =
+            TerminationReason::RetryLater.to_frame(KeyPair::new().unwrap().public_key).unwrap();
+        match client.make_initiate(&stranger) {
+            Err(WhisperError::InvalidSessionState) => {}
+            other => panic!("Expected InvalidSessionState, got {:?}", other),
+        }
+        let initiate = client.make_initiate(&welcome).unwrap();
+        let refusal = TerminationReason::RetryLater.to_frame(hello.id).unwrap();
+        match client.read_ready(&refusal) {
+            Err(WhisperError::Terminated(TerminationReason::RetryLater)) => {}
+            other => panic!("Expected Terminated, got {:?}", other),
+        }
+        assert_eq!(client.info().state, SessionState::Terminated);
+        assert!(client.read_ready(&refusal).is_err());
+
+        let bye = TerminationReason::Timeout.to_frame(hello.id).unwrap();
+        let client_key = server.validate_initiate(&initiate).unwrap();
+        match server.validate_initiate(&bye) {
+            Err(WhisperError::Terminated(TerminationReason::Timeout)) => {}
+            other => panic!("Expected Terminated, got {:?}", other),
+        }
+        assert_eq!(server.info().state, SessionState::Terminated);
+        assert!(server.make_ready(&initiate, &client_key).is_err());
+        match other_server.make_ready(&bye, &client_key) {
+            Err(WhisperError::Terminated(TerminationReason::Timeout)) => {}
+            other => panic!("Expected Terminated, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(other_server.info().state, SessionState::Terminated);
+    }
+
+    #[test]
+    fn test_session_info() {
+        use digest::fingerprint;
+        use session::Side;
+
+        let client_keypair = KeyPair::new().unwrap();
+        let server_keypair = KeyPair::new().unwrap();
+        let mut client_session = ClientSession::new(client_keypair.clone(),
+                                                    server_keypair.public_key).unwrap();
+        let info = client_session.info();
+        assert_eq!(info.state, SessionState::Fresh);
+        assert_eq!(info.side, Side::Client);
+        assert_eq!(info.peer_fingerprint, Some(fingerprint(&server_keypair.public_key)));
+        let hello = client_session.make_hello().unwrap();
+        let server_session = ServerSession::new(server_keypair.clone(), hello.id).unwrap();
+        assert_eq!(server_session.info().peer_fingerprint, None);
+        assert_eq!(server_session.info().id, client_session.info().id);
+
+        let (client, server) = handshake_with(client_keypair.clone(), server_keypair.clone());
+        let ping = client.make_request(b"ping").unwrap();
+        server.read_msg(&ping).unwrap();
+        let (reader, _) = server.clone().split();
+        let info = reader.info();
+        assert_eq!(info.side, Side::Server);
+        assert_eq!(info.state, SessionState::Ready);
+        assert_eq!(info.peer_fingerprint, Some(fingerprint(&client_keypair.public_key)));
+        assert_eq!(info.frames_received, 1);
+        assert_eq!(client.info().frames_sent, 1);
+        assert!(info.expires_at > info.created_at);
+    }
+
+    #[test]
+    fn test_skew_tolerance() {
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let just_expired = KeyValidity {
+            not_before: Utc::now() - Duration::days(1),
+            not_after: Utc::now() - Duration::minutes(1),
+        };
+        let welcome_for = |client_session: &mut ClientSession| {
+            let hello_frame = client_session.make_hello().unwrap();
+            let mut server_session = ServerSession::new(server_identity_keypair.clone(),
+                                                        hello_frame.id).unwrap();
+            server_session.set_identity_validity(just_expired);
+            server_session.make_welcome(&hello_frame).unwrap()
+        };
+
+        // Identity that expired a minute ago is fine with 5 minutes of slack.
+        let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                    server_identity_keypair.public_key).unwrap();
+        client_session.set_skew_tolerance(Duration::minutes(5));
+        let welcome_frame = welcome_for(&mut client_session);
+        assert!(client_session.make_initiate(&welcome_frame).is_ok());
+
+        // Clock that is 10 minutes behind is too much.
+        let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                    server_identity_keypair.public_key).unwrap();
+        client_session.set_skew_tolerance(Duration::minutes(5));
+        client_session.clock_offset = Duration::minutes(-10);
+        let welcome_frame = welcome_for(&mut client_session);
+        match client_session.make_initiate(&welcome_frame) {
+            Err(WhisperError::ClockSkew) => {}
+            other => panic!("Expected ClockSkew, got {:?}", other),
+        }
+
+        // Server checks client clock from Initiate.
+        let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                    server_identity_keypair.public_key).unwrap();
+        client_session.clock_offset = Duration::minutes(-10);
+        let mut server_session = ServerSession::new(server_identity_keypair.clone(),
+                                                    client_session.id()).unwrap();
+        server_session.set_skew_tolerance(Duration::minutes(5));
+        match finish_handshake(&mut client_session, &mut server_session) {
+            Err(WhisperError::ClockSkew) => {}
+            other => panic!("Expected ClockSkew, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(server_session.state(), SessionState::Error);
+
+        // Expiry is pushed back too.
+        let mut server_session = ServerSession::new(server_identity_keypair.clone(),
+                                                    KeyPair::new().unwrap().public_key).unwrap();
+        server_session.expire_at = Utc::now() - Duration::minutes(1);
+        assert!(server_session.is_expired());
+        server_session.set_skew_tolerance(Duration::minutes(5));
+        assert!(!server_session.is_expired());
+    }
+
+    #[test]
+    fn test_handshake_deadlines() {
+        use session::{HandshakeDeadlines, HandshakePhase};
+
+        let server_identity_keypair = KeyPair::new().unwrap();
+        let deadlines = HandshakeDeadlines {
+            welcome: Duration::seconds(5),
+            initiate: Duration::seconds(30),
+            ready: Duration::seconds(5),
+        };
+        let expect_timeout = |result: Result<(), WhisperError>, phase| match result {
+            Err(WhisperError::HandshakeTimeout(p)) if p == phase => {}
+            Err(e) => panic!("Expected timeout in {:?}, got {:?}", phase, e),
+            Ok(_) => panic!("Expected timeout in {:?}", phase),
+        };
+        let start = || {
+            let mut client_session = ClientSession::new(KeyPair::new().unwrap(),
+                                                        server_identity_keypair.public_key)
+                .unwrap();
+            client_session.set_deadlines(deadlines);
+            let hello_frame = client_session.make_hello().unwrap();
+            let mut server_session = ServerSession::new(server_identity_keypair.clone(),
+                                                        hello_frame.id).unwrap();
+            server_session.set_deadlines(deadlines);
+            let welcome_frame = server_session.make_welcome(&hello_frame).unwrap();
+            (client_session, server_session, welcome_frame)
+        };
+        let ten_seconds_ago = Utc::now() - Duration::seconds(10);
+
+        // Welcome came too late.
+        let (mut client_session, _, welcome_frame) = start();
+        client_session.phase_started = ten_seconds_ago;
+        expect_timeout(client_session.make_initiate(&welcome_frame).map(|_| ()),
+                       HandshakePhase::Welcome);
+
+        // Initiate phase has more time.
+        let (mut client_session, mut server_session, welcome_frame) = start();
+        server_session.phase_started = ten_seconds_ago;
+        let initiate_frame = client_session.make_initiate(&welcome_frame).unwrap();
+        let client_key = server_session.validate_initiate(&initiate_frame).unwrap();
+        let (_, ready_frame) = server_session.make_ready(&initiate_frame, &client_key).unwrap();
+
+        // But Ready came too late.
+        client_session.phase_started = ten_seconds_ago;
+        expect_timeout(client_session.read_ready(&ready_frame).map(|_| ()), HandshakePhase::Ready);
+        assert_eq!(client_session.state(), SessionState::Error);
+        match client_session.read_ready(&ready_frame) {
+            Err(WhisperError::InvalidSessionState) => {}
+            other => panic!("Expected InvalidSessionState, got {:?}", other.map(|_| ())),
+        }
+    }
 }
@@ -0,0 +1,264 @@
+//! Deterministic network simulator for testing. Two endpoints are connected
+//! through a virtual `Link` that can drop, duplicate, reorder, corrupt and
+//! delay packets. All randomness comes from a seeded generator, so the same
+//! seed always produces the same sequence of events.
+//!
+//! Time is measured in ticks. Nothing happens until `tick()` is called.
+
+use bytes::Bytes;
+
+/// Describes how bad a link is. Probabilities are in range from 0.0 to 1.0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkConfig {
+    /// Probability that packet is lost.
+    pub drop: f64,
+    /// Probability that packet is delivered twice.
+    pub duplicate: f64,
+    /// Probability that one bit of packet is flipped.
+    pub corrupt: f64,
+    /// How many ticks every packet spends on the wire.
+    pub latency: u64,
+    /// Up to this many extra ticks are added to latency of each packet. Any
+    /// jitter means packets can arrive out of order.
+    pub jitter: u64,
+}
+
+impl Default for LinkConfig {
+    /// Perfect link: nothing is lost and everything arrives on next tick.
+    fn default() -> LinkConfig {
+        LinkConfig {
+            drop: 0.0,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            latency: 0,
+            jitter: 0,
+        }
+    }
+}
+
+/// Counters of everything that happened on a link.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// Packets handed to the link.
+    pub sent: u64,
+    /// Packets lost.
+    pub dropped: u64,
+    /// Extra copies created.
+    pub duplicated: u64,
+    /// Packets with a flipped bit.
+    pub corrupted: u64,
+    /// Packets that came out on the other side.
+    pub delivered: u64,
+}
+
+/// One direction of a virtual connection.
+#[derive(Debug, Clone)]
+pub struct Link {
+    config: LinkConfig,
+    rng: SimRng,
+    now: u64,
+    seq: u64,
+    // (deliver at, send order, packet)
+    in_flight: Vec<(u64, u64, Bytes)>,
+    stats: LinkStats,
+}
+
+impl Link {
+    /// Create new link. Same config and seed always behave the same way.
+    pub fn new(config: LinkConfig, seed: u64) -> Link {
+        Link {
+            config,
+            rng: SimRng::new(seed),
+            now: 0,
+            seq: 0,
+            in_flight: Vec::new(),
+            stats: LinkStats::default(),
+        }
+    }
+
+    /// Put packet on the wire.
+    pub fn send(&mut self, packet: Bytes) {
+        self.stats.sent += 1;
+        if self.rng.chance(self.config.drop) {
+            self.stats.dropped += 1;
+            return;
+        }
+        let copies = if self.rng.chance(self.config.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let packet = if !packet.is_empty() && self.rng.chance(self.config.corrupt) {
+                self.stats.corrupted += 1;
+                let mut bytes = packet.to_vec();
+                let bit = self.rng.below(bytes.len() as u64 * 8);
+                bytes[(bit / 8) as usize] ^= 1 << (bit % 8);
+                bytes.into()
+            } else {
+                packet.clone()
+            };
+            let delay = 1 + self.config.latency + self.rng.below(self.config.jitter + 1);
+            self.in_flight.push((self.now + delay, self.seq, packet));
+            self.seq += 1;
+        }
+    }
+
+    /// Advance time by one tick and return packets that arrived, in order of
+    /// arrival.
+    pub fn tick(&mut self) -> Vec<Bytes> {
+        self.now += 1;
+        let now = self.now;
+        let mut arrived: Vec<(u64, u64, Bytes)> = Vec::new();
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            if self.in_flight[i].0 <= now {
+                arrived.push(self.in_flight.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        arrived.sort_by_key(|&(at, seq, _)| (at, seq));
+        self.stats.delivered += arrived.len() as u64;
+        arrived.into_iter().map(|(_, _, packet)| packet).collect()
+    }
+
+    /// Returns true if nothing is in flight.
+    pub fn is_idle(&self) -> bool { self.in_flight.is_empty() }
+
+    /// Current time of the link in ticks.
+    pub fn now(&self) -> u64 { self.now }
+
+    /// Counters collected so far.
+    pub fn stats(&self) -> LinkStats { self.stats }
+}
+
+/// Two links — one per direction — between client and server.
+#[derive(Debug, Clone)]
+pub struct Network {
+    /// Packets going from client to server.
+    pub to_server: Link,
+    /// Packets going from server to client.
+    pub to_client: Link,
+}
+
+impl Network {
+    /// Create network where both directions share config. Directions get
+    /// different streams of randomness derived from the seed.
+    pub fn new(config: LinkConfig, seed: u64) -> Network {
+        Network {
+            to_server: Link::new(config.clone(), seed),
+            to_client: Link::new(config, seed ^ 0x9E37_79B9_7F4A_7C15),
+        }
+    }
+}
+
+// xorshift64*. Not suitable for anything but simulation.
+#[derive(Debug, Clone)]
+pub(crate) struct SimRng(u64);
+
+impl SimRng {
+    pub(crate) fn new(seed: u64) -> SimRng {
+        // Zero is a fixed point of xorshift.
+        SimRng(if seed == 0 { 0x2545_F491_4F6C_DD1D } else { seed })
+    }
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next() % n
+        }
+    }
+    pub(crate) fn chance(&mut self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::KeyPair;
+    use frame::{Frame, FrameKind};
+    use session::{ClientSession, ServerSession};
+
+    fn run(config: LinkConfig, seed: u64) -> (Vec<Bytes>, LinkStats) {
+        let mut link = Link::new(config, seed);
+        for i in 0..100u8 {
+            link.send(vec![i; 8].into());
+        }
+        let mut out = Vec::new();
+        while !link.is_idle() {
+            out.extend(link.tick());
+        }
+        (out, link.stats())
+    }
+
+    #[test]
+    fn same_seed_same_events() {
+        let config = LinkConfig {
+            drop: 0.1,
+            duplicate: 0.1,
+            corrupt: 0.1,
+            latency: 2,
+            jitter: 5,
+        };
+        assert_eq!(run(config.clone(), 42), run(config.clone(), 42));
+        assert!(run(config.clone(), 42) != run(config, 43));
+    }
+
+    #[test]
+    fn faults_are_counted() {
+        let (out, stats) = run(LinkConfig {
+                                   drop: 1.0,
+                                   ..LinkConfig::default()
+                               },
+                               1);
+        assert!(out.is_empty());
+        assert_eq!(stats.dropped, 100);
+
+        let (out, stats) = run(LinkConfig {
+                                   corrupt: 1.0,
+                                   ..LinkConfig::default()
+                               },
+                               1);
+        assert_eq!(stats.corrupted, 100);
+        for (i, packet) in out.iter().enumerate() {
+            assert!(packet.as_ref() != &[i as u8; 8][..]);
+        }
+
+        let (out, stats) = run(LinkConfig {
+                                   jitter: 10,
+                                   ..LinkConfig::default()
+                               },
+                               1);
+        assert_eq!(stats.delivered, 100);
+        assert!(out.windows(2).any(|w| w[0][0] > w[1][0]));
+    }
+
+    #[test]
+    fn handshake_over_perfect_network() {
+        let server_identity = KeyPair::new().unwrap();
+        let mut client =
+            ClientSession::new(KeyPair::new().unwrap(), server_identity.public_key).unwrap();
+        let mut net = Network::new(LinkConfig::default(), 7);
+
+        let hello = client.make_hello().unwrap();
+        let mut server = ServerSession::new(server_identity, hello.id).unwrap();
+        net.to_server.send(hello.pack());
+        let hello = Frame::from_slice(&net.to_server.tick()[0]).unwrap();
+        net.to_client.send(server.make_welcome(&hello).unwrap().pack());
+        let welcome = Frame::from_slice(&net.to_client.tick()[0]).unwrap();
+        assert_eq!(welcome.kind, FrameKind::Welcome);
+        assert!(client.make_initiate(&welcome).is_ok());
+    }
+}
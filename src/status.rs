@@ -0,0 +1,82 @@
+//! Status of Response. Body of enveloped Response is prefixed with one
+//! status byte inside the encrypted payload: zero is success, anything else
+//! is application defined error code. Build responses with
+//! `make_ok_response`/`make_error_response` and read them with `unwrap`.
+//! Like content type hints, peers have to agree to use it.
+
+use bytes::{Bytes, BytesMut};
+use errors::{WhisperError, WhisperResult};
+use std::num::NonZeroU8;
+
+/// Status byte of successful Response.
+pub const OK: u8 = 0;
+
+/// Outcome of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Status {
+    /// Request succeeded.
+    Ok,
+    /// Request failed with application defined code.
+    Error(NonZeroU8),
+}
+
+impl Status {
+    /// Decode status byte.
+    pub fn from(code: u8) -> Status {
+        match NonZeroU8::new(code) {
+            Some(code) => Status::Error(code),
+            None => Status::Ok,
+        }
+    }
+
+    /// Status byte.
+    pub fn code(&self) -> u8 {
+        match *self {
+            Status::Ok => OK,
+            Status::Error(code) => code.get(),
+        }
+    }
+
+    /// Returns true for `Ok`.
+    pub fn is_ok(&self) -> bool { *self == Status::Ok }
+}
+
+/// Prefix body with status.
+pub fn wrap(status: Status, body: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(1 + body.len());
+    buf.extend_from_slice(&[status.code()]);
+    buf.extend_from_slice(body);
+    buf
+}
+
+/// Split opened Response payload into status and body.
+pub fn unwrap(payload: &Bytes) -> WhisperResult<(Status, Bytes)> {
+    let code = *payload.first().ok_or(WhisperError::BadFrame)?;
+    Ok((Status::from(code), payload.slice_from(1)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use frame::FrameKind;
+    use session::test::handshake;
+
+    #[test]
+    fn ok_and_error_responses() {
+        let (client, server) = handshake();
+        let ok = server.make_ok_response(b"pong").unwrap();
+        assert_eq!(ok.kind, FrameKind::Response);
+        let (status, body) = unwrap(&client.read_msg(&ok).unwrap()).unwrap();
+        assert!(status.is_ok());
+        assert_eq!(body.as_ref(), b"pong");
+
+        let not_found = NonZeroU8::new(44).unwrap();
+        let error = server.make_error_response(not_found, b"no such thing").unwrap();
+        let (status, body) = unwrap(&client.read_msg(&error).unwrap()).unwrap();
+        assert_eq!(status, Status::Error(not_found));
+        assert_eq!(status.code(), 44);
+        assert_eq!(body.as_ref(), b"no such thing");
+
+        assert!(unwrap(&Bytes::new()).is_err());
+    }
+}
@@ -0,0 +1,500 @@
+//! Server side bookkeeping of many clients. `SessionStore` keeps
+//! handshakes and established sessions by session id, which is the `id` of
+//! every frame client sends. Feed it frames as they come off the network
+//! with `ingest`, send back whatever it returns, and call `evict_expired`
+//! now and then.
+//!
+//! Retransmitted Hello and Initiate get the same Welcome and Ready again,
+//! so clients on lossy links can simply repeat the last handshake frame.
+//! Handshake is kept until it expires for that, even after the session is
+//! established.
+//!
+//! With `set_ticket_keys` store also takes Resume (see `resumption`
+//! module) and answers it with Ready right away. Resumed session skips
+//! `SessionSetup`, so check `client` of `Established` and `remove` it if
+//! it's not welcome anymore. Repeated Resume is `Ignored`; client can
+//! make new one from the same ticket.
+//!
+//! Store keeps at most `max_handshakes` handshakes, Hello over that is
+//! `ResourceExhausted`. Every new handshake session goes through
+//! `SessionSetup` first, so authenticator, cipher suites, compression and
+//! the rest apply to sessions made by the store too.
+
+use bytes::Bytes;
+use clock::{self, Clock, SharedClock};
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use resumption::ResumeRequest;
+use server::{DrainSwitch, ServerIdentity};
+use session::{EstablishedSession, ServerSession, SessionConfig, SessionState};
+use sodiumoxide::crypto::box_::PublicKey;
+use std::collections::HashMap;
+use std::sync::Arc;
+use termination::TerminationReason;
+use tickets::TicketKeys;
+
+/// Default cap of handshakes kept by `SessionStore`.
+pub const DEFAULT_MAX_HANDSHAKES: usize = 4096;
+
+/// Prepares every handshake session store makes, before it answers Hello.
+pub trait SessionSetup: Send + Sync {
+    /// Configure new session.
+    fn setup(&self, session: &mut ServerSession);
+}
+
+impl<F: Fn(&mut ServerSession) + Send + Sync> SessionSetup for F {
+    fn setup(&self, session: &mut ServerSession) { self(session) }
+}
+
+/// What ingested frame turned into.
+#[derive(Debug)]
+pub enum Ingested {
+    /// Handshake frame to send back: Welcome, or repeated Ready.
+    Reply(Frame),
+    /// Handshake or resumption is done and session is in the store. Send
+    /// Ready back.
+    Established {
+        /// Client identity key.
+        client: PublicKey,
+        /// Ready frame.
+        ready: Frame,
+    },
+    /// Message opened by established session. Control frames end up here
+    /// too, pass them to control handlers.
+    Message(Frame, Bytes),
+    /// Client ended the session, it's gone from the store.
+    Closed(PublicKey, TerminationReason),
+    /// Copy of handshake frame that was already answered differently.
+    /// Nothing to send.
+    Ignored,
+}
+
+struct Handshake {
+    session: ServerSession,
+    hello: Bytes,
+    welcome: Frame,
+    ready: Option<Frame>,
+}
+
+/// Sessions of one server identity.
+pub struct SessionStore {
+    identity: ServerIdentity,
+    handshakes: HashMap<PublicKey, Handshake>,
+    established: HashMap<PublicKey, EstablishedSession>,
+    max_handshakes: usize,
+    setup: Option<Arc<dyn SessionSetup>>,
+    config: SessionConfig,
+    tickets: Option<TicketKeys>,
+    drain: Option<DrainSwitch>,
+    clock: SharedClock,
+}
+
+impl SessionStore {
+    /// Empty store. Handshakes use keys of this identity.
+    pub fn new(identity: ServerIdentity) -> SessionStore {
+        SessionStore {
+            identity,
+            handshakes: HashMap::new(),
+            established: HashMap::new(),
+            max_handshakes: DEFAULT_MAX_HANDSHAKES,
+            setup: None,
+            config: SessionConfig::default(),
+            tickets: None,
+            drain: None,
+            clock: clock::system(),
+        }
+    }
+
+    /// Keep at most this many handshakes, finished ones included. Expired
+    /// ones are dropped to make room before Hello is refused.
+    pub fn set_max_handshakes(&mut self, max_handshakes: usize) {
+        self.max_handshakes = max_handshakes;
+    }
+
+    /// Configure every new handshake session with this.
+    pub fn set_session_setup(&mut self, setup: Arc<dyn SessionSetup>) { self.setup = Some(setup); }
+
+    /// Make handshake sessions with these protocol parameters.
+    pub fn set_session_config(&mut self, config: SessionConfig) { self.config = config; }
+
+    /// Resume sessions with tickets sealed by these keys. Without them
+    /// Resume is `InvalidTicket`.
+    pub fn set_ticket_keys(&mut self, keys: TicketKeys) { self.tickets = Some(keys); }
+
+    /// Answer Hello with `RetryLater` Termination while switch is draining.
+    pub fn set_drain_switch(&mut self, drain: DrainSwitch) { self.drain = Some(drain); }
+
+    /// Use this clock for expiry of the store and of sessions it makes.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) { self.clock = clock; }
+
+    /// Parse packed frame (no length prefix) and `ingest` it.
+    pub fn ingest_packet(&mut self, packet: &[u8]) -> WhisperResult<Ingested> {
+        self.ingest(&Frame::from_slice(packet)?)
+    }
+
+    /// Route frame to session it belongs to. Frame of unknown session is
+    /// `InvalidSessionState`, frame of expired one is `ExpiredSession` and
+    /// the session is evicted. Handshake that failed is dropped.
+    pub fn ingest(&mut self, frame: &Frame) -> WhisperResult<Ingested> {
+        match frame.kind {
+            FrameKind::Hello => self.hello(frame),
+            FrameKind::Initiate => self.initiate(frame),
+            FrameKind::Resume => self.resume(frame),
+            FrameKind::Welcome | FrameKind::Ready => Err(WhisperError::InvalidSessionState),
+            _ => self.message(frame),
+        }
+    }
+
+    /// Established session by id. Use it to answer messages.
+    pub fn get(&self, id: &PublicKey) -> Option<&EstablishedSession> { self.established.get(id) }
+
+    /// Established session by id.
+    pub fn get_mut(&mut self, id: &PublicKey) -> Option<&mut EstablishedSession> {
+        self.established.get_mut(id)
+    }
+
+    /// Take session out of the store. Its handshake is forgotten too.
+    pub fn remove(&mut self, id: &PublicKey) -> Option<EstablishedSession> {
+        self.handshakes.remove(id);
+        self.established.remove(id)
+    }
+
+    /// Number of established sessions.
+    pub fn len(&self) -> usize { self.established.len() }
+
+    /// Returns true if there are no established sessions.
+    pub fn is_empty(&self) -> bool { self.established.is_empty() }
+
+    /// Number of handshakes kept, finished ones included.
+    pub fn handshakes(&self) -> usize { self.handshakes.len() }
+
+    /// Drop expired handshakes and sessions. Returns ids of dropped
+    /// established sessions.
+    pub fn evict_expired(&mut self) -> Vec<PublicKey> {
+        let now = self.clock.now();
+        self.handshakes.retain(|_, handshake| handshake.session.info().expires_at >= now);
+        let expired: Vec<PublicKey> = self.established
+                                          .iter()
+                                          .filter(|&(_, session)| session.info().expires_at < now)
+                                          .map(|(id, _)| *id)
+                                          .collect();
+        for id in &expired {
+            self.established.remove(id);
+        }
+        expired
+    }
+
+    fn hello(&mut self, hello: &Frame) -> WhisperResult<Ingested> {
+        if let Some(handshake) = self.handshakes.get(&hello.id) {
+            if handshake.hello == hello.pack() {
+                return Ok(Ingested::Reply(handshake.welcome.clone()));
+            }
+            return Ok(Ingested::Ignored);
+        }
+        if let Some(ref drain) = self.drain {
+            if let Some(termination) = drain.refuse(hello)? {
+                return Ok(Ingested::Reply(termination));
+            }
+        }
+        if self.handshakes.len() >= self.max_handshakes {
+            let now = self.clock.now();
+            self.handshakes.retain(|_, handshake| handshake.session.info().expires_at >= now);
+            if self.handshakes.len() >= self.max_handshakes {
+                return Err(WhisperError::ResourceExhausted);
+            }
+        }
+        let mut session = self.identity.session_for_hello_with_config(hello, self.config)?;
+        session.set_clock(self.clock.clone());
+        if let Some(ref setup) = self.setup {
+            setup.setup(&mut session);
+        }
+        let welcome = session.make_welcome(hello)?;
+        self.handshakes.insert(hello.id,
+                               Handshake {
+                                   session,
+                                   hello: hello.pack(),
+                                   welcome: welcome.clone(),
+                                   ready: None,
+                               });
+        Ok(Ingested::Reply(welcome))
+    }
+
+    fn initiate(&mut self, initiate: &Frame) -> WhisperResult<Ingested> {
+        let result = {
+            let handshake = self.handshakes
+                                .get_mut(&initiate.id)
+                                .ok_or(WhisperError::InvalidSessionState)?;
+            let client = handshake.session.validate_initiate(initiate);
+            match (client, handshake.ready.clone()) {
+                // Client made another Initiate from repeated Welcome.
+                (Ok(_), Some(ready)) => return Ok(Ingested::Reply(ready)),
+                (Ok(client), None) => {
+                    handshake.session.make_ready(initiate, &client).map(|(session, ready)| {
+                        handshake.ready = Some(ready.clone());
+                        (client, session, ready)
+                    })
+                }
+                (Err(err), _) => Err(err),
+            }
+        };
+        match result {
+            Ok((client, session, ready)) => {
+                self.established.insert(initiate.id, session);
+                Ok(Ingested::Established { client, ready })
+            }
+            Err(err) => {
+                let failed = match self.handshakes[&initiate.id].session.info().state {
+                    SessionState::Error | SessionState::Terminated => true,
+                    _ => false,
+                };
+                if failed {
+                    self.handshakes.remove(&initiate.id);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn resume(&mut self, resume: &Frame) -> WhisperResult<Ingested> {
+        if self.established.contains_key(&resume.id) || self.handshakes.contains_key(&resume.id) {
+            return Ok(Ingested::Ignored);
+        }
+        if let Some(ref drain) = self.drain {
+            if let Some(termination) = drain.refuse(resume)? {
+                return Ok(Ingested::Reply(termination));
+            }
+        }
+        let keys = self.tickets.as_ref().ok_or(WhisperError::InvalidTicket)?;
+        let request = ResumeRequest::open_with_clock(keys, resume, self.clock.clone())?;
+        let client = request.client_identity_key();
+        let (session, ready) = request.make_ready()?;
+        self.established.insert(resume.id, session);
+        Ok(Ingested::Established { client, ready })
+    }
+
+    fn message(&mut self, frame: &Frame) -> WhisperResult<Ingested> {
+        let expired = {
+            let session = self.established
+                              .get(&frame.id)
+                              .ok_or(WhisperError::InvalidSessionState)?;
+            session.info().expires_at < self.clock.now()
+        };
+        if expired {
+            self.remove(&frame.id);
+            return Err(WhisperError::ExpiredSession);
+        }
+        if frame.kind == FrameKind::Termination {
+            let reason = self.established[&frame.id].read_termination(frame)?;
+            self.remove(&frame.id);
+            return Ok(Ingested::Closed(frame.id, reason));
+        }
+        let msg = self.established[&frame.id].read_msg(frame)?;
+        Ok(Ingested::Message(frame.clone(), msg))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use auth::{AuthDecision, Authenticator};
+    use chrono::{Duration, Utc};
+    use clock::MockClock;
+    use crypto::KeyPair;
+    use resumption::{self, ResumptionTicket};
+    use session::ClientSession;
+
+    #[test]
+    fn store_routes_frames() {
+        let identity = ServerIdentity::new(KeyPair::new().unwrap());
+        let mut store = SessionStore::new(identity.clone());
+        let mut client =
+            ClientSession::new(KeyPair::new().unwrap(), identity.public_key()).unwrap();
+        let client_key = client.info().id;
+
+        let hello = client.make_hello().unwrap();
+        let welcome = match store.ingest_packet(&hello.pack()).unwrap() {
+            Ingested::Reply(welcome) => welcome,
+            other => panic!("Expected Welcome, got {:?}", other),
+        };
+        match store.ingest(&hello).unwrap() {
+            Ingested::Reply(again) => assert_eq!(again.pack(), welcome.pack()),
+            other => panic!("Expected same Welcome, got {:?}", other),
+        }
+
+        let initiate = client.make_initiate(&welcome).unwrap();
+        let ready = match store.ingest(&initiate).unwrap() {
+            Ingested::Established { ready, .. } => ready,
+            other => panic!("Expected Ready, got {:?}", other),
+        };
+        match store.ingest(&initiate).unwrap() {
+            Ingested::Reply(again) => assert_eq!(again.pack(), ready.pack()),
+            other => panic!("Expected same Ready, got {:?}", other),
+        }
+        let session = client.read_ready(&ready).unwrap();
+        assert_eq!(store.len(), 1);
+
+        let request = session.make_request(b"ping").unwrap();
+        match store.ingest(&request).unwrap() {
+            Ingested::Message(frame, msg) => {
+                assert_eq!(frame.kind, FrameKind::Request);
+                assert_eq!(msg.as_ref(), b"ping");
+                let response = store.get(&frame.id).unwrap().make_response(b"pong").unwrap();
+                assert_eq!(session.read_msg(&response).unwrap().as_ref(), b"pong");
+            }
+            other => panic!("Expected message, got {:?}", other),
+        }
+        assert!(store.evict_expired().is_empty());
+
+        let termination = session.close(TerminationReason::Unspecified).unwrap();
+        match store.ingest(&termination).unwrap() {
+            Ingested::Closed(id, _) => assert_eq!(id, client_key),
+            other => panic!("Expected Closed, got {:?}", other),
+        }
+        assert!(store.is_empty());
+        assert!(store.ingest(&request).is_err());
+    }
+
+    #[test]
+    fn store_limits_and_configures_handshakes() {
+        let identity = ServerIdentity::new(KeyPair::new().unwrap());
+        let mut store = SessionStore::new(identity.clone());
+        let clock = MockClock::new(Utc::now());
+        store.set_clock(Arc::new(clock.clone()));
+        store.set_max_handshakes(1);
+        let deny = Arc::new(|_: &PublicKey| AuthDecision::Deny) as Arc<dyn Authenticator>;
+        store.set_session_setup(Arc::new(move |session: &mut ServerSession| {
+                                             session.set_authenticator(deny.clone())
+                                         }));
+        let client = || {
+            let mut client =
+                ClientSession::new(KeyPair::new().unwrap(), identity.public_key()).unwrap();
+            client.set_clock(Arc::new(clock.clone()));
+            client
+        };
+
+        assert!(store.ingest(&client().make_hello().unwrap()).is_ok());
+        match store.ingest(&client().make_hello().unwrap()) {
+            Err(WhisperError::ResourceExhausted) => {}
+            other => panic!("Expected ResourceExhausted, got {:?}", other),
+        }
+
+        // Expired handshake makes room.
+        clock.advance(Duration::hours(1));
+        let drain = DrainSwitch::new();
+        store.set_drain_switch(drain.clone());
+        drain.drain();
+        let mut late = client();
+        let hello = late.make_hello().unwrap();
+        match store.ingest(&hello).unwrap() {
+            Ingested::Reply(frame) => assert_eq!(frame.kind, FrameKind::Termination),
+            other => panic!("Expected Termination, got {:?}", other),
+        }
+        drain.resume();
+        let welcome = match store.ingest(&hello).unwrap() {
+            Ingested::Reply(welcome) => welcome,
+            other => panic!("Expected Welcome, got {:?}", other),
+        };
+        assert_eq!(store.handshakes(), 1);
+        // Authenticator from setup is consulted.
+        let initiate = late.make_initiate(&welcome).unwrap();
+        assert!(store.ingest(&initiate).is_err());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn store_resumes_sessions() {
+        let identity = ServerIdentity::new(KeyPair::new().unwrap());
+        let mut store = SessionStore::new(identity.clone());
+        let client_identity = KeyPair::new().unwrap();
+        let mut client =
+            ClientSession::new(client_identity.clone(), identity.public_key()).unwrap();
+        let welcome = match store.ingest(&client.make_hello().unwrap()).unwrap() {
+            Ingested::Reply(welcome) => welcome,
+            other => panic!("Expected Welcome, got {:?}", other),
+        };
+        let ready = match store.ingest(&client.make_initiate(&welcome).unwrap()).unwrap() {
+            Ingested::Established { ready, .. } => ready,
+            other => panic!("Expected Ready, got {:?}", other),
+        };
+        let session = client.read_ready(&ready).unwrap();
+        let keys = TicketKeys::new(Duration::hours(1), Duration::hours(1)).unwrap();
+        let frame = resumption::issue(&keys, store.get(&ready.id).unwrap(), Duration::hours(1))
+            .unwrap();
+        let ticket = ResumptionTicket::from_frame(&session, &frame).unwrap().unwrap();
+
+        let (resuming, resume) = ticket.make_resume().unwrap();
+        match store.ingest(&resume) {
+            Err(WhisperError::InvalidTicket) => {}
+            other => panic!("Expected InvalidTicket, got {:?}", other),
+        }
+        store.set_ticket_keys(keys);
+        let ready = match store.ingest(&resume).unwrap() {
+            Ingested::Established { client, ready } => {
+                assert_eq!(client, client_identity.public_key);
+                ready
+            }
+            other => panic!("Expected Ready, got {:?}", other),
+        };
+        match store.ingest(&resume).unwrap() {
+            Ingested::Ignored => {}
+            other => panic!("Expected Ignored, got {:?}", other),
+        }
+        let resumed = resuming.read_ready(&ready).unwrap();
+        let request = resumed.make_request(b"ping").unwrap();
+        match store.ingest(&request).unwrap() {
+            Ingested::Message(_, msg) => assert_eq!(msg.as_ref(), b"ping"),
+            other => panic!("Expected message, got {:?}", other),
+        }
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn store_resumes_on_its_clock() {
+        // Yesterday: tickets issued then are expired by wall clock.
+        let clock = MockClock::new(Utc::now() - Duration::days(1));
+        let client_clock = MockClock::new(clock.now());
+        let identity = ServerIdentity::new(KeyPair::new().unwrap());
+        let mut store = SessionStore::new(identity.clone());
+        store.set_clock(Arc::new(clock.clone()));
+        let keys = TicketKeys::new_at(clock.now(), Duration::hours(1), Duration::hours(1))
+            .unwrap();
+        store.set_ticket_keys(keys.clone());
+        let mut client = ClientSession::new(KeyPair::new().unwrap(), identity.public_key())
+            .unwrap();
+        client.set_clock(Arc::new(client_clock.clone()));
+        let welcome = match store.ingest(&client.make_hello().unwrap()).unwrap() {
+            Ingested::Reply(welcome) => welcome,
+            other => panic!("Expected Welcome, got {:?}", other),
+        };
+        let ready = match store.ingest(&client.make_initiate(&welcome).unwrap()).unwrap() {
+            Ingested::Established { ready, .. } => ready,
+            other => panic!("Expected Ready, got {:?}", other),
+        };
+        let session = client.read_ready(&ready).unwrap();
+        let frame = resumption::issue(&keys, store.get(&ready.id).unwrap(), Duration::hours(1))
+            .unwrap();
+        let ticket = ResumptionTicket::from_frame(&session, &frame).unwrap().unwrap();
+        assert_eq!(ticket.expires_at().timestamp(),
+                   (clock.now() + Duration::hours(1)).timestamp());
+
+        let (_, resume) = ticket.make_resume().unwrap();
+        let resumed = match store.ingest(&resume).unwrap() {
+            Ingested::Established { .. } => store.get(&resume.id).unwrap(),
+            other => panic!("Expected Ready, got {:?}", other),
+        };
+        assert_eq!(resumed.info().created_at, clock.now());
+
+        // Ticket is still good for client, but not for the store.
+        clock.advance(Duration::hours(2));
+        let (_, resume) = ticket.make_resume().unwrap();
+        match store.ingest(&resume) {
+            Err(WhisperError::InvalidTicket) => {}
+            other => panic!("Expected InvalidTicket, got {:?}", other),
+        }
+        client_clock.advance(Duration::hours(2));
+        match ticket.make_resume() {
+            Err(WhisperError::InvalidTicket) => {}
+            other => panic!("Expected InvalidTicket, got {:?}", other.map(|_| ())),
+        }
+    }
+}
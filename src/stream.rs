@@ -0,0 +1,430 @@
+//! Logical streams inside one established session. Each stream is an
+//! independent conversation, so application can run several
+//! request/response exchanges at once and tell them apart.
+//!
+//! Every payload sent through `StreamMux` is wrapped into envelope:
+//! - Stream id as u32 BigEndian. 4 bytes.
+//! - Control byte: `DATA`, `OPEN`, `CLOSE` or `CREDIT`. 1 byte.
+//! - Application data.
+//!
+//! Client opens streams with odd ids and server with even ones, so both
+//! sides can open streams without coordination. Stream 0 is never used.
+//!
+//! ### Flow control
+//! Each side may send at most `INITIAL_WINDOW` bytes of data on a stream
+//! before receiving credit. Receiver grants credit with `CREDIT` envelope
+//! (data is u32 BigEndian amount) once half of the window was consumed.
+//! Peer that goes over the window gets `FlowControl` error.
+//!
+//! Messages queued with `StreamMux::queue` are sent by `poll_outbound` using
+//! smooth weighted round robin among streams that have credit, so bulk
+//! transfer on one stream doesn't starve others. Queued bytes are charged
+//! to session's memory quota, if mux was given one (`set_quota`).
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use quota::{Reservation, SessionQuota};
+use session::EstablishedSession;
+use std::collections::{BTreeMap, VecDeque};
+
+/// Size of stream envelope.
+pub static ENVELOPE_SIZE: usize = 5;
+/// Control byte of a regular message.
+pub const DATA: u8 = 0;
+/// Control byte of a stream opening.
+pub const OPEN: u8 = 1;
+/// Control byte of a stream closing.
+pub const CLOSE: u8 = 2;
+/// Control byte of a credit grant.
+pub const CREDIT: u8 = 3;
+/// How many bytes of data can be in flight on a stream without credit.
+pub const INITIAL_WINDOW: u32 = 65_536;
+/// Weight of a stream unless set otherwise.
+pub const DEFAULT_WEIGHT: u8 = 16;
+
+/// Stream identifier.
+pub type StreamId = u32;
+
+/// What came out of the session after unwrapping envelope.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// Remote side opened a stream.
+    Opened(StreamId),
+    /// Message on an open stream. Kind is kind of the frame it came with.
+    Message(StreamId, FrameKind, Bytes),
+    /// Remote side closed a stream.
+    Closed(StreamId),
+    /// Remote side granted more credit. Queued messages may be sendable now.
+    Credit(StreamId),
+}
+
+#[derive(Debug)]
+struct StreamState {
+    send_credit: u32,
+    recv_window: u32,
+    recv_consumed: u32,
+    weight: u8,
+    current: i64,
+    // Reservation is dropped together with the message.
+    queue: VecDeque<(FrameKind, Bytes, Option<Reservation>)>,
+}
+
+impl Default for StreamState {
+    fn default() -> StreamState {
+        StreamState {
+            send_credit: INITIAL_WINDOW,
+            recv_window: INITIAL_WINDOW,
+            recv_consumed: 0,
+            weight: DEFAULT_WEIGHT,
+            current: 0,
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+/// Keeps track of streams of one session. One side of the session. Not
+/// `Clone`, since queued messages hold quota reservations.
+#[derive(Debug)]
+pub struct StreamMux {
+    next_id: StreamId,
+    // BTreeMap so scheduler breaks ties the same way every time.
+    streams: BTreeMap<StreamId, StreamState>,
+    quota: Option<SessionQuota>,
+}
+
+impl StreamMux {
+    /// Stream multiplexer for client side of a session.
+    pub fn client() -> StreamMux { StreamMux::starting_at(1) }
+
+    /// Stream multiplexer for server side of a session.
+    pub fn server() -> StreamMux { StreamMux::starting_at(2) }
+
+    fn starting_at(next_id: StreamId) -> StreamMux {
+        StreamMux {
+            next_id,
+            streams: BTreeMap::new(),
+            quota: None,
+        }
+    }
+
+    /// Charge queued messages to session quota.
+    pub fn set_quota(&mut self, quota: SessionQuota) { self.quota = Some(quota); }
+
+    /// Returns true if stream is open.
+    pub fn is_open(&self, stream: StreamId) -> bool { self.streams.contains_key(&stream) }
+
+    /// Number of open streams.
+    pub fn open_streams(&self) -> usize { self.streams.len() }
+
+    /// How many bytes can be sent on a stream right now.
+    pub fn send_credit(&self, stream: StreamId) -> Option<u32> {
+        self.streams.get(&stream).map(|state| state.send_credit)
+    }
+
+    /// Set scheduling weight of a stream. Stream with weight 4 gets twice as
+    /// many turns as stream with weight 2. Zero is treated as one.
+    pub fn set_weight(&mut self, stream: StreamId, weight: u8) -> WhisperResult<()> {
+        let state = self.streams.get_mut(&stream).ok_or(WhisperError::UnknownStream)?;
+        state.weight = weight.max(1);
+        Ok(())
+    }
+
+    /// Open new stream. Returns its id and Notification frame that announces
+    /// it to remote side.
+    pub fn open_stream(&mut self, session: &EstablishedSession) -> WhisperResult<(StreamId, Frame)> {
+        // Ids are never reused, 2^31 streams per session is plenty.
+        let stream = self.next_id;
+        let next_id = stream.checked_add(2).ok_or(WhisperError::InvalidSessionState)?;
+        let frame = session.make_notification(&envelope(stream, OPEN, &[]))?;
+        self.next_id = next_id;
+        self.streams.insert(stream, StreamState::default());
+        Ok((stream, frame))
+    }
+
+    /// Close stream. Returns Notification frame that tells remote side.
+    /// Messages still queued on the stream are dropped.
+    pub fn close_stream(&mut self,
+                        session: &EstablishedSession,
+                        stream: StreamId)
+                        -> WhisperResult<Frame> {
+        if self.streams.remove(&stream).is_none() {
+            return Err(WhisperError::UnknownStream);
+        }
+        session.make_notification(&envelope(stream, CLOSE, &[]))
+    }
+
+    /// Make request on a stream. Fails with `FlowControl` if stream doesn't
+    /// have enough credit.
+    pub fn make_request(&mut self,
+                        session: &EstablishedSession,
+                        stream: StreamId,
+                        data: &[u8])
+                        -> WhisperResult<Frame> {
+        self.make_message(session, stream, FrameKind::Request, data)
+    }
+
+    /// Make response on a stream. Fails with `FlowControl` if stream doesn't
+    /// have enough credit.
+    pub fn make_response(&mut self,
+                         session: &EstablishedSession,
+                         stream: StreamId,
+                         data: &[u8])
+                         -> WhisperResult<Frame> {
+        self.make_message(session, stream, FrameKind::Response, data)
+    }
+
+    /// Make notification on a stream. Fails with `FlowControl` if stream doesn't
+    /// have enough credit.
+    pub fn make_notification(&mut self,
+                             session: &EstablishedSession,
+                             stream: StreamId,
+                             data: &[u8])
+                             -> WhisperResult<Frame> {
+        self.make_message(session, stream, FrameKind::Notification, data)
+    }
+
+    /// Queue message to be sent by `poll_outbound` once stream has credit.
+    /// Message must fit into `INITIAL_WINDOW` and into memory quota
+    /// (`ResourceExhausted` otherwise).
+    pub fn queue<B: Into<Bytes>>(&mut self,
+                                 stream: StreamId,
+                                 kind: FrameKind,
+                                 data: B)
+                                 -> WhisperResult<()> {
+        let data = data.into();
+        if data.len() > INITIAL_WINDOW as usize {
+            return Err(WhisperError::FlowControl);
+        }
+        let state = self.streams.get_mut(&stream).ok_or(WhisperError::UnknownStream)?;
+        let reservation = match self.quota {
+            Some(ref quota) => Some(quota.reserve(data.len())?),
+            None => None,
+        };
+        state.queue.push_back((kind, data, reservation));
+        Ok(())
+    }
+
+    /// Returns true if nothing is queued.
+    pub fn is_idle(&self) -> bool { self.streams.values().all(|state| state.queue.is_empty()) }
+
+    /// Next frame to send. Credit grants go first, then queued messages
+    /// picked by weight. Returns `None` if nothing can be sent right now.
+    pub fn poll_outbound(&mut self, session: &EstablishedSession) -> WhisperResult<Option<Frame>> {
+        let grant = self.streams
+                        .iter()
+                        .find(|&(_, state)| state.recv_consumed >= INITIAL_WINDOW / 2)
+                        .map(|(&stream, state)| (stream, state.recv_consumed));
+        if let Some((stream, amount)) = grant {
+            let mut buf = [0; 4];
+            BigEndian::write_u32(&mut buf, amount);
+            let frame = session.make_notification(&envelope(stream, CREDIT, &buf))?;
+            let state = self.streams.get_mut(&stream).expect("Stream was just found");
+            state.recv_consumed = 0;
+            state.recv_window += amount;
+            return Ok(Some(frame));
+        }
+
+        // Smooth weighted round robin among streams that can send.
+        let mut total = 0;
+        let mut best: Option<(StreamId, i64)> = None;
+        for (&stream, state) in &mut self.streams {
+            let ready = state.queue
+                             .front()
+                             .is_some_and(|(_, data, _)| data.len() as u32 <= state.send_credit);
+            if !ready {
+                continue;
+            }
+            state.current += i64::from(state.weight);
+            total += i64::from(state.weight);
+            if best.is_none_or(|(_, current)| state.current > current) {
+                best = Some((stream, state.current));
+            }
+        }
+        let stream = match best {
+            Some((stream, _)) => stream,
+            None => return Ok(None),
+        };
+        let (kind, data, _reservation) = {
+            let state = self.streams.get_mut(&stream).expect("Stream was just found");
+            state.current -= total;
+            state.queue.pop_front().expect("Stream has queued message")
+        };
+        self.make_message(session, stream, kind, &data).map(Some)
+    }
+
+    /// Open frame and unwrap envelope. Messages on streams that aren't open
+    /// are refused with `UnknownStream`.
+    pub fn read(&mut self, session: &EstablishedSession, frame: &Frame) -> WhisperResult<StreamEvent> {
+        let payload = session.read_msg(frame)?;
+        if payload.len() < ENVELOPE_SIZE {
+            return Err(WhisperError::BadFrame);
+        }
+        let stream = BigEndian::read_u32(&payload[0..4]);
+        let data = payload.slice_from(ENVELOPE_SIZE);
+        match payload[4] {
+            DATA => {
+                let state = self.streams.get_mut(&stream).ok_or(WhisperError::UnknownStream)?;
+                let len = data.len() as u32;
+                if len > state.recv_window {
+                    return Err(WhisperError::FlowControl);
+                }
+                state.recv_window -= len;
+                state.recv_consumed += len;
+                Ok(StreamEvent::Message(stream, frame.kind, data))
+            }
+            // Remote side may only open streams with its own parity.
+            OPEN if stream != 0 && stream % 2 != self.next_id % 2 && frame.kind == FrameKind::Notification => {
+                if self.streams.contains_key(&stream) {
+                    return Err(WhisperError::InvalidSessionState);
+                }
+                self.streams.insert(stream, StreamState::default());
+                Ok(StreamEvent::Opened(stream))
+            }
+            CLOSE if self.streams.remove(&stream).is_some() => Ok(StreamEvent::Closed(stream)),
+            CLOSE => Err(WhisperError::UnknownStream),
+            CREDIT if data.len() == 4 => {
+                let state = self.streams.get_mut(&stream).ok_or(WhisperError::UnknownStream)?;
+                state.send_credit = state.send_credit.saturating_add(BigEndian::read_u32(&data));
+                Ok(StreamEvent::Credit(stream))
+            }
+            _ => Err(WhisperError::BadFrame),
+        }
+    }
+
+    fn make_message(&mut self,
+                    session: &EstablishedSession,
+                    stream: StreamId,
+                    kind: FrameKind,
+                    data: &[u8])
+                    -> WhisperResult<Frame> {
+        let state = self.streams.get_mut(&stream).ok_or(WhisperError::UnknownStream)?;
+        let len = data.len() as u32;
+        if data.len() > INITIAL_WINDOW as usize || len > state.send_credit {
+            return Err(WhisperError::FlowControl);
+        }
+        let frame = match kind {
+            FrameKind::Request => session.make_request(&envelope(stream, DATA, data))?,
+            FrameKind::Response => session.make_response(&envelope(stream, DATA, data))?,
+            _ => session.make_notification(&envelope(stream, DATA, data))?,
+        };
+        state.send_credit -= len;
+        Ok(frame)
+    }
+}
+
+fn envelope(stream: StreamId, control: u8, data: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(ENVELOPE_SIZE + data.len());
+    buf.put_u32_be(stream);
+    buf.put_u8(control);
+    buf.extend_from_slice(data);
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::test::handshake;
+
+    #[test]
+    fn interleaved_streams() {
+        let (client, server) = handshake();
+        let mut client_mux = StreamMux::client();
+        let mut server_mux = StreamMux::server();
+
+        let (first, open_first) = client_mux.open_stream(&client).unwrap();
+        let (second, open_second) = client_mux.open_stream(&client).unwrap();
+        assert!(first != second);
+        assert_eq!(server_mux.read(&server, &open_first).unwrap(), StreamEvent::Opened(first));
+        assert_eq!(server_mux.read(&server, &open_second).unwrap(), StreamEvent::Opened(second));
+
+        let ping_first = client_mux.make_request(&client, first, b"one").unwrap();
+        let ping_second = client_mux.make_request(&client, second, b"two").unwrap();
+        // Answers come in whatever order.
+        assert_eq!(server_mux.read(&server, &ping_second).unwrap(),
+                   StreamEvent::Message(second, FrameKind::Request, Bytes::from(&b"two"[..])));
+        assert_eq!(server_mux.read(&server, &ping_first).unwrap(),
+                   StreamEvent::Message(first, FrameKind::Request, Bytes::from(&b"one"[..])));
+        let pong = server_mux.make_response(&server, second, b"2").unwrap();
+        assert_eq!(client_mux.read(&client, &pong).unwrap(),
+                   StreamEvent::Message(second, FrameKind::Response, Bytes::from(&b"2"[..])));
+
+        let close = client_mux.close_stream(&client, first).unwrap();
+        assert_eq!(server_mux.read(&server, &close).unwrap(), StreamEvent::Closed(first));
+        assert_eq!(server_mux.open_streams(), 1);
+        assert!(server_mux.make_response(&server, first, b"1").is_err());
+    }
+
+    #[test]
+    fn unknown_and_foreign_streams() {
+        let (client, server) = handshake();
+        let mut client_mux = StreamMux::client();
+        let mut server_mux = StreamMux::server();
+
+        assert!(client_mux.make_request(&client, 1, b"nope").is_err());
+        let stray = client.make_request(&envelope(1, DATA, b"nope")).unwrap();
+        assert!(server_mux.read(&server, &stray).is_err());
+        // Client can't open stream with server's parity.
+        let foreign = client.make_notification(&envelope(2, OPEN, &[])).unwrap();
+        assert!(server_mux.read(&server, &foreign).is_err());
+        let short = client.make_notification(&[0, 0]).unwrap();
+        assert!(server_mux.read(&server, &short).is_err());
+    }
+
+    #[test]
+    fn credit_is_enforced_and_granted() {
+        let (client, server) = handshake();
+        let mut client_mux = StreamMux::client();
+        let mut server_mux = StreamMux::server();
+        let (bulk, open) = client_mux.open_stream(&client).unwrap();
+        server_mux.read(&server, &open).unwrap();
+
+        let chunk = vec![0; INITIAL_WINDOW as usize / 2];
+        for _ in 0..3 {
+            client_mux.queue(bulk, FrameKind::Notification, chunk.clone()).unwrap();
+        }
+        let first = client_mux.poll_outbound(&client).unwrap().unwrap();
+        let second = client_mux.poll_outbound(&client).unwrap().unwrap();
+        // Window is used up.
+        assert!(client_mux.poll_outbound(&client).unwrap().is_none());
+        assert_eq!(client_mux.send_credit(bulk), Some(0));
+        assert!(client_mux.make_notification(&client, bulk, b"x").is_err());
+
+        server_mux.read(&server, &first).unwrap();
+        server_mux.read(&server, &second).unwrap();
+        // Peer ignoring the window is caught.
+        let cheat = client.make_notification(&envelope(bulk, DATA, b"x")).unwrap();
+        match server_mux.read(&server, &cheat) {
+            Err(WhisperError::FlowControl) => {}
+            other => panic!("Expected FlowControl, got {:?}", other),
+        }
+
+        let grant = server_mux.poll_outbound(&server).unwrap().unwrap();
+        assert_eq!(client_mux.read(&client, &grant).unwrap(), StreamEvent::Credit(bulk));
+        assert!(client_mux.poll_outbound(&client).unwrap().is_some());
+        assert!(client_mux.is_idle());
+    }
+
+    #[test]
+    fn weighted_scheduling() {
+        let (client, _) = handshake();
+        let mut mux = StreamMux::client();
+        let (bulk, _) = mux.open_stream(&client).unwrap();
+        let (control, _) = mux.open_stream(&client).unwrap();
+        mux.set_weight(bulk, 1).unwrap();
+        mux.set_weight(control, 3).unwrap();
+        for _ in 0..8 {
+            mux.queue(bulk, FrameKind::Notification, vec![1; 16]).unwrap();
+            mux.queue(control, FrameKind::Request, vec![2; 16]).unwrap();
+        }
+        let mut kinds = Vec::new();
+        for _ in 0..8 {
+            kinds.push(mux.poll_outbound(&client).unwrap().unwrap().kind);
+        }
+        let control_turns = kinds.iter().filter(|&&kind| kind == FrameKind::Request).count();
+        assert_eq!(control_turns, 6);
+        // Bulk stream isn't starved either.
+        assert!(kinds[..4].contains(&FrameKind::Notification));
+    }
+}
@@ -0,0 +1,174 @@
+//! Symmetric ciphers of established sessions. Handshake always uses
+//! curve25519xsalsa20poly1305 boxes; messages after it are sealed with
+//! direction keys using suite agreed on in handshake.
+//!
+//! Client offers suite with `ClientSession::offer_cipher_suite`, it goes
+//! as `metadata::CIPHER_SUITE` in Initiate. Server that accepts it (see
+//! `ServerSession::accept_cipher_suite`) puts the same entry into Ready.
+//! Ready itself is always sealed with `XSalsa20Poly1305`, since client
+//! doesn't know the answer before opening it; suite is used from the next
+//! frame on. Offer that isn't accepted leaves session on the default.
+//!
+//! Client can also list suites it supports in Hello, most preferred first,
+//! with `ClientSession::set_cipher_suites`. The list takes the start of
+//! Hello's zero padding: count, then ids. Server picks the first listed
+//! suite it accepts and names it in Welcome metadata; client refuses Welcome
+//! naming a suite it didn't list and offers the pick in Initiate as above.
+//! Server refuses Initiate that offers anything else. List holds at most
+//! 255 suites. Hello without the list is all zeros, same as before. Negotiated suite is
+//! `EstablishedSession::cipher_suite` on both sides.
+//!
+//! Both suites take 24 byte nonces and add 16 bytes to payload, so nonces
+//! (direction bit, counters) and frame sizes are the same. Only the
+//! position of the tag differs: in front of ciphertext for XSalsa20, after
+//! it for XChaCha20 (libsodium's combined mode). No additional data.
+//!
+//! Direction keys of XChaCha20 sessions are hashed with the suite name, so
+//! keys Ready was sealed with never meet the other cipher.
+//!
+//! libsodium-sys 0.0.15 has no XChaCha20-Poly1305 bindings, so they are
+//! declared here; libsodium has the functions since 1.0.12.
+
+use sodiumoxide::crypto::box_::{self, Nonce, PrecomputedKey};
+use std::os::raw::c_int;
+use std::ptr;
+
+extern "C" {
+    fn crypto_aead_xchacha20poly1305_ietf_encrypt(c: *mut u8,
+                                                  clen_p: *mut u64,
+                                                  m: *const u8,
+                                                  mlen: u64,
+                                                  ad: *const u8,
+                                                  adlen: u64,
+                                                  nsec: *const u8,
+                                                  npub: *const u8,
+                                                  k: *const u8)
+                                                  -> c_int;
+    fn crypto_aead_xchacha20poly1305_ietf_decrypt(m: *mut u8,
+                                                  mlen_p: *mut u64,
+                                                  nsec: *mut u8,
+                                                  c: *const u8,
+                                                  clen: u64,
+                                                  ad: *const u8,
+                                                  adlen: u64,
+                                                  npub: *const u8,
+                                                  k: *const u8)
+                                                  -> c_int;
+}
+
+/// Size of authentication tag. Same for both suites.
+pub const TAG_SIZE: usize = 16;
+
+/// Cipher of established session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CipherSuite {
+    /// XSalsa20 with Poly1305, same as libsodium's `crypto_box`.
+    #[default]
+    XSalsa20Poly1305 = 0,
+    /// IETF XChaCha20-Poly1305 AEAD.
+    XChaCha20Poly1305 = 1,
+}
+
+/// Every suite known to this library in wire order.
+pub static CIPHER_SUITES: [CipherSuite; 2] =
+    [CipherSuite::XSalsa20Poly1305, CipherSuite::XChaCha20Poly1305];
+
+impl CipherSuite {
+    /// Suite by its wire id.
+    pub fn from(id: u8) -> Option<CipherSuite> {
+        CIPHER_SUITES.iter().cloned().find(|suite| *suite as u8 == id)
+    }
+
+    /// Lowercase name of the suite.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CipherSuite::XSalsa20Poly1305 => "xsalsa20poly1305",
+            CipherSuite::XChaCha20Poly1305 => "xchacha20poly1305",
+        }
+    }
+}
+
+pub(crate) fn seal(suite: CipherSuite,
+                   data: &[u8],
+                   nonce: &Nonce,
+                   key: &PrecomputedKey)
+                   -> Vec<u8> {
+    match suite {
+        CipherSuite::XSalsa20Poly1305 => box_::seal_precomputed(data, nonce, key),
+        CipherSuite::XChaCha20Poly1305 => {
+            let mut sealed = vec![0; data.len() + TAG_SIZE];
+            let mut sealed_len = 0;
+            unsafe {
+                crypto_aead_xchacha20poly1305_ietf_encrypt(sealed.as_mut_ptr(),
+                                                           &mut sealed_len,
+                                                           data.as_ptr(),
+                                                           data.len() as u64,
+                                                           ptr::null(),
+                                                           0,
+                                                           ptr::null(),
+                                                           nonce.0.as_ptr(),
+                                                           key.0.as_ptr());
+            }
+            sealed.truncate(sealed_len as usize);
+            sealed
+        }
+    }
+}
+
+pub(crate) fn open(suite: CipherSuite,
+                   payload: &[u8],
+                   nonce: &Nonce,
+                   key: &PrecomputedKey)
+                   -> Option<Vec<u8>> {
+    match suite {
+        CipherSuite::XSalsa20Poly1305 => box_::open_precomputed(payload, nonce, key).ok(),
+        CipherSuite::XChaCha20Poly1305 => {
+            if payload.len() < TAG_SIZE {
+                return None;
+            }
+            let mut data = vec![0; payload.len() - TAG_SIZE];
+            let mut data_len = 0;
+            let result = unsafe {
+                crypto_aead_xchacha20poly1305_ietf_decrypt(data.as_mut_ptr(),
+                                                           &mut data_len,
+                                                           ptr::null_mut(),
+                                                           payload.as_ptr(),
+                                                           payload.len() as u64,
+                                                           ptr::null(),
+                                                           0,
+                                                           nonce.0.as_ptr(),
+                                                           key.0.as_ptr())
+            };
+            if result != 0 {
+                return None;
+            }
+            data.truncate(data_len as usize);
+            Some(data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sodiumoxide::crypto::box_::MACBYTES;
+
+    #[test]
+    fn suites_seal_and_open() {
+        let key = PrecomputedKey([42; 32]);
+        let nonce = box_::gen_nonce();
+        assert_eq!(TAG_SIZE, MACBYTES);
+        for suite in CIPHER_SUITES.iter().cloned() {
+            assert_eq!(CipherSuite::from(suite as u8), Some(suite));
+            let sealed = seal(suite, b"attack at dawn", &nonce, &key);
+            assert_eq!(sealed.len(), 14 + TAG_SIZE);
+            assert_eq!(open(suite, &sealed, &nonce, &key).unwrap(), b"attack at dawn");
+            let mut forged = sealed.clone();
+            forged[0] ^= 1;
+            assert!(open(suite, &forged, &nonce, &key).is_none());
+        }
+        let xsalsa = seal(CipherSuite::XSalsa20Poly1305, b"", &nonce, &key);
+        assert!(open(CipherSuite::XChaCha20Poly1305, &xsalsa, &nonce, &key).is_none());
+        assert_eq!(CipherSuite::from(7), None);
+    }
+}
@@ -0,0 +1,141 @@
+//! Reasons carried in Termination frames. Payload of Termination sent
+//! before session is established is a single reason byte in the clear —
+//! there is no shared secret to seal it with yet. Reasons that tell when
+//! to come back (`Banned`) follow the byte with seconds to wait as u32
+//! BigEndian. Handshake sessions make these with `make_termination`.
+//!
+//! ### Polite drop
+//! Sessions can be given a `TerminationSink`. Session dropped while still
+//! active (handshake in progress, or established and not expired) hands
+//! the sink a ready to send Termination, so peer learns about it right
+//! away instead of waiting for a timeout. Sending it is up to the sink.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+use crypto;
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use sodiumoxide::crypto::box_::{PublicKey, gen_nonce};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Gets Termination frames of sessions dropped while still active.
+pub trait TerminationSink: Send + Sync {
+    /// Called from `drop`, so keep it short. Don't block on IO here.
+    fn terminated(&self, frame: Frame);
+}
+
+impl<F: Fn(Frame) + Send + Sync> TerminationSink for F {
+    fn terminated(&self, frame: Frame) { self(frame) }
+}
+
+pub(crate) type SharedTerminationSink = Arc<dyn TerminationSink>;
+
+/// Why the other side is hanging up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminationReason {
+    /// No reason given.
+    Unspecified = 0,
+    /// Server isn't accepting new sessions. Retry later or elsewhere.
+    RetryLater = 1,
+    /// Our identity is temporarily banned. Frame says for how long.
+    Banned = 2,
+    /// Peer's identity isn't allowed: unknown, expired or failed
+    /// attestation.
+    Unauthorized = 3,
+    /// Peer sent frame that makes no sense at this point.
+    ProtocolError = 4,
+    /// Peer took too long.
+    Timeout = 5,
+}
+
+impl TerminationReason {
+    /// Decode reason byte. Unknown codes are `Unspecified`.
+    pub fn from(code: u8) -> TerminationReason {
+        match code {
+            1 => TerminationReason::RetryLater,
+            2 => TerminationReason::Banned,
+            3 => TerminationReason::Unauthorized,
+            4 => TerminationReason::ProtocolError,
+            5 => TerminationReason::Timeout,
+            _ => TerminationReason::Unspecified,
+        }
+    }
+
+    /// Reason to tell peer when session fails with this error.
+    pub fn for_error(error: &WhisperError) -> TerminationReason {
+        match *error {
+            WhisperError::Banned(_) => TerminationReason::Banned,
+            WhisperError::RateLimited(_) |
+            WhisperError::ResourceExhausted => TerminationReason::RetryLater,
+            WhisperError::ExpiredIdentity |
+            WhisperError::AttestationFailed |
+            WhisperError::EnrollmentRejected |
+            WhisperError::PasswordAuthFailed |
+            WhisperError::InvalidTicket => TerminationReason::Unauthorized,
+            WhisperError::HandshakeTimeout(_) |
+            WhisperError::ExpiredSession => TerminationReason::Timeout,
+            WhisperError::InvalidHelloFrame |
+            WhisperError::InvalidWelcomeFrame |
+            WhisperError::InvalidInitiateFrame |
+            WhisperError::InvalidReadyFrame |
+            WhisperError::InvalidSessionState |
+            WhisperError::BadFrame |
+            WhisperError::WrongKind(..) => TerminationReason::ProtocolError,
+            _ => TerminationReason::Unspecified,
+        }
+    }
+
+    /// Reason of unauthenticated Termination frame. None if frame isn't
+    /// Termination.
+    pub fn from_frame(frame: &Frame) -> Option<TerminationReason> {
+        if frame.kind != FrameKind::Termination {
+            return None;
+        }
+        let code = frame.payload.first().cloned().unwrap_or(0);
+        Some(TerminationReason::from(code))
+    }
+
+    /// Unauthenticated Termination frame for given session. Fails with
+    /// `InitializationFailed` if libsodium can't be initialized.
+    pub fn to_frame(self, session_id: PublicKey) -> WhisperResult<Frame> {
+        crypto::init()?;
+        Ok(Frame {
+               id: session_id,
+               nonce: gen_nonce(),
+               kind: FrameKind::Termination,
+               payload: vec![self as u8].into(),
+           })
+    }
+
+    /// Same as `to_frame`, but also says when to retry. Rounded up to whole
+    /// seconds.
+    pub fn to_frame_with_retry(self,
+                               session_id: PublicKey,
+                               retry_in: Duration)
+                               -> WhisperResult<Frame> {
+        crypto::init()?;
+        let mut secs = retry_in.as_secs();
+        if retry_in.subsec_nanos() > 0 {
+            secs += 1;
+        }
+        let mut payload = BytesMut::with_capacity(5);
+        payload.put_u8(self as u8);
+        payload.put_u32_be(secs.min(u64::from(u32::MAX)) as u32);
+        Ok(Frame {
+               id: session_id,
+               nonce: gen_nonce(),
+               kind: FrameKind::Termination,
+               payload: payload.freeze(),
+           })
+    }
+
+    /// When unauthenticated Termination frame says to retry. None if it
+    /// doesn't say.
+    pub fn retry_after(frame: &Frame) -> Option<Duration> {
+        if frame.kind != FrameKind::Termination || frame.payload.len() != 5 {
+            return None;
+        }
+        Some(Duration::from_secs(u64::from(BigEndian::read_u32(&frame.payload[1..]))))
+    }
+}
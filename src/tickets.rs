@@ -0,0 +1,228 @@
+//! Server side keys for resumption tickets (STEK). Ticket is whatever
+//! server wants back from client later — redirect ticket, resumption state
+//! — sealed so only servers holding the key can read it.
+//!
+//! One eternal ticket key would undo forward secrecy of everything ever
+//! resumed with it: whoever steals it reads all tickets recorded so far.
+//! `TicketKeys` makes a new key every `rotate_every`, seals with the newest
+//! one and keeps older ones only for `accept_for` after they were replaced.
+//! Keys past that are dropped (and zeroed), so are tickets sealed with them.
+//!
+//! Ticket is key id as u32 BigEndian, nonce (24 bytes) and secretbox of the
+//! data. Servers sharing tickets have to share `TicketKeys` state; clones
+//! share it within one process.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{DateTime, Duration};
+use chrono::offset::Utc;
+use crypto;
+use errors::{WhisperError, WhisperResult};
+use sodiumoxide::crypto::secretbox;
+use std::sync::{Arc, RwLock};
+
+/// Size of key id and nonce in front of sealed data.
+pub const TICKET_HEADER_SIZE: usize = 4 + secretbox::NONCEBYTES;
+
+struct TicketKey {
+    id: u32,
+    key: secretbox::Key,
+    created_at: DateTime<Utc>,
+    // Set once newer key takes over.
+    replaced_at: Option<DateTime<Utc>>,
+}
+
+struct Keys {
+    next_id: u32,
+    // Newest last.
+    keys: Vec<TicketKey>,
+}
+
+/// Rotating set of ticket keys. Cheap to clone, clones share keys.
+#[derive(DebugStub, Clone)]
+pub struct TicketKeys {
+    rotate_every: Duration,
+    accept_for: Duration,
+    #[debug_stub = "Keys"]
+    keys: Arc<RwLock<Keys>>,
+}
+
+impl TicketKeys {
+    /// Seal with a key for `rotate_every`, then replace it. Replaced keys
+    /// still open tickets for `accept_for`. Fails with
+    /// `InitializationFailed` if libsodium can't be initialized.
+    pub fn new(rotate_every: Duration, accept_for: Duration) -> WhisperResult<TicketKeys> {
+        TicketKeys::new_at(Utc::now(), rotate_every, accept_for)
+    }
+
+    /// Same as above with given current time.
+    pub fn new_at(now: DateTime<Utc>,
+                  rotate_every: Duration,
+                  accept_for: Duration)
+                  -> WhisperResult<TicketKeys> {
+        // Keys are generated from here on, initialization is done once for all.
+        crypto::init()?;
+        let mut keys = Keys {
+            next_id: 0,
+            keys: Vec::new(),
+        };
+        keys.push(now);
+        Ok(TicketKeys {
+               rotate_every,
+               accept_for,
+               keys: Arc::new(RwLock::new(keys)),
+           })
+    }
+
+    /// Number of keys that still open tickets.
+    pub fn len(&self) -> usize { self.keys.read().expect("Ticket keys lock poisoned").keys.len() }
+
+    /// Always false: there is at least the key tickets are sealed with.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Replace sealing key right away, e.g. on suspected leak. Doesn't drop
+    /// older keys before their time; use `retire_all` for that.
+    pub fn rotate(&self) { self.rotate_at(Utc::now()) }
+
+    /// Same as above with given current time.
+    pub fn rotate_at(&self, now: DateTime<Utc>) {
+        let mut keys = self.keys.write().expect("Ticket keys lock poisoned");
+        keys.push(now);
+        keys.forget(now, self.accept_for);
+    }
+
+    /// Drop every key but a freshly made one. All tickets out there stop
+    /// working.
+    pub fn retire_all(&self) {
+        let mut keys = self.keys.write().expect("Ticket keys lock poisoned");
+        keys.keys.clear();
+        keys.push(Utc::now());
+    }
+
+    /// Seal data with the newest key. Rotates first if it's due.
+    pub fn seal(&self, data: &[u8]) -> Bytes { self.seal_at(Utc::now(), data) }
+
+    /// Same as above with given current time.
+    pub fn seal_at(&self, now: DateTime<Utc>, data: &[u8]) -> Bytes {
+        self.rotate_if_due(now);
+        let keys = self.keys.read().expect("Ticket keys lock poisoned");
+        let current = keys.keys.last().expect("There is always a sealing key");
+        let nonce = secretbox::gen_nonce();
+        let mut ticket = BytesMut::with_capacity(TICKET_HEADER_SIZE + secretbox::MACBYTES +
+                                                 data.len());
+        ticket.put_u32_be(current.id);
+        ticket.extend_from_slice(&nonce.0);
+        ticket.extend_from_slice(&secretbox::seal(data, &nonce, &current.key));
+        ticket.freeze()
+    }
+
+    /// Open ticket sealed with any key that is still accepted. Anything else
+    /// is `InvalidTicket`.
+    pub fn open(&self, ticket: &[u8]) -> WhisperResult<Bytes> { self.open_at(Utc::now(), ticket) }
+
+    /// Same as above with given current time.
+    pub fn open_at(&self, now: DateTime<Utc>, ticket: &[u8]) -> WhisperResult<Bytes> {
+        self.rotate_if_due(now);
+        if ticket.len() < TICKET_HEADER_SIZE {
+            return Err(WhisperError::InvalidTicket);
+        }
+        let id = BigEndian::read_u32(&ticket[..4]);
+        let nonce = secretbox::Nonce::from_slice(&ticket[4..TICKET_HEADER_SIZE])
+            .ok_or(WhisperError::InvalidTicket)?;
+        let keys = self.keys.read().expect("Ticket keys lock poisoned");
+        let key = keys.keys
+                      .iter()
+                      .find(|key| key.id == id && key.accepted(now, self.accept_for))
+                      .ok_or(WhisperError::InvalidTicket)?;
+        secretbox::open(&ticket[TICKET_HEADER_SIZE..], &nonce, &key.key)
+            .map(Bytes::from)
+            .map_err(|_| WhisperError::InvalidTicket)
+    }
+
+    fn rotate_if_due(&self, now: DateTime<Utc>) {
+        let due = {
+            let keys = self.keys.read().expect("Ticket keys lock poisoned");
+            let current = keys.keys.last().expect("There is always a sealing key");
+            current.created_at + self.rotate_every <= now ||
+            keys.keys.iter().any(|key| !key.accepted(now, self.accept_for))
+        };
+        if !due {
+            return;
+        }
+        let mut keys = self.keys.write().expect("Ticket keys lock poisoned");
+        // Someone could've rotated while we waited for the lock.
+        let rotate = keys.keys
+                         .last()
+                         .is_none_or(|current| current.created_at + self.rotate_every <= now);
+        if rotate {
+            keys.push(now);
+        }
+        keys.forget(now, self.accept_for);
+    }
+}
+
+impl TicketKey {
+    fn accepted(&self, now: DateTime<Utc>, accept_for: Duration) -> bool {
+        self.replaced_at.is_none_or(|replaced_at| replaced_at + accept_for > now)
+    }
+}
+
+impl Keys {
+    fn push(&mut self, now: DateTime<Utc>) {
+        if let Some(current) = self.keys.last_mut() {
+            current.replaced_at = Some(now);
+        }
+        self.keys.push(TicketKey {
+                           id: self.next_id,
+                           key: secretbox::gen_key(),
+                           created_at: now,
+                           replaced_at: None,
+                       });
+        self.next_id = self.next_id.wrapping_add(1);
+    }
+
+    fn forget(&mut self, now: DateTime<Utc>, accept_for: Duration) {
+        self.keys.retain(|key| key.accepted(now, accept_for));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::offset::TimeZone;
+
+    #[test]
+    fn rotation_and_grace_period() {
+        let start = Utc.timestamp_opt(1_500_000_000, 0).unwrap();
+        let keys = TicketKeys::new_at(start, Duration::hours(1), Duration::hours(2)).unwrap();
+        let first = keys.seal_at(start, b"resume me");
+        assert_eq!(keys.open_at(start, &first).unwrap().as_ref(), b"resume me");
+
+        // Rotated on next use, old key still opens.
+        let later = start + Duration::minutes(90);
+        let second = keys.seal_at(later, b"again");
+        assert_eq!(keys.len(), 2);
+        assert!(first[..4] != second[..4]);
+        assert_eq!(keys.open_at(later, &first).unwrap().as_ref(), b"resume me");
+        assert_eq!(keys.clone().open_at(later, &second).unwrap().as_ref(), b"again");
+
+        // First key was replaced at `later`, so it's gone two hours after.
+        let much_later = later + Duration::hours(2);
+        match keys.open_at(much_later, &first) {
+            Err(WhisperError::InvalidTicket) => {}
+            other => panic!("Expected InvalidTicket, got {:?}", other),
+        }
+        assert_eq!(keys.open_at(much_later, &second).unwrap().as_ref(), b"again");
+
+        let mut tampered = second.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(keys.open_at(much_later, &tampered).is_err());
+        assert!(keys.open_at(much_later, &second[..10]).is_err());
+
+        keys.retire_all();
+        assert_eq!(keys.len(), 1);
+        assert!(keys.open(&second).is_err());
+        let fresh = keys.seal(b"fresh");
+        assert_eq!(keys.open(&fresh).unwrap().as_ref(), b"fresh");
+    }
+}
@@ -0,0 +1,207 @@
+//! Trace context for distributed tracing across whisper hops, so
+//! frameworks don't each invent their own header. Context is W3C
+//! `traceparent` (trace id, parent span id, flags) in compact binary form.
+//!
+//! Traced Request or Response payload is prefixed with context length
+//! (1 byte, zero for no context) and the context inside the encrypted
+//! payload, so it's authenticated and hidden from the network like the
+//! rest of the message. Context is trace id (16 bytes), parent span id
+//! (8 bytes) and flags (1 byte). Like content type hints, peers have to
+//! agree to use it.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use crypto;
+use encoding;
+use errors::{WhisperError, WhisperResult};
+use sodiumoxide::randombytes::randombytes_into;
+use std::fmt;
+
+/// Size of encoded context.
+pub const CONTEXT_SIZE: usize = 16 + 8 + 1;
+/// `sampled` bit of trace flags.
+pub const SAMPLED: u8 = 1;
+
+/// Position in a distributed trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    trace_id: [u8; 16],
+    parent_id: [u8; 8],
+    flags: u8,
+}
+
+impl TraceContext {
+    /// Start new sampled trace. Fails with `InitializationFailed` if
+    /// libsodium can't be initialized.
+    pub fn new() -> WhisperResult<TraceContext> {
+        crypto::init()?;
+        let mut trace_id = [0; 16];
+        randombytes_into(&mut trace_id);
+        Ok(TraceContext {
+               trace_id,
+               parent_id: span_id()?,
+               flags: SAMPLED,
+           })
+    }
+
+    /// Context from its parts. None if trace id or span id is all zeroes,
+    /// those are invalid.
+    pub fn from_parts(trace_id: [u8; 16], parent_id: [u8; 8], flags: u8) -> Option<TraceContext> {
+        if trace_id == [0; 16] || parent_id == [0; 8] {
+            return None;
+        }
+        Some(TraceContext {
+                 trace_id,
+                 parent_id,
+                 flags,
+             })
+    }
+
+    /// Parse `traceparent` header value, e.g.
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`. Only
+    /// version 00 is understood.
+    pub fn parse(traceparent: &str) -> Option<TraceContext> {
+        let mut parts = traceparent.trim().split('-');
+        if parts.next() != Some("00") {
+            return None;
+        }
+        let mut trace_id = [0; 16];
+        let mut parent_id = [0; 8];
+        let mut flags = [0; 1];
+        decode_hex(parts.next()?, &mut trace_id)?;
+        decode_hex(parts.next()?, &mut parent_id)?;
+        decode_hex(parts.next()?, &mut flags)?;
+        if parts.next().is_some() {
+            return None;
+        }
+        TraceContext::from_parts(trace_id, parent_id, flags[0])
+    }
+
+    /// Trace id.
+    pub fn trace_id(&self) -> [u8; 16] { self.trace_id }
+
+    /// Span id of the caller.
+    pub fn parent_id(&self) -> [u8; 8] { self.parent_id }
+
+    /// Trace flags.
+    pub fn flags(&self) -> u8 { self.flags }
+
+    /// Returns true if caller records this trace.
+    pub fn is_sampled(&self) -> bool { self.flags & SAMPLED != 0 }
+
+    /// Set parent span id, e.g. to id of span that makes the request.
+    pub fn set_parent_id(&mut self, parent_id: [u8; 8]) { self.parent_id = parent_id; }
+
+    /// Set trace flags.
+    pub fn set_flags(&mut self, flags: u8) { self.flags = flags; }
+
+    /// Same trace, new random span id. Use for the next hop.
+    pub fn child(&self) -> WhisperResult<TraceContext> {
+        Ok(TraceContext {
+               parent_id: span_id()?,
+               ..*self
+           })
+    }
+
+    /// `traceparent` header value.
+    pub fn to_traceparent(&self) -> String { self.to_string() }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "00-{}-{}-{:02x}",
+               encoding::hex(&self.trace_id),
+               encoding::hex(&self.parent_id),
+               self.flags)
+    }
+}
+
+/// Prefix data with context. Pass result to `make_request` or
+/// `make_response`.
+pub fn wrap(context: Option<&TraceContext>, data: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(1 + CONTEXT_SIZE + data.len());
+    match context {
+        Some(context) => {
+            buf.put_u8(CONTEXT_SIZE as u8);
+            buf.extend_from_slice(&context.trace_id);
+            buf.extend_from_slice(&context.parent_id);
+            buf.put_u8(context.flags);
+        }
+        None => buf.put_u8(0),
+    }
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// Split opened payload into context and data.
+pub fn unwrap(payload: &Bytes) -> WhisperResult<(Option<TraceContext>, Bytes)> {
+    let len = *payload.first().ok_or(WhisperError::BadFrame)? as usize;
+    if len == 0 {
+        return Ok((None, payload.slice_from(1)));
+    }
+    if len != CONTEXT_SIZE || payload.len() < 1 + len {
+        return Err(WhisperError::BadFrame);
+    }
+    let mut trace_id = [0; 16];
+    let mut parent_id = [0; 8];
+    trace_id.copy_from_slice(&payload[1..17]);
+    parent_id.copy_from_slice(&payload[17..25]);
+    let context = TraceContext::from_parts(trace_id, parent_id, payload[25])
+        .ok_or(WhisperError::BadFrame)?;
+    Ok((Some(context), payload.slice_from(1 + len)))
+}
+
+fn span_id() -> WhisperResult<[u8; 8]> {
+    crypto::init()?;
+    let mut id = [0; 8];
+    while id == [0; 8] {
+        randombytes_into(&mut id);
+    }
+    Ok(id)
+}
+
+// Lowercase only, as traceparent requires.
+// `traceparent` is lowercase only.
+fn decode_hex(hex: &str, out: &mut [u8]) -> Option<()> {
+    if hex.len() != out.len() * 2 || hex.bytes().any(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+    out.copy_from_slice(&encoding::from_hex(hex).ok()?);
+    Some(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::test::handshake;
+
+    #[test]
+    fn context_crosses_hop() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse(header).unwrap();
+        assert!(context.is_sampled());
+        assert_eq!(context.to_traceparent(), header);
+        assert!(TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01")
+                    .is_none());
+        assert!(TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+                    .is_none());
+        assert!(TraceContext::parse("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01")
+                    .is_none());
+
+        let (client, server) = handshake();
+        let request = client.make_request(&wrap(Some(&context), b"hop")).unwrap();
+        let (received, data) = unwrap(&server.read_msg(&request).unwrap()).unwrap();
+        assert_eq!(received, Some(context));
+        assert_eq!(data.as_ref(), b"hop");
+
+        let child = received.unwrap().child().unwrap();
+        assert_eq!(child.trace_id(), context.trace_id());
+        assert!(child.parent_id() != context.parent_id());
+
+        let untraced = server.make_response(&wrap(None, b"done")).unwrap();
+        let (none, data) = unwrap(&client.read_msg(&untraced).unwrap()).unwrap();
+        assert!(none.is_none());
+        assert_eq!(data.as_ref(), b"done");
+        assert!(unwrap(&Bytes::from(&[CONTEXT_SIZE as u8, 1][..])).is_err());
+    }
+}
@@ -0,0 +1,396 @@
+//! Correlation of requests and responses, deadlines and cancellation.
+//!
+//! Tracked Request and Response payloads are prefixed with u32 BigEndian
+//! request id inside the encrypted payload, so responses can come in any
+//! order. Request id is followed by time to live in milliseconds as u32
+//! BigEndian, zero for none.
+//!
+//! ### Deadlines
+//! Requester can give request a time to live. Responder counts it from
+//! arrival (so clocks don't have to agree), `remaining` tells how much of
+//! it is left, and `respond` doesn't bother making a frame once it's gone.
+//! Requester drops responses that arrive too late as well; `forget_expired`
+//! tells which requests timed out.
+//!
+//! ### Cancellation
+//! Requester that no longer cares sends Cancel: Control frame with
+//! op byte 8 followed by the id. Responder learns about it from `read` and
+//! can abort the work; `respond` won't make a frame for cancelled request,
+//! and requester drops responses that arrive after cancelling anyway.
+//!
+//! ### Orphans
+//! Response that matches no request we wait for — unknown id, too late or
+//! cancelled — is dropped and counted by `orphans`. Steady growth means
+//! peer answers requests nobody made or answers them twice.
+//!
+//! ### Limits
+//! At most `max_incoming` peer's requests are waiting for our response;
+//! Request over that is `ResourceExhausted`. Request ids wrap around, and
+//! ids of our requests still waiting for response are skipped.
+//!
+//! Both sides need a `RequestTracker`, and all Requests, Responses and
+//! Control frames should go through `read`.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use control::CANCEL;
+use errors::{WhisperError, WhisperResult};
+use frame::{Frame, FrameKind};
+use session::EstablishedSession;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Size of request id prefix.
+pub const REQUEST_ID_SIZE: usize = 4;
+/// Size of time to live that follows request id in Requests.
+pub const TTL_SIZE: usize = 4;
+
+/// Id of a tracked request. Unique per requesting side of a session.
+pub type RequestId = u32;
+
+/// Default cap on peer's requests waiting for our response.
+pub const DEFAULT_MAX_INCOMING: usize = 1024;
+
+/// Prefix data with request id.
+pub fn wrap(id: RequestId, data: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(REQUEST_ID_SIZE + data.len());
+    buf.put_u32_be(id);
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// Split opened payload into request id and data.
+pub fn unwrap(payload: &Bytes) -> WhisperResult<(RequestId, Bytes)> {
+    if payload.len() < REQUEST_ID_SIZE {
+        return Err(WhisperError::BadFrame);
+    }
+    Ok((BigEndian::read_u32(payload), payload.slice_from(REQUEST_ID_SIZE)))
+}
+
+/// Prefix Request data with request id and time to live. Time to live is
+/// rounded up to milliseconds and capped at u32::MAX of them.
+pub fn wrap_request(id: RequestId, ttl: Option<Duration>, data: &[u8]) -> BytesMut {
+    let ttl = ttl.map_or(0, |ttl| {
+        let millis = ttl.as_nanos().div_ceil(1_000_000);
+        millis.clamp(1, u128::from(u32::MAX)) as u32
+    });
+    let mut buf = BytesMut::with_capacity(REQUEST_ID_SIZE + TTL_SIZE + data.len());
+    buf.put_u32_be(id);
+    buf.put_u32_be(ttl);
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// Split opened Request payload into request id, time to live and data.
+pub fn unwrap_request(payload: &Bytes) -> WhisperResult<(RequestId, Option<Duration>, Bytes)> {
+    let (id, rest) = unwrap(payload)?;
+    if rest.len() < TTL_SIZE {
+        return Err(WhisperError::BadFrame);
+    }
+    let ttl = match BigEndian::read_u32(&rest) {
+        0 => None,
+        millis => Some(Duration::from_millis(u64::from(millis))),
+    };
+    Ok((id, ttl, rest.slice_from(TTL_SIZE)))
+}
+
+fn deadline(ttl: Option<Duration>) -> Option<Instant> { ttl.map(|ttl| Instant::now() + ttl) }
+
+fn expired(deadline: Option<Instant>) -> bool { deadline.is_some_and(|d| d <= Instant::now()) }
+
+/// What came in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerEvent {
+    /// Peer's request. Answer with `respond`.
+    Request(RequestId, Bytes),
+    /// Response to our request.
+    Response(RequestId, Bytes),
+    /// Peer cancelled its request. Abort the work, there is no one to
+    /// respond to.
+    Cancelled(RequestId),
+}
+
+/// Requests in flight on one session, in both directions.
+#[derive(Debug, Clone)]
+pub struct RequestTracker {
+    next_id: RequestId,
+    // Ours, waiting for response, with their deadlines.
+    outgoing: HashMap<RequestId, Option<Instant>>,
+    // Theirs, waiting for our response, with their deadlines.
+    incoming: HashMap<RequestId, Option<Instant>>,
+    max_incoming: usize,
+    orphans: u64,
+}
+
+impl Default for RequestTracker {
+    fn default() -> RequestTracker { RequestTracker::new() }
+}
+
+impl RequestTracker {
+    /// Tracker without requests.
+    pub fn new() -> RequestTracker {
+        RequestTracker {
+            next_id: 1,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            max_incoming: DEFAULT_MAX_INCOMING,
+            orphans: 0,
+        }
+    }
+
+    /// Keep at most this many peer's requests waiting for our response.
+    pub fn set_max_incoming(&mut self, max_incoming: usize) { self.max_incoming = max_incoming; }
+
+    /// Make tracked request without deadline.
+    pub fn make_request(&mut self,
+                        session: &EstablishedSession,
+                        data: &[u8])
+                        -> WhisperResult<(RequestId, Frame)> {
+        self.make_request_with_ttl(session, data, None)
+    }
+
+    /// Make tracked request that is only worth answering within `ttl`.
+    pub fn make_request_with_ttl(&mut self,
+                                 session: &EstablishedSession,
+                                 data: &[u8],
+                                 ttl: Option<Duration>)
+                                 -> WhisperResult<(RequestId, Frame)> {
+        // Once ids wrap, skip the ones still waiting for response.
+        while self.outgoing.contains_key(&self.next_id) {
+            self.next_id = self.next_id.wrapping_add(1);
+        }
+        let id = self.next_id;
+        let frame = session.make_request(&wrap_request(id, ttl, data))?;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.outgoing.insert(id, deadline(ttl));
+        Ok((id, frame))
+    }
+
+    /// Returns true if we still wait for response to this request.
+    pub fn is_pending(&self, id: RequestId) -> bool { self.outgoing.contains_key(&id) }
+
+    /// Forget our requests whose deadline has passed and return their ids.
+    /// Peer's requests that expired are forgotten as well.
+    pub fn forget_expired(&mut self) -> Vec<RequestId> {
+        self.incoming.retain(|_, deadline| !expired(*deadline));
+        let timed_out: Vec<RequestId> = self.outgoing
+                                            .iter()
+                                            .filter(|&(_, deadline)| expired(*deadline))
+                                            .map(|(id, _)| *id)
+                                            .collect();
+        for id in &timed_out {
+            self.outgoing.remove(id);
+        }
+        timed_out
+    }
+
+    /// Time left to answer peer's request. None if it has no deadline or
+    /// isn't waiting for response; zero once deadline has passed.
+    pub fn remaining(&self, id: RequestId) -> Option<Duration> {
+        self.incoming
+            .get(&id)
+            .and_then(|deadline| *deadline)
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Number of our requests waiting for response.
+    pub fn pending(&self) -> usize { self.outgoing.len() }
+
+    /// Responses dropped because they matched no request we wait for.
+    pub fn orphans(&self) -> u64 { self.orphans }
+
+    /// Tell peer we don't need response anymore. Response that still
+    /// arrives is dropped.
+    pub fn cancel(&mut self, session: &EstablishedSession, id: RequestId) -> WhisperResult<Frame> {
+        if !self.outgoing.contains_key(&id) {
+            return Err(WhisperError::InvalidSessionState);
+        }
+        let mut payload = BytesMut::with_capacity(1 + REQUEST_ID_SIZE);
+        payload.put_u8(CANCEL);
+        payload.put_u32_be(id);
+        let frame = session.make_control(&payload)?;
+        self.outgoing.remove(&id);
+        Ok(frame)
+    }
+
+    /// Answer peer's request. None if it was cancelled, has expired (or
+    /// was already answered): there is nothing to send then.
+    pub fn respond(&mut self,
+                   session: &EstablishedSession,
+                   id: RequestId,
+                   data: &[u8])
+                   -> WhisperResult<Option<Frame>> {
+        match self.incoming.get(&id) {
+            Some(&deadline) if !expired(deadline) => {}
+            Some(_) => {
+                self.incoming.remove(&id);
+                return Ok(None);
+            }
+            None => return Ok(None),
+        }
+        let frame = session.make_response(&wrap(id, data))?;
+        self.incoming.remove(&id);
+        Ok(Some(frame))
+    }
+
+    /// Handle incoming frame. Notifications, Control frames of other
+    /// modules, responses we don't wait for (or that are too late) and
+    /// cancels of requests we've answered already give None. Request over
+    /// `max_incoming` is `ResourceExhausted`.
+    pub fn read(&mut self,
+                session: &EstablishedSession,
+                frame: &Frame)
+                -> WhisperResult<Option<TrackerEvent>> {
+        match frame.kind {
+            FrameKind::Request => {
+                let (id, ttl, data) = unwrap_request(&session.read_msg(frame)?)?;
+                if !self.incoming.contains_key(&id) && self.incoming.len() >= self.max_incoming {
+                    self.incoming.retain(|_, deadline| !expired(*deadline));
+                    if self.incoming.len() >= self.max_incoming {
+                        return Err(WhisperError::ResourceExhausted);
+                    }
+                }
+                self.incoming.insert(id, deadline(ttl));
+                Ok(Some(TrackerEvent::Request(id, data)))
+            }
+            FrameKind::Response => {
+                let (id, data) = unwrap(&session.read_msg(frame)?)?;
+                match self.outgoing.remove(&id) {
+                    Some(deadline) if !expired(deadline) => {
+                        Ok(Some(TrackerEvent::Response(id, data)))
+                    }
+                    _ => {
+                        self.orphans += 1;
+                        Ok(None)
+                    }
+                }
+            }
+            FrameKind::Control => {
+                let payload = session.peek_msg(frame)?;
+                if payload.first() != Some(&CANCEL) {
+                    return Ok(None);
+                }
+                session.claim(frame)?;
+                let (id, _) = unwrap(&payload.slice_from(1))?;
+                if self.incoming.remove(&id).is_some() {
+                    Ok(Some(TrackerEvent::Cancelled(id)))
+                } else {
+                    Ok(None)
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::test::handshake;
+
+    #[test]
+    fn responses_are_correlated() {
+        let (client, server) = handshake();
+        let mut requester = RequestTracker::new();
+        let mut responder = RequestTracker::new();
+        let (first, first_frame) = requester.make_request(&client, b"one").unwrap();
+        let (second, second_frame) = requester.make_request(&client, b"two").unwrap();
+        assert!(first != second);
+        assert_eq!(requester.pending(), 2);
+
+        responder.read(&server, &first_frame).unwrap();
+        responder.read(&server, &second_frame).unwrap();
+        // Answered out of order.
+        let response = responder.respond(&server, second, b"2").unwrap().unwrap();
+        assert_eq!(requester.read(&client, &response).unwrap(),
+                   Some(TrackerEvent::Response(second, Bytes::from(&b"2"[..]))));
+        let response = responder.respond(&server, first, b"1").unwrap().unwrap();
+        assert_eq!(requester.read(&client, &response).unwrap(),
+                   Some(TrackerEvent::Response(first, Bytes::from(&b"1"[..]))));
+        assert_eq!(requester.pending(), 0);
+        assert!(responder.respond(&server, first, b"again").unwrap().is_none());
+
+        // Nobody asked for this one.
+        let orphan = server.make_response(&wrap(first, b"1")).unwrap();
+        assert_eq!(requester.read(&client, &orphan).unwrap(), None);
+        assert_eq!(requester.orphans(), 1);
+    }
+
+    #[test]
+    fn expired_requests() {
+        let (client, server) = handshake();
+        let mut requester = RequestTracker::new();
+        let mut responder = RequestTracker::new();
+        let minute = Some(Duration::from_secs(60));
+        let (patient, frame) = requester.make_request_with_ttl(&client, b"a", minute).unwrap();
+        responder.read(&server, &frame).unwrap();
+        assert!(responder.remaining(patient).unwrap() > Duration::from_secs(59));
+
+        let moment = Some(Duration::from_millis(1));
+        let (hasty, frame) = requester.make_request_with_ttl(&client, b"b", moment).unwrap();
+        responder.read(&server, &frame).unwrap();
+        ::std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(responder.remaining(hasty), Some(Duration::from_secs(0)));
+        // Nobody waits for it anymore, so nothing is sent.
+        assert!(responder.respond(&server, hasty, b"late").unwrap().is_none());
+        let late = server.make_response(&wrap(hasty, b"late")).unwrap();
+        assert_eq!(requester.forget_expired(), vec![hasty]);
+        assert_eq!(requester.read(&client, &late).unwrap(), None);
+
+        assert!(responder.respond(&server, patient, b"ok").unwrap().is_some());
+        let (_, frame) = requester.make_request(&client, b"c").unwrap();
+        let (id, ttl, _) = unwrap_request(&server.read_msg(&frame).unwrap()).unwrap();
+        assert_eq!(ttl, None);
+        responder.read(&server, &frame).unwrap();
+        assert_eq!(responder.remaining(id), None);
+    }
+
+    #[test]
+    fn cancelled_request() {
+        let (client, server) = handshake();
+        let mut requester = RequestTracker::new();
+        let mut responder = RequestTracker::new();
+        let (id, frame) = requester.make_request(&client, b"slow").unwrap();
+        assert_eq!(responder.read(&server, &frame).unwrap(),
+                   Some(TrackerEvent::Request(id, Bytes::from(&b"slow"[..]))));
+
+        let cancel = requester.cancel(&client, id).unwrap();
+        assert!(!requester.is_pending(id));
+        assert!(requester.cancel(&client, id).is_err());
+        // Responder already answered before cancel arrived.
+        let late = server.make_response(&wrap(id, b"done")).unwrap();
+        assert_eq!(requester.read(&client, &late).unwrap(), None);
+        assert_eq!(requester.orphans(), 1);
+
+        assert_eq!(responder.read(&server, &cancel).unwrap(), Some(TrackerEvent::Cancelled(id)));
+        assert!(responder.respond(&server, id, b"done").unwrap().is_none());
+        assert_eq!(responder.read(&server, &cancel).unwrap(), None);
+    }
+
+    #[test]
+    fn limits() {
+        let (client, server) = handshake();
+        let mut requester = RequestTracker::new();
+        let (first, _) = requester.make_request(&client, b"stuck").unwrap();
+        requester.next_id = RequestId::MAX;
+        let (last, _) = requester.make_request(&client, b"a").unwrap();
+        let (wrapped, _) = requester.make_request(&client, b"b").unwrap();
+        let (next, _) = requester.make_request(&client, b"c").unwrap();
+        assert_eq!((last, wrapped), (RequestId::MAX, 0));
+        // Id of request still waiting for response is skipped.
+        assert_eq!(first, 1);
+        assert_eq!(next, 2);
+
+        let mut responder = RequestTracker::new();
+        responder.set_max_incoming(2);
+        for data in &[b"1", b"2"] {
+            let (_, frame) = requester.make_request(&client, *data).unwrap();
+            responder.read(&server, &frame).unwrap();
+        }
+        let (_, frame) = requester.make_request(&client, b"3").unwrap();
+        match responder.read(&server, &frame) {
+            Err(WhisperError::ResourceExhausted) => {}
+            other => panic!("Expected ResourceExhausted, got {:?}", other),
+        }
+    }
+}
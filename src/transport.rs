@@ -0,0 +1,290 @@
+//! Kind of transport session runs over. Client declares it in Initiate
+//! metadata (tag `metadata::TRANSPORT`), and both sides pick framing based
+//! on it:
+//! - `Stream` (TCP, pipes) — frames are delimited by u32 BigEndian length
+//!   prefix. `frame::FrameDecoder` reassembles frames from partial reads.
+//! - `Datagram` (UDP and friends) — one frame per datagram, no prefix.
+//!   Lost packets are not retransmitted: retry requests that got no
+//!   response.
+//!
+//! Frames can be replayed by anyone on the path in either mode. Every frame
+//! of established session has counter nonce (see `session::nonce_counter`),
+//! and every session keeps `ReplayWindow` of the last `REPLAY_WINDOW`
+//! counters it opened: each of them opens once, anything older never. See
+//! `EstablishedSession::set_replay_protection`.
+//!
+//! Sessions that didn't declare anything are `Stream`. Server listening on
+//! one kind of transport can refuse the other with
+//! `ServerSession::require_transport_mode`.
+//!
+//! ### Coalescing
+//! In datagram mode, many tiny frames cost a packet each. `coalesce` packs
+//! several of them into one datagram up to MTU, each behind u16 BigEndian
+//! length prefix, and `split_coalesced` takes them apart again. Every
+//! piece is then fed to `read_packet` as usual. Coalesced and plain
+//! datagrams look nothing alike, so both sides must agree on using it.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use errors::{WhisperError, WhisperResult};
+use frame::Frame;
+use std::io::{self, Read};
+
+/// Size of length prefix in stream mode.
+pub static LENGTH_PREFIX_SIZE: usize = 4;
+/// Largest frame `read_prefixed` is willing to read.
+pub static MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// How far behind the highest counter opened so far a frame may fall, e.g.
+/// when datagrams are reordered.
+pub const REPLAY_WINDOW: usize = 1024;
+/// Size of length prefix of every frame inside coalesced datagram.
+pub static COALESCED_PREFIX_SIZE: usize = 2;
+/// MTU to use when you don't know better. Fits into IPv6 minimum MTU with
+/// room to spare for IP and UDP headers.
+pub static DEFAULT_MTU: usize = 1200;
+
+/// Transport session runs over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportMode {
+    /// Reliable ordered byte stream.
+    #[default]
+    Stream = 0,
+    /// Unreliable datagrams.
+    Datagram = 1,
+}
+
+impl TransportMode {
+    /// Decode mode from metadata value.
+    pub fn from_slice(value: &[u8]) -> Option<TransportMode> {
+        match value {
+            [0] => Some(TransportMode::Stream),
+            [1] => Some(TransportMode::Datagram),
+            _ => None,
+        }
+    }
+}
+
+/// Pack frame with length prefix.
+pub fn pack_prefixed(frame: &Frame) -> Bytes {
+    let mut buf = BytesMut::with_capacity(LENGTH_PREFIX_SIZE + frame.length());
+    buf.put_u32_be(frame.length() as u32);
+    frame.pack_to_buf(&mut buf);
+    buf.freeze()
+}
+
+/// Parse exactly one length prefixed frame.
+pub fn unpack_prefixed(packet: &[u8]) -> WhisperResult<Frame> {
+    if packet.len() < LENGTH_PREFIX_SIZE {
+        return Err(WhisperError::IncompleteFrame);
+    }
+    let len = BigEndian::read_u32(&packet[..LENGTH_PREFIX_SIZE]) as usize;
+    if packet.len() - LENGTH_PREFIX_SIZE != len {
+        return Err(WhisperError::BadFrame);
+    }
+    Frame::from_slice(&packet[LENGTH_PREFIX_SIZE..])
+}
+
+/// Read one length prefixed packet from blocking stream. Returned packet
+/// includes prefix. None if stream ended right at packet boundary.
+pub fn read_prefixed<R: Read>(reader: &mut R) -> WhisperResult<Option<Vec<u8>>> {
+    match read_length(reader)? {
+        Some(len) => read_body(reader, len).map(Some),
+        None => Ok(None),
+    }
+}
+
+// First half of `read_prefixed`: just the prefix, so caller can decide
+// whether to take the frame before reading it.
+pub(crate) fn read_length<R: Read>(reader: &mut R) -> WhisperResult<Option<usize>> {
+    let mut prefix = [0; LENGTH_PREFIX_SIZE];
+    match reader.read_exact(&mut prefix) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = BigEndian::read_u32(&prefix) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(WhisperError::BadFrame);
+    }
+    Ok(Some(len))
+}
+
+// Second half of `read_prefixed`: frame of `len` bytes, returned with
+// prefix.
+pub(crate) fn read_body<R: Read>(reader: &mut R, len: usize) -> WhisperResult<Vec<u8>> {
+    let mut packet = vec![0; LENGTH_PREFIX_SIZE + len];
+    BigEndian::write_u32(&mut packet, len as u32);
+    reader.read_exact(&mut packet[LENGTH_PREFIX_SIZE..])?;
+    Ok(packet)
+}
+
+/// Pack datagram mode packets (i.e. `EstablishedSession::pack` output) into
+/// as few datagrams of at most `mtu` bytes as possible, keeping order.
+/// Packet that doesn't fit into `mtu` on its own gets its own datagram
+/// as is — fragmenting is not our business here. Fails with `BadFrame` on
+/// packets longer than 65535 bytes, which no datagram can carry anyway.
+pub fn coalesce(packets: &[Bytes], mtu: usize) -> WhisperResult<Vec<Bytes>> {
+    let mut datagrams = Vec::new();
+    let mut current = BytesMut::with_capacity(mtu);
+    for packet in packets {
+        // Anything longer can't be described by the prefix.
+        if packet.len() > u16::MAX as usize {
+            return Err(WhisperError::BadFrame);
+        }
+        let size = COALESCED_PREFIX_SIZE + packet.len();
+        if !current.is_empty() && current.len() + size > mtu {
+            datagrams.push(current.take().freeze());
+        }
+        current.reserve(size);
+        current.put_u16_be(packet.len() as u16);
+        current.put_slice(packet);
+    }
+    if !current.is_empty() {
+        datagrams.push(current.freeze());
+    }
+    Ok(datagrams)
+}
+
+/// Split coalesced datagram back into packets. Nothing is copied.
+pub fn split_coalesced(datagram: &Bytes) -> WhisperResult<Vec<Bytes>> {
+    let mut packets = Vec::new();
+    let mut offset = 0;
+    while offset < datagram.len() {
+        if datagram.len() - offset < COALESCED_PREFIX_SIZE {
+            return Err(WhisperError::IncompleteFrame);
+        }
+        let len = BigEndian::read_u16(&datagram[offset..]) as usize;
+        offset += COALESCED_PREFIX_SIZE;
+        if datagram.len() - offset < len {
+            return Err(WhisperError::IncompleteFrame);
+        }
+        packets.push(datagram.slice(offset, offset + len));
+        offset += len;
+    }
+    Ok(packets)
+}
+
+/// Sliding window over nonce counters. Remembers which of the last
+/// `REPLAY_WINDOW` counters up to the highest one were seen, and refuses
+/// everything below them, so replayed frame can't wait until it's
+/// forgotten.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayWindow {
+    highest: u64,
+    // Bit i is counter `highest - i`.
+    seen: [u64; REPLAY_WINDOW / 64],
+}
+
+impl ReplayWindow {
+    /// Create empty window.
+    pub fn new() -> ReplayWindow { ReplayWindow::default() }
+
+    /// Returns false if counter was already seen or is below the window.
+    /// Remembers it otherwise. Counters start at 1.
+    pub fn check(&mut self, counter: u64) -> bool {
+        if counter > self.highest {
+            self.shift(counter - self.highest);
+            self.highest = counter;
+            self.seen[0] |= 1;
+            return true;
+        }
+        let offset = self.highest - counter;
+        if counter == 0 || offset >= REPLAY_WINDOW as u64 {
+            return false;
+        }
+        let (word, bit) = (offset as usize / 64, offset % 64);
+        if self.seen[word] & 1 << bit != 0 {
+            return false;
+        }
+        self.seen[word] |= 1 << bit;
+        true
+    }
+
+    // Move bits up as highest counter grows.
+    fn shift(&mut self, by: u64) {
+        if by >= REPLAY_WINDOW as u64 {
+            self.seen = [0; REPLAY_WINDOW / 64];
+            return;
+        }
+        let (words, bits) = (by as usize / 64, by % 64);
+        for i in (0..self.seen.len()).rev() {
+            let mut word = 0;
+            if i >= words {
+                word = self.seen[i - words] << bits;
+                if bits > 0 && i > words {
+                    word |= self.seen[i - words - 1] >> (64 - bits);
+                }
+            }
+            self.seen[i] = word;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn replay_window_slides() {
+        let mut window = ReplayWindow::new();
+        assert!(!window.check(0));
+        assert!(window.check(1));
+        assert!(!window.check(1));
+        // Reordered within the window.
+        assert!(window.check(70));
+        assert!(window.check(3));
+        assert!(!window.check(3));
+        assert!(window.check(2));
+        assert!(!window.check(70));
+        // Old frames never open again, however many came after them.
+        let highest = REPLAY_WINDOW as u64 + 69;
+        for counter in (71..highest + 1).filter(|&counter| counter != 75) {
+            assert!(window.check(counter));
+        }
+        for counter in &[1, 2, 3, 69, 70, 71, highest] {
+            assert!(!window.check(*counter));
+        }
+        assert!(window.check(75));
+        assert!(window.check(u64::MAX));
+        assert!(!window.check(highest));
+    }
+
+    #[test]
+    fn coalesce_round_trip() {
+        let (client, server) = ::session::test::handshake();
+        let packets: Vec<Bytes> = (0..20u8)
+            .map(|i| client.make_notification(&[i; 10]).unwrap().pack())
+            .collect();
+        let mtu = (packets[0].len() + COALESCED_PREFIX_SIZE) * 8;
+        let datagrams = coalesce(&packets, mtu).unwrap();
+        assert_eq!(datagrams.len(), 3);
+        assert!(datagrams.iter().all(|d| d.len() <= mtu));
+
+        let mut received = Vec::new();
+        for datagram in &datagrams {
+            received.extend(split_coalesced(datagram).unwrap());
+        }
+        assert_eq!(received, packets);
+        let frame = Frame::from_slice(&received[19]).unwrap();
+        assert_eq!(server.read_msg(&frame).unwrap().as_ref(), &[19; 10]);
+
+        // Truncated datagram.
+        let broken = datagrams[0].slice(0, datagrams[0].len() - 1);
+        assert!(split_coalesced(&broken).is_err());
+
+        // Too long for the prefix.
+        let huge = Bytes::from(vec![0; u16::MAX as usize + 1]);
+        match coalesce(&[huge], mtu) {
+            Err(WhisperError::BadFrame) => {}
+            other => panic!("Expected BadFrame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mode_from_slice() {
+        assert_eq!(TransportMode::from_slice(&[1]), Some(TransportMode::Datagram));
+        assert_eq!(TransportMode::from_slice(&[0]), Some(TransportMode::Stream));
+        assert!(TransportMode::from_slice(&[2]).is_none());
+        assert!(TransportMode::from_slice(&[]).is_none());
+    }
+}
@@ -0,0 +1,370 @@
+//! Known answer vectors for implementations in other languages. All four
+//! keypairs and every nonce are fixed, so `transcript` gives the same
+//! frames on every run and implementation that follows the wire format
+//! has to produce them byte for byte from the same inputs.
+//!
+//! Transcript is a full handshake followed by a few messages:
+//! - `hello`, `welcome` and `initiate` with nonces `HELLO_NONCE`,
+//!   `WELCOME_NONCE` and `INITIATE_NONCE`; vouch inside Initiate uses
+//!   `VOUCH_NONCE`. Welcome carries only `metadata::TIMESTAMP` of
+//!   `TIMESTAMP_MILLIS`, Initiate no metadata.
+//! - `ready`, `request`, `response` and `notification` sealed with
+//!   direction keys and counter nonces (see `session::nonce_counter`),
+//!   server counter starting with Ready. Ready carries `READY_METADATA`, a tag no implementation
+//!   knows, which client must keep as is.
+//!
+//! `to_json` has keys, plaintexts and packed frames as hex for test suites
+//! of other implementations; `to_hex_lines` has just `name hex` lines.
+//! `verify_lines` checks lines in the same format made by other
+//! implementation. `whisper-vectors` binary does all three.
+//!
+//! Module isn't there with `null-cipher` feature: frames made with it
+//! aren't the real ones.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Bytes, BytesMut};
+use crypto::KeyPair;
+use encoding;
+use frame::{Frame, FrameKind};
+use import;
+use keyfile::KeyEncoding;
+use metadata::{self, Metadata};
+use session::{EstablishedSession, NULL_BYTES, READY_PAYLOAD, Side};
+use sodiumoxide::crypto::box_::{self, NONCEBYTES, Nonce, PublicKey, SecretKey};
+use std::fmt::{self, Write};
+
+/// Version of the transcript. Changes whenever frames change.
+pub const VECTORS_VERSION: u32 = 2;
+/// Secret key of client identity.
+pub const CLIENT_IDENTITY_SECRET: [u8; 32] = [0x11; 32];
+/// Secret key of client short term keypair.
+pub const CLIENT_SESSION_SECRET: [u8; 32] = [0x22; 32];
+/// Secret key of server identity.
+pub const SERVER_IDENTITY_SECRET: [u8; 32] = [0x33; 32];
+/// Secret key of server short term keypair.
+pub const SERVER_SESSION_SECRET: [u8; 32] = [0x44; 32];
+/// Nonce of Hello.
+pub const HELLO_NONCE: [u8; NONCEBYTES] = [0x01; NONCEBYTES];
+/// Nonce of Welcome.
+pub const WELCOME_NONCE: [u8; NONCEBYTES] = [0x02; NONCEBYTES];
+/// Nonce of vouch inside Initiate.
+pub const VOUCH_NONCE: [u8; NONCEBYTES] = [0x03; NONCEBYTES];
+/// Nonce of Initiate.
+pub const INITIATE_NONCE: [u8; NONCEBYTES] = [0x04; NONCEBYTES];
+/// Server clock in Welcome: 2017-07-14T02:40:00Z.
+pub const TIMESTAMP_MILLIS: i64 = 1_500_000_000_000;
+/// Tag and value of the only Ready metadata entry.
+pub const READY_METADATA: (u8, &[u8]) = (0xf0, b"vectors");
+
+/// One frame of the transcript.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vector {
+    /// Name of the vector, e.g. `hello`.
+    pub name: &'static str,
+    /// What was sealed into the payload.
+    pub plaintext: Bytes,
+    /// The frame.
+    pub frame: Frame,
+}
+
+/// Fixed keys and frames made with them.
+#[derive(Debug, Clone)]
+pub struct Transcript {
+    /// Client identity.
+    pub client_identity: KeyPair,
+    /// Client short term keypair. Its public key is the session id.
+    pub client_session: KeyPair,
+    /// Server identity.
+    pub server_identity: KeyPair,
+    /// Server short term keypair.
+    pub server_session: KeyPair,
+    /// Frames in the order they are sent.
+    pub vectors: Vec<Vector>,
+}
+
+/// Difference between transcript and output of other implementation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// Transcript has no vector with this name.
+    Unknown(String),
+    /// Vector wasn't checked.
+    Missing(&'static str),
+    /// Hex of this vector doesn't decode.
+    BadHex(String),
+    /// Frames differ starting at this byte.
+    Differs(&'static str, usize),
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Mismatch::Unknown(ref name) => write!(f, "{}: no such vector", name),
+            Mismatch::Missing(name) => write!(f, "{}: missing", name),
+            Mismatch::BadHex(ref name) => write!(f, "{}: bad hex", name),
+            Mismatch::Differs(name, offset) => write!(f, "{}: differs at byte {}", name, offset),
+        }
+    }
+}
+
+/// Build the transcript.
+pub fn transcript() -> Transcript {
+    let client_identity = import::x25519_keypair(&CLIENT_IDENTITY_SECRET);
+    let client_session = import::x25519_keypair(&CLIENT_SESSION_SECRET);
+    let server_identity = import::x25519_keypair(&SERVER_IDENTITY_SECRET);
+    let server_session = import::x25519_keypair(&SERVER_SESSION_SECRET);
+    let id = client_session.public_key;
+    let mut vectors = Vec::new();
+
+    let mut sealed = |name: &'static str,
+                      kind: FrameKind,
+                      nonce: [u8; NONCEBYTES],
+                      plaintext: Bytes,
+                      to: &PublicKey,
+                      by: &SecretKey| {
+        let nonce = Nonce(nonce);
+        let payload = box_::seal(&plaintext, &nonce, to, by);
+        vectors.push(Vector {
+                         name,
+                         plaintext,
+                         frame: Frame {
+                             id,
+                             nonce,
+                             kind,
+                             payload: payload.into(),
+                         },
+                     });
+    };
+    sealed("hello",
+           FrameKind::Hello,
+           HELLO_NONCE,
+           Bytes::from(&NULL_BYTES[..]),
+           &server_identity.public_key,
+           &client_session.secret_key);
+
+    let mut welcome = BytesMut::new();
+    welcome.extend_from_slice(&server_session.public_key.0);
+    let mut welcome_metadata = Metadata::new();
+    let mut timestamp = [0; 8];
+    BigEndian::write_i64(&mut timestamp, TIMESTAMP_MILLIS);
+    welcome_metadata.insert(metadata::TIMESTAMP, &timestamp[..]);
+    welcome_metadata.encode(&mut welcome);
+    sealed("welcome",
+           FrameKind::Welcome,
+           WELCOME_NONCE,
+           welcome.freeze(),
+           &client_session.public_key,
+           &server_identity.secret_key);
+
+    let mut initiate = BytesMut::new();
+    initiate.extend_from_slice(&client_identity.public_key.0);
+    initiate.extend_from_slice(&VOUCH_NONCE);
+    initiate.extend_from_slice(&box_::seal(&client_session.public_key.0,
+                                           &Nonce(VOUCH_NONCE),
+                                           &server_session.public_key,
+                                           &client_identity.secret_key));
+    // No metadata.
+    initiate.extend_from_slice(&[0, 0]);
+    sealed("initiate",
+           FrameKind::Initiate,
+           INITIATE_NONCE,
+           initiate.freeze(),
+           &server_session.public_key,
+           &client_session.secret_key);
+
+    let client = EstablishedSession::new(server_session.public_key,
+                                         client_session.clone(),
+                                         Side::Client);
+    let server = EstablishedSession::new(id, server_session.clone(), Side::Server);
+    let mut ready = BytesMut::new();
+    ready.extend_from_slice(READY_PAYLOAD);
+    let mut ready_metadata = Metadata::new();
+    ready_metadata.insert(READY_METADATA.0, READY_METADATA.1);
+    ready_metadata.encode(&mut ready);
+    let ready = ready.freeze();
+    let (nonce, payload) = server.seal_msg(&ready);
+    vectors.push(Vector {
+                     name: "ready",
+                     plaintext: ready,
+                     frame: Frame {
+                         id,
+                         nonce,
+                         kind: FrameKind::Ready,
+                         payload,
+                     },
+                 });
+    let messages: [(&'static str, &EstablishedSession, FrameKind, &[u8]); 3] =
+        [("request", &client, FrameKind::Request, b"ping"),
+         ("response", &server, FrameKind::Response, b"pong"),
+         ("notification", &client, FrameKind::Notification, b"hello from client")];
+    for &(name, session, kind, plaintext) in &messages {
+        let frame = match kind {
+            FrameKind::Request => session.make_request(plaintext),
+            FrameKind::Response => session.make_response(plaintext),
+            _ => session.make_notification(plaintext),
+        };
+        vectors.push(Vector {
+                         name,
+                         plaintext: Bytes::from(plaintext),
+                         frame: frame.expect("Fresh session can make messages"),
+                     });
+    }
+
+    Transcript {
+        client_identity,
+        client_session,
+        server_identity,
+        server_session,
+        vectors,
+    }
+}
+
+impl Transcript {
+    /// Vector by name.
+    pub fn get(&self, name: &str) -> Option<&Vector> {
+        self.vectors.iter().find(|vector| vector.name == name)
+    }
+
+    /// Keys, plaintexts and packed frames as JSON. Bytes are lowercase hex.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        json.push_str("{\n");
+        let _ = writeln!(json, "  \"version\": {},", VECTORS_VERSION);
+        let _ = writeln!(json, "  \"timestamp_millis\": {},", TIMESTAMP_MILLIS);
+        json.push_str("  \"keys\": {\n");
+        let keys = [("client_identity", &self.client_identity),
+                    ("client_session", &self.client_session),
+                    ("server_identity", &self.server_identity),
+                    ("server_session", &self.server_session)];
+        for (i, &(name, keypair)) in keys.iter().enumerate() {
+            let _ = write!(json,
+                           "    \"{}\": {{\"secret\": \"{}\", \"public\": \"{}\"}}",
+                           name,
+                           encoding::hex(&keypair.secret_key.0),
+                           keypair.public_key.to_hex());
+            json.push_str(if i + 1 < keys.len() { ",\n" } else { "\n" });
+        }
+        json.push_str("  },\n");
+        json.push_str("  \"frames\": [\n");
+        for (i, vector) in self.vectors.iter().enumerate() {
+            let _ = write!(json,
+                           "    {{\"name\": \"{}\", \"kind\": {}, \"nonce\": \"{}\", \
+                            \"plaintext\": \"{}\", \"packed\": \"{}\"}}",
+                           vector.name,
+                           vector.frame.kind as u8,
+                           encoding::hex(&vector.frame.nonce.0),
+                           encoding::hex(&vector.plaintext),
+                           encoding::hex(&vector.frame.pack()));
+            json.push_str(if i + 1 < self.vectors.len() { ",\n" } else { "\n" });
+        }
+        json.push_str("  ]\n");
+        json.push_str("}\n");
+        json
+    }
+
+    /// Packed frames as `name hex` lines.
+    pub fn to_hex_lines(&self) -> String {
+        let mut lines = String::new();
+        for vector in &self.vectors {
+            let _ = writeln!(lines, "{} {}", vector.name, encoding::hex(&vector.frame.pack()));
+        }
+        lines
+    }
+
+    /// Compare packed frame made by other implementation with vector.
+    pub fn verify(&self, name: &str, packed: &[u8]) -> Result<(), Mismatch> {
+        let vector = self.get(name).ok_or_else(|| Mismatch::Unknown(name.to_owned()))?;
+        let expected = vector.frame.pack();
+        if expected.as_ref() == packed {
+            return Ok(());
+        }
+        let offset = expected.iter()
+                             .zip(packed)
+                             .position(|(a, b)| a != b)
+                             .unwrap_or_else(|| expected.len().min(packed.len()));
+        Err(Mismatch::Differs(vector.name, offset))
+    }
+
+    /// Check `name hex` lines made by other implementation. Empty lines and
+    /// lines starting with `#` are skipped. Returns every mismatch, vectors
+    /// that aren't in the text included; empty means all is well.
+    pub fn verify_lines(&self, text: &str) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        let mut seen = Vec::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let name = parts.next().unwrap_or("");
+            seen.push(name.to_owned());
+            match encoding::from_hex(parts.next().unwrap_or("").trim()) {
+                Ok(packed) => {
+                    if let Err(mismatch) = self.verify(name, &packed) {
+                        mismatches.push(mismatch);
+                    }
+                }
+                Err(_) => mismatches.push(Mismatch::BadHex(name.to_owned())),
+            }
+        }
+        for vector in &self.vectors {
+            if !seen.iter().any(|name| name == vector.name) {
+                mismatches.push(Mismatch::Missing(vector.name));
+            }
+        }
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use clock::MockClock;
+    use session::{ClientSession, ServerSession};
+    use std::sync::Arc;
+
+    #[test]
+    fn transcript_is_fixed_and_valid() {
+        let transcript = transcript();
+        assert_eq!(transcript.to_json(), super::transcript().to_json());
+        assert!(transcript.verify_lines(&transcript.to_hex_lines()).is_empty());
+
+        let frame = |name| transcript.get(name).unwrap().frame.clone();
+        let mut server = ServerSession::with_session_keypair(transcript.server_identity.clone(),
+                                                             transcript.server_session.clone(),
+                                                             transcript.client_session
+                                                                       .public_key);
+        server.make_welcome(&frame("hello")).unwrap();
+        let client_identity = server.validate_initiate(&frame("initiate")).unwrap();
+        assert_eq!(client_identity, transcript.client_identity.public_key);
+        let (established, _) = server.make_ready(&frame("initiate"), &client_identity).unwrap();
+        assert_eq!(established.read_msg(&frame("request")).unwrap().as_ref(), b"ping");
+
+        let mut client = ClientSession::with_session_keypair(transcript.client_identity.clone(),
+                                                             transcript.client_session.clone(),
+                                                             transcript.server_identity
+                                                                       .public_key)
+            .unwrap();
+        let now = Utc.timestamp_opt(TIMESTAMP_MILLIS / 1000, 0).unwrap();
+        client.set_clock(Arc::new(MockClock::new(now)));
+        client.make_hello().unwrap();
+        client.make_initiate(&frame("welcome")).unwrap();
+        let established = client.read_ready(&frame("ready")).unwrap();
+        assert_eq!(client.ready_metadata().get(READY_METADATA.0).map(|v| v.as_ref()),
+                   Some(READY_METADATA.1));
+        assert_eq!(established.read_msg(&frame("response")).unwrap().as_ref(), b"pong");
+
+        let mut ready = frame("ready").pack().to_vec();
+        let last = ready.len() - 1;
+        ready[last] ^= 1;
+        let mut lines: String = transcript.to_hex_lines()
+                                          .lines()
+                                          .filter(|line| !line.starts_with("ready "))
+                                          .map(|line| format!("{}\n", line))
+                                          .collect();
+        lines.push_str(&format!("ready {}\nbogus 00\n", encoding::hex(&ready)));
+        let mismatches = transcript.verify_lines(&lines);
+        assert_eq!(mismatches,
+                   vec![Mismatch::Differs("ready", last), Mismatch::Unknown("bogus".to_owned())]);
+    }
+}
@@ -0,0 +1,172 @@
+//! Signed vouch. Regular vouch is a box from client identity key to
+//! server's short term key: server learns the client owns that key, but
+//! can't prove it to anyone else, since it could have made the box itself.
+//! Client that also has an Ed25519 `SigningIdentity` signs its short term
+//! key together with the server's short term and identity keys, and sends
+//! the signature in Initiate metadata (tag `metadata::SIGNED_VOUCH`).
+//! Anyone holding the Initiate transcript can check that signature, and
+//! servers can keep signing keys as the only identity of their users (see
+//! `ServerSession::signed_identity`), with a throwaway box identity key on
+//! the client.
+//!
+//! Signed vouch is signing public key (32 bytes) followed by signature (64
+//! bytes). Signature covers client short term key, server short term key,
+//! server identity key and client box identity key, so it can't be moved to
+//! another session, another server or another client.
+
+use crypto;
+use errors::{WhisperError, WhisperResult};
+use sodiumoxide::crypto::box_::PublicKey;
+use sodiumoxide::crypto::sign;
+use std::fmt;
+
+/// Size of encoded signed vouch.
+pub const SIGNED_VOUCH_SIZE: usize = 32 + sign::SIGNATUREBYTES;
+
+// So signature can't be confused with signatures made for other purposes.
+const CONTEXT: &[u8] = b"whisper signed vouch";
+
+/// Ed25519 key client signs its vouch with. `Debug` shows public key only.
+#[derive(Clone)]
+pub struct SigningIdentity {
+    /// Public key. What server knows the client by.
+    pub public_key: sign::PublicKey,
+    /// Secret key.
+    pub secret_key: sign::SecretKey,
+}
+
+impl SigningIdentity {
+    /// Generate new signing key. Fails with `InitializationFailed` if
+    /// libsodium can't be initialized.
+    pub fn new() -> WhisperResult<SigningIdentity> {
+        crypto::init()?;
+        let (public_key, secret_key) = sign::gen_keypair();
+        Ok(SigningIdentity {
+               public_key,
+               secret_key,
+           })
+    }
+
+    /// Sign vouch for this handshake.
+    pub fn vouch(&self, keys: &VouchKeys) -> SignedVouch {
+        SignedVouch {
+            identity: self.public_key,
+            signature: sign::sign_detached(&keys.signed_message(), &self.secret_key),
+        }
+    }
+}
+
+impl fmt::Debug for SigningIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SigningIdentity")
+         .field("public_key", &self.public_key)
+         .field("secret_key", &format_args!("<redacted>"))
+         .finish()
+    }
+}
+
+/// Keys of one handshake that signed vouch covers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VouchKeys {
+    /// Client short term key.
+    pub client_session_key: PublicKey,
+    /// Server short term key.
+    pub server_session_key: PublicKey,
+    /// Server identity key.
+    pub server_identity_key: PublicKey,
+    /// Client box identity key, the one regular vouch is made with.
+    pub client_identity_key: PublicKey,
+}
+
+impl VouchKeys {
+    fn signed_message(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(CONTEXT.len() + 128);
+        message.extend_from_slice(CONTEXT);
+        message.extend_from_slice(&self.client_session_key.0);
+        message.extend_from_slice(&self.server_session_key.0);
+        message.extend_from_slice(&self.server_identity_key.0);
+        message.extend_from_slice(&self.client_identity_key.0);
+        message
+    }
+}
+
+/// Client's signature of handshake keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedVouch {
+    /// Key that signed it.
+    pub identity: sign::PublicKey,
+    signature: sign::Signature,
+}
+
+impl SignedVouch {
+    /// Encode vouch.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(SIGNED_VOUCH_SIZE);
+        buf.extend_from_slice(&self.identity.0);
+        buf.extend_from_slice(&self.signature.0);
+        buf
+    }
+
+    /// Decode vouch. Signature isn't checked here, see `verify`.
+    pub fn from_bytes(bytes: &[u8]) -> WhisperResult<SignedVouch> {
+        if bytes.len() != SIGNED_VOUCH_SIZE {
+            return Err(WhisperError::BadFrame);
+        }
+        Ok(SignedVouch {
+               identity: sign::PublicKey::from_slice(&bytes[..32])
+                   .ok_or(WhisperError::BadFrame)?,
+               signature: sign::Signature::from_slice(&bytes[32..])
+                   .ok_or(WhisperError::BadFrame)?,
+           })
+    }
+
+    /// Returns true if identity really signed these keys.
+    pub fn verify(&self, keys: &VouchKeys) -> bool {
+        sign::verify_detached(&self.signature, &keys.signed_message(), &self.identity)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::KeyPair;
+    use session::{ClientSession, ServerSession, Session};
+
+    #[test]
+    fn signed_vouch_binds_handshake() {
+        let signer = SigningIdentity::new().unwrap();
+        let keys = VouchKeys {
+            client_session_key: KeyPair::new().unwrap().public_key,
+            server_session_key: KeyPair::new().unwrap().public_key,
+            server_identity_key: KeyPair::new().unwrap().public_key,
+            client_identity_key: KeyPair::new().unwrap().public_key,
+        };
+        let vouch = signer.vouch(&keys);
+        let decoded = SignedVouch::from_bytes(&vouch.to_bytes()).unwrap();
+        assert_eq!(decoded, vouch);
+        assert!(decoded.verify(&keys));
+        let other_server =
+            VouchKeys { server_identity_key: KeyPair::new().unwrap().public_key, ..keys };
+        assert!(!decoded.verify(&other_server));
+        assert!(format!("{:?}", signer).contains("secret_key: <redacted>"));
+
+        let server_identity = KeyPair::new().unwrap();
+        let mut client =
+            ClientSession::new(KeyPair::new().unwrap(), server_identity.public_key).unwrap();
+        client.set_signing_identity(signer.clone());
+        let mut server = ServerSession::new(server_identity.clone(), client.id()).unwrap();
+        let welcome = server.make_welcome(&client.make_hello().unwrap()).unwrap();
+        let initiate = client.make_initiate(&welcome).unwrap();
+        let opened = server.open_initiate(&initiate).unwrap();
+        assert_eq!(opened.signed_identity, Some(signer.public_key));
+        assert_eq!(server.signed_identity(&initiate).unwrap(), Some(signer.public_key));
+        assert!(server.make_ready(&initiate, &opened.client_identity_key).is_ok());
+
+        let mut plain =
+            ClientSession::new(KeyPair::new().unwrap(), server_identity.public_key).unwrap();
+        let mut server = ServerSession::new(server_identity, plain.id()).unwrap();
+        let welcome = server.make_welcome(&plain.make_hello().unwrap()).unwrap();
+        let initiate = plain.make_initiate(&welcome).unwrap();
+        assert_eq!(server.signed_identity(&initiate).unwrap(), None);
+    }
+}